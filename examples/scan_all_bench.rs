@@ -0,0 +1,59 @@
+//! Times `scan` of each root against `scan_all` with several threads, on a generated tree of many small files:
+//!
+//! ```text
+//! cargo run --release --example scan_all_bench [roots] [dirs per root] [threads]
+//! ```
+//!
+//! The second run benefits from the first one's page cache, so they're run in both orders.
+//! Drop caches before each run (`echo 3 > /proc/sys/vm/drop_caches`) to see the difference on a cold disk.
+extern crate duplicate_kriller;
+extern crate tempdir;
+
+use duplicate_kriller::Scanner;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+fn main() {
+    let args: Vec<usize> = env::args().skip(1).map(|arg| arg.parse().expect("numbers")).collect();
+    let roots = args.first().cloned().unwrap_or(4);
+    let dirs = args.get(1).cloned().unwrap_or(500);
+    let threads = args.get(2).cloned().unwrap_or(8);
+
+    let tmp = tempdir::TempDir::new("scanallbench").unwrap();
+    let roots: Vec<PathBuf> = (0..roots).map(|i| tmp.path().join(format!("root{}", i))).collect();
+    for root in &roots {
+        for d in 0..dirs {
+            let dir = root.join(format!("{}/{}", d % 16, d));
+            fs::create_dir_all(&dir).unwrap();
+            for f in 0..10 {
+                // Every tenth file is a duplicate across all roots
+                let content = if f == 0 {format!("dupe {}", d)} else {format!("{} {} {}", root.display(), d, f)};
+                fs::write(dir.join(f.to_string()), content).unwrap();
+            }
+        }
+    }
+
+    for &parallel_first in &[false, true] {
+        let (a, b) = if parallel_first {
+            let parallel = time(|d| d.scan_all(&roots, threads).unwrap());
+            (time(|d| for root in &roots { d.scan(root).unwrap(); }), parallel)
+        } else {
+            (time(|d| for root in &roots { d.scan(root).unwrap(); }), time(|d| d.scan_all(&roots, threads).unwrap()))
+        };
+        println!("scan of each root: {:?}, scan_all with {} threads: {:?}", a, threads, b);
+    }
+}
+
+/// In dry run, so that both see the same tree
+fn time<F: FnOnce(&mut Scanner)>(scan: F) -> Duration {
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    let start = Instant::now();
+    scan(&mut d);
+    let elapsed = start.elapsed();
+    assert!(d.stats().dupes > 0);
+    elapsed
+}
@@ -0,0 +1,95 @@
+use sha1::Sha1;
+use std::fs;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"DKBLOOM1";
+
+/// A plain bloom filter. It answers "definitely not in the set" or "maybe in the set".
+///
+/// Bit positions are derived from SHA-1 rather than `std` hashers,
+/// because the filter is saved to disk and must mean the same thing in the next run.
+#[derive(Debug)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// Sized for `items` entries at roughly 1% false positive rate
+    pub fn with_capacity(items: usize) -> Self {
+        // ~9.6 bits per item and 7 hashes give 1%
+        let words = (items.max(1) * 10).div_ceil(64);
+        BloomFilter {
+            bits: vec![0; words],
+            hashes: 7,
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        for bit in self.bit_positions(key) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.bit_positions(key).all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    fn bit_positions(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let mut sha1 = Sha1::new();
+        sha1.update(key);
+        let digest = sha1.digest().bytes();
+        let mut h1 = [0; 8];
+        let mut h2 = [0; 8];
+        h1.copy_from_slice(&digest[0..8]);
+        h2.copy_from_slice(&digest[8..16]);
+        let h1 = u64::from_le_bytes(h1);
+        let h2 = u64::from_le_bytes(h2);
+        let nbits = self.bits.len() as u64 * 64;
+        (0..u64::from(self.hashes)).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % nbits) as usize)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut file = BufReader::new(fs::File::open(path)?);
+        let mut magic = [0; 8];
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a bloom filter file"));
+        }
+        let mut word = [0; 8];
+        file.read_exact(&mut word)?;
+        let hashes = u64::from_le_bytes(word) as u32;
+        file.read_exact(&mut word)?;
+        let words = u64::from_le_bytes(word) as usize;
+        if hashes == 0 || words == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "empty bloom filter"));
+        }
+        let mut bits = Vec::with_capacity(words);
+        for _ in 0..words {
+            file.read_exact(&mut word)?;
+            bits.push(u64::from_le_bytes(word));
+        }
+        Ok(BloomFilter { bits, hashes })
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut file = BufWriter::new(fs::File::create(path)?);
+        file.write_all(MAGIC)?;
+        file.write_all(&u64::from(self.hashes).to_le_bytes())?;
+        file.write_all(&(self.bits.len() as u64).to_le_bytes())?;
+        for word in &self.bits {
+            file.write_all(&word.to_le_bytes())?;
+        }
+        file.flush()
+    }
+}
+
+#[test]
+fn bloom_roundtrip() {
+    let mut filter = BloomFilter::with_capacity(100);
+    filter.insert(b"hello");
+    assert!(filter.contains(b"hello"));
+    assert!(!filter.contains(b"world"));
+}
@@ -0,0 +1,156 @@
+use dupe::{DedupeMode, DigestCache, ScanListener, Scanner, Settings};
+use filesystem::FileSystem;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Configures a `Scanner` in one expression, e.g.
+///
+/// ```no_run
+/// # use duplicate_kriller::ScannerBuilder;
+/// let mut scanner = ScannerBuilder::new().dry_run(true).ignore_small(false).build();
+/// scanner.scan("/tmp").unwrap();
+/// ```
+///
+/// Defaults are the same as `Scanner::new`. Settings without a method of their own can be set with `configure`.
+#[derive(Debug)]
+pub struct ScannerBuilder {
+    settings: Settings,
+    listener: Option<Box<dyn ScanListener>>,
+    filesystem: Option<Box<dyn FileSystem>>,
+    digest_cache: Option<DigestCache>,
+}
+
+impl Default for ScannerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScannerBuilder {
+    pub fn new() -> Self {
+        ScannerBuilder {
+            settings: Scanner::new().settings,
+            listener: None,
+            filesystem: None,
+            digest_cache: None,
+        }
+    }
+
+    /// See `Settings::ignore_small`
+    pub fn ignore_small(mut self, ignore_small: bool) -> Self {
+        self.settings.ignore_small = ignore_small;
+        self
+    }
+
+    /// See `Settings::min_size`
+    pub fn min_size(mut self, min_size: u64) -> Self {
+        self.settings.min_size = Some(min_size);
+        self
+    }
+
+    /// See `Settings::dry_run`
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.settings.dry_run = dry_run;
+        self
+    }
+
+    /// See `Settings::verify_content`
+    pub fn verify_content(mut self, verify_content: bool) -> Self {
+        self.settings.verify_content = verify_content;
+        self
+    }
+
+    /// See `Settings::dry_run_verify`
+    pub fn dry_run_verify(mut self, dry_run_verify: bool) -> Self {
+        self.settings.dry_run_verify = dry_run_verify;
+        self
+    }
+
+    /// Adds a name to `Settings::follow_symlink_names`
+    pub fn follow_symlink_name<N: Into<OsString>>(mut self, name: N) -> Self {
+        self.settings.follow_symlink_names.insert(name.into());
+        self
+    }
+
+    /// See `Settings::script_output`
+    pub fn script_output<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.settings.script_output = Some(path.into());
+        self
+    }
+
+    /// See `Settings::read_timeout`
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.read_timeout = Some(timeout);
+        self
+    }
+
+    /// See `Settings::mode_chain`
+    pub fn mode_chain(mut self, modes: &[DedupeMode]) -> Self {
+        self.settings.mode_chain = modes.to_vec();
+        self
+    }
+
+    /// See `Settings::stable_order`
+    pub fn stable_order(mut self, stable_order: bool) -> Self {
+        self.settings.stable_order = stable_order;
+        self
+    }
+
+    /// See `Settings::max_ops_per_sec`
+    pub fn max_ops_per_sec(mut self, ops: u32) -> Self {
+        self.settings.max_ops_per_sec = Some(ops);
+        self
+    }
+
+    /// See `Settings::max_duration`
+    pub fn max_duration(mut self, duration: Duration) -> Self {
+        self.settings.max_duration = Some(duration);
+        self
+    }
+
+    /// Turns on all safety guards, see `Settings::make_paranoid`
+    pub fn paranoid(mut self) -> Self {
+        self.settings.make_paranoid();
+        self
+    }
+
+    /// Changes any of the settings, e.g. `.configure(|s| s.hash_only = true)`
+    pub fn configure<F: FnOnce(&mut Settings)>(mut self, configure: F) -> Self {
+        configure(&mut self.settings);
+        self
+    }
+
+    /// See `Scanner::set_listener`
+    pub fn listener(mut self, listener: Box<dyn ScanListener>) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    /// See `Scanner::set_filesystem`
+    pub fn filesystem(mut self, filesystem: Box<dyn FileSystem>) -> Self {
+        self.filesystem = Some(filesystem);
+        self
+    }
+
+    /// See `Scanner::with_digest_cache`
+    pub fn digest_cache(mut self, cache: DigestCache) -> Self {
+        self.digest_cache = Some(cache);
+        self
+    }
+
+    pub fn build(self) -> Scanner {
+        let mut scanner = match self.digest_cache {
+            Some(cache) => Scanner::with_digest_cache(cache),
+            None => Scanner::new(),
+        };
+        scanner.settings = self.settings;
+        if let Some(listener) = self.listener {
+            scanner.set_listener(listener);
+        }
+        if let Some(filesystem) = self.filesystem {
+            scanner.set_filesystem(filesystem);
+        }
+        scanner
+    }
+}
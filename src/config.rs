@@ -0,0 +1,30 @@
+//! Fields of `Settings` in config files that serde wouldn't read from plain values
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A list of strings, rather than serde's platform-specific representation of `OsString`
+pub fn os_string_set<'de, D: Deserializer<'de>>(d: D) -> Result<HashSet<OsString>, D::Error> {
+    let names: Vec<String> = Vec::deserialize(d)?;
+    Ok(names.into_iter().map(OsString::from).collect())
+}
+
+/// Seconds, which can have a fraction, rather than `{secs, nanos}`
+pub fn secs<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+    Duration::try_from_secs_f64(f64::deserialize(d)?).map_err(D::Error::custom)
+}
+
+/// Like `secs`, or null
+pub fn opt_secs<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    match Option::<f64>::deserialize(d)? {
+        Some(secs) => Duration::try_from_secs_f64(secs).map(Some).map_err(D::Error::custom),
+        None => Ok(None),
+    }
+}
+
+/// Seconds since the epoch, or null
+pub fn opt_unix_time<'de, D: Deserializer<'de>>(d: D) -> Result<Option<SystemTime>, D::Error> {
+    Ok(opt_secs(d)?.map(|since_epoch| UNIX_EPOCH + since_epoch))
+}
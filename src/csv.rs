@@ -0,0 +1,24 @@
+use std::io;
+use std::io::Write;
+
+/// Writes one RFC 4180 row. Fields with commas, quotes or newlines are quoted.
+pub fn write_row<W: Write>(w: &mut W, fields: &[&str]) -> io::Result<()> {
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            w.write_all(b",")?;
+        }
+        if field.contains([',', '"', '\n', '\r']) {
+            write!(w, "\"{}\"", field.replace('"', "\"\""))?;
+        } else {
+            w.write_all(field.as_bytes())?;
+        }
+    }
+    w.write_all(b"\r\n")
+}
+
+#[test]
+fn escaping() {
+    let mut out = Vec::new();
+    write_row(&mut out, &["1", "/a,b/\"c\"", "plain"]).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "1,\"/a,b/\"\"c\"\"\",plain\r\n");
+}
@@ -0,0 +1,17 @@
+use std::io;
+use std::io::Write;
+
+/// A Graphviz DOT string with quotes and backslashes escaped
+pub fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"))
+}
+
+/// Writes a node (`n<id>`) with the label
+pub fn write_node<W: Write>(w: &mut W, id: usize, label: &str, master: bool) -> io::Result<()> {
+    writeln!(w, "    n{} [label={}{}];", id, quote(label), if master {", shape=box"} else {""})
+}
+
+#[test]
+fn quoting() {
+    assert_eq!(quote("a \"b\"\\c\nd"), "\"a \\\"b\\\"\\\\c\\nd\"");
+}
@@ -1,245 +1,4697 @@
 use std::fs;
 use std::io;
-use file::{FileContent, FileSet};
+use std::io::{BufRead, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::mem;
+use file::{ContentDigest, ContentNormalizer, ContentOptions, FileContent, FileSet, DEFAULT_MAX_READ_SIZE};
 use std::path::{Path, PathBuf};
+use builder::ScannerBuilder;
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::collections::BinaryHeap;
+use std::collections::VecDeque;
 use metadata::Metadata;
-use std::rc::Rc;
-use std::sync::Mutex;
-use std::os::unix::fs::MetadataExt;
+use hasher::full_digest;
+use script::ScriptWriter;
+use bloom::BloomFilter;
+#[cfg(feature = "json")]
+use config;
+use csv;
+use dot;
+use glob::Glob;
+use gzip;
+use incremental::DirMtimes;
+use journal::{self, Journal, JournalRecovery};
+use hashcache::HashCache;
+use near;
+use prefix;
+use sha256::{self, Sha256};
+use ssdeep;
+use plan::{DryRunReport, PendingMerge, PlannedLink};
+use rebase::{self, RebasedListener};
+use error::DupeError;
+use probe;
+use probe::FsCapabilities;
+use sys;
+use tar;
+use temp;
+use subtree;
+use verify;
+use shared::{Shared, MaybeSend};
+use filesystem::{FileSystem, StdFileSystem};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering as AtomicOrdering};
+use std::os::unix::fs::{fchown, FileTypeExt, MetadataExt, PermissionsExt};
 use std::collections::hash_map::Entry as HashEntry;
 use std::collections::btree_map::Entry as BTreeEntry;
+use std::fmt;
+use std::cmp::Reverse;
 use std::fmt::Debug;
-use std::time::{Duration,Instant};
+use std::time::{Duration,Instant,SystemTime,UNIX_EPOCH};
+use std::thread;
+use std::ffi::{OsStr, OsString};
+use std::str;
 
-#[derive(Debug)]
+/// A set of paths of one inode, shared between the scanner's indexes (see `Scanner::content_groups`)
+pub type SharedFileSet = Shared<Mutex<FileSet>>;
+
+/// See `Settings::choose_master`
+#[cfg(not(feature = "sync"))]
+pub type MasterChooser = Box<dyn FnMut(&[FileSet]) -> usize>;
+/// See `Settings::choose_master`
+#[cfg(feature = "sync")]
+pub type MasterChooser = Box<dyn FnMut(&[FileSet]) -> usize + Send>;
+
+/// See `SourcePolicy::Custom`
+#[cfg(not(feature = "sync"))]
+pub type PathPicker = Box<dyn Fn(&[PathBuf]) -> usize>;
+/// See `SourcePolicy::Custom`
+#[cfg(feature = "sync")]
+pub type PathPicker = Box<dyn Fn(&[PathBuf]) -> usize + Send>;
+
+/// See `Settings::equality_oracle`
+#[cfg(not(feature = "sync"))]
+pub type EqualityOracle = Box<dyn Fn(&Path, &Path) -> io::Result<bool>>;
+/// See `Settings::equality_oracle`
+#[cfg(feature = "sync")]
+pub type EqualityOracle = Box<dyn Fn(&Path, &Path) -> io::Result<bool> + Send>;
+
+/// Known hashes of files by size and path, see `Scanner::with_digest_cache`
+pub type DigestCache = HashMap<(u64, PathBuf), ContentDigest>;
+
+/// Throughput for the ETA of `ScanListener::bytes_read_update` is taken over this long
+const ETA_WINDOW: Duration = Duration::from_secs(30);
+
+/// What the scanner does, and how.
+///
+/// With the `json` feature it can be deserialized, e.g. from a config file, with fields named as here.
+/// Missing fields are the defaults, and unknown fields are errors. Durations are seconds (with a fraction),
+/// file names and extensions are strings, `protected_inodes` are `[device, inode]` pairs, and enums are
+/// variant names, e.g. `"Skip"`. The callbacks `choose_master`, `content_normalizer` and `equality_oracle`
+/// can only be set in code.
+#[cfg_attr(feature = "json", derive(Deserialize))]
+#[cfg_attr(feature = "json", serde(default, deny_unknown_fields))]
 pub struct Settings {
     // Ignore files smaller than a filesystem block.
     // Deduping of such files is unlikely to save space.
     pub ignore_small: bool,
+    /// Size below which `ignore_small` skips files, instead of the filesystem's block size,
+    /// e.g. 512 bytes for lots of small configs, or 1 MiB to touch fewer inodes. Empty files are always skipped.
+    pub min_size: Option<u64>,
+    /// Extensions (lowercase, without the dot) of files that are deduped even if `ignore_small` would skip them,
+    /// e.g. lots of tiny identical configs. Extensions of files are compared ASCII-lowercased.
+    #[cfg_attr(feature = "json", serde(deserialize_with = "config::os_string_set"))]
+    pub always_consider_extensions: HashSet<OsString>,
     pub dry_run: bool,
+    /// Only plan links while scanning, like `dry_run`, and make them all (like `apply_plan`) at the end of a `flush`
+    /// that scanned everything queued, so the scan only reads. A scan stopped before that, e.g. by `max_duration`,
+    /// hasn't changed anything yet, and its links are made at the end of the `flush` that finishes it.
+    /// Files that changed in the meantime are skipped and counted in `Stats::plan_skipped`.
+    pub defer_links: bool,
+    /// Symlinks with these file names (or pointing to targets with these names) are followed
+    /// instead of being skipped, e.g. `latest` pointers in backup trees.
+    #[cfg_attr(feature = "json", serde(deserialize_with = "config::os_string_set"))]
+    pub follow_symlink_names: HashSet<OsString>,
+    /// Follow all symlinks, like `follow_symlink_names` does for some. Their targets are added even if they're outside
+    /// the roots (unless `confine_to_roots`), once each. Symlinks to their own ancestors, to targets followed before,
+    /// or to directories listed already are skipped, so that loops end.
+    pub follow_symlinks: bool,
+    /// In dry run, write a shell script with the commands that would have been run to this path
+    pub script_output: Option<PathBuf>,
+    /// Append a record of each replacement to this file, synced to disk before the file is touched, and one of
+    /// its outcome after, so that `Scanner::recover_journal` can clean up after a crash
+    pub journal: Option<PathBuf>,
+    /// Bloom filter of files that were unique at the end of the previous scan. It's read before and written after the scan.
+    /// Files that are unchanged (same inode, size and mtime) and still in the filter aren't compared with each other,
+    /// only with new or changed files.
+    ///
+    /// A false positive of the filter can't cause wrong links (linking always needs a content match),
+    /// but it can make the scanner miss a duplicate between two unchanged files.
+    pub unique_bloom: Option<PathBuf>,
+    /// Picks the master (the file others are linked to) instead of the default of the most hardlinked one.
+    /// It gets non-empty filesets of a content group every time the group grows, and returns index of the master.
+    /// Code only, it's not read from configs.
+    #[cfg_attr(feature = "json", serde(skip))]
+    pub choose_master: Option<MasterChooser>,
+    /// Give up on files that take longer than this to read (each read call), e.g. on failing disks.
+    /// Such files are skipped, and the scan continues.
+    #[cfg_attr(feature = "json", serde(deserialize_with = "config::opt_secs"))]
+    pub read_timeout: Option<Duration>,
+    /// Before linking anything on a filesystem, try out linking and renaming, the way it's done for duplicates,
+    /// with scratch files in the directory of the first master (see `Scanner::probe_filesystem`).
+    /// If it doesn't work, the scan fails with `DupeError::PreflightFailed` before any file is touched.
+    /// The scratch files are removed either way. It's done once per device.
+    pub preflight: bool,
+    /// Fail on the first error, including those that are otherwise only recorded in `Scanner::errors`
+    /// (unreadable directories and files, files that can't be stat-ed, paths outside the roots, etc.), for CI jobs
+    /// that should fail on any problem. The error has the path in its message. Links made until then stay.
+    pub strict: bool,
+    /// Extended attribute (e.g. `"user.dupe.sha1"`) with a hash of the file's content, which is used instead of reading it
+    /// if the file's mtime is still the one the hash was taken at. Files are then compared by hashes of their whole
+    /// content, like with `Scanner::with_digest_cache`, and files without it are read in full when compared.
+    /// The value is `<mtime seconds>.<nanoseconds> <SHA-1 in hex>`, as written with `write_hash_xattr`
+    /// (see `ContentDigest::to_xattr`). Values in other formats are ignored. It's Linux only.
+    pub hash_xattr: Option<String>,
+    /// Store hashes computed for comparisons in `hash_xattr`, for the next scan. Files that can't be written
+    /// (or whose filesystem doesn't support extended attributes) don't get one. It changes their ctime.
+    pub write_hash_xattr: bool,
+    /// Report files whose reads for comparisons took at least this long in total, e.g. on bad sectors
+    /// or slow network mounts (see `ScanListener::slow_file` and `Scanner::slow_files`)
+    #[cfg_attr(feature = "json", serde(deserialize_with = "config::opt_secs"))]
+    pub slow_file_threshold: Option<Duration>,
+    /// What to do with files that have the immutable attribute (`chattr +i`), which makes linking fail
+    pub on_immutable: OnImmutable,
+    /// What to do with duplicates that turn out to be on another filesystem than their master. Default is `Error`.
+    pub cross_fs: CrossFsPolicy,
+    /// Which mtime the master ends up with when duplicates are hardlinked to it. Default is `Keep`.
+    pub mtime_policy: MtimePolicy,
+    /// Prefer filesets with a path under this directory as the master, so that links point into it.
+    /// Scanned paths are canonical, so this should be too. Ignored when `choose_master` is set.
+    pub master_path_prefix: Option<PathBuf>,
+    /// Paths given to the listener, and in `write_csv` and `write_manifest`, are relative to this directory
+    /// (or absolute outside of it), so that reports from other machines or mounts can be compared.
+    /// Scanned paths are canonical, so this should be too. Everything else still uses absolute paths.
+    pub report_base: Option<PathBuf>,
+    /// Record at most this many paths of an inode found by scanning. Further ones are only counted
+    /// (see `FileSet::untracked_paths`), which bounds memory on trees with huge numbers of hardlinks.
+    /// Reports then show only some of the paths, and `write_csv` a `+N more` row for the rest.
+    /// Untracked paths aren't relinked when their inode is replaced, so they keep the old inode,
+    /// and aren't recognized as aliases (see `Stats::path_aliases`) either.
+    pub max_tracked_paths_per_inode: Option<usize>,
+    /// Compare files starting from their ends, which finds differences sooner in logs and other appended-to files.
+    /// It can't be changed once files have been added.
+    pub compare_from_tail: bool,
+    /// Compare the last 4KB of files right after the first 4KB (or the other way around with `compare_from_tail`),
+    /// before reading more of them. Files of formats with fixed headers, e.g. media, differ there sooner.
+    /// It only changes the order of reads. It can't be changed once files have been added. Default is on.
+    pub quick_check: bool,
+    /// Skip files not owned by the effective user, which is unlikely to have permission to replace them anyway
+    pub only_own_files: bool,
+    /// Reflink instead of hardlinking duplicates owned by another user than the master, so that each owner
+    /// keeps a copy of their own (with its owner, group and permissions) that shares storage until it's written to.
+    /// It's for caches shared by users. `mode_chain` isn't used for them, so where reflinks aren't supported
+    /// (or the owner can't be set, which usually needs root) replacing them fails.
+    pub cow_across_owners: bool,
+    /// Link files even if their permissions, owner or group differ. Linked files share them (and a reflink or symlink
+    /// gets the master's), so by default such duplicates are left alone, and reported with
+    /// `ScanListener::permission_mismatch`. With `cow_across_owners` only permissions have to match.
+    pub ignore_permissions: bool,
+    /// Prefer the oldest file (by birth time) as the master, to keep the original copy.
+    /// Ignored when `choose_master` is set.
+    ///
+    /// Where the filesystem doesn't record birth times (e.g. some network filesystems, or older kernels without `statx`),
+    /// the mtime is used instead. Copying usually sets the mtime to the time of the copy, so the pick is
+    /// only as good as the tool that copied the files. Files with neither are picked last.
+    pub master_by_creation_time: bool,
+    /// Which file of a group is the master, i.e. whose inode is kept. `keep_roots` and `master_path_prefix`
+    /// still come first. Ignored when `choose_master` is set.
+    pub source_policy: SourcePolicy,
+    /// Link at most this many paths to the master each time a duplicate is found, to bound the work done at once
+    /// for files with a huge number of copies. The rest stay in their own fileset (and are reported as such),
+    /// and are linked next time the group gets a new duplicate.
+    pub max_group_members: Option<usize>,
+    /// Once the master has this many links, the remaining duplicates are linked to a new master (one of them)
+    /// instead, so that there are several inodes with a moderate number of links rather than one with a huge number,
+    /// which some backup tools and `fsck` are slow with. Links of the master outside the scanned roots count too.
+    pub max_links_per_master: Option<u64>,
+    /// If linking a member of a group fails, undo the links already made in the group, so that it's either
+    /// deduped in full or not at all. Each of them is replaced with a new copy of the master's content
+    /// (with its old permissions, owner and mtime), so undoing takes the disk space and time of copying them.
+    /// Members that were hardlinks of each other before become separate copies, extended attributes and
+    /// immutable flags aren't restored, and a failure while undoing (e.g. the disk is full) leaves the group half linked.
+    /// The counters and listener events of the undone links aren't taken back, see `Stats::rolled_back`.
+    pub atomic_groups: bool,
+    /// Leave groups alone until linking them would free at least this many bytes (file size times inodes merged).
+    /// Avoids churn for lots of tiny files. Groups are checked again whenever they grow.
+    pub min_savings_per_group: u64,
+    /// How often `ScanListener::bytes_read_update` is called at most
+    #[cfg_attr(feature = "json", serde(deserialize_with = "config::secs"))]
+    pub progress_interval: Duration,
+    /// Whether to check that each link really made the paths share the inode
+    pub on_link_anomaly: OnLinkAnomaly,
+    /// `(dev, ino)` of files that must never be replaced. They can still be the master others are linked to.
+    /// Inodes stay the same when files are renamed. See `protect_path`.
+    pub protected_inodes: HashSet<(u64, u64)>,
+    /// Directories whose files are never replaced, and are always preferred as the master (even over `choose_master`),
+    /// e.g. an old backup that new copies should be linked into. See `Scanner::set_keep_root`.
+    /// Duplicates within one keep root are left alone, and content in two keep roots is an error.
+    pub keep_roots: Vec<PathBuf>,
+    /// Check whether the first root is on a spinning disk, and pick `inode_order` and `max_read_size`
+    /// for it, unless they're set explicitly. Spinning disks get inode order and reads of up to 128MB to keep reads
+    /// sequential. SSDs, where seeks are cheap, get directories in the order they're found and reads of up to 8MB.
+    /// Only Linux can tell (from `/sys/dev/block`), and it's left at defaults if it can't.
+    pub auto_tune: bool,
+    /// Scan directories in order of their inode numbers, assuming it's close to their order on disk. Default is on.
+    pub inode_order: Option<bool>,
+    /// Largest chunk of a file read at once when comparing. Default is 128MB.
+    pub max_read_size: Option<u64>,
+    /// Compare files byte by byte before linking, instead of trusting hashes.
+    ///
+    /// Off (the default), files of the same size with equal (salted) SHA-1s of all their ranges are duplicates. That reads
+    /// each file once, and an accidental collision is practically impossible, but a crafted one could get two different
+    /// files linked if both can be put in the scanned tree (see `double_hash` for a second hash). On, every pair is read
+    /// again before it's linked, which about doubles the reads of duplicates, and nothing is ever linked by hashes alone.
+    pub verify_content: bool,
+    /// Hash the master before linking a group, and read it back after, to catch silent corruption (e.g. by flaky
+    /// hardware) of the one copy left. It reads the master twice more. A mismatch is counted in
+    /// `Stats::corruption_detected` and reported with `ScanListener::corruption_detected`, and the links stay.
+    pub verify_after_link: bool,
+    /// Link only duplicates found with at least this certainty. The others are left as they are, and reported with
+    /// `GuardReason::LowConfidence`, e.g. with `Confidence::Bytes` but without `verify_content` nothing is linked,
+    /// and everything is reported for review. Default is `Confidence::Hashes`, i.e. everything found is linked.
+    pub min_confidence: Confidence,
+    /// Do the `verify_content` comparison in dry run too, so that the reported duplicates are certain
+    pub dry_run_verify: bool,
+    /// Compare files after transforming their content, e.g. to ignore line endings or trailing whitespace.
+    /// Files are then compared by hashes of their whole normalized content, regardless of their sizes.
+    ///
+    /// Files that are equal only after normalization aren't identical, and linking them would lose data,
+    /// so with a normalizer nothing is ever linked (as in dry run), and no script or plan is written.
+    /// Results are for reporting only (`dupes`, listeners). The digest cache isn't used with it.
+    /// It can't be changed once files have been added. Code only, it's not read from configs.
+    #[cfg_attr(feature = "json", serde(skip))]
+    pub content_normalizer: Option<ContentNormalizer>,
+    /// Only compare files in the same directory, e.g. for stores bucketed by hash prefix (`ab/cdef...`), where
+    /// copies in different buckets can't happen (or don't matter). It saves comparisons, but misses duplicates
+    /// in different directories, which are counted in `Stats::cross_bucket_skipped`.
+    /// It can't be changed once files have been added.
+    pub bucket_by_parent: bool,
+    /// Stop with an error before replacing a file on a filesystem with less than this many bytes free
+    pub min_free_space: Option<u64>,
+    /// Compare files at least this big by mapping them into memory instead of reading them.
+    /// It's not used with `read_timeout`. A file truncated while it's being hashed crashes the process (SIGBUS),
+    /// so it's best for files that aren't being written to.
+    pub use_mmap_threshold: Option<u64>,
+    /// `Scanner::compressed_duplicates` doesn't decompress files to more than this, e.g. zip bombs. Default is 256MB.
+    pub max_decompressed_size: u64,
+    /// Replace files with hardlinks by swapping them with the link (`renameat2` with `RENAME_EXCHANGE`, Linux only),
+    /// and check that the file swapped out is the one that was compared, before deleting it.
+    /// It's swapped back if the file was replaced by another one in the meantime.
+    /// Falls back to the usual rename where the kernel or the filesystem can't swap.
+    pub use_renameat2: bool,
+    /// Count disk space in `Stats::bytes_saved` as allocated (`st_blocks`) rather than apparent size.
+    /// On filesystems with transparent compression (e.g. ZFS) that's the compressed size, so the savings aren't overstated.
+    /// Btrfs reports uncompressed blocks, so there it's only more honest about sparse files and the block size.
+    pub compression_aware_savings: bool,
+    /// Group files by SHA-256 as well as the salted SHA-1, so that they're duplicates only if both match,
+    /// for data where even a deliberate collision of one hash must not cause a link. Both are computed
+    /// from the same reads, but it takes more CPU. It's not used with `content_normalizer`, can't be used
+    /// with a digest cache, and can't be changed once files have been added. `verify_content` still adds a byte comparison.
+    pub double_hash: bool,
+    /// Count comparisons of files for `Scanner::comparison_metrics`, e.g. to see how often sizes alone tell files apart.
+    /// It costs a little time in every comparison.
+    pub collect_comparison_metrics: bool,
+    /// Skip files with names ending with one of `partial_suffixes`, since they're still being downloaded and will change
+    pub skip_partial_downloads: bool,
+    /// Files with these names are never linked (nor linked to, as the master), since tools that edit one of them
+    /// expect the others to stay as they are, e.g. `__init__.py` in source trees. They're still reported as duplicates.
+    /// Counted in `Stats::protected_name_skipped`. Default is `NEVER_LINK_NAMES`.
+    #[cfg_attr(feature = "json", serde(deserialize_with = "config::os_string_set"))]
+    pub never_link_names: HashSet<OsString>,
+    /// Name endings for `skip_partial_downloads`, compared ignoring ASCII case.
+    /// Default is `PARTIAL_DOWNLOAD_SUFFIXES`.
+    pub partial_suffixes: Vec<String>,
+    /// Skip files modified less than this long ago (or in the future), since they may still be being written.
+    /// A few minutes is plenty for download and cache directories.
+    #[cfg_attr(feature = "json", serde(deserialize_with = "config::opt_secs"))]
+    pub min_age: Option<Duration>,
+    /// Only consider files whose ctime is after this, e.g. to find files touched by a recent operation.
+    /// Others are skipped, and counted in `Stats::ctime_skipped`. In config files it's seconds since the epoch.
+    ///
+    /// Unlike the mtime (which `min_age` looks at), the ctime can't be set back by tools like `touch` or `cp -p`:
+    /// it's set by the kernel to the time of any change of the inode, be it of the content or of metadata
+    /// like permissions, owner, links or xattrs. So a file that is renamed, `chmod`ed or hardlinked (including by a
+    /// previous run of this) counts as changed, and a file restored with an old mtime does too.
+    #[cfg_attr(feature = "json", serde(deserialize_with = "config::opt_unix_time"))]
+    pub ctime_after: Option<SystemTime>,
+    /// Never touch anything outside of the roots given to `enqueue`, e.g. via followed symlinks,
+    /// or directories swapped for symlinks after they were scanned. Such paths are skipped,
+    /// counted in `Stats::outside_roots` and recorded in `Scanner::errors`.
+    /// Paths from `add_with_metadata` count as outside unless they're in a root.
+    pub confine_to_roots: bool,
+    /// Don't replace a duplicate that has more hardlinks than paths of it found by the scan, likely outside of the roots,
+    /// where the links would keep the old copy. Counted in `Stats::external_link_skipped`.
+    /// Links in the roots that the scan hasn't got to yet count as outside too, so it may skip more than necessary.
+    pub skip_externally_linked: bool,
+    /// Ways of replacing a duplicate, tried in order until one works. Default (and when empty) is only `Hardlink`.
+    pub mode_chain: Vec<DedupeMode>,
+    /// Where `DedupeMode::Quarantine` moves duplicates to
+    pub quarantine_dir: Option<PathBuf>,
+    /// Scan directories and their entries sorted by path, so that runs over identical trees
+    /// find files (and pick masters) in the same order. It's slower, and overrides `inode_order`.
+    pub stable_order: bool,
+    /// Add all other entries of a directory before its subdirectories. Their contents are always scanned later,
+    /// but without it the subdirectories are queued (and reported to the listener) as they're listed, between the files.
+    /// With `max_duration` it means a directory's files are added before the time runs out in its subdirectories' turn.
+    pub files_before_subdirs: bool,
+    /// List each directory (by device and inode) only by the path it was first listed by, even if it's reached by others,
+    /// e.g. through bind mounts or directory hardlinks. Without it each path is scanned and its files added again.
+    /// Scanning the same path again (e.g. a root after it changed) still lists it.
+    pub dedup_directory_visits: bool,
+    /// Delete temporary files left by interrupted runs when they're found (except in dry run).
+    /// They're always skipped and reported. Temporary files are named by the process that made them,
+    /// and those of processes that are still running aren't orphans. Only those that are another link of a file are
+    /// deleted. One without other links may be the only copy of a replaced file, so it's left in `Scanner::errors`.
+    pub clean_orphans: bool,
+    /// Don't look for duplicates at all. Only hash every file that passes the filters (size, ownership, etc.)
+    /// with SHA-256, and give it to `ScanListener::content_indexed`. Paths of the same inode are hashed separately.
+    /// Files that can't be read are recorded in `Scanner::errors`.
+    pub hash_only: bool,
+    /// Replace at most this many files per second, sleeping between them, to go easy on a busy disk.
+    /// Scanning and hashing aren't slowed down, and neither is dry run, which doesn't replace anything.
+    pub max_ops_per_sec: Option<u32>,
+    /// Try replacing a file this many more times when it fails with an error that's usually transient on network
+    /// filesystems (`EAGAIN`, `ESTALE`, `ETIMEDOUT`, `EINTR`). Other errors, e.g. `EXDEV` or `EPERM`, fail at once.
+    pub retry_count: u32,
+    /// Wait before the first retry, doubled for each one after it (see `retry_count`)
+    #[cfg_attr(feature = "json", serde(deserialize_with = "config::secs"))]
+    pub retry_backoff: Duration,
+    /// Stop a `flush` (or `scan`) after this long, between files. Links made so far stay,
+    /// and directories and files not scanned yet are left for the next `flush` (see `Scanner::completed`).
+    #[cfg_attr(feature = "json", serde(deserialize_with = "config::opt_secs"))]
+    pub max_duration: Option<Duration>,
+    /// Call `ScanListener::heartbeat` this often during a `flush`, also when nothing else is reported.
+    /// It's checked between files and directories, so a single slow read delays it.
+    #[cfg_attr(feature = "json", serde(deserialize_with = "config::opt_secs"))]
+    pub heartbeat_interval: Option<Duration>,
+    /// At the end of `flush`, list directories whose mtime changed since they were scanned again, and add new entries,
+    /// e.g. files created during the scan. It's repeated until nothing changes, up to `MAX_RESCAN_PASSES` times.
+    /// Entries that were replaced under the same name aren't noticed. Names of all entries are kept until the end of `flush`.
+    ///
+    /// Linking changes mtimes of directories too, so they're listed again as well, but only new names are looked at.
+    pub rescan_changed_dirs: bool,
+    /// Decides whether a duplicate found is really one (called with the duplicate and the master),
+    /// instead of the byte comparison of `verify_content`/`dry_run_verify`. It's always called when set.
+    ///
+    /// It can only reject files, which are grouped by hashes (or `content_normalizer`) first.
+    /// Since its idea of equal may be looser than identical bytes, it's not trusted for linking:
+    /// files are still linked only if the hashes match, and with `verify_content` if bytes match too.
+    /// Code only, it's not read from configs.
+    #[cfg_attr(feature = "json", serde(skip))]
+    pub equality_oracle: Option<EqualityOracle>,
+    /// Scan all directories at one depth before going deeper, so that duplicates near the roots are found first.
+    /// Directories at the same depth are scanned in the order they're found (or by path with `stable_order`).
+    /// It overrides `inode_order`, so reads may be less sequential on disk.
+    pub breadth_first: bool,
+    /// Only look this many levels of directories below each root (which is depth 0), e.g. `Some(2)` for its
+    /// subdirectories and theirs. Files in the deepest directories are still deduped, but their subdirectories are skipped
+    /// and counted in `Stats::too_deep`. `None` (the default) is no limit.
+    pub max_depth: Option<usize>,
+    /// Don't try to link on filesystems mounted read-only (checked once per device), which would fail with an error.
+    /// Their duplicates are still found and reported. Default is on.
+    pub skip_readonly_mounts: bool,
+    /// Don't link duplicates that are fully sparse (no blocks allocated, despite their size).
+    /// They're reported with `ScanListener::sparse_dupe_warning` either way.
+    pub skip_sparse: bool,
+    /// Make `scan` compare files in a snapshot of the root, and link them in the live tree (see `Scanner::scan_snapshot`)
+    pub use_snapshot: bool,
+    /// Command that makes a read-only snapshot of `{src}` at `{dst}`. Default is `btrfs subvolume snapshot -r`,
+    /// which needs the root to be a subvolume, and root (or `CAP_SYS_ADMIN`), as does deleting it.
+    pub snapshot_command: Vec<String>,
+    /// Command that deletes the snapshot at `{dst}`. Default is `btrfs subvolume delete`.
+    pub snapshot_delete_command: Vec<String>,
+    /// Where the snapshot appears (`{dst}`). Default is next to the root, named `<root>.dupe-snapshot-<pid>`.
+    /// For ZFS it's in `.zfs/snapshot`, and the commands are `zfs snapshot`/`zfs destroy` of a fixed name.
+    pub snapshot_path: Option<PathBuf>,
+    /// Also compare files with files of the same size on other devices, which can't be linked,
+    /// to report how much could be saved by moving them to one filesystem (see `Stats::cross_device_bytes`).
+    /// It costs extra reads.
+    pub report_cross_device: bool,
+    /// Keep masters in a content-addressed store: each master is hardlinked to `<root>/ab/cdef…`
+    /// (its SHA-256), and everything else is linked to that. If the store already has the content,
+    /// the master's paths are linked to the file in the store instead (after a byte comparison).
+    ///
+    /// The store must be on the same filesystem as the files. It's not used in dry run, with `content_normalizer`
+    /// or by `apply_plan`, and other `mode_chain` modes than `Hardlink` aren't used for the store.
+    pub content_address_root: Option<PathBuf>,
+    /// Prefer the copy in the fewest extents on disk (`FIEMAP`) as the master, after `keep_roots` and
+    /// `master_path_prefix`. Linked and reflinked copies are read from the master's blocks from then on,
+    /// so it's for reflinks and `content_address_root` in particular. It costs a call per fileset,
+    /// and does nothing where the filesystem can't tell (e.g. tmpfs). Ignored when `choose_master` is set.
+    pub prefer_contiguous_master: bool,
+}
+
+/// Limit of `Settings::rescan_changed_dirs`, so that a directory that never stops changing can't keep `flush` going
+pub const MAX_RESCAN_PASSES: usize = 3;
+
+/// Fewest copies for a group of tiny files to be a `Scanner::packing_candidates`
+pub const MIN_PACK_FILES: usize = 100;
+
+/// Files that browsers and torrent clients are still writing to
+pub const PARTIAL_DOWNLOAD_SUFFIXES: &[&str] = &[
+    ".part", ".partial", ".crdownload", ".download", ".opdownload", ".!ut", ".!qb", ".!bt",
+];
+
+/// Files that are meant to be separate copies, see `Settings::never_link_names`
+pub const NEVER_LINK_NAMES: &[&str] = &[
+    "__init__.py", "__main__.py", "LICENSE", "LICENSE.txt", "COPYING", "index.html", ".gitignore", ".gitkeep",
+];
+
+/// Minimum file age of `Settings::paranoid`
+pub const PARANOID_MIN_AGE: Duration = Duration::from_secs(10 * 60);
+
+impl Settings {
+    /// Defaults with all safety guards on, see `make_paranoid`
+    pub fn paranoid() -> Settings {
+        let mut settings = Settings::default();
+        settings.make_paranoid();
+        settings
+    }
+
+    /// Turns on every guard against linking files that aren't really duplicates, or shouldn't be touched,
+    /// at the cost of speed (and of some duplicates being left alone):
+    ///
+    /// - `verify_content` and `dry_run_verify`: files are compared byte by byte, also in dry run
+    /// - `double_hash`: SHA-256 has to match as well as SHA-1 (unless there's a digest cache or normalizer)
+    /// - `use_renameat2`: the file swapped out has to be the one that was compared
+    /// - `on_link_anomaly` is `Error`: a link that didn't end up sharing the master's inode aborts
+    /// - `on_immutable` is `Skip`, and `skip_readonly_mounts`
+    /// - `only_own_files`: files of other users are left alone
+    /// - `confine_to_roots`: nothing outside the scanned roots is touched, even through symlinks
+    /// - `skip_partial_downloads`, and `min_age` of at least `PARANOID_MIN_AGE` for files still being written
+    /// - `skip_sparse`: fully sparse files aren't linked
+    ///
+    /// Other settings are left as they are.
+    pub fn make_paranoid(&mut self) {
+        self.verify_content = true;
+        self.dry_run_verify = true;
+        self.double_hash = true;
+        self.use_renameat2 = true;
+        self.on_link_anomaly = OnLinkAnomaly::Error;
+        self.on_immutable = OnImmutable::Skip;
+        self.skip_readonly_mounts = true;
+        self.only_own_files = true;
+        self.confine_to_roots = true;
+        self.skip_partial_downloads = true;
+        self.min_age = Some(self.min_age.map_or(PARANOID_MIN_AGE, |age| age.max(PARANOID_MIN_AGE)));
+        self.skip_sparse = true;
+    }
+
+    /// Adds the file (or symlink's target) to `protected_inodes`
+    pub fn protect_path<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let m = fs::metadata(path)?;
+        self.protected_inodes.insert((m.dev(), m.ino()));
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Deserialize))]
+pub enum OnImmutable {
+    /// Leave such files alone, but count them in `Stats::immutable_skipped`
+    Skip,
+    /// Abort with an error
+    Error,
+    /// Clear the attribute for linking, and set it again afterwards.
+    /// Since the paths then share an inode, the attribute applies to all of them.
+    /// Requires `CAP_LINUX_IMMUTABLE`.
+    TempUnset,
+}
+
+/// What to do when a duplicate can't be linked because it's on another filesystem than its master (`EXDEV`),
+/// e.g. in a bind mount, which is the same device. Files on different devices are only compared with `Symlink`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Deserialize))]
+pub enum CrossFsPolicy {
+    /// Keep `DupeError::CrossDevice` in `Scanner::errors`, like other failures to link (or fail with `Settings::strict`)
+    Error,
+    /// Leave it alone, but count it in `Stats::cross_fs_skipped`
+    Skip,
+    /// Replace it with a symlink to the master's path (see `DedupeMode::Symlink`). Files are then grouped by size only,
+    /// not by device, so that duplicates on other mounted volumes are found and symlinked too.
+    Symlink,
+}
+
+/// Which mtime the inode of a group has after its duplicates are hardlinked to the master (see `Settings::mtime_policy`).
+/// It's picked from the mtimes the master and the linked duplicates had before, and set once, since they're one inode.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Deserialize))]
+pub enum MtimePolicy {
+    /// The master's, so the others' are lost
+    Keep,
+    /// The oldest of them, e.g. for photos, whose mtime is when they were taken or imported
+    Oldest,
+    /// The newest of them
+    Newest,
+}
+
+impl MtimePolicy {
+    /// The mtime of `a` and `b` this picks, or `None` for `Keep`
+    fn pick(self, a: SystemTime, b: SystemTime) -> Option<SystemTime> {
+        match self {
+            MtimePolicy::Keep => None,
+            MtimePolicy::Oldest => Some(a.min(b)),
+            MtimePolicy::Newest => Some(a.max(b)),
+        }
+    }
+}
+
+/// How the master of a group is picked (see `Settings::source_policy`)
+#[cfg_attr(feature = "json", derive(Deserialize))]
+pub enum SourcePolicy {
+    /// The file with the most hardlinks, since then the fewest paths have to be replaced
+    MostLinks,
+    /// The file with the shortest path, e.g. `~/Music/a.mp3` rather than `~/Downloads/tmp/a.mp3`,
+    /// and of those the one with the most hardlinks
+    ShortestPath,
+    /// Gets a path of each file of the group (the first one found), and returns the index of the master.
+    /// An index out of range is an `InvalidInput` error. Code only, it's not read from configs.
+    #[cfg_attr(feature = "json", serde(skip))]
+    Custom(PathPicker),
 }
 
+impl fmt::Debug for SourcePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SourcePolicy::MostLinks => f.write_str("MostLinks"),
+            SourcePolicy::ShortestPath => f.write_str("ShortestPath"),
+            SourcePolicy::Custom(_) => f.write_str("Custom(<callback>)"),
+        }
+    }
+}
+
+/// How certain it is that a duplicate has the same content as its master (see `Settings::min_confidence`)
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+pub enum Confidence {
+    /// Same size, and hashes of all of the content (in ranges, or of whole files with digests) are equal
+    #[default]
+    Hashes,
+    /// Compared byte by byte as well (see `Settings::verify_content` and `Settings::dry_run_verify`)
+    Bytes,
+}
+
+/// A link that reported success, but didn't end up sharing the master's inode (or didn't bump its link count),
+/// which may happen on exotic filesystems
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Deserialize))]
+pub enum OnLinkAnomaly {
+    /// Don't check (saves a stat per link)
+    Unchecked,
+    /// Count in `Stats::link_anomalies` and tell the listener
+    Record,
+    /// Abort with an error
+    Error,
+}
+
+/// Why a file with the same content as the master wasn't linked
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GuardReason {
+    /// Over `Settings::max_group_members`
+    MaxGroupMembers,
+    /// In `Settings::protected_inodes`
+    Protected,
+    /// It (or the master) is outside of the roots (see `Settings::confine_to_roots`)
+    OutsideRoots,
+    /// It (or the master) is immutable, and `Settings::on_immutable` is `Skip`
+    Immutable,
+    /// The group is below `Settings::min_savings_per_group`
+    LowSavings,
+    /// It's on a read-only filesystem (see `Settings::skip_readonly_mounts`)
+    ReadOnlyMount,
+    /// It's in one of `Settings::keep_roots`, like its master
+    KeepRoot,
+    /// It (or the master) is fully sparse, and `Settings::skip_sparse` is on
+    Sparse,
+    /// It has hardlinks that weren't found, and `Settings::skip_externally_linked` is on
+    ExternallyLinked,
+    /// Its name (or the master's) is in `Settings::never_link_names`
+    ProtectedName,
+    /// It wasn't compared with the certainty of `Settings::min_confidence`
+    LowConfidence,
+    /// Its filesystem can't reflink it, and `DedupeMode::Reflink` was the last mode to try
+    ReflinkUnsupported,
+    /// Its permissions, owner or group differ from the master's (see `Settings::ignore_permissions`)
+    PermissionMismatch,
+    /// It can't be linked to the master, since it's on another filesystem (or mount), and `Settings::cross_fs` is `Skip`
+    CrossFilesystem,
+}
+
+/// How a duplicate is replaced (see `Settings::mode_chain`)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Deserialize))]
+pub enum DedupeMode {
+    /// A copy-on-write clone of the master (`FICLONE` on Linux, `clonefile` on macOS), for filesystems that support it.
+    /// It's a separate file that only shares storage, so it keeps its own metadata and can be changed safely.
+    /// It gets the master's permissions and modification time.
+    Reflink,
+    /// A hardlink of the master
+    Hardlink,
+    /// A symlink to the master's path. It breaks if the master is moved or deleted.
+    Symlink,
+    /// Moved into `Settings::quarantine_dir` (at its absolute path under it), with a symlink to the master's path
+    /// left in its place. Nothing is freed until the quarantine is deleted, and `Scanner::restore_quarantine`
+    /// puts it all back. The quarantine must be on the same filesystem, since files are moved by linking them.
+    Quarantine,
+}
+
+impl fmt::Debug for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Settings")
+            .field("ignore_small", &self.ignore_small)
+            .field("min_size", &self.min_size)
+            .field("always_consider_extensions", &self.always_consider_extensions)
+            .field("dry_run", &self.dry_run)
+            .field("defer_links", &self.defer_links)
+            .field("follow_symlink_names", &self.follow_symlink_names)
+            .field("follow_symlinks", &self.follow_symlinks)
+            .field("script_output", &self.script_output)
+            .field("journal", &self.journal)
+            .field("unique_bloom", &self.unique_bloom)
+            .field("choose_master", &self.choose_master.as_ref().map(|_| "<callback>"))
+            .field("read_timeout", &self.read_timeout)
+            .field("preflight", &self.preflight)
+            .field("strict", &self.strict)
+            .field("hash_xattr", &self.hash_xattr)
+            .field("write_hash_xattr", &self.write_hash_xattr)
+            .field("slow_file_threshold", &self.slow_file_threshold)
+            .field("on_immutable", &self.on_immutable)
+            .field("cross_fs", &self.cross_fs)
+            .field("mtime_policy", &self.mtime_policy)
+            .field("master_path_prefix", &self.master_path_prefix)
+            .field("report_base", &self.report_base)
+            .field("max_tracked_paths_per_inode", &self.max_tracked_paths_per_inode)
+            .field("compare_from_tail", &self.compare_from_tail)
+            .field("quick_check", &self.quick_check)
+            .field("only_own_files", &self.only_own_files)
+            .field("cow_across_owners", &self.cow_across_owners)
+            .field("ignore_permissions", &self.ignore_permissions)
+            .field("master_by_creation_time", &self.master_by_creation_time)
+            .field("source_policy", &self.source_policy)
+            .field("max_group_members", &self.max_group_members)
+            .field("max_links_per_master", &self.max_links_per_master)
+            .field("atomic_groups", &self.atomic_groups)
+            .field("min_savings_per_group", &self.min_savings_per_group)
+            .field("progress_interval", &self.progress_interval)
+            .field("on_link_anomaly", &self.on_link_anomaly)
+            .field("protected_inodes", &self.protected_inodes)
+            .field("keep_roots", &self.keep_roots)
+            .field("auto_tune", &self.auto_tune)
+            .field("inode_order", &self.inode_order)
+            .field("max_read_size", &self.max_read_size)
+            .field("verify_content", &self.verify_content)
+            .field("min_confidence", &self.min_confidence)
+            .field("verify_after_link", &self.verify_after_link)
+            .field("dry_run_verify", &self.dry_run_verify)
+            .field("bucket_by_parent", &self.bucket_by_parent)
+            .field("content_normalizer", &self.content_normalizer.as_ref().map(|_| "<callback>"))
+            .field("min_free_space", &self.min_free_space)
+            .field("use_mmap_threshold", &self.use_mmap_threshold)
+            .field("max_decompressed_size", &self.max_decompressed_size)
+            .field("use_renameat2", &self.use_renameat2)
+            .field("compression_aware_savings", &self.compression_aware_savings)
+            .field("double_hash", &self.double_hash)
+            .field("collect_comparison_metrics", &self.collect_comparison_metrics)
+            .field("skip_partial_downloads", &self.skip_partial_downloads)
+            .field("never_link_names", &self.never_link_names)
+            .field("partial_suffixes", &self.partial_suffixes)
+            .field("min_age", &self.min_age)
+            .field("ctime_after", &self.ctime_after)
+            .field("confine_to_roots", &self.confine_to_roots)
+            .field("skip_externally_linked", &self.skip_externally_linked)
+            .field("mode_chain", &self.mode_chain)
+            .field("quarantine_dir", &self.quarantine_dir)
+            .field("stable_order", &self.stable_order)
+            .field("files_before_subdirs", &self.files_before_subdirs)
+            .field("dedup_directory_visits", &self.dedup_directory_visits)
+            .field("clean_orphans", &self.clean_orphans)
+            .field("hash_only", &self.hash_only)
+            .field("max_ops_per_sec", &self.max_ops_per_sec)
+            .field("retry_count", &self.retry_count)
+            .field("retry_backoff", &self.retry_backoff)
+            .field("max_duration", &self.max_duration)
+            .field("heartbeat_interval", &self.heartbeat_interval)
+            .field("rescan_changed_dirs", &self.rescan_changed_dirs)
+            .field("equality_oracle", &self.equality_oracle.as_ref().map(|_| "<callback>"))
+            .field("breadth_first", &self.breadth_first)
+            .field("max_depth", &self.max_depth)
+            .field("skip_readonly_mounts", &self.skip_readonly_mounts)
+            .field("skip_sparse", &self.skip_sparse)
+            .field("use_snapshot", &self.use_snapshot)
+            .field("snapshot_command", &self.snapshot_command)
+            .field("snapshot_delete_command", &self.snapshot_delete_command)
+            .field("snapshot_path", &self.snapshot_path)
+            .field("report_cross_device", &self.report_cross_device)
+            .field("content_address_root", &self.content_address_root)
+            .field("prefer_contiguous_master", &self.prefer_contiguous_master)
+            .finish()
+    }
+}
+
+/// Duplicates of one file extension, see `Scanner::stats_by_extension`
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct ExtensionStats {
+    /// Paths with the same content as another path (linked or not)
+    pub dupes: usize,
+    /// Bytes that linking would free, i.e. size of inodes other than the master's
+    pub reclaimable_bytes: u64,
+}
+
+/// Files with the same content, see `Scanner::report`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct DuplicateGroup {
+    /// All paths with the content, the master's first
+    pub paths: Vec<PathBuf>,
+    /// Size of one copy
+    pub file_size: u64,
+    /// Bytes taken by all copies but one, if none of them were linked yet
+    pub wasted_bytes: u64,
+}
+
+/// A group of duplicates to clean up by hand, see `Scanner::cleanup_worklist`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct WorklistItem {
+    /// Hex of the SHA-256 of the content, which is the same across runs and machines (as in `Scanner::write_manifest`).
+    /// `None` if `keep` couldn't be read.
+    pub group_id: Option<String>,
+    /// Master of the group, as picked by the scan
+    pub keep: PathBuf,
+    /// Paths with the same content that aren't links of `keep`
+    pub remove: Vec<PathBuf>,
+    /// Size of one copy
+    pub size: u64,
+    /// Bytes freed by removing all of `remove`
+    pub reclaimable_bytes: u64,
+}
+
+/// Many identical tiny files, which could be packed into one file with an index, see `Scanner::packing_candidates`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct PackGroup {
+    /// Size of each file, less than a block
+    pub size: u64,
+    /// All paths with the content
+    pub paths: Vec<PathBuf>,
+    /// Inodes the paths have now (in dry run counting the ones that would have been linked as their own)
+    pub inodes: usize,
+    /// Disk space the inodes take now, in blocks of the filesystem
+    pub allocated_bytes: u64,
+}
+
+/// What deduping the scanned files with a `Settings::max_links_per_master` would give, see `Scanner::simulate_link_cap`
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct SimulationResult {
+    /// Inodes that would be left of the groups of duplicates
+    pub masters: usize,
+    /// Bytes freed with the cap
+    pub reclaimable_bytes: u64,
+    /// Bytes freed without a cap, with one inode per group
+    pub uncapped_reclaimable_bytes: u64,
+}
+
+/// How much work comparing files took, see `Settings::collect_comparison_metrics`
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct ComparisonMetrics {
+    /// Comparisons of two files, most of which are decided by device and size alone
+    pub comparisons: u64,
+    /// Comparisons that had to read (or hash) some of the files' content
+    pub byte_reads: u64,
+    /// Bytes read for those
+    pub bytes_compared: u64,
+}
+
+/// Files that were hardlinked before the scan, see `Scanner::preexisting_link_stats`
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct PreexistingStats {
+    /// Inodes added that already had more than one link
+    pub inodes: usize,
+    /// Size of those inodes
+    pub bytes: u64,
+    /// What their extra links save, i.e. size times links beyond the first
+    pub saved_bytes: u64,
+}
+
+/// Key of `Scanner::stats_by_extension` for files without an extension
+pub const NO_EXTENSION: &str = "(none)";
+
 #[derive(Debug,Default,Copy,Clone)]
 #[cfg_attr(feature = "json",derive(Serialize))]
 pub struct Stats {
     pub added: usize,
     pub skipped: usize,
     pub dupes: usize,
+    /// Sum of `existing_hardlinks` and `created_hardlinks`
+    #[deprecated(note = "use existing_hardlinks or created_hardlinks")]
     pub hardlinks: usize,
+    /// Paths found to be hardlinks of an already added file
+    pub existing_hardlinks: usize,
+    /// Destinations that turned out to share the master's inode by the time they were linked
+    pub already_linked: usize,
+    pub symlinks_followed: usize,
+    /// Symlinks not followed, including those skipped by `Settings::follow_symlinks` to end loops
+    pub symlinks_skipped: usize,
+    /// Files and directories matching a pattern of `Scanner::add_exclude` (a directory's contents aren't counted)
+    pub excluded: usize,
+    /// Directories deeper than `Settings::max_depth`, which weren't listed
+    pub too_deep: usize,
+    /// Files not compared, since they were unique and unchanged since the previous run
+    pub known_unique: usize,
+    pub read_timeouts: usize,
+    pub immutable_skipped: usize,
+    /// FIFOs, sockets and device files. They're always skipped, and counted in `skipped` too.
+    pub special_files: usize,
+    /// Files skipped because of `Settings::only_own_files`. Also counted in `skipped`.
+    pub not_owned_skipped: usize,
+    /// Times a duplicate was found, but its group wasn't worth linking yet (see `Settings::min_savings_per_group`)
+    pub low_savings_skipped: usize,
+    /// Bytes of file content read for comparisons
+    pub bytes_read: u64,
+    /// Files whose hash came from the digest cache
+    pub cached_digests: usize,
+    /// Files not compared with files of the same size in other directories added before them (see `Settings::bucket_by_parent`)
+    pub cross_bucket_skipped: usize,
+    /// Files whose hash came from their extended attribute (see `Settings::hash_xattr`)
+    pub xattr_digests: usize,
+    /// See `OnLinkAnomaly`
+    pub link_anomalies: usize,
+    /// Times a path wasn't replaced, because it's in `Settings::protected_inodes`.
+    /// It stays in the group, so it's counted again whenever the group gets another duplicate.
+    pub protected_skipped: usize,
+    /// Files that had the same hashes as the master, but not the same content (see `Settings::verify_content`)
+    pub verify_mismatches: usize,
+    /// Masters that didn't have the same content anymore after linking (see `Settings::verify_after_link`)
+    pub corruption_detected: usize,
+    /// Paths skipped since they're too long for the OS (`ENAMETOOLONG`). They're in `Scanner::errors` too.
+    pub name_too_long: usize,
+    /// Planned links not made by `Scanner::apply_plan`, because the files changed since, or can't be replaced
+    pub plan_skipped: usize,
+    /// Directories that couldn't be listed, e.g. for lack of permissions. They're in `Scanner::errors` too.
+    pub unreadable_dirs: usize,
+    /// Files skipped because of `Settings::skip_partial_downloads`. Also counted in `skipped`.
+    pub partial_skipped: usize,
+    /// Files skipped because of `Settings::min_age`. Also counted in `skipped`.
+    pub too_fresh_skipped: usize,
+    /// Files skipped because of `Settings::ctime_after`. Also counted in `skipped`.
+    pub ctime_skipped: usize,
+    /// Directories listed again because of `Settings::rescan_changed_dirs` (once per pass)
+    pub rescanned_dirs: usize,
+    /// Entries that were listed, but deleted before they could be stat-ed (or had no links left). Also counted in `skipped`.
+    pub deleted_during_scan: usize,
+    /// Directories whose files weren't read by `Scanner::incremental_scan`, since they're unchanged since the previous run
+    pub unchanged_dirs: usize,
+    /// Files of unchanged directories added by their cached hashes, without reading them (see `Scanner::incremental_scan`)
+    pub seeded_files: usize,
+    /// Sum of sizes of files added, once per inode (like `du --apparent-size`, sizes as they were when added)
+    pub total_apparent_bytes: u64,
+    /// Disk space used by files added, once per inode (like `du`). It's less than `total_apparent_bytes` for sparse files.
+    pub total_allocated_bytes: u64,
+    /// Disk space freed by replacing files whose last link was the replaced path
+    /// (apparent sizes, or allocated with `Settings::compression_aware_savings`)
+    pub bytes_saved: u64,
+    /// Sizes of files freed by hardlinking, once per inode, when its last link was replaced.
+    /// Each duplicate beyond the master counts once, so a group of `n` files that were all separate frees `n - 1` sizes.
+    pub reclaimed_bytes: u64,
+    /// What `reclaimed_bytes` would be in dry run, once per inode with any of its paths planned to be linked
+    pub would_reclaim_bytes: u64,
+    /// Paths skipped because of `Settings::confine_to_roots`. Also in `Scanner::errors`.
+    pub outside_roots: usize,
+    /// Duplicates not replaced, because they have hardlinks the scan didn't find (see `Settings::skip_externally_linked`)
+    pub external_link_skipped: usize,
+    /// Duplicates not linked, because their name or their master's is in `Settings::never_link_names`
+    pub protected_name_skipped: usize,
+    /// Duplicates not linked, because they weren't found with `Settings::min_confidence`
+    pub low_confidence_skipped: usize,
+    /// Duplicates left as they were, because their filesystem doesn't support reflinks (see `GuardReason::ReflinkUnsupported`)
+    pub reflink_unsupported: usize,
+    /// Duplicates left as they were, because they turned out to be on another filesystem than their master
+    /// (see `CrossFsPolicy::Skip`)
+    pub cross_fs_skipped: usize,
+    /// Duplicates left as they were, because their permissions, owner or group differ from the master's
+    /// (see `Settings::ignore_permissions`)
+    pub permission_mismatches: usize,
+    /// Times a duplicate wasn't linked because of a guard (see `GuardReason`).
+    /// It stays in its group, so it's counted again whenever the group gets another duplicate.
+    pub dupes_not_linked: usize,
+    /// Masters started because the previous one had `Settings::max_links_per_master` links
+    pub extra_masters: usize,
+    /// Groups not linked, because they're on a read-only filesystem (see `Settings::skip_readonly_mounts`)
+    pub readonly_skipped: usize,
+    /// Dupes, or masters with their whole group, not linked, because they're fully sparse (see `Settings::skip_sparse`)
+    pub sparse_skipped: usize,
+    /// Masters added to `Settings::content_address_root`
+    pub content_stored: usize,
+    /// Dupes not linked, because they have the same path as their master
+    pub self_link_skipped: usize,
+    /// Size of files that have a duplicate only on another device (see `Settings::report_cross_device`).
+    /// Counted once for every device's copy but the first.
+    pub cross_device_bytes: u64,
+    /// Paths linked again by `Scanner::repair`
+    pub repaired: usize,
+    /// Directories listed. A directory scanned again (e.g. under overlapping roots) is counted once.
+    pub directories: usize,
+    /// Paths of an inode that were already added under another spelling, e.g. a root given twice,
+    /// or `Foo` and `foo` on a case-insensitive filesystem. Also counted in `skipped`.
+    pub path_aliases: usize,
+    /// Files hashed with `Settings::hash_only`
+    pub indexed: usize,
+    /// Temporary files of interrupted runs found (see `Settings::clean_orphans`). Also counted in `skipped`.
+    pub orphan_temps: usize,
+    /// Files from `Scanner::enqueue_with_sizes` not looked at, since their listed size was unique. Also counted in `skipped`.
+    pub listed_unique_skipped: usize,
+    /// Files from `Scanner::enqueue_with_sizes` whose actual size wasn't the listed one
+    pub listed_size_changed: usize,
+    /// Duplicates replaced with a hardlink
+    pub created_hardlinks: usize,
+    /// Duplicates replaced with a reflink (see `DedupeMode::Reflink`)
+    pub reflinked: usize,
+    /// Duplicates replaced with a symlink (see `DedupeMode::Symlink`)
+    pub symlinked: usize,
+    /// Duplicates moved into the quarantine (see `DedupeMode::Quarantine`)
+    pub quarantined: usize,
+    /// Links undone, because linking another member of their group failed (see `Settings::atomic_groups`)
+    pub rolled_back: usize,
+    /// Replacements tried again after a transient error (see `Settings::retry_count`)
+    pub retries: usize,
+    /// Files of `Scanner::scan_with_manifest` whose content doesn't have the hash of the manifest
+    pub integrity_failures: usize,
+}
+
+/// Type of a file that's neither a regular file, a directory nor a symlink
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SpecialFileKind {
+    Fifo,
+    Socket,
+    BlockDevice,
+    CharDevice,
+    Unknown,
+}
+
+impl SpecialFileKind {
+    fn new(ty: fs::FileType) -> Self {
+        if ty.is_fifo() {
+            SpecialFileKind::Fifo
+        } else if ty.is_socket() {
+            SpecialFileKind::Socket
+        } else if ty.is_block_device() {
+            SpecialFileKind::BlockDevice
+        } else if ty.is_char_device() {
+            SpecialFileKind::CharDevice
+        } else {
+            SpecialFileKind::Unknown
+        }
+    }
 }
 
-pub trait ScanListener : Debug {
-    fn file_scanned(&mut self, path: &PathBuf, stats: &Stats);
+pub trait ScanListener : Debug + MaybeSend {
+    fn file_scanned(&mut self, path: &Path, stats: &Stats);
     fn scan_over(&self, scanner: &Scanner, stats: &Stats, scan_duration: Duration);
     fn hardlinked(&mut self, src: &Path, dst: &Path);
     fn duplicate_found(&mut self, src: &Path, dst: &Path);
+    /// The file was too slow to read (see `Settings::read_timeout`) and has been skipped
+    fn read_timeout(&mut self, _path: &Path) {}
+    /// Total bytes read for comparisons so far. Called at most once per `Settings::progress_interval`.
+    /// `eta` is the time left to read the rest of `Scanner::set_expected_bytes`, at the rate of the last 30 seconds,
+    /// if that's known.
+    fn bytes_read_update(&mut self, _total_bytes_read: u64, _eta: Option<Duration>) {}
+    /// Replacing `src` with a link to `dst` seemed to succeed, but the result doesn't look like a hardlink (see `OnLinkAnomaly`)
+    fn link_anomaly(&mut self, _src: &Path, _dst: &Path) {}
+    /// A duplicate (or a master) has no blocks allocated, despite its size, so linking it may save less than expected
+    fn sparse_dupe_warning(&mut self, _path: &Path) {}
+    /// A duplicate has the same path as its master (e.g. the file was replaced since it was scanned), so it's not linked
+    fn self_link_skipped(&mut self, _path: &Path) {}
+    /// `src` matched `dst` by hashes, but not byte for byte, so it won't be linked
+    fn content_mismatch(&mut self, _src: &Path, _dst: &Path) {}
+    /// The master's content changed while its group was linked, so it and all its links may be corrupt (see `Settings::verify_after_link`)
+    fn corruption_detected(&mut self, _path: &Path) {}
+    /// A link planned earlier wasn't made by `Scanner::apply_plan`
+    fn plan_skipped(&mut self, _src: &Path, _dst: &Path) {}
+    /// `src` has been replaced with a reflink of `dst` (see `DedupeMode::Reflink`)
+    fn reflinked(&mut self, _src: &Path, _dst: &Path) {}
+    /// `src` has been replaced with a symlink to `dst` (see `DedupeMode::Symlink`)
+    fn symlinked(&mut self, _src: &Path, _dst: &Path) {}
+    /// `src` has been moved into the quarantine, and replaced with a symlink to `dst` (see `DedupeMode::Quarantine`)
+    fn quarantined(&mut self, _src: &Path, _dst: &Path) {}
+    /// `path` is a duplicate, but won't be linked (yet) because of a guard
+    fn dupe_skipped(&mut self, _path: &Path, _reason: GuardReason) {}
+    /// SHA-256 of a file's content (see `Settings::hash_only`)
+    fn content_indexed(&mut self, _path: &Path, _size: u64, _sha256: &[u8; 32]) {}
+    /// A temporary file left by an interrupted run has been skipped (and deleted with `Settings::clean_orphans`)
+    fn orphan_temp_found(&mut self, _path: &Path) {}
+    /// A directory has been listed. It's called once per directory, even if it's scanned again.
+    fn directory_scanned(&mut self, _path: &Path, _stats: &Stats) {}
+    /// `a` has the same content as `b`, but they're on different devices (see `Settings::report_cross_device`)
+    fn cross_device_dupe(&mut self, _a: &Path, _b: &Path) {}
+    /// A FIFO, socket or device file has been skipped
+    fn special_file(&mut self, _path: &Path, _kind: SpecialFileKind) {}
+    /// The scan is still going, `elapsed` since the `flush` started (see `Settings::heartbeat_interval`).
+    /// It's never called after `scan_over`.
+    fn heartbeat(&mut self, _elapsed: Duration, _stats: &Stats) {}
+    /// Reading `path` for comparisons has taken `elapsed` so far, which is over `Settings::slow_file_threshold`.
+    /// It's called once per file.
+    fn slow_file(&mut self, _path: &Path, _elapsed: Duration) {}
+    /// The content of `path` doesn't have the SHA-256 listed in the manifest (see `Scanner::scan_with_manifest`)
+    fn integrity_failure(&mut self, _path: &Path, _expected: &[u8; 32]) {}
+    /// `path` couldn't be read, stat-ed or linked, and the error has been added to `Scanner::errors`.
+    /// The scan goes on with the rest (unless `Settings::strict`).
+    fn scan_error(&mut self, _path: &Path, _err: &io::Error) {}
+    /// `a` has the content of the master `b`, but other permissions, owner or group, so it's left alone
+    /// (see `Settings::ignore_permissions`)
+    fn permission_mismatch(&mut self, _a: &Path, _b: &Path) {}
+    /// A `flush` is starting, with the number of files it's expected to find (see `Scanner::set_expected_files`)
+    /// or `None` if they weren't counted
+    fn scan_started(&mut self, _total_files: Option<u64>) {}
 }
 
 #[derive(Debug)]
 struct SilentListener;
 impl ScanListener for SilentListener {
-    fn file_scanned(&mut self, _: &PathBuf, _: &Stats) {}
+    fn file_scanned(&mut self, _: &Path, _: &Stats) {}
     fn scan_over(&self, _: &Scanner, _: &Stats, _: Duration) {}
     fn hardlinked(&mut self, _: &Path, _: &Path) {}
     fn duplicate_found(&mut self, _: &Path, _: &Path) {}
 }
 
+/// Order key, path, device and inode, and depth below the root of a directory to scan
+type QueuedDir = (u64, Reverse<PathBuf>, (u64, u64), usize);
+
+/// Why a regular file isn't added, by the settings alone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FileRule {
+    NotOwned,
+    PartialDownload,
+    TooFresh,
+    Ctime,
+    Small,
+}
+
 #[derive(Debug)]
 pub struct Scanner {
     /// All hardlinks of the same inode have to be treated as the same file
-    by_inode: HashMap<(u64, u64), Rc<Mutex<FileSet>>>,
+    by_inode: HashMap<(u64, u64), SharedFileSet>,
     /// See Hasher for explanation
-    by_content: BTreeMap<FileContent, Vec<Rc<Mutex<FileSet>>>>,
+    by_content: BTreeMap<FileContent, Vec<SharedFileSet>>,
     /// Directories left to scan. Sorted by inode number.
     /// I'm assuming scanning in this order is faster, since inode is related to file's age,
     /// which is related to its physical position on disk, which makes the scan more sequential.
-    to_scan: BinaryHeap<(u64, PathBuf)>,
+    /// Paths are reversed, so that ties are scanned alphabetically
+    to_scan: BinaryHeap<QueuedDir>,
+    /// Depth of directories queued by `add`: 0 for roots, and one more than the directory whose entries are added
+    entry_depth: usize,
+    /// Device and inode of directories counted in `Stats::directories`, and the path they were first listed by
+    scanned_dirs: HashMap<(u64, u64), PathBuf>,
+    /// Files that were unique in the previous run. They're moved to `by_content` only when a new file matches them.
+    known_unique: HashMap<Metadata, Vec<(FileContent, SharedFileSet)>>,
+    /// Directories with files of each size, for `Stats::cross_bucket_skipped`
+    bucket_parents: HashMap<Metadata, HashSet<PathBuf>>,
+    unique_filter: Option<BloomFilter>,
+    /// Canonical paths passed to `enqueue`
+    roots: Vec<PathBuf>,
+    /// Targets of followed symlinks, to avoid following the same target twice
+    followed_symlinks: HashSet<(u64, u64)>,
+    /// See `add_exclude`
+    excludes: Vec<Glob>,
+    /// Files are compared by digests, since `flush_parallel` was used
+    parallel_digests: bool,
+    /// Files with a new inode found during `flush_parallel`, to be hashed before they're indexed
+    deferred: Option<Vec<(PathBuf, fs::Metadata, SharedFileSet)>>,
 
-    scan_listener: Box<ScanListener>,
+    content_options: Shared<ContentOptions>,
+    scan_listener: RebasedListener,
+    /// See `set_filesystem`
+    filesystem: Box<dyn FileSystem>,
+    /// Opened on first use, when `script_output` is set
+    script: Option<ScriptWriter>,
+    /// Opened on first use, when `Settings::journal` is set
+    journal: Option<Journal>,
+    /// Directories something was replaced in, during `incremental_scan`
+    linked_dirs: Option<HashSet<PathBuf>>,
+    errors: Vec<(PathBuf, io::Error)>,
+    digest_cache: Option<DigestCache>,
+    /// See `Scanner::load_cache`
+    hash_cache: Option<HashCache>,
+    /// Links found in dry run
+    plan: DryRunReport,
+    /// Whether the first root is on a spinning disk, when `auto_tune` is on
+    rotational: Option<bool>,
+    /// Counter for `inode_order` off
+    dirs_queued: u64,
+    /// When `bytes_read_update` was last called
+    last_progress: Option<Instant>,
+    /// See `set_expected_bytes`
+    expected_bytes: Option<u64>,
+    /// See `set_expected_files`
+    expected_files: Option<u64>,
+    throughput: Throughput,
+    /// See `Settings::slow_file_threshold`
+    slow_files: Vec<(PathBuf, Duration)>,
+    /// Start of the current `flush`, while heartbeats are due
+    heartbeat_start: Option<Instant>,
+    last_heartbeat: Instant,
+    /// Directory `flush` is in the middle of
+    current_dir: Option<PathBuf>,
+    /// Rest of the directory `flush` stopped in the middle of, because of `max_duration`, with the depth of directories in it
+    unfinished_entries: Vec<(PathBuf, usize)>,
+    /// Earliest time for the next replacement with `max_ops_per_sec`
+    next_op: Option<Instant>,
+    /// Whether devices are mounted read-only, for `skip_readonly_mounts`
+    readonly_devs: HashMap<u64, bool>,
+    /// Devices that passed `Settings::preflight`
+    preflight_devs: HashSet<u64>,
+    /// Devices seen, for `Settings::report_cross_device`
+    devices: BTreeSet<u64>,
+    preexisting: PreexistingStats,
+    comparison_metrics: ComparisonMetrics,
+    /// Directories scanned during this `flush`, with their mtime and names of entries, for `Settings::rescan_changed_dirs`
+    dir_snapshots: HashMap<PathBuf, DirSnapshot>,
+    /// During `incremental_scan`
+    dir_mtimes: Option<DirMtimes>,
+    /// Paths of members of archives given to `scan_tar`, by size and SHA-256
+    tar_members: HashMap<(u64, [u8; 32]), Vec<PathBuf>>,
     stats: Stats,
     pub settings: Settings,
 }
 
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            ignore_small: true,
+            min_size: None,
+            always_consider_extensions: HashSet::new(),
+            dry_run: false,
+            defer_links: false,
+            follow_symlink_names: HashSet::new(),
+            follow_symlinks: false,
+            script_output: None,
+            journal: None,
+            unique_bloom: None,
+            choose_master: None,
+            read_timeout: None,
+            preflight: false,
+            strict: false,
+            hash_xattr: None,
+            write_hash_xattr: false,
+            slow_file_threshold: None,
+            on_immutable: OnImmutable::Skip,
+            cross_fs: CrossFsPolicy::Error,
+            mtime_policy: MtimePolicy::Keep,
+            master_path_prefix: None,
+            report_base: None,
+            max_tracked_paths_per_inode: None,
+            compare_from_tail: false,
+            quick_check: true,
+            only_own_files: false,
+            cow_across_owners: false,
+            ignore_permissions: false,
+            master_by_creation_time: false,
+            source_policy: SourcePolicy::MostLinks,
+            max_group_members: None,
+            max_links_per_master: None,
+            atomic_groups: false,
+            min_savings_per_group: 0,
+            progress_interval: Duration::from_secs(1),
+            on_link_anomaly: OnLinkAnomaly::Record,
+            protected_inodes: HashSet::new(),
+            keep_roots: Vec::new(),
+            auto_tune: false,
+            inode_order: None,
+            max_read_size: None,
+            verify_content: false,
+            min_confidence: Confidence::Hashes,
+            verify_after_link: false,
+            dry_run_verify: false,
+            content_normalizer: None,
+            bucket_by_parent: false,
+            min_free_space: None,
+            use_mmap_threshold: None,
+            max_decompressed_size: 256 * 1024 * 1024,
+            use_renameat2: false,
+            compression_aware_savings: false,
+            double_hash: false,
+            collect_comparison_metrics: false,
+            skip_partial_downloads: false,
+            never_link_names: NEVER_LINK_NAMES.iter().map(OsString::from).collect(),
+            partial_suffixes: PARTIAL_DOWNLOAD_SUFFIXES.iter().map(|s| s.to_string()).collect(),
+            min_age: None,
+            ctime_after: None,
+            confine_to_roots: false,
+            skip_externally_linked: false,
+            mode_chain: vec![DedupeMode::Hardlink],
+            quarantine_dir: None,
+            stable_order: false,
+            files_before_subdirs: false,
+            dedup_directory_visits: true,
+            clean_orphans: false,
+            hash_only: false,
+            max_ops_per_sec: None,
+            retry_count: 0,
+            retry_backoff: Duration::from_millis(100),
+            max_duration: None,
+            heartbeat_interval: None,
+            rescan_changed_dirs: false,
+            equality_oracle: None,
+            breadth_first: false,
+            max_depth: None,
+            skip_readonly_mounts: true,
+            skip_sparse: false,
+            use_snapshot: false,
+            snapshot_command: ["btrfs", "subvolume", "snapshot", "-r", "{src}", "{dst}"].iter().map(|s| s.to_string()).collect(),
+            snapshot_delete_command: ["btrfs", "subvolume", "delete", "{dst}"].iter().map(|s| s.to_string()).collect(),
+            snapshot_path: None,
+            report_cross_device: false,
+            content_address_root: None,
+            prefer_contiguous_master: false,
+        }
+    }
+}
+
+impl Default for Scanner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Scanner {
     pub fn new() -> Self {
         Scanner {
-            settings: Settings {
-                ignore_small: true,
-                dry_run: false,
-            },
+            settings: Settings::default(),
             by_inode: HashMap::new(),
             by_content: BTreeMap::new(),
             to_scan: BinaryHeap::new(),
-            scan_listener: Box::new(SilentListener),
+            entry_depth: 0,
+            scanned_dirs: HashMap::new(),
+            known_unique: HashMap::new(),
+            bucket_parents: HashMap::new(),
+            unique_filter: None,
+            roots: Vec::new(),
+            followed_symlinks: HashSet::new(),
+            excludes: Vec::new(),
+            parallel_digests: false,
+            deferred: None,
+            content_options: Shared::new(ContentOptions::default()),
+            scan_listener: RebasedListener {base: None, inner: Box::new(SilentListener)},
+            filesystem: Box::new(StdFileSystem),
+            script: None,
+            journal: None,
+            linked_dirs: None,
+            errors: Vec::new(),
+            digest_cache: None,
+            hash_cache: None,
+            plan: DryRunReport::default(),
+            rotational: None,
+            dirs_queued: 0,
+            last_progress: None,
+            expected_bytes: None,
+            expected_files: None,
+            throughput: Throughput::default(),
+            slow_files: Vec::new(),
+            heartbeat_start: None,
+            last_heartbeat: Instant::now(),
+            current_dir: None,
+            unfinished_entries: Vec::new(),
+            next_op: None,
+            readonly_devs: HashMap::new(),
+            preflight_devs: HashSet::new(),
+            devices: BTreeSet::new(),
+            preexisting: PreexistingStats::default(),
+            comparison_metrics: ComparisonMetrics::default(),
+            tar_members: HashMap::new(),
+            dir_snapshots: HashMap::new(),
+            dir_mtimes: None,
             stats: Stats::default(),
         }
     }
 
-    /// Set the scan listener. Caution: This overrides previously set listeners!
-    /// Use a multiplexing listener if multiple listeners are required.
-    pub fn set_listener(&mut self, listener: Box<ScanListener>) {
-        self.scan_listener = listener;
+    /// Scanner that compares files by hashes of their whole content, and takes the hashes from the cache
+    /// for files with unchanged size and mtime instead of reading them.
+    ///
+    /// Files not in the cache are read in full when compared, rather than only until the first difference.
+    pub fn with_digest_cache(cache: DigestCache) -> Self {
+        let mut scanner = Self::new();
+        scanner.digest_cache = Some(cache);
+        scanner
     }
 
-    /// Scan any file or directory for dupes.
-    /// Dedupe is done within the path as well as against all previously added paths.
-    pub fn scan<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
-        self.enqueue(path)?;
-        self.flush()?;
-        Ok(())
+    /// Same as `ScannerBuilder::new`
+    pub fn builder() -> ScannerBuilder {
+        ScannerBuilder::new()
     }
 
-    pub fn enqueue<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
-        let path = fs::canonicalize(path)?;
-        let metadata = fs::symlink_metadata(&path)?;
-        self.add(path, metadata)?;
-        Ok(())
+    /// Tests in `dir` (by creating and removing a temporary directory) whether its filesystem
+    /// supports hardlinks and atomic renames, which deduping relies on.
+    /// Front-ends can use it to warn about e.g. FUSE mounts that don't.
+    pub fn probe_filesystem<P: AsRef<Path>>(dir: P) -> io::Result<FsCapabilities> {
+        probe::probe(dir.as_ref())
     }
 
-    /// Drains the queue of directories to scan
-    pub fn flush(&mut self) -> io::Result<()> {
-        let start_time = Instant::now();
-        while let Some((_, path)) = self.to_scan.pop() {
-            self.scan_dir(path)?;
+    /// Makes the links planned by an earlier dry run (see `dry_run_report`), without scanning.
+    ///
+    /// Links whose files changed since (gone, different size or device, already linked, or different content
+    /// with `Settings::verify_content`), are protected, immutable or on a read-only mount are skipped,
+    /// and counted in `plan_skipped`.
+    /// In dry run it only checks the plan. Returns counters of just this call.
+    pub fn apply_plan(&mut self, plan: &DryRunReport) -> io::Result<Stats> {
+        self.scan_listener.base = self.settings.report_base.clone();
+        let mut stats = Stats::default();
+        let mut script = None;
+        let mut unused_plan = DryRunReport::default();
+        let mut deduper = Deduper {
+            settings: &mut self.settings,
+            stats: &mut stats,
+            scan_listener: &mut self.scan_listener,
+            script: &mut script,
+            plan: &mut unused_plan,
+            roots: &self.roots,
+            errors: &mut self.errors,
+            next_op: &mut self.next_op,
+            readonly_devs: &mut self.readonly_devs,
+            preflight_devs: &mut self.preflight_devs,
+            journal: &mut self.journal,
+            linked_dirs: &mut self.linked_dirs,
+            filesystem: &*self.filesystem,
+        };
+        for link in &plan.links {
+            deduper.apply_link(link)?;
         }
-        let scan_duration = Instant::now().duration_since(start_time);
-        self.scan_listener.scan_over(&self, &self.stats, scan_duration);
+        Ok(stats)
+    }
+
+    /// Links again paths that should share an inode, but don't anymore, e.g. after a file has been replaced
+    /// by an editor. It works on what's been scanned (or linked) so far: paths of each `FileSet` that still have
+    /// the same content as its first path, but not its inode, are linked to it as with `apply_plan`.
+    /// Paths whose content changed are left alone. Returns counters of just this call.
+    pub fn repair(&mut self) -> io::Result<Stats> {
+        let mut plan = DryRunReport::default();
+        for set in self.by_inode.values() {
+            let paths = set.lock().unwrap().paths.clone();
+            let (master, members) = match paths.split_first() {
+                Some(split) => split,
+                None => continue,
+            };
+            let master_metadata = match fs::symlink_metadata(master) {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    if self.settings.strict {
+                        return Err(strict_error(master, err));
+                    }
+                    self.scan_listener.scan_error(master, &err);
+                    self.errors.push((master.clone(), err));
+                    continue;
+                },
+            };
+            for path in members {
+                let split = match fs::symlink_metadata(path) {
+                    Ok(m) => m.is_file() && m.dev() == master_metadata.dev() && m.size() == master_metadata.size()
+                        && m.ino() != master_metadata.ino(),
+                    Err(err) => {
+                        if self.settings.strict {
+                            return Err(strict_error(path, err));
+                        }
+                        self.scan_listener.scan_error(path, &err);
+                        self.errors.push((path.clone(), err));
+                        continue;
+                    },
+                };
+                // Hashes are of the old content, so only bytes can tell
+                if split && verify::same_content(master, path)? {
+                    plan.links.push(PlannedLink {
+                        source: master.clone(),
+                        dest: path.clone(),
+                        size: master_metadata.size(),
+                        dev: master_metadata.dev(),
+                        confidence: Confidence::Bytes,
+                    });
+                }
+            }
+        }
+        let mut stats = self.apply_plan(&plan)?;
+        stats.repaired = stats.created_hardlinks + stats.reflinked + stats.symlinked;
+        Ok(stats)
+    }
+
+    /// Moves all files of a quarantine (see `DedupeMode::Quarantine`) back, replacing the symlinks left in their place,
+    /// and removes the directories of the quarantine that are empty then. Files whose path has since been taken by
+    /// something other than a symlink to a file with their content stay in the quarantine, and are in `errors`,
+    /// as are entries that can't be read. Returns how many were moved back.
+    pub fn restore_quarantine<P: AsRef<Path>>(&mut self, dir: P) -> io::Result<usize> {
+        let mut restored = 0;
+        restore_quarantined(dir.as_ref(), dir.as_ref(), &mut restored, &mut self.errors)?;
+        Ok(restored)
+    }
+
+    /// Tells from a `Settings::journal` which replacements completed, which had replaced the file without recording it,
+    /// and which hadn't started when a previous run stopped, removing the temporary files those left behind.
+    /// The journal itself is left as it is, it can be removed once the files are taken care of.
+    pub fn recover_journal<P: AsRef<Path>>(&mut self, path: P) -> io::Result<JournalRecovery> {
+        journal::recover(path.as_ref())
+    }
+
+    /// Takes hashes of files from a cache of previous runs (see `save_cache`) instead of reading the files,
+    /// for files with the same inode, size and mtime. A missing cache file is an empty cache.
+    ///
+    /// Files are then compared by hashes of their whole content, like with `with_digest_cache`,
+    /// so it has to be loaded before anything is scanned. It's not used with `Settings::content_normalizer`.
+    pub fn load_cache<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.hash_cache = Some(HashCache::load(path.as_ref())?);
         Ok(())
     }
 
-    fn scan_dir(&mut self, path: PathBuf) -> io::Result<()> {
-        /// Errors are ignored here, since it's super common to find permission denied and unreadable symlinks,
-        /// and it'd be annoying if that aborted the whole operation.
-        // FIXME: store the errors somehow to report them in a controlled manner
-        for entry in fs::read_dir(path)?.filter_map(|p|p.ok()) {
-            let path = entry.path();
-            self.add(path, entry.metadata()?).unwrap_or_else(|e| println!("{:?}", e));
+    /// Writes the cache loaded with `load_cache`, with the hashes of the files scanned since.
+    /// Entries of files that are gone or changed since they were hashed are dropped.
+    pub fn save_cache<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let cache = match self.hash_cache {
+            Some(ref mut cache) => cache,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "no cache was loaded")),
+        };
+        let inodes: HashMap<*const Mutex<FileSet>, (u64, u64)> = self.by_inode.iter()
+            .map(|(&key, set)| (Shared::as_ptr(set), key)).collect();
+        for (content, filesets) in &self.by_content {
+            // Files of a group have the same content, but only the first one's is kept
+            if let Some(hash) = content.digest() {
+                for set in filesets {
+                    if let Some(&(dev, ino)) = inodes.get(&Shared::as_ptr(set)) {
+                        cache.insert(dev, ino, hash);
+                    }
+                }
+            }
+        }
+        cache.save(path.as_ref())
+    }
+
+    /// Links groups of duplicates found by another tool, without scanning. The groups are lists of paths,
+    /// one per line, separated by blank lines (as printed by `fdupes`).
+    ///
+    /// Members are linked like duplicates found by a scan (master choice, `dry_run`, `verify_content`, etc. apply),
+    /// except that only files of the same size on the same device are linked together. Paths that can't be stat-ed
+    /// are recorded in `errors`. Returns counters of just this call.
+    pub fn link_groups_from_reader<R: BufRead>(&mut self, reader: R) -> io::Result<Stats> {
+        self.apply_settings()?;
+        let mut stats = Stats::default();
+        let mut group = Vec::new();
+        for line in reader.split(b'\n') {
+            let line = line?;
+            if line.is_empty() {
+                self.link_group(mem::take(&mut group), &mut stats)?;
+            } else {
+                group.push(PathBuf::from(OsStr::from_bytes(&line)));
+            }
+        }
+        self.link_group(group, &mut stats)?;
+        Ok(stats)
+    }
+
+    /// Verifies files against a manifest of their SHA-256s (like a backup's), and links those that match it
+    /// and have the same hash, without scanning or comparing them again.
+    ///
+    /// Each file is read once, to hash it. Files that don't match are reported to `ScanListener::integrity_failure`,
+    /// counted in `integrity_failures` and left alone. Groups are linked as with `link_groups_from_reader`.
+    /// Files that can't be read are recorded in `errors`. Returns counters of just this call.
+    pub fn scan_with_manifest(&mut self, manifest: &HashMap<PathBuf, [u8; 32]>) -> io::Result<Stats> {
+        self.apply_settings()?;
+        self.scan_listener.base = self.settings.report_base.clone();
+        let mut stats = Stats::default();
+        let mut groups: BTreeMap<[u8; 32], Vec<PathBuf>> = BTreeMap::new();
+        let mut paths: Vec<_> = manifest.iter().collect();
+        paths.sort();
+        for (path, expected) in paths {
+            match sha256_file(path) {
+                Ok(ref actual) if actual == expected => groups.entry(*expected).or_default().push(path.clone()),
+                Ok(_) => {
+                    stats.integrity_failures += 1;
+                    self.scan_listener.integrity_failure(path, expected);
+                },
+                Err(err) => self.record_error(path.clone(), err)?,
+            }
+        }
+        for group in groups.into_values() {
+            self.link_group(group, &mut stats)?;
+        }
+        Ok(stats)
+    }
+
+    fn link_group(&mut self, paths: Vec<PathBuf>, stats: &mut Stats) -> io::Result<()> {
+        let mut by_inode: HashMap<(u64, u64), SharedFileSet> = HashMap::new();
+        let mut by_metadata: BTreeMap<Metadata, Vec<SharedFileSet>> = BTreeMap::new();
+        for path in paths {
+            let metadata = match fs::symlink_metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    self.record_error(path, err)?;
+                    continue;
+                },
+            };
+            if !metadata.is_file() {
+                stats.skipped += 1;
+                continue;
+            }
+            stats.added += 1;
+            match by_inode.entry((metadata.dev(), metadata.ino())) {
+                HashEntry::Occupied(e) => {
+                    stats.existing_hardlinks += 1;
+                    #[allow(deprecated)] {
+                        stats.hardlinks += 1;
+                    }
+                    e.get().lock().unwrap().push(path, metadata.nlink());
+                },
+                HashEntry::Vacant(e) => {
+                    let mut fileset = FileSet::new(path, metadata.nlink());
+                    fileset.created = metadata.created().ok();
+                    fileset.modified = metadata.modified().ok();
+                    let fileset = Shared::new(Mutex::new(fileset));
+                    e.insert(fileset.clone());
+                    by_metadata.entry(Metadata::new(&metadata)).or_default().push(fileset);
+                },
+            }
+        }
+
+        for filesets in by_metadata.values_mut().filter(|filesets| filesets.len() > 1) {
+            stats.dupes += filesets.len() - 1;
+            Deduper {
+                settings: &mut self.settings,
+                stats: &mut *stats,
+                scan_listener: &mut self.scan_listener,
+                script: &mut self.script,
+                plan: &mut self.plan,
+                roots: &self.roots,
+                errors: &mut self.errors,
+                next_op: &mut self.next_op,
+                readonly_devs: &mut self.readonly_devs,
+                preflight_devs: &mut self.preflight_devs,
+                journal: &mut self.journal,
+                linked_dirs: &mut self.linked_dirs,
+                filesystem: &*self.filesystem,
+            }.dedupe(filesets)?;
         }
         Ok(())
     }
 
+    /// Set the scan listener. Caution: This overrides previously set listeners!
+    /// Use a `MultiListener` if multiple listeners are required.
+    pub fn set_listener(&mut self, listener: Box<dyn ScanListener>) {
+        self.scan_listener.inner = listener;
+    }
 
-    fn add(&mut self, path: PathBuf, metadata: fs::Metadata) -> io::Result<()> {
-        self.scan_listener.file_scanned(&path, &self.stats);
+    /// Set what links, renames and removes files when duplicates are replaced. It's `StdFileSystem` by default.
+    pub fn set_filesystem(&mut self, filesystem: Box<dyn FileSystem>) {
+        self.filesystem = filesystem;
+    }
 
-        let ty = metadata.file_type();
-        if ty.is_dir() {
-            // Inode is truncated to group scanning of roughly close inodes together,
-            // But still preserve some directory traversal order.
-            // Negation to scan from the highest (assuming latest) first.
-            let order_key = !(metadata.ino() >> 8);
-            self.to_scan.push((order_key, path));
-            return Ok(());
-        } else if ty.is_symlink() {
-            // Support for traversing symlinks would require preventing loops
-            self.stats.skipped += 1;
-            return Ok(());
-        } else if !ty.is_file() {
-            // Deduping /dev/ would be funny
-            self.stats.skipped += 1;
+    /// Scan any file or directory for dupes.
+    /// Dedupe is done within the path as well as against all previously added paths.
+    pub fn scan<P: AsRef<Path>>(&mut self, path: P) -> Result<(), DupeError> {
+        if self.settings.use_snapshot {
+            self.scan_snapshot(path)?;
             return Ok(());
         }
+        self.enqueue(path)?;
+        self.flush()?;
+        Ok(())
+    }
 
-        if metadata.size() == 0 || (self.settings.ignore_small && metadata.size() < metadata.blksize()) {
-            self.stats.skipped += 1;
+    /// Like `scan`, and returns the stats of everything scanned so far
+    pub fn scan_report<P: AsRef<Path>>(&mut self, path: P) -> Result<Stats, DupeError> {
+        self.scan(path)?;
+        Ok(self.stats)
+    }
+
+    /// Scans all the roots into the same index (so duplicates across them are found and linked, as with `scan`
+    /// of each), with directories listed and stat-ed by `threads` threads at once.
+    /// That's what takes the longest on trees of many small files, especially over the network or on spinning disks
+    /// with several roots. Files are still compared and linked one at a time on this thread, as are files found
+    /// by listings of the threads, in the order they're done. So with more than one thread the order isn't stable,
+    /// even with `stable_order`, and `inode_order` only applies to the order of starting to list directories.
+    pub fn scan_all<P: AsRef<Path>>(&mut self, roots: &[P], threads: usize) -> io::Result<()> {
+        if self.settings.use_snapshot {
+            for root in roots {
+                self.scan_snapshot(root)?;
+            }
             return Ok(());
         }
+        for root in roots {
+            self.enqueue(root)?;
+        }
+        self.flush_with_threads(threads.max(1), None)
+    }
 
-        self.stats.added += 1;
+    /// Scans a snapshot of the root (see `Settings::snapshot_command`), so that files don't change while they're
+    /// compared, and then links the duplicates found in the live tree, like `apply_plan` with `verify_content`
+    /// (so files that changed since the snapshot are left alone). The snapshot is deleted afterwards.
+    ///
+    /// Results of the scan (`dupes` etc.) are of paths in the snapshot, which is gone by then,
+    /// so it's meant for a fresh `Scanner`, once. Returns counters of just the linking.
+    pub fn scan_snapshot<P: AsRef<Path>>(&mut self, root: P) -> io::Result<Stats> {
+        let root = fs::canonicalize(root)?;
+        let root_metadata = fs::metadata(&root)?;
+        let snapshot = match self.settings.snapshot_path {
+            Some(ref path) => path.clone(),
+            None => {
+                let mut name = root.file_name().unwrap_or_default().to_owned();
+                name.push(format!(".dupe-snapshot-{}", ::std::process::id()));
+                root.with_file_name(name)
+            },
+        };
+        run_snapshot_command(&self.settings.snapshot_command, &root, &snapshot)?;
 
-        let path_hardlinks = metadata.nlink();
-        let m = (metadata.dev(), metadata.ino());
+        let dry_run = mem::replace(&mut self.settings.dry_run, true);
+        let planned = self.plan.links.len();
+        let res = fs::canonicalize(&snapshot).and_then(|snapshot_root| {
+            self.enqueue(&snapshot_root)?;
+            self.flush()?;
+            Ok(snapshot_root)
+        });
+        self.settings.dry_run = dry_run;
+        let deleted = run_snapshot_command(&self.settings.snapshot_delete_command, &root, &snapshot);
+        let snapshot_root = res?;
+        deleted?;
 
-        // That's handling hardlinks
-        let fileset = match self.by_inode.entry(m) {
-            HashEntry::Vacant(e) => {
-                let fileset = Rc::new(Mutex::new(FileSet::new(path.clone(), path_hardlinks)));
-                e.insert(fileset.clone()); // clone just bumps a refcount here
-                fileset
-            },
-            HashEntry::Occupied(mut e) => {
-                self.stats.hardlinks += 1;
-                let mut t = e.get_mut().lock().unwrap();
-                t.push(path, path_hardlinks);
+        // Snapshots are usually a device of their own
+        let links = self.plan.links.split_off(planned).into_iter().filter_map(|link| Some(PlannedLink {
+            source: root.join(link.source.strip_prefix(&snapshot_root).ok()?),
+            dest: root.join(link.dest.strip_prefix(&snapshot_root).ok()?),
+            size: link.size,
+            dev: root_metadata.dev(),
+            confidence: link.confidence,
+        })).collect();
+        let verify_content = mem::replace(&mut self.settings.verify_content, true);
+        let res = self.apply_plan(&DryRunReport {links});
+        self.settings.verify_content = verify_content;
+        res
+    }
+
+    /// Scans the root like `scan`, but skips directories whose mtime is the same as in the previous run, which is read
+    /// from `snapshot_path` (if it exists) and written there when the scan is completed. Their subdirectories
+    /// (as of the previous run) are still visited, since a directory's mtime doesn't change when a subdirectory does.
+    ///
+    /// Files in unchanged directories aren't read, trusting that the previous run linked them already. Those with a hash
+    /// from the previous run (see `load_cache` and `with_digest_cache`) are added by it, so that new files elsewhere
+    /// are still linked to them, and the others aren't added at all. With `load_cache` every file this adds is hashed,
+    /// also unique ones, so that `save_cache` keeps the hashes for the next run. Files that changed in place (which doesn't
+    /// change the directory's mtime) are missed. A full `scan` now and then finds the rest.
+    ///
+    /// Linking changes the mtimes of directories, so those linked into are taken again at the end (which takes in
+    /// changes made by others in them during the scan too). The others keep the mtime they had when they were listed,
+    /// so that what others changed in them meanwhile is found by the next run.
+    pub fn incremental_scan<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, root: P, snapshot_path: Q) -> io::Result<()> {
+        self.dir_mtimes = Some(DirMtimes::load(snapshot_path.as_ref())?);
+        self.linked_dirs = Some(HashSet::new());
+        let res = self.enqueue(root).and_then(|_| self.flush());
+        let mut dir_mtimes = self.dir_mtimes.take().expect("set above");
+        let linked_dirs = self.linked_dirs.take().expect("set above");
+        res?;
+        if self.completed() {
+            dir_mtimes.refresh(&linked_dirs);
+            dir_mtimes.save(snapshot_path.as_ref())?;
+        }
+        Ok(())
+    }
+
+    /// Loop-mounts a disk image read-only, scans it and unmounts it again (also when the scan fails).
+    /// Nothing can be linked in it, so it's a dry run: duplicates are only reported, and in `dry_run_report`,
+    /// with paths under the mount point, which is gone by then.
+    ///
+    /// `mount_opts` are passed on to `mount -o` (e.g. `"offset=1048576"` for a partition of a whole-disk image),
+    /// and `ro` is always added after them. It's Linux only, and runs `mount(8)` and `umount(8)`, so it needs
+    /// the privileges to mount (usually root) and a free loop device. The mount point is a new directory
+    /// in the temporary directory, removed afterwards.
+    pub fn scan_image<P: AsRef<Path>>(&mut self, image: P, mount_opts: &str) -> io::Result<()> {
+        let image = fs::canonicalize(image)?;
+        let mount_point = ::std::env::temp_dir().join(format!("dupe-image-{}", ::std::process::id()));
+        fs::create_dir(&mount_point)?;
+        let mut opts = String::from("loop,");
+        if !mount_opts.is_empty() {
+            opts.push_str(mount_opts);
+            opts.push(',');
+        }
+        opts.push_str("ro");
+        let mount = ["mount".to_owned(), "-o".to_owned(), opts, "{src}".to_owned(), "{dst}".to_owned()];
+        if let Err(err) = run_snapshot_command(&mount, &image, &mount_point) {
+            let _ = fs::remove_dir(&mount_point);
+            return Err(err);
+        }
+
+        let dry_run = mem::replace(&mut self.settings.dry_run, true);
+        let res = self.enqueue(&mount_point).and_then(|_| self.flush());
+        self.settings.dry_run = dry_run;
+        let umount = ["umount".to_owned(), "{dst}".to_owned()];
+        let unmounted = run_snapshot_command(&umount, &image, &mount_point).and_then(|_| fs::remove_dir(&mount_point));
+        res?;
+        unmounted
+    }
+
+    /// Reads a tar stream (e.g. from stdin) and finds members with the same content, without extracting anything.
+    /// Members are only compared with members of archives scanned before, not with files on disk,
+    /// and there's nothing to link, so they're only reported, via `ScanListener::duplicate_found` and `tar_duplicates`.
+    ///
+    /// Regular files are read (ustar, GNU and pax formats), other members are skipped. Compressed streams aren't supported.
+    pub fn scan_tar<R: Read>(&mut self, reader: R) -> io::Result<()> {
+        let members = &mut self.tar_members;
+        let stats = &mut self.stats;
+        let scan_listener = &mut self.scan_listener;
+        tar::for_each_file(reader, |path, size, content| {
+            scan_listener.file_scanned(&path, stats);
+            if size == 0 {
+                stats.skipped += 1;
                 return Ok(());
             }
-        };
+            let mut hash = Sha256::new();
+            let mut buf = vec![0; 1 << 16];
+            loop {
+                let len = content.read(&mut buf)?;
+                if len == 0 {
+                    break;
+                }
+                hash.update(&buf[..len]);
+            }
+            stats.added += 1;
+            let paths = members.entry((size, hash.finish())).or_default();
+            if let Some(first) = paths.first() {
+                stats.dupes += 1;
+                scan_listener.duplicate_found(&path, first);
+            }
+            paths.push(path);
+            Ok(())
+        })
+    }
 
-        // Here's where all the magic happens
-        match self.by_content.entry(FileContent::new(path, Metadata::new(&metadata))) {
-            BTreeEntry::Vacant(e) => {
-                // Seems unique so far
-                e.insert(vec![fileset]);
-            },
-            BTreeEntry::Occupied(mut e) => {
-                // Found a dupe!
-                self.stats.dupes += 1;
-                let filesets = e.get_mut();
-                filesets.push(fileset);
-                Self::dedupe(filesets, self.settings.dry_run, &mut self.scan_listener)?;
-            },
+    /// Paths (as in the archives) of members with the same content, found by `scan_tar`
+    pub fn tar_duplicates(&self) -> Vec<Vec<PathBuf>> {
+        let mut groups: Vec<Vec<PathBuf>> = self.tar_members.values().filter(|paths| paths.len() > 1).cloned().collect();
+        groups.sort();
+        groups
+    }
+
+    pub fn enqueue<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.apply_settings()?;
+        let path = fs::canonicalize(path)?;
+        let metadata = fs::symlink_metadata(&path)?;
+        self.add_root(path, metadata)
+    }
+
+    /// Like `enqueue`, but a root that can't be resolved or stat-ed (e.g. deleted since the list of paths was made)
+    /// is recorded in `errors` instead of failing the scan.
+    pub fn enqueue_lenient<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.apply_settings()?;
+        let path = path.as_ref();
+        let root = fs::canonicalize(path).and_then(|root| {
+            let metadata = fs::symlink_metadata(&root)?;
+            Ok((root, metadata))
+        });
+        match root {
+            Ok((root, metadata)) => self.add_root(root, metadata),
+            Err(err) => self.record_error(path.to_owned(), err),
+        }
+    }
+
+    /// Like `enqueue`, but without canonicalizing the path, which saves a few syscalls per path
+    /// for tools that enqueue millions of them.
+    ///
+    /// The path must be absolute (it's an `InvalidInput` error otherwise) and canonical already: without symlinks,
+    /// `.` or `..`. Otherwise the same file may be reached by different paths, and is then processed twice,
+    /// and `Settings::confine_to_roots` compares against a root that other paths don't start with.
+    pub fn enqueue_trusted<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.apply_settings()?;
+        let path = path.as_ref();
+        if !path.is_absolute() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not an absolute path", path.display())));
+        }
+        let metadata = fs::symlink_metadata(path)?;
+        self.add_root(path.to_owned(), metadata)
+    }
+
+    fn add_root(&mut self, root: PathBuf, metadata: fs::Metadata) -> io::Result<()> {
+        if self.settings.auto_tune && self.roots.is_empty() {
+            self.rotational = sys::is_rotational(metadata.dev());
+            self.apply_settings()?;
         }
+        self.roots.push(root.clone());
+        self.add(root, metadata)
+    }
+
+    /// Skips files and directories matching the pattern, e.g. `**/.git/**`, `**/node_modules/**` or `*.tmp`.
+    /// An excluded directory isn't listed at all. Patterns are matched against the canonicalized paths (or
+    /// against names if they have no `/`), with `*` for any part of a name, `**` for any part of a path,
+    /// `?` for one character, and `[a-z]`/`[!a-z]` for a set. An invalid pattern is an `InvalidInput` error.
+    pub fn add_exclude(&mut self, pattern: &str) -> io::Result<()> {
+        self.excludes.push(Glob::new(pattern)?);
         Ok(())
     }
 
-    fn dedupe(filesets: &mut Vec<Rc<Mutex<FileSet>>>, dry_run: bool, scan_listener: &mut Box<ScanListener>) -> io::Result<()> {
-        // Find file with the largest number of hardlinks, since it's less work to merge a small group into a large group
-        let (largest_idx, merged_fileset) = filesets.iter().enumerate().max_by_key(|&(i,f)| (f.lock().unwrap().links(),!i)).expect("fileset can't be empty");
+    /// Adds a path whose `symlink_metadata` the caller already has, e.g. from its own directory walk.
+    ///
+    /// Unlike `enqueue` the path is not canonicalized nor stat-ed again, and it's not treated as a scan root.
+    /// Directories are queued as usual and scanned on the next `flush`.
+    pub fn add_with_metadata(&mut self, path: PathBuf, metadata: fs::Metadata) -> io::Result<()> {
+        self.apply_settings()?;
+        self.add(path, metadata)
+    }
 
-        // The set is still going to be in use! So everything has to be updated to make sense for the next call
-        let merged_paths = &mut merged_fileset.lock().unwrap().paths;
-        let source_path = merged_paths[0].clone();
-        for (i, set) in filesets.iter().enumerate() {
-            // We don't want to merge the set with itself
-            if i == largest_idx {continue;}
+    /// Adds one regular file right away, like a file found by a scan (so with `dry_run` nothing is changed),
+    /// and returns the other paths with its content found so far, or `None` if there are none.
+    /// Its hardlinks are included, and a file skipped by the settings (e.g. small with `ignore_small`) has none.
+    /// Directories queued before aren't scanned. Anything else than a regular file is an `InvalidInput` error.
+    pub fn check_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<Option<Vec<PathBuf>>> {
+        self.apply_settings()?;
+        let path = fs::canonicalize(path)?;
+        let metadata = fs::symlink_metadata(&path)?;
+        if !metadata.is_file() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a regular file", path.display())));
+        }
+        let (dev, ino, size) = (metadata.dev(), metadata.ino(), metadata.size());
+        let key = self.content_metadata(&metadata);
+        self.add(path.clone(), metadata)?;
+        let fileset = match self.by_inode.get(&(dev, ino)) {
+            Some(fileset) => fileset,
+            None => return Ok(None),
+        };
+        // Merged sets stay in their group, so it's found even if the file was linked
+        let normalized = self.settings.content_normalizer.is_some();
+        let group = self.by_content.iter()
+            .filter(|(content, _)| content.metadata().dev == key.dev && (normalized || content.metadata().size == size))
+            .map(|(_, filesets)| filesets)
+            .find(|filesets| filesets.iter().any(|set| Shared::ptr_eq(set, fileset)));
+        let others: Vec<PathBuf> = group.into_iter().flatten()
+            .flat_map(|set| set.lock().unwrap().paths.clone())
+            .filter(|other| *other != path)
+            .collect();
+        Ok(if others.is_empty() {None} else {Some(others)})
+    }
 
-            let paths = &mut set.lock().unwrap().paths;
-            // dest_path will be "lost" on error, but that's fine, since we don't want to dedupe it if it causes errors
-            for dest_path in paths.drain(..) {
-                assert_ne!(&source_path, &dest_path);
-                debug_assert_ne!(fs::symlink_metadata(&source_path)?.ino(), fs::symlink_metadata(&dest_path)?.ino());
+    /// Adds files from a list of `path<TAB>size` lines, e.g. from an index of a huge dataset.
+    ///
+    /// Files whose listed size is unique in the list can't have duplicates in it, so they're dropped
+    /// without even being stat-ed, and counted in `Stats::listed_unique_skipped`. Duplicates of files added
+    /// before, or of files that grew to another listed size since the list was made, won't be found then.
+    /// The rest are stat-ed and added like with `add_with_metadata`, using their actual size
+    /// (it's a changed file if it's not the listed one, counted in `Stats::listed_size_changed`).
+    /// Paths that can't be stat-ed are recorded in `errors`. A malformed line is an `InvalidData` error.
+    pub fn enqueue_with_sizes<R: BufRead>(&mut self, list: R) -> io::Result<()> {
+        self.apply_settings()?;
+        let mut files = Vec::new();
+        for (line_no, line) in list.split(b'\n').enumerate() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            // Size is last, so that paths can have tabs
+            let parsed = line.iter().rposition(|&c| c == b'\t').and_then(|tab| {
+                let size = str::from_utf8(&line[tab+1..]).ok()?.parse::<u64>().ok()?;
+                Some((PathBuf::from(OsStr::from_bytes(&line[..tab])), size))
+            });
+            match parsed {
+                Some(file) => files.push(file),
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("line {} isn't path<TAB>size", line_no + 1))),
+            }
+        }
 
-                if dry_run {
-                    scan_listener.duplicate_found(&dest_path, &source_path);
-                    merged_paths.push(dest_path);
+        let mut same_size = HashMap::new();
+        for &(_, size) in &files {
+            *same_size.entry(size).or_insert(0usize) += 1;
+        }
+        for (path, size) in files {
+            if same_size[&size] < 2 {
+                self.stats.listed_unique_skipped += 1;
+                self.stats.skipped += 1;
+                continue;
+            }
+            let metadata = match fs::symlink_metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    self.skip_error(path, err)?;
                     continue;
-                }
-
-                let temp_path = dest_path.with_file_name(".tmp-dupe-e1iIQcBFn5pC4MUSm-xkcd-221");
-                debug_assert!(!temp_path.exists());
-                debug_assert!(source_path.exists());
-                debug_assert!(dest_path.exists());
+                },
+            };
+            if metadata.size() != size {
+                self.stats.listed_size_changed += 1;
+            }
+            self.add(path, metadata)?;
+        }
+        Ok(())
+    }
 
-                // In posix link guarantees not to overwrite, and mv guarantes to move atomically
-                // so this two-step replacement is pretty robust
-                if let Err(err) = fs::hard_link(&source_path, &temp_path) {
-                    println!("unable to hardlink {} {} due to {:?}", source_path.display(), temp_path.display(), err);
-                    fs::remove_file(temp_path).ok();
-                    return Err(err);
-                }
-                if let Err(err) = fs::rename(&temp_path, &dest_path) {
-                    println!("unable to rename {} {} due to {:?}", temp_path.display(), dest_path.display(), err);
-                    fs::remove_file(temp_path).ok();
-                    return Err(err);
-                }
-                debug_assert!(!temp_path.exists());
-                debug_assert!(source_path.exists());
-                debug_assert!(dest_path.exists());
-                scan_listener.hardlinked(&dest_path, &source_path);
-                merged_paths.push(dest_path);
+    /// Brings lazily-initialized state in line with `settings`, which may have changed since the last call
+    fn apply_settings(&mut self) -> io::Result<()> {
+        self.scan_listener.base = self.settings.report_base.clone();
+        if self.unique_filter.is_none() {
+            if let Some(ref filter_path) = self.settings.unique_bloom {
+                self.unique_filter = Some(match BloomFilter::load(filter_path) {
+                    Ok(filter) => filter,
+                    Err(ref err) if err.kind() == io::ErrorKind::NotFound => BloomFilter::with_capacity(0),
+                    Err(err) => return Err(err),
+                });
             }
         }
+
+        if self.content_options.compare_from_tail != self.settings.compare_from_tail && self.stats.added > 0 {
+            return Err(DupeError::InvalidSettings("compare_from_tail can't be changed after files have been added").into());
+        }
+        if self.content_options.quick_check != self.settings.quick_check && self.stats.added > 0 {
+            return Err(DupeError::InvalidSettings("quick_check can't be changed after files have been added").into());
+        }
+        let same_normalizer = match (&self.content_options.normalizer, &self.settings.content_normalizer) {
+            (Some(a), Some(b)) => Shared::ptr_eq(a, b),
+            (a, b) => a.is_none() && b.is_none(),
+        };
+        if !same_normalizer && self.stats.added > 0 {
+            return Err(DupeError::InvalidSettings("content_normalizer can't be changed after files have been added").into());
+        }
+        if self.content_options.bucket_by_parent != self.settings.bucket_by_parent && self.stats.added > 0 {
+            return Err(DupeError::InvalidSettings("bucket_by_parent can't be changed after files have been added").into());
+        }
+        let use_digests = self.digest_cache.is_some() || self.hash_cache.is_some() || self.settings.hash_xattr.is_some() || self.parallel_digests;
+        if self.content_options.use_digests != use_digests && self.stats.added > 0 {
+            return Err(DupeError::InvalidSettings("hash_xattr can't be changed (nor flush_parallel be used) after files have been added").into());
+        }
+        let write_xattr = if self.settings.write_hash_xattr {self.settings.hash_xattr.clone()} else {None};
+        if self.content_options.double_hash != self.settings.double_hash && self.stats.added > 0 {
+            return Err(DupeError::InvalidSettings("double_hash can't be changed after files have been added").into());
+        }
+        if self.settings.double_hash && use_digests {
+            return Err(DupeError::InvalidSettings("double_hash can't be used with a digest cache").into());
+        }
+        let max_read_size = self.settings.max_read_size
+            .or(self.tuned(|rotational| if rotational {DEFAULT_MAX_READ_SIZE} else {8*1024*1024}))
+            .unwrap_or(DEFAULT_MAX_READ_SIZE);
+        if self.content_options.read_timeout != self.settings.read_timeout
+            || self.content_options.slow_file_threshold != self.settings.slow_file_threshold
+            || self.content_options.compare_from_tail != self.settings.compare_from_tail
+            || self.content_options.quick_check != self.settings.quick_check
+            || self.content_options.use_digests != use_digests || self.content_options.max_read_size != max_read_size || !same_normalizer
+            || self.content_options.bucket_by_parent != self.settings.bucket_by_parent
+            || self.content_options.mmap_threshold != self.settings.use_mmap_threshold || self.content_options.double_hash != self.settings.double_hash
+            || self.content_options.count_comparisons != self.settings.collect_comparison_metrics
+            || self.content_options.write_xattr != write_xattr {
+            // Counts not taken yet would be lost with the old options
+            self.report_reads();
+            self.content_options = Shared::new(ContentOptions {
+                read_timeout: self.settings.read_timeout,
+                slow_file_threshold: self.settings.slow_file_threshold,
+                compare_from_tail: self.settings.compare_from_tail,
+                quick_check: self.settings.quick_check,
+                use_digests,
+                max_read_size,
+                normalizer: self.settings.content_normalizer.clone(),
+                bucket_by_parent: self.settings.bucket_by_parent,
+                mmap_threshold: self.settings.use_mmap_threshold,
+                double_hash: self.settings.double_hash,
+                count_comparisons: self.settings.collect_comparison_metrics,
+                write_xattr,
+                ..ContentOptions::default()
+            });
+        }
         Ok(())
     }
 
-    pub fn dupes(&self) -> Vec<FileSet> {
-        self.by_inode.iter().map(|(_,d)|{
-            let tmp = d.lock().unwrap();
-            (*tmp).clone()
-        }).collect()
+    /// Value picked by `Settings::auto_tune` for the kind of disk, if it's known
+    fn tuned<T, F: FnOnce(bool) -> T>(&self, pick: F) -> Option<T> {
+        if self.settings.auto_tune {self.rotational.map(pick)} else {None}
+    }
+
+    /// Drains the queue of directories to scan (or stops early, see `Settings::max_duration`)
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.flush_with_threads(1, None)
+    }
+
+    /// Like `flush`, but it also stops once `cancel` is set, e.g. by a signal handler or another thread, as it does
+    /// at `Settings::max_duration`: between files, so a file is never left half replaced. Links made so far stay,
+    /// `scan_over` gets the stats of what was done, and the rest is left for the next `flush` (see `completed`).
+    pub fn flush_cancellable(&mut self, cancel: Arc<AtomicBool>) -> io::Result<()> {
+        self.flush_with_threads(1, Some(&cancel))
     }
-}
 
+    /// Like `flush`, but directories are listed by `threads` threads (as with `scan_all`), and the files found are
+    /// hashed by as many at once, before they're added to the content index. Linking stays on this thread, one
+    /// group after the other.
+    ///
+    /// Files are compared by hashes of their whole content (as with a digest cache) instead of ranges, so it can't be
+    /// mixed with `flush` once files have been added. Only files with the size of another one found in the same call
+    /// are hashed ahead, the others are read only if they need to be. With a `content_normalizer` or a `read_timeout`
+    /// everything is read on this thread, as with `flush`.
+    pub fn flush_parallel(&mut self, threads: usize) -> io::Result<()> {
+        self.parallel_digests = true;
+        if let Err(err) = self.apply_settings() {
+            self.parallel_digests = false;
+            return Err(err);
+        }
+        self.deferred = Some(Vec::new());
+        self.flush_with_threads(threads.max(1), None)
+    }
+
+    fn flush_with_threads(&mut self, threads: usize, cancel: Option<&AtomicBool>) -> io::Result<()> {
+        let start_time = Instant::now();
+        let deadline = self.settings.max_duration.map(|max| start_time + max);
+        let past_deadline = || cancel.is_some_and(|cancel| cancel.load(AtomicOrdering::Relaxed)) ||
+            deadline.is_some_and(|deadline| Instant::now() >= deadline);
+
+        self.heartbeat_start = Some(start_time);
+        self.last_heartbeat = start_time;
+        let total_files = self.expected_files.take();
+        self.scan_listener.scan_started(total_files);
+        let mut res = self.drain(threads, &past_deadline);
+        // Files added so far are indexed even if the scan failed, like with `flush`
+        if let Some(deferred) = self.deferred.take() {
+            res = res.and(self.index_deferred(deferred, threads));
+        }
+        // Not after scan_over
+        self.heartbeat_start = None;
+        res?;
+        if self.settings.defer_links && !self.settings.dry_run && self.completed() {
+            let plan = mem::take(&mut self.plan);
+            let stats = self.apply_plan(&plan)?;
+            self.add_link_stats(&stats);
+        }
+        if let Some(ref filter_path) = self.settings.unique_bloom {
+            self.unique_filter().save(filter_path)?;
+        }
+        let scan_duration = Instant::now().duration_since(start_time);
+        self.scan_listener.scan_over(self, &self.stats, scan_duration);
+        Ok(())
+    }
+
+    /// Scans what's queued (and changed, with `Settings::rescan_changed_dirs`), until the deadline
+    fn drain(&mut self, threads: usize, past_deadline: &dyn Fn() -> bool) -> io::Result<()> {
+        let unfinished = mem::take(&mut self.unfinished_entries);
+        for (path, depth) in unfinished {
+            if past_deadline() {
+                self.unfinished_entries.push((path, depth));
+                continue;
+            }
+            let metadata = fs::symlink_metadata(&path);
+            self.entry_depth = depth;
+            let res = self.add_listed(path, metadata);
+            self.entry_depth = 0;
+            res?;
+        }
+        let mut rescans = 0;
+        loop {
+            if threads > 1 {
+                self.scan_dirs_in_threads(threads, past_deadline)?;
+            }
+            while !past_deadline() {
+                let (path, inode, depth) = match self.to_scan.pop() {
+                    Some((_, Reverse(path), inode, depth)) => (path, inode, depth),
+                    None => break,
+                };
+                self.current_dir = Some(path.clone());
+                self.entry_depth = depth + 1;
+                let res = self.scan_dir(path, inode, depth, past_deadline);
+                self.current_dir = None;
+                self.entry_depth = 0;
+                res?;
+                self.heartbeat();
+            }
+            if !self.settings.rescan_changed_dirs || past_deadline() || !self.to_scan.is_empty() {
+                break;
+            }
+            let changed: Vec<_> = self.dir_snapshots.iter()
+                .filter(|&(path, snapshot)| fs::metadata(path).and_then(|m| m.modified()).ok() != Some(snapshot.mtime))
+                .map(|(path, snapshot)| (path.clone(), snapshot.inode, snapshot.depth))
+                .collect();
+            if changed.is_empty() || rescans == MAX_RESCAN_PASSES {
+                self.dir_snapshots.clear();
+                break;
+            }
+            rescans += 1;
+            for (path, inode, depth) in changed {
+                self.stats.rescanned_dirs += 1;
+                self.current_dir = Some(path.clone());
+                self.entry_depth = depth + 1;
+                let res = self.scan_dir(path, inode, depth, past_deadline);
+                self.current_dir = None;
+                self.entry_depth = 0;
+                res?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drains the queue like `flush`, but directories are listed and their entries stat-ed by worker threads,
+    /// a few directories ahead. Entries are added on this thread, in the order the listings are done.
+    fn scan_dirs_in_threads(&mut self, threads: usize, past_deadline: &dyn Fn() -> bool) -> io::Result<()> {
+        let with_mtime = self.settings.rescan_changed_dirs;
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::scope(|scope| {
+            // Dropped on return, which lets the workers go
+            let (job_tx, job_rx) = mpsc::channel::<(PathBuf, (u64, u64), usize)>();
+            let job_rx = Arc::new(Mutex::new(job_rx));
+            for _ in 0..threads {
+                let job_rx = job_rx.clone();
+                let done_tx = done_tx.clone();
+                scope.spawn(move || loop {
+                    let job = job_rx.lock().unwrap().recv();
+                    let (path, inode, depth) = match job {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+                    let listing = Listing::read(&path, with_mtime, true);
+                    if done_tx.send((path, inode, depth, listing)).is_err() {
+                        return;
+                    }
+                });
+            }
+
+            let mut listing = 0;
+            loop {
+                // Enough to keep all threads busy while the entries of one are added
+                while listing < threads * 2 && !past_deadline() {
+                    match self.to_scan.pop() {
+                        Some((_, Reverse(path), inode, depth)) => {
+                            job_tx.send((path, inode, depth)).expect("workers wait for jobs");
+                            listing += 1;
+                        },
+                        None => break,
+                    }
+                }
+                if listing == 0 {
+                    return Ok(());
+                }
+                let (path, inode, depth, entries) = done_rx.recv().expect("workers finish their jobs");
+                listing -= 1;
+                self.current_dir = Some(path.clone());
+                self.entry_depth = depth + 1;
+                let res = self.add_listing(path, inode, depth, entries, past_deadline);
+                self.current_dir = None;
+                self.entry_depth = 0;
+                res?;
+            }
+        })
+    }
+
+    fn scan_dir(&mut self, path: PathBuf, inode: (u64, u64), depth: usize, past_deadline: &dyn Fn() -> bool) -> io::Result<()> {
+        if self.dir_mtimes.is_some() {
+            if let Ok(mtime) = fs::metadata(&path).and_then(|m| m.modified()) {
+                let unchanged = self.dir_mtimes.as_mut().and_then(|mtimes| mtimes.visit(&path, mtime)).map(<[PathBuf]>::to_vec);
+                if let Some(subdirs) = unchanged {
+                    // Its files were deduped by the previous run, but its subdirectories may have changed
+                    self.stats.unchanged_dirs += 1;
+                    if self.digest_cache.is_some() || self.hash_cache.is_some() {
+                        self.seed_unchanged(&path)?;
+                    }
+                    for subdir in subdirs {
+                        match fs::symlink_metadata(&subdir) {
+                            Ok(metadata) if metadata.is_dir() => self.add_entry(subdir, metadata)?,
+                            _ => {},
+                        }
+                    }
+                    return Ok(());
+                }
+            }
+        }
+        let listing = Listing::read(&path, self.settings.rescan_changed_dirs, false);
+        self.add_listing(path, inode, depth, listing, past_deadline)
+    }
+
+    /// Adds the files of an unchanged directory that have a cached hash, so that new duplicates of them are found
+    /// without reading them
+    fn seed_unchanged(&mut self, dir: &Path) -> io::Result<()> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) => return self.skip_error(dir.to_owned(), err),
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            match fs::symlink_metadata(&path) {
+                Ok(metadata) if metadata.is_file() && self.has_cached_hash(&path, &metadata) => {
+                    self.stats.seeded_files += 1;
+                    self.add_entry(path, metadata)?;
+                },
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the hash of the file is known from `with_digest_cache` or `load_cache`, so it needn't be read
+    fn has_cached_hash(&self, path: &Path, metadata: &fs::Metadata) -> bool {
+        self.digest_cache.as_ref()
+            .and_then(|cache| cache.get(&(metadata.size(), path.to_owned())))
+            .is_some_and(|digest| digest.mtime == (metadata.mtime(), metadata.mtime_nsec()))
+            || self.hash_cache.as_ref().is_some_and(|cache| cache.get(metadata).is_some())
+    }
+
+    fn add_listing(&mut self, path: PathBuf, inode: (u64, u64), depth: usize, listing: Listing, past_deadline: &dyn Fn() -> bool) -> io::Result<()> {
+        let mtime = listing.mtime;
+        // Names listed before, if it's a rescan
+        let mut names = self.dir_snapshots.remove(&path).map(|snapshot| snapshot.names).unwrap_or_default();
+
+        // Errors are collected rather than returned, since it's super common to find permission denied
+        // and unreadable symlinks, and it'd be annoying if that aborted the whole operation.
+        let mut entries = match listing.entries {
+            Ok(entries) => entries,
+            Err(err) => {
+                self.stats.unreadable_dirs += 1;
+                self.skip_error(path, err)?;
+                return Ok(());
+            },
+        };
+        match self.scanned_dirs.entry(inode) {
+            HashEntry::Vacant(entry) => {
+                entry.insert(path.clone());
+                self.stats.directories += 1;
+                self.scan_listener.directory_scanned(&path, &self.stats);
+            },
+            HashEntry::Occupied(ref entry) if self.settings.dedup_directory_visits && *entry.get() != path => return Ok(()),
+            HashEntry::Occupied(_) => {},
+        }
+        if self.settings.stable_order {
+            entries.sort_by_key(|(entry, _)| entry.file_name());
+        }
+        if self.settings.files_before_subdirs {
+            // Stable, so it keeps the order within files and within directories
+            entries.sort_by_key(|(entry, _)| entry.file_type().is_ok_and(|ty| ty.is_dir()));
+        }
+        for (entry, metadata) in entries {
+            if mtime.is_some() && !names.insert(entry.file_name()) {
+                continue;
+            }
+            let path = entry.path();
+            if past_deadline() {
+                self.unfinished_entries.push((path, self.entry_depth));
+                continue;
+            }
+            self.add_listed(path, metadata.unwrap_or_else(|| entry.metadata()))?;
+            self.heartbeat();
+        }
+        if let Some(mtime) = mtime {
+            self.dir_snapshots.insert(path, DirSnapshot {inode, depth, mtime, names});
+        }
+        Ok(())
+    }
+
+    /// Adds an entry found in a directory listing, which may have been deleted since
+    fn add_listed(&mut self, path: PathBuf, metadata: io::Result<fs::Metadata>) -> io::Result<()> {
+        match metadata {
+            // Still open somewhere, but it's gone from the directory
+            Ok(ref metadata) if metadata.nlink() == 0 => self.skip_deleted(),
+            Ok(metadata) => self.add_entry(path, metadata)?,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => self.skip_deleted(),
+            Err(err) => self.skip_error(path, err)?,
+        }
+        Ok(())
+    }
+
+    fn skip_deleted(&mut self) {
+        self.stats.deleted_during_scan += 1;
+        self.stats.skipped += 1;
+    }
+
+    fn add_entry(&mut self, path: PathBuf, metadata: fs::Metadata) -> io::Result<()> {
+        if let Err(err) = self.add(path.clone(), metadata) {
+            if self.settings.strict {
+                return Err(strict_error(&path, err));
+            }
+            if sys::is_name_too_long(&err) {
+                self.skip_error(path, err)?;
+            } else {
+                self.record_error(path, err)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The rule that skips the regular file, if any, for `add` and `count_entries`
+    fn file_rule(&self, path: &Path, metadata: &fs::Metadata) -> Option<FileRule> {
+        if self.settings.only_own_files && metadata.uid() != sys::effective_uid() {
+            return Some(FileRule::NotOwned);
+        }
+        if self.settings.skip_partial_downloads && self.is_partial_download(path) {
+            return Some(FileRule::PartialDownload);
+        }
+        if let Some(min_age) = self.settings.min_age {
+            // Unknown mtime doesn't make it fresh
+            let fresh = metadata.modified().map(|mtime| mtime.elapsed().map_or(true, |age| age < min_age)).unwrap_or(false);
+            if fresh {
+                return Some(FileRule::TooFresh);
+            }
+        }
+        if self.settings.ctime_after.is_some_and(|after| ctime(metadata) <= after) {
+            return Some(FileRule::Ctime);
+        }
+        let exempt = || lowercase_extension(path).is_some_and(|ext| self.settings.always_consider_extensions.contains(&ext));
+        let min_size = self.settings.min_size.unwrap_or_else(|| metadata.blksize());
+        if metadata.size() == 0 || (self.settings.ignore_small && metadata.size() < min_size && !exempt()) {
+            return Some(FileRule::Small);
+        }
+        None
+    }
+
+    fn is_partial_download(&self, path: &Path) -> bool {
+        let name = match path.file_name() {
+            Some(name) => name.as_bytes(),
+            None => return false,
+        };
+        self.settings.partial_suffixes.iter().any(|suffix| {
+            let suffix = suffix.as_bytes();
+            name.len() >= suffix.len() && name[name.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+        })
+    }
+
+    /// The path is skipped, but the error is kept for `errors` (or returned, see `Settings::strict`)
+    fn skip_error(&mut self, path: PathBuf, err: io::Error) -> io::Result<()> {
+        if sys::is_name_too_long(&err) {
+            self.stats.name_too_long += 1;
+        }
+        self.stats.skipped += 1;
+        self.record_error(path, err)
+    }
+
+    /// Keeps the error for `errors`, or returns it with `Settings::strict`
+    fn record_error(&mut self, path: PathBuf, err: io::Error) -> io::Result<()> {
+        if self.settings.strict {
+            return Err(strict_error(&path, err));
+        }
+        self.scan_listener.scan_error(&path, &err);
+        self.errors.push((path, err));
+        Ok(())
+    }
+
+
+    fn add(&mut self, path: PathBuf, metadata: fs::Metadata) -> io::Result<()> {
+        self.scan_listener.file_scanned(&path, &self.stats);
+
+        // Paths found by scanning are in a root already, but followed symlinks' targets may not be
+        if self.settings.confine_to_roots && !self.roots.iter().any(|root| path.starts_with(root)) {
+            self.stats.outside_roots += 1;
+            self.skip_error(path.clone(), outside_roots_error(&path))?;
+            return Ok(());
+        }
+
+        let ty = metadata.file_type();
+        if self.excludes.iter().any(|glob| glob.matches(&path, ty.is_dir())) {
+            self.stats.excluded += 1;
+            self.stats.skipped += 1;
+            return Ok(());
+        }
+
+        // It's only a link to another file, and it'd get in the way of linking in its directory
+        if !ty.is_dir() && path.file_name().is_some_and(temp::is_temp_name) {
+            self.stats.skipped += 1;
+            // Another run (or another scanner of this one) is replacing a file
+            if path.file_name().is_some_and(temp::in_use) {
+                return Ok(());
+            }
+            self.stats.orphan_temps += 1;
+            self.scan_listener.orphan_temp_found(&path);
+            if self.settings.clean_orphans && !self.settings.dry_run {
+                // One without other links may be the only copy of a replaced file, so it's kept and reported
+                if let Err(err) = temp::remove_stale(&path) {
+                    self.record_error(path, err)?;
+                }
+            }
+            return Ok(());
+        }
+
+        if ty.is_dir() {
+            let depth = self.entry_depth;
+            if self.settings.max_depth.is_some_and(|max_depth| depth > max_depth) {
+                self.stats.too_deep += 1;
+                self.stats.skipped += 1;
+                return Ok(());
+            }
+            let inode_order = self.settings.inode_order.or(self.tuned(|rotational| rotational)).unwrap_or(true);
+            let order_key = if self.settings.breadth_first {
+                // Shallowest first, then first found (or the path decides)
+                let depth = path.components().count() as u64;
+                let queued = if self.settings.stable_order {0} else {
+                    self.dirs_queued += 1;
+                    self.dirs_queued
+                };
+                !(depth << 40 | queued)
+            } else if self.settings.stable_order {
+                // All equal, so it's by path only
+                0
+            } else if inode_order {
+                // Inode is truncated to group scanning of roughly close inodes together,
+                // But still preserve some directory traversal order.
+                // Negation to scan from the highest (assuming latest) first.
+                !(metadata.ino() >> 8)
+            } else {
+                // First found, first scanned
+                self.dirs_queued += 1;
+                !self.dirs_queued
+            };
+            self.to_scan.push((order_key, Reverse(path), (metadata.dev(), metadata.ino()), depth));
+            return Ok(());
+        } else if ty.is_symlink() {
+            if self.should_follow_symlink(&path) {
+                return self.follow_symlink(path);
+            }
+            // Following them is opt-in (see `Settings::follow_symlinks`), since they may point anywhere
+            self.stats.symlinks_skipped += 1;
+            self.stats.skipped += 1;
+            return Ok(());
+        } else if !ty.is_file() {
+            // Deduping /dev/ would be funny
+            self.stats.special_files += 1;
+            self.stats.skipped += 1;
+            self.scan_listener.special_file(&path, SpecialFileKind::new(ty));
+            return Ok(());
+        }
+
+        if let Some(rule) = self.file_rule(&path, &metadata) {
+            match rule {
+                FileRule::NotOwned => self.stats.not_owned_skipped += 1,
+                FileRule::PartialDownload => self.stats.partial_skipped += 1,
+                FileRule::TooFresh => self.stats.too_fresh_skipped += 1,
+                FileRule::Ctime => self.stats.ctime_skipped += 1,
+                FileRule::Small => {},
+            }
+            self.stats.skipped += 1;
+            return Ok(());
+        }
+
+        if self.settings.hash_only {
+            match sha256_file(&path) {
+                Ok(hash) => {
+                    self.stats.indexed += 1;
+                    self.stats.bytes_read += metadata.size();
+                    self.scan_listener.content_indexed(&path, metadata.size(), &hash);
+                },
+                Err(err) => self.skip_error(path, err)?,
+            }
+            return Ok(());
+        }
+
+        self.stats.added += 1;
+
+        let path_hardlinks = metadata.nlink();
+        let m = (metadata.dev(), metadata.ino());
+
+        // That's handling hardlinks
+        let fileset = match self.by_inode.entry(m) {
+            HashEntry::Vacant(e) => {
+                self.stats.total_apparent_bytes += metadata.size();
+                self.stats.total_allocated_bytes += metadata.blocks() * 512;
+                if path_hardlinks > 1 {
+                    self.preexisting.inodes += 1;
+                    self.preexisting.bytes += metadata.size();
+                    self.preexisting.saved_bytes += metadata.size() * (path_hardlinks - 1);
+                }
+                let mut fileset = FileSet::new(path.clone(), path_hardlinks);
+                fileset.created = metadata.created().ok();
+                fileset.modified = metadata.modified().ok();
+                let fileset = Shared::new(Mutex::new(fileset));
+                e.insert(fileset.clone()); // clone just bumps a refcount here
+                fileset
+            },
+            HashEntry::Occupied(mut e) => {
+                let mut t = e.get_mut().lock().unwrap();
+                // Not another hardlink, but the same one again. Linking it to itself would be nonsense.
+                if t.paths.iter().any(|known| is_same_entry(known, &path)) {
+                    self.stats.path_aliases += 1;
+                    self.stats.skipped += 1;
+                    return Ok(());
+                }
+                self.stats.existing_hardlinks += 1;
+                #[allow(deprecated)] {
+                    self.stats.hardlinks += 1;
+                }
+                t.push_limited(path, path_hardlinks, self.settings.max_tracked_paths_per_inode);
+                return Ok(());
+            }
+        };
+
+        if let Some(ref mut deferred) = self.deferred {
+            deferred.push((path, metadata, fileset));
+            return Ok(());
+        }
+        self.index(path, metadata, fileset, None)
+    }
+
+    /// Adds a file with a new inode to the content index, which finds (and links) its duplicates
+    fn index(&mut self, path: PathBuf, metadata: fs::Metadata, fileset: SharedFileSet, digest: Option<[u8; 20]>) -> io::Result<()> {
+        let content_metadata = self.content_metadata(&metadata);
+        let content = FileContent::with_options(path, content_metadata, self.content_options.clone());
+        if let Some(digest) = digest {
+            content.set_digest(digest);
+        }
+        if self.settings.bucket_by_parent {
+            let parent = content.path().parent().unwrap_or(Path::new("")).to_owned();
+            let parents = self.bucket_parents.entry(content_metadata).or_default();
+            if parents.len() > 1 || (!parents.is_empty() && !parents.contains(&parent)) {
+                self.stats.cross_bucket_skipped += 1;
+            }
+            parents.insert(parent);
+        }
+        // Cached digests are of the raw content
+        let mut cached = false;
+        if let (Some(cache), None) = (&self.digest_cache, &self.settings.content_normalizer) {
+            if let Some(digest) = cache.get(&(metadata.size(), content.path().to_owned())) {
+                if digest.mtime == (metadata.mtime(), metadata.mtime_nsec()) {
+                    self.stats.cached_digests += 1;
+                    content.set_digest(digest.hash);
+                    cached = true;
+                }
+            }
+        }
+        if let (Some(cache), None) = (&mut self.hash_cache, &self.settings.content_normalizer) {
+            cache.scanned(content.path(), &metadata);
+            if let (Some(hash), false) = (cache.get(&metadata), cached) {
+                self.stats.cached_digests += 1;
+                content.set_digest(hash);
+                cached = true;
+            }
+        }
+        // The next `incremental_scan` adds it by its hash without reading it, if its directory is unchanged by then
+        if let (Some(_), Some(_), None, None) = (&self.linked_dirs, &self.hash_cache, &self.settings.content_normalizer, content.digest()) {
+            if let Ok(hash) = full_digest(content.path(), &self.content_options.bytes_read, None) {
+                content.set_digest(hash);
+                cached = true;
+            }
+        }
+        if let (Some(name), None, false) = (&self.settings.hash_xattr, &self.settings.content_normalizer, cached) {
+            let digest = sys::get_xattr(content.path(), name).ok().flatten().and_then(|value| ContentDigest::from_xattr(&value));
+            if let Some(digest) = digest.filter(|digest| digest.mtime == (metadata.mtime(), metadata.mtime_nsec())) {
+                self.stats.xattr_digests += 1;
+                content.set_digest(digest.hash);
+            }
+        }
+
+        if !self.known_unique.is_empty() {
+            self.promote_known_unique(&content, content_metadata);
+        }
+        let was_unique = self.unique_filter.as_ref().is_some_and(|f| f.contains(&unique_key(&metadata)));
+        if was_unique && !self.by_content.contains_key(&content) && !content.timed_out() {
+            self.stats.known_unique += 1;
+            self.known_unique.entry(content_metadata).or_default().push((content, fileset));
+            self.report_reads();
+            return Ok(());
+        }
+
+        // Here's where all the magic happens
+        let mut first_on_device = None;
+        let res = match self.by_content.entry(content) {
+            BTreeEntry::Vacant(e) => {
+                // Seems unique so far (or unreadable, and then it doesn't go in the map)
+                if !e.key().timed_out() {
+                    first_on_device = Some(e.key().path().to_owned());
+                    e.insert(vec![fileset]);
+                }
+                Ok(())
+            },
+            BTreeEntry::Occupied(mut e) => {
+                // Found a dupe!
+                self.stats.dupes += 1;
+                let filesets = e.get_mut();
+                filesets.push(fileset);
+                Deduper {
+                    settings: &mut self.settings,
+                    stats: &mut self.stats,
+                    scan_listener: &mut self.scan_listener,
+                    script: &mut self.script,
+                    plan: &mut self.plan,
+                    roots: &self.roots,
+                    errors: &mut self.errors,
+                    next_op: &mut self.next_op,
+                    readonly_devs: &mut self.readonly_devs,
+                    preflight_devs: &mut self.preflight_devs,
+                    journal: &mut self.journal,
+                    linked_dirs: &mut self.linked_dirs,
+                    filesystem: &*self.filesystem,
+                }.dedupe(filesets)
+            },
+        };
+        if let (true, Some(path)) = (self.settings.report_cross_device, first_on_device) {
+            self.find_cross_device(path, content_metadata);
+        }
+        self.report_reads();
+        res
+    }
+
+    /// Hashes files in `threads` worker threads, and adds them to the content index on this one,
+    /// so that `Stats` and linking are done as with `flush`
+    fn index_deferred(&mut self, files: Vec<(PathBuf, fs::Metadata, SharedFileSet)>, threads: usize) -> io::Result<()> {
+        let mut same_size: HashMap<Metadata, usize> = HashMap::new();
+        for (_, metadata, _) in &files {
+            *same_size.entry(self.content_metadata(metadata)).or_default() += 1;
+        }
+        let hash_ahead = self.settings.content_normalizer.is_none() && self.settings.read_timeout.is_none();
+        let to_hash: Vec<(usize, &Path)> = files.iter().enumerate()
+            .filter(|&(_, (path, metadata, _))| hash_ahead && same_size[&self.content_metadata(metadata)] > 1 && !self.has_cached_hash(path, metadata))
+            .map(|(i, (path, _, _))| (i, path.as_path()))
+            .collect();
+
+        let bytes_read = AtomicU64::new(0);
+        let next = AtomicUsize::new(0);
+        let mut digests: Vec<Option<[u8; 20]>> = vec![None; files.len()];
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads.min(to_hash.len())).map(|_| scope.spawn(|| {
+                let mut done = Vec::new();
+                loop {
+                    let job = next.fetch_add(1, AtomicOrdering::Relaxed);
+                    let (i, path) = match to_hash.get(job) {
+                        Some(&job) => job,
+                        None => return done,
+                    };
+                    // Unreadable files are left for the comparison, which handles them as with `flush`
+                    if let Ok(digest) = full_digest(path, &bytes_read, None) {
+                        done.push((i, digest));
+                    }
+                }
+            })).collect();
+            for worker in workers {
+                for (i, digest) in worker.join().expect("hashing thread panicked") {
+                    digests[i] = Some(digest);
+                }
+            }
+        });
+        self.content_options.bytes_read.fetch_add(bytes_read.into_inner(), AtomicOrdering::Relaxed);
+
+        for ((path, metadata, fileset), digest) in files.into_iter().zip(digests) {
+            self.index(path, metadata, fileset, digest)?;
+            self.heartbeat();
+        }
+        Ok(())
+    }
+
+    /// What files are grouped by in the content index: size and device, or only size with `CrossFsPolicy::Symlink`,
+    /// since only symlinks can reach other devices
+    fn content_metadata(&self, metadata: &fs::Metadata) -> Metadata {
+        let dev = if self.settings.cross_fs == CrossFsPolicy::Symlink {0} else {metadata.dev()};
+        Metadata {dev, size: metadata.size()}
+    }
+
+    /// Looks for the file's content among files of the same size on other devices
+    fn find_cross_device(&mut self, path: PathBuf, metadata: Metadata) {
+        self.devices.insert(metadata.dev);
+        let others: Vec<u64> = self.devices.iter().cloned().filter(|&dev| dev != metadata.dev).collect();
+        for dev in others {
+            let probe = FileContent::with_options(path.clone(), Metadata {dev, ..metadata}, self.content_options.clone());
+            if let Some((other, _)) = self.by_content.get_key_value(&probe) {
+                self.stats.cross_device_bytes += metadata.size;
+                self.scan_listener.cross_device_dupe(&path, other.path());
+                return;
+            }
+        }
+    }
+
+    /// Collects what happened in comparisons since the last call.
+    /// Files that timed out stay in `by_content` if they were there already, but never compare equal to anything.
+    fn report_reads(&mut self) {
+        let timed_out: Vec<PathBuf> = self.content_options.timed_out.lock().unwrap().drain(..).collect();
+        for path in timed_out {
+            self.stats.read_timeouts += 1;
+            self.scan_listener.read_timeout(&path);
+        }
+        let slow: Vec<_> = self.content_options.slow.lock().unwrap().drain(..).collect();
+        for (path, elapsed) in slow {
+            self.scan_listener.slow_file(&path, elapsed);
+            self.slow_files.push((path, elapsed));
+        }
+
+        let bytes_read = self.content_options.bytes_read.swap(0, AtomicOrdering::Relaxed);
+        if self.content_options.count_comparisons {
+            self.comparison_metrics.comparisons += self.content_options.comparisons.swap(0, AtomicOrdering::Relaxed);
+            self.comparison_metrics.byte_reads += self.content_options.byte_comparisons.swap(0, AtomicOrdering::Relaxed);
+            self.comparison_metrics.bytes_compared += bytes_read;
+        }
+        if bytes_read > 0 {
+            self.stats.bytes_read += bytes_read;
+            let now = Instant::now();
+            if self.last_progress.is_none_or(|last| now.duration_since(last) >= self.settings.progress_interval) {
+                self.last_progress = Some(now);
+                self.throughput.sample(now, self.stats.bytes_read);
+                let eta = self.expected_bytes.and_then(|expected| self.throughput.eta(expected.saturating_sub(self.stats.bytes_read)));
+                self.scan_listener.bytes_read_update(self.stats.bytes_read, eta);
+            }
+        }
+    }
+
+    /// Calls `ScanListener::heartbeat` if it's due
+    fn heartbeat(&mut self) {
+        let (start, interval) = match (self.heartbeat_start, self.settings.heartbeat_interval) {
+            (Some(start), Some(interval)) => (start, interval),
+            _ => return,
+        };
+        let now = Instant::now();
+        if now.duration_since(self.last_heartbeat) >= interval {
+            self.last_heartbeat = now;
+            self.scan_listener.heartbeat(now.duration_since(start), &self.stats);
+        }
+    }
+
+    /// Known-unique files need to be compared with the new file after all
+    fn promote_known_unique(&mut self, content: &FileContent, content_metadata: Metadata) {
+        if let Some(candidates) = self.known_unique.get_mut(&content_metadata) {
+            let mut i = 0;
+            while i < candidates.len() {
+                if candidates[i].0 == *content {
+                    let (known, fileset) = candidates.swap_remove(i);
+                    self.stats.known_unique -= 1;
+                    self.by_content.entry(known).or_default().push(fileset);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    /// Filter of all files that are unique now
+    fn unique_filter(&self) -> BloomFilter {
+        let unique_paths: Vec<PathBuf> = self.by_content.values()
+            .filter(|filesets| filesets.len() == 1)
+            .map(|filesets| &filesets[0])
+            .chain(self.known_unique.values().flat_map(|known| known.iter().map(|(_, set)| set)))
+            .filter_map(|set| set.lock().unwrap().paths.first().cloned())
+            .collect();
+        let mut filter = BloomFilter::with_capacity(unique_paths.len());
+        for path in unique_paths {
+            // Files that vanished or can't be read just won't be skipped next time
+            if let Ok(metadata) = fs::symlink_metadata(&path) {
+                filter.insert(&unique_key(&metadata));
+            }
+        }
+        filter
+    }
+
+    fn should_follow_symlink(&self, path: &Path) -> bool {
+        if self.settings.follow_symlinks {
+            return true;
+        }
+        let names = &self.settings.follow_symlink_names;
+        if names.is_empty() {
+            return false;
+        }
+        if path.file_name().is_some_and(|name| names.contains(name)) {
+            return true;
+        }
+        match fs::read_link(path) {
+            Ok(target) => names.contains(target.as_os_str()) ||
+                target.file_name().is_some_and(|name| names.contains(name)),
+            Err(_) => false,
+        }
+    }
+
+    /// Adds the target of the symlink instead of the symlink itself,
+    /// so that the link is never replaced, only what it points to.
+    fn follow_symlink(&mut self, path: PathBuf) -> io::Result<()> {
+        let target = fs::canonicalize(&path)?;
+        let metadata = fs::symlink_metadata(&target)?;
+
+        // A link to its own ancestor would be an infinite loop,
+        // and links to an already followed target (or a listed directory) would scan it twice.
+        let inode = (metadata.dev(), metadata.ino());
+        let is_loop = metadata.is_dir() && path.starts_with(&target);
+        let listed = metadata.is_dir() && self.scanned_dirs.contains_key(&inode);
+        if is_loop || listed || !self.followed_symlinks.insert(inode) {
+            self.stats.symlinks_skipped += 1;
+            self.stats.skipped += 1;
+            return Ok(());
+        }
+
+        self.stats.symlinks_followed += 1;
+        self.add(target, metadata)
+    }
+
+    /// Counters of everything done so far
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Number of directories found, but not scanned yet
+    pub fn pending_directories(&self) -> usize {
+        self.to_scan.len()
+    }
+
+    /// False if the last `flush` stopped at `Settings::max_duration` with directories or files left to scan
+    pub fn completed(&self) -> bool {
+        self.to_scan.is_empty() && self.unfinished_entries.is_empty()
+    }
+
+    /// Directory being scanned right now, if `flush` is running
+    pub fn current_directory(&self) -> Option<&Path> {
+        self.current_dir.as_deref()
+    }
+
+    /// Bytes the scan is expected to read in total, e.g. the sum of file sizes from counting them before,
+    /// for the ETA of `ScanListener::bytes_read_update`. Duplicates are read in full, but unique files
+    /// only until the first difference, so it's an upper bound.
+    pub fn set_expected_bytes(&mut self, bytes: u64) {
+        self.expected_bytes = Some(bytes);
+    }
+
+    /// Files the next `flush` is expected to find, e.g. from `count_entries`, for `ScanListener::scan_started`.
+    /// It's used once, so a later `flush` gets `None` unless it's set again.
+    pub fn set_expected_files(&mut self, files: u64) {
+        self.expected_files = Some(files);
+    }
+
+    /// Counts the regular files in the directories queued so far, that `flush` would add, without reading them.
+    /// The same rules skip files as when they're added (size, excludes, ownership, age, etc.) and hardlinks count
+    /// once per path, so it's the number of files a scan adds if nothing changes in between.
+    ///
+    /// Unreadable entries are skipped, unless `Settings::strict`, which makes them an error as in the scan.
+    pub fn count_entries(&self) -> io::Result<u64> {
+        let mut dirs: Vec<(PathBuf, usize)> = self.to_scan.iter().map(|(_, Reverse(path), _, depth)| (path.clone(), *depth)).collect();
+        let mut count = 0;
+        for (path, depth) in &self.unfinished_entries {
+            match fs::symlink_metadata(path) {
+                Ok(metadata) => self.count_entry(path.clone(), metadata, *depth, &mut dirs, &mut count),
+                Err(err) => self.count_error(path, err)?,
+            }
+        }
+        // By device and inode, so that a directory reached by several paths is counted once
+        let mut listed = HashSet::new();
+        while let Some((dir, depth)) = dirs.pop() {
+            let entries = match fs::metadata(&dir) {
+                Ok(ref m) if !listed.insert((m.dev(), m.ino())) => continue,
+                Ok(_) => fs::read_dir(&dir),
+                Err(err) => Err(err),
+            };
+            let entries = match entries {
+                Ok(entries) => entries,
+                Err(err) => {
+                    self.count_error(&dir, err)?;
+                    continue;
+                },
+            };
+            for entry in entries {
+                match entry.and_then(|entry| Ok((entry.path(), entry.metadata()?))) {
+                    Ok((path, metadata)) => self.count_entry(path, metadata, depth + 1, &mut dirs, &mut count),
+                    Err(err) => self.count_error(&dir, err)?,
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    /// Like `add`, but it only counts the file, or queues the directory in `dirs`
+    fn count_entry(&self, path: PathBuf, metadata: fs::Metadata, depth: usize, dirs: &mut Vec<(PathBuf, usize)>, count: &mut u64) {
+        let (path, metadata) = if metadata.file_type().is_symlink() && self.should_follow_symlink(&path) {
+            let target = fs::canonicalize(&path).and_then(|target| {
+                let metadata = fs::symlink_metadata(&target)?;
+                Ok((target, metadata))
+            });
+            match target {
+                // A link to its own ancestor is skipped, others to a directory counted already are left to `listed`
+                Ok((target, m)) if !(m.is_dir() && path.starts_with(&target)) => (target, m),
+                _ => return,
+            }
+        } else {
+            (path, metadata)
+        };
+        let ty = metadata.file_type();
+        if metadata.nlink() == 0
+            || (self.settings.confine_to_roots && !self.roots.iter().any(|root| path.starts_with(root)))
+            || self.excludes.iter().any(|glob| glob.matches(&path, ty.is_dir()))
+            || (!ty.is_dir() && path.file_name().is_some_and(temp::is_temp_name)) {
+            return;
+        }
+        if ty.is_dir() {
+            if self.settings.max_depth.is_none_or(|max_depth| depth <= max_depth) {
+                dirs.push((path, depth));
+            }
+        } else if ty.is_file() && self.file_rule(&path, &metadata).is_none() {
+            *count += 1;
+        }
+    }
+
+    fn count_error(&self, path: &Path, err: io::Error) -> io::Result<()> {
+        if self.settings.strict && err.kind() != io::ErrorKind::NotFound {
+            return Err(strict_error(path, err));
+        }
+        Ok(())
+    }
+
+    /// Files that were slow to read (see `Settings::slow_file_threshold`), slowest first
+    pub fn slow_files(&self) -> Vec<(PathBuf, Duration)> {
+        let mut files = self.slow_files.clone();
+        files.sort_by_key(|&(_, elapsed)| Reverse(elapsed));
+        files
+    }
+
+    /// Comparisons of files so far, counted only while `Settings::collect_comparison_metrics` is on
+    pub fn comparison_metrics(&self) -> ComparisonMetrics {
+        self.comparison_metrics
+    }
+
+    /// Inodes that already had hardlinks when they were added (anywhere, not only in the scanned paths),
+    /// i.e. how deduped the tree was before this scanner linked anything
+    pub fn preexisting_link_stats(&self) -> PreexistingStats {
+        self.preexisting
+    }
+
+    /// Lowers the priority of the scan, so it runs in the background without slowing down other work:
+    /// the lowest CPU priority (nice 19), and on Linux the idle I/O class, which only gets the disk when no one
+    /// else uses it. It's of the calling thread on Linux, so call it on the thread that scans, before `scan_all` starts threads.
+    ///
+    /// Returns false if the I/O priority couldn't be lowered, because the platform (or kernel) doesn't have I/O priorities.
+    /// It can't be raised again without privileges.
+    pub fn set_low_priority(&self) -> io::Result<bool> {
+        sys::set_low_priority()
+    }
+
+    /// Adds a directory to `Settings::keep_roots`, so that its files are only ever linked to, never replaced.
+    /// Its duplicates in other roots are linked to its copies.
+    pub fn set_keep_root<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let root = fs::canonicalize(path)?;
+        self.settings.keep_roots.push(root);
+        Ok(())
+    }
+
+    /// Links that dry run would have made so far. It's empty when not in dry run.
+    pub fn dry_run_report(&self) -> &DryRunReport {
+        &self.plan
+    }
+
+    /// Links proposed by dry run that haven't been approved or rejected with `apply_decision` yet, in order.
+    /// It's for front-ends that let the user review each of them.
+    pub fn pending_decisions(&self) -> Vec<PendingMerge> {
+        self.plan.links.clone()
+    }
+
+    /// Takes the link off `pending_decisions`, and if approved makes it like `apply_plan` does, even in dry run.
+    /// A link whose files changed since is skipped and counted in `plan_skipped`.
+    /// Counters of links made are added to `stats`. It's an error if the link isn't pending.
+    pub fn apply_decision(&mut self, decision: &PendingMerge, approve: bool) -> io::Result<()> {
+        let pos = self.plan.links.iter().position(|link| link == decision)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} isn't a pending decision", decision.dest.display())))?;
+        let link = self.plan.links.remove(pos);
+        if !approve {
+            return Ok(());
+        }
+        let dry_run = mem::replace(&mut self.settings.dry_run, false);
+        let res = self.apply_plan(&DryRunReport {links: vec![link]});
+        self.settings.dry_run = dry_run;
+        let stats = res?;
+        self.add_link_stats(&stats);
+        Ok(())
+    }
+
+    /// Adds counters of `apply_plan` to `stats`
+    fn add_link_stats(&mut self, stats: &Stats) {
+        self.stats.created_hardlinks += stats.created_hardlinks;
+        #[allow(deprecated)] {
+            self.stats.hardlinks += stats.hardlinks;
+        }
+        self.stats.reflinked += stats.reflinked;
+        self.stats.symlinked += stats.symlinked;
+        self.stats.quarantined += stats.quarantined;
+        self.stats.plan_skipped += stats.plan_skipped;
+        self.stats.bytes_saved += stats.bytes_saved;
+        self.stats.reclaimed_bytes += stats.reclaimed_bytes;
+        self.stats.retries += stats.retries;
+    }
+
+    /// Paths that were skipped because of an error, e.g. unreadable directories,
+    /// or roots that `enqueue_lenient` couldn't add
+    pub fn errors(&self) -> &[(PathBuf, io::Error)] {
+        &self.errors
+    }
+
+    /// The scanner's index of content: each file content (the first file found with it, per device)
+    /// with the sets of hardlinks that have it, without copying anything like `dupes` does.
+    /// Sets are in the order they were found, and a group of one set has no duplicates (yet).
+    ///
+    /// The sets are shared with the scanner's other indexes, so lock each one only briefly, and one at a time
+    /// (locking one that's already locked on the same thread deadlocks). Comparing the `FileContent`s may read files.
+    pub fn content_groups(&self) -> impl Iterator<Item = (&FileContent, &[SharedFileSet])> {
+        self.by_content.iter().map(|(content, filesets)| (content, &filesets[..]))
+    }
+
+    pub fn dupes(&self) -> Vec<FileSet> {
+        self.by_inode.values().map(|d|{
+            let tmp = d.lock().unwrap();
+            (*tmp).clone()
+        }).collect()
+    }
+
+    /// Directories (within the scanned paths) that have the same structure and content.
+    /// Each inner `Vec` is a group of identical directories. Subdirectories of identical directories
+    /// aren't reported separately.
+    ///
+    /// This lists directories again (but doesn't read files), since skipped files aren't remembered.
+    pub fn duplicate_subtrees(&self) -> Vec<Vec<PathBuf>> {
+        subtree::duplicate_subtrees(&self.content_group_paths(), &self.roots)
+    }
+
+    /// Writes all files that have duplicates as CSV, with columns:
+    /// group (a number, in the order of this report), path, size, is_master, device, inode, group_id.
+    /// `group_id` is the hex of the SHA-256 of the content, which stays the same across runs and machines
+    /// (as in `write_manifest`), so it reads the master of each group again.
+    /// Paths that aren't valid UTF-8 are written lossily. Paths not tracked because of
+    /// `Settings::max_tracked_paths_per_inode` are a `+N more` row of their inode.
+    ///
+    /// In dry run paths are merged into the master without being linked,
+    /// so inodes are checked again to tell which ones really are the master.
+    pub fn write_csv<W: Write>(&self, mut w: W) -> io::Result<()> {
+        csv::write_row(&mut w, &["group", "path", "size", "is_master", "device", "inode", "group_id"])?;
+        for (id, group) in self.duplicate_groups().iter().enumerate() {
+            let master_ino = group.members[0].ino;
+            let group_id = group_id(&group.members[0].paths[0])?;
+            for member in &group.members {
+                for path in &member.paths {
+                    let ino = fs::symlink_metadata(path).map(|m| m.ino()).unwrap_or(member.ino);
+                    csv::write_row(&mut w, &[
+                        &id.to_string(), &rebase::relative(self.settings.report_base.as_deref(), path).to_string_lossy(), &group.size.to_string(),
+                        if ino == master_ino {"true"} else {"false"},
+                        &group.dev.to_string(), &ino.to_string(), &group_id,
+                    ])?;
+                }
+                if member.untracked_paths > 0 {
+                    csv::write_row(&mut w, &[
+                        &id.to_string(), &format!("+{} more", member.untracked_paths), &group.size.to_string(),
+                        if member.ino == master_ino {"true"} else {"false"},
+                        &group.dev.to_string(), &member.ino.to_string(), &group_id,
+                    ])?;
+                }
+            }
+        }
+        w.flush()
+    }
+
+    /// Writes a Graphviz DOT graph of all files that have duplicates: a node per path, labeled with the path,
+    /// and an edge labeled with the size from each of them to the first path of its group's master (drawn as a box).
+    /// Like `write_csv`, paths are relative to `Settings::report_base`, paths not tracked are a `+N more` node
+    /// of their inode, and inodes are checked again to tell which paths are really the master's.
+    /// Each group starts with a `// group <group_id>` comment, with the `group_id` of `write_csv`.
+    pub fn write_dot<W: Write>(&self, mut w: W) -> io::Result<()> {
+        w.write_all(b"digraph duplicates {\n")?;
+        let mut next_id = 0;
+        for group in self.duplicate_groups() {
+            let master_ino = group.members[0].ino;
+            let master_id = next_id;
+            writeln!(w, "    // group {}", group_id(&group.members[0].paths[0])?)?;
+            for member in &group.members {
+                for path in &member.paths {
+                    let ino = fs::symlink_metadata(path).map(|m| m.ino()).unwrap_or(member.ino);
+                    let label = rebase::relative(self.settings.report_base.as_deref(), path).to_string_lossy().into_owned();
+                    dot::write_node(&mut w, next_id, &label, ino == master_ino)?;
+                    if next_id != master_id {
+                        writeln!(w, "    n{} -> n{} [label=\"{}\"];", next_id, master_id, group.size)?;
+                    }
+                    next_id += 1;
+                }
+                if member.untracked_paths > 0 {
+                    dot::write_node(&mut w, next_id, &format!("+{} more", member.untracked_paths), member.ino == master_ino)?;
+                    writeln!(w, "    n{} -> n{} [label=\"{}\"];", next_id, master_id, group.size)?;
+                    next_id += 1;
+                }
+            }
+        }
+        w.write_all(b"}\n")?;
+        w.flush()
+    }
+
+    /// Writes `sha256  inode  path` lines for every added file, sorted by path, for checking the deduped files later.
+    /// Each inode is read once. Inodes are checked again, since in dry run paths aren't really linked.
+    pub fn write_manifest<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let mut paths: Vec<&PathBuf> = Vec::new();
+        let sets: Vec<_> = self.by_inode.values().map(|set| set.lock().unwrap()).collect();
+        for set in &sets {
+            paths.extend(set.paths.iter());
+        }
+        paths.sort();
+
+        let mut hashes = HashMap::new();
+        for path in paths {
+            let m = fs::symlink_metadata(path)?;
+            let hash = match hashes.entry((m.dev(), m.ino())) {
+                HashEntry::Occupied(e) => *e.get(),
+                HashEntry::Vacant(e) => *e.insert(sha256_file(path)?),
+            };
+            w.write_all(format!("{}  {}  ", sha256::hex(&hash), m.ino()).as_bytes())?;
+            w.write_all(rebase::relative(self.settings.report_base.as_deref(), path).as_os_str().as_bytes())?;
+            w.write_all(b"\n")?;
+        }
+        w.flush()
+    }
+
+    /// Duplicates found so far, by extension (ASCII-lowercased, or `NO_EXTENSION`).
+    /// Each path of a group but the first is a dupe, and each inode but the master's counts
+    /// as reclaimable for the extension of its first path.
+    ///
+    /// Inodes are checked again (like `write_csv`), so after linking only what's still unlinked is reclaimable.
+    pub fn stats_by_extension(&self) -> HashMap<OsString, ExtensionStats> {
+        let mut by_extension: HashMap<OsString, ExtensionStats> = HashMap::new();
+        for group in self.duplicate_groups() {
+            let mut inodes = HashSet::new();
+            let paths = group.members.iter().flat_map(|member| member.paths.iter().map(move |path| (member.ino, path)));
+            for (i, (member_ino, path)) in paths.enumerate() {
+                let ino = fs::symlink_metadata(path).map(|m| m.ino()).unwrap_or(member_ino);
+                let stats = by_extension.entry(lowercase_extension(path).unwrap_or_else(|| NO_EXTENSION.into())).or_default();
+                if i > 0 {
+                    stats.dupes += 1;
+                }
+                if inodes.insert(ino) && i > 0 {
+                    stats.reclaimable_bytes += group.size;
+                }
+            }
+        }
+        by_extension.retain(|_, stats| stats.dupes > 0);
+        by_extension
+    }
+
+    /// Content groups with more than one path, with the master inode first
+    fn duplicate_groups(&self) -> Vec<DuplicateGroupInfo> {
+        let inodes: HashMap<*const Mutex<FileSet>, u64> = self.by_inode.iter()
+            .map(|(&(_, ino), set)| (Shared::as_ptr(set), ino)).collect();
+
+        self.by_content.iter().filter_map(|(content, filesets)| {
+            let mut members: Vec<_> = filesets.iter().enumerate().filter_map(|(i, set)| {
+                let set_ref = set.lock().unwrap();
+                if set_ref.paths.is_empty() {
+                    // Merged into another set
+                    return None;
+                }
+                Some((set_ref.links(), i, DuplicateMember {
+                    ino: inodes.get(&Shared::as_ptr(set)).cloned().unwrap_or(0),
+                    paths: set_ref.paths.clone(),
+                    untracked_paths: set_ref.untracked_paths,
+                }))
+            }).collect();
+            if members.iter().map(|(_, _, m)| m.paths.len()).sum::<usize>() < 2 {
+                return None;
+            }
+            // Same order as in `dedupe`, so that the master (with all the paths merged into it) is first
+            members.sort_by_key(|&(links, i, _)| (!links, i));
+            let members = members.into_iter().map(|(_, _, m)| m).collect();
+            let metadata = content.metadata();
+            Some(DuplicateGroupInfo {
+                size: metadata.size,
+                dev: metadata.dev,
+                members,
+            })
+        }).collect()
+    }
+
+    /// All groups of files with the same content found so far, most wasted bytes first, e.g. to review the plan
+    /// of a dry run before a real one. Paths that are already hardlinks of each other count as copies too.
+    pub fn report(&self) -> Vec<DuplicateGroup> {
+        let mut groups: Vec<_> = self.duplicate_groups().into_iter().map(|group| {
+            let paths: Vec<PathBuf> = group.members.into_iter().flat_map(|member| member.paths).collect();
+            DuplicateGroup {
+                file_size: group.size,
+                wasted_bytes: group.size * (paths.len() - 1) as u64,
+                paths,
+            }
+        }).collect();
+        groups.sort_by_key(|group| Reverse(group.wasted_bytes));
+        groups
+    }
+
+    /// Groups of duplicates that are still separate copies, biggest savings first, for cleaning up by hand
+    /// (e.g. where nothing can be linked). Each keeps the master picked by the scan (see `Settings::choose_master`).
+    ///
+    /// It's made from what the scan found, but the master of each group is read again for its `group_id`.
+    /// In dry run, paths that would have been linked count as a copy each, even where some of them are hardlinks of each other.
+    pub fn cleanup_worklist(&self) -> Vec<WorklistItem> {
+        let planned: HashSet<&Path> = self.plan.links.iter().map(|link| link.dest.as_path()).collect();
+        let mut worklist: Vec<_> = self.duplicate_groups().into_iter().filter_map(|group| {
+            let (master, others) = group.members.split_first()?;
+            let keep = master.paths[0].clone();
+            // In dry run (or with defer_links), the paths that would have been linked are merged into the master
+            let merged: Vec<_> = master.paths[1..].iter().filter(|path| planned.contains(path.as_path())).cloned().collect();
+            let copies = merged.len() + others.len();
+            if copies == 0 {
+                return None;
+            }
+            let remove = merged.into_iter().chain(others.iter().flat_map(|member| member.paths.iter().cloned())).collect();
+            Some(WorklistItem {
+                group_id: group_id(&keep).ok(),
+                keep,
+                remove,
+                size: group.size,
+                reclaimable_bytes: group.size * copies as u64,
+            })
+        }).collect();
+        worklist.sort_by_key(|item| Reverse(item.reclaimable_bytes));
+        worklist
+    }
+
+    /// What linking the duplicates found so far would give with `Settings::max_links_per_master` set to `cap`,
+    /// for picking a cap. It's computed from what the scan found, as if paths were linked one by one
+    /// to masters until they have `cap` links each. Inodes that have more links already are kept as they are.
+    /// As in `cleanup_worklist`, paths that would have been linked in dry run count as a copy each.
+    pub fn simulate_link_cap(&self, cap: u64) -> SimulationResult {
+        let cap = cap.max(1);
+        let planned: HashSet<&Path> = self.plan.links.iter().map(|link| link.dest.as_path()).collect();
+        let mut result = SimulationResult::default();
+        for group in self.duplicate_groups() {
+            // Links of each inode there is now
+            let mut inodes = Vec::new();
+            for member in &group.members {
+                let merged = member.paths.iter().filter(|path| planned.contains(path.as_path())).count() as u64;
+                inodes.push(member.paths.len() as u64 + member.untracked_paths - merged);
+                inodes.extend((0..merged).map(|_| 1));
+            }
+            let full = inodes.iter().filter(|&&links| links >= cap).count();
+            let rest: u64 = inodes.iter().filter(|&&links| links < cap).sum();
+            let masters = full + rest.div_ceil(cap) as usize;
+            result.masters += masters;
+            result.reclaimable_bytes += group.size * (inodes.len() - masters) as u64;
+            result.uncapped_reclaimable_bytes += group.size * (inodes.len() - 1) as u64;
+        }
+        result
+    }
+
+    /// Pairs of `(file, its gzip)`, e.g. `foo` and `foo.gz`, anywhere in the scanned paths. They can't be linked,
+    /// but one of them is redundant. Gzip files are recognized by content, not name, and only decompressed
+    /// (with `gzip -dc`, up to `Settings::max_decompressed_size`) if a file of the size they store was scanned.
+    ///
+    /// This reads the files. Files skipped by the scan (e.g. small ones with `ignore_small`) aren't in it.
+    pub fn compressed_duplicates(&self) -> Vec<(PathBuf, PathBuf)> {
+        gzip::compressed_duplicates(self.distinct_files(), self.settings.max_decompressed_size)
+    }
+
+    /// Groups of at least `MIN_PACK_FILES` identical files smaller than a block of their filesystem, most paths first.
+    /// Each still takes a directory entry and at least one block per inode, so huge numbers of them are better
+    /// packed into one file with an index (e.g. an archive), which this is guidance for. Nothing is changed.
+    ///
+    /// Such files are skipped with `Settings::ignore_small`, so it needs that off. The master of each group is stat-ed
+    /// for the block size, and otherwise it's made from what the scan found, like `cleanup_worklist`.
+    pub fn packing_candidates(&self) -> Vec<PackGroup> {
+        let planned: HashSet<&Path> = self.plan.links.iter().map(|link| link.dest.as_path()).collect();
+        let mut candidates: Vec<_> = self.duplicate_groups().into_iter().filter_map(|group| {
+            let paths: Vec<PathBuf> = group.members.iter().flat_map(|member| member.paths.iter().cloned()).collect();
+            if paths.len() < MIN_PACK_FILES {
+                return None;
+            }
+            let metadata = fs::symlink_metadata(&paths[0]).ok()?;
+            if group.size >= metadata.blksize() {
+                return None;
+            }
+            let inodes = group.members.len() + paths.iter().filter(|path| planned.contains(path.as_path())).count();
+            Some(PackGroup {
+                size: group.size,
+                inodes,
+                allocated_bytes: inodes as u64 * metadata.blocks() * 512,
+                paths,
+            })
+        }).collect();
+        candidates.sort_by_key(|group| Reverse(group.paths.len()));
+        candidates
+    }
+
+    /// Pairs of files that aren't identical, but similar (e.g. edited documents, or re-encoded media),
+    /// with their similarity, most similar first. Nothing is linked, they're candidates for review.
+    ///
+    /// Similarity is by context-triggered piecewise hashes, compatible in scale with `ssdeep`: from 0 to 100, and only
+    /// pairs of at least `threshold` are returned. Files of very different size are never similar, and small ones
+    /// (of a few hundred bytes) hardly ever. This reads every file in full, often twice, and compares all pairs.
+    pub fn similar_files(&self, threshold: u32) -> Vec<(PathBuf, PathBuf, u32)> {
+        ssdeep::similar_files(self.distinct_files().into_iter().map(|(path, _)| path).collect(), threshold)
+    }
+
+    /// Files that aren't identical, but have sizes within `size_tolerance` bytes of each other,
+    /// and the same first and last 4KB. These can't be linked, but may be worth a manual review,
+    /// e.g. slightly edited copies of documents.
+    ///
+    /// Each inner `Vec` has one path per distinct content. This reads the files.
+    pub fn near_duplicates(&self, size_tolerance: u64) -> Vec<Vec<PathBuf>> {
+        near::near_duplicates(self.distinct_files(), size_tolerance)
+    }
+
+    /// Pairs of `(shorter, longer)` files where all of the shorter one is the beginning of the longer one,
+    /// e.g. yesterday's and today's copy of a log that's appended to. Each file is paired with the shortest
+    /// such file, so a file that kept growing shows up as a chain of pairs. Empty files aren't paired.
+    ///
+    /// It's only a report: they can't be linked, though reflinks could share the blocks of the common part.
+    /// This reads the first 4KB of every file (and keeps it in memory), and compares candidates in full.
+    pub fn prefix_duplicates(&self) -> Vec<(PathBuf, PathBuf)> {
+        prefix::prefix_duplicates(self.distinct_files())
+    }
+
+    /// One path (and the size) of each distinct content
+    fn distinct_files(&self) -> Vec<(PathBuf, u64)> {
+        self.by_content.iter()
+            .map(|(content, filesets)| (content, filesets.iter().find_map(|set| set.lock().unwrap().paths.first().cloned())))
+            .chain(self.known_unique.values().flat_map(|known| known.iter().map(|(content, set)| (content, set.lock().unwrap().paths.first().cloned()))))
+            .filter_map(|(content, path)| path.map(|path| (path, content.metadata().size)))
+            .collect()
+    }
+
+    /// Paths of all the files, grouped by content
+    fn content_group_paths(&self) -> Vec<Vec<PathBuf>> {
+        let known_unique = self.known_unique.values().flat_map(|known| known.iter().map(|(_, set)| {
+            set.lock().unwrap().paths.clone()
+        }));
+        self.by_content.values().map(|filesets| {
+            filesets.iter().flat_map(|set| set.lock().unwrap().paths.clone()).collect()
+        }).chain(known_unique).collect()
+    }
+}
+
+/// What linking a group did that has to be finished or undone afterwards
+struct GroupChanges {
+    /// The master was immutable or got the flag of a member, see `OnImmutable::TempUnset`
+    restore_immutable: bool,
+    /// The paths replaced and their metadata from before, with `Settings::atomic_groups`
+    replaced: Vec<(PathBuf, fs::Metadata)>,
+    /// Duplicates were left for another master, see `Settings::max_links_per_master`
+    master_full: bool,
+    /// Duplicates linked to the master (or planned to be)
+    linked: u64,
+    /// The mtime `Settings::mtime_policy` picked so far, from the master's and those hardlinked to it, as they were before
+    mtime: Option<SystemTime>,
+}
+
+/// Parts of the scanner needed for linking, borrowed separately from the content index
+struct Deduper<'a> {
+    settings: &'a mut Settings,
+    stats: &'a mut Stats,
+    scan_listener: &'a mut dyn ScanListener,
+    script: &'a mut Option<ScriptWriter>,
+    plan: &'a mut DryRunReport,
+    /// For `Settings::confine_to_roots`
+    roots: &'a [PathBuf],
+    errors: &'a mut Vec<(PathBuf, io::Error)>,
+    next_op: &'a mut Option<Instant>,
+    readonly_devs: &'a mut HashMap<u64, bool>,
+    preflight_devs: &'a mut HashSet<u64>,
+    journal: &'a mut Option<Journal>,
+    /// See `Scanner::incremental_scan`
+    linked_dirs: &'a mut Option<HashSet<PathBuf>>,
+    filesystem: &'a dyn FileSystem,
+}
+
+impl<'a> Deduper<'a> {
+    /// Links are only planned, see `Settings::defer_links`
+    fn plans_only(&self) -> bool {
+        self.settings.dry_run || self.settings.defer_links
+    }
+
+    fn master_index(&mut self, filesets: &[SharedFileSet]) -> io::Result<usize> {
+        let in_keep_root = |settings: &Settings, set: &FileSet| set.paths.iter().any(|path| keep_root_of(settings, path).is_some());
+        let any_kept = filesets.iter().any(|set| in_keep_root(self.settings, &set.lock().unwrap()));
+        if let (Some(choose_master), false) = (self.settings.choose_master.as_mut(), any_kept) {
+            // Sets merged into another group earlier don't have any paths left
+            let candidates: Vec<usize> = (0..filesets.len()).filter(|&i| !filesets[i].lock().unwrap().paths.is_empty()).collect();
+            let sets: Vec<FileSet> = candidates.iter().map(|&i| filesets[i].lock().unwrap().clone()).collect();
+            let chosen = choose_master(&sets);
+            return candidates.get(chosen).cloned().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("choose_master returned {}, but there are only {} filesets", chosen, sets.len()))
+            });
+        }
+        let kept_or_preferred = any_kept || self.settings.master_path_prefix.as_ref().is_some_and(|prefix| {
+            filesets.iter().any(|set| set.lock().unwrap().paths.iter().any(|path| path.starts_with(prefix)))
+        });
+        if let (SourcePolicy::Custom(ref pick), false) = (&self.settings.source_policy, kept_or_preferred) {
+            let candidates: Vec<usize> = (0..filesets.len()).filter(|&i| !filesets[i].lock().unwrap().paths.is_empty()).collect();
+            let paths: Vec<PathBuf> = candidates.iter().map(|&i| filesets[i].lock().unwrap().paths[0].clone()).collect();
+            let chosen = pick(&paths);
+            return candidates.get(chosen).cloned().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("SourcePolicy::Custom returned {}, but there are only {} paths", chosen, paths.len()))
+            });
+        }
+
+        // Find file with the largest number of hardlinks, since it's less work to merge a small group into a large group
+        let prefix = self.settings.master_path_prefix.as_ref();
+        let by_creation_time = self.settings.master_by_creation_time;
+        let contiguous = self.settings.prefer_contiguous_master;
+        let shortest_path = matches!(self.settings.source_policy, SourcePolicy::ShortestPath);
+        let settings = &*self.settings;
+        let (largest_idx, _) = filesets.iter().enumerate().max_by_key(|&(i,f)| {
+            let f = f.lock().unwrap();
+            let kept = in_keep_root(settings, &f);
+            let in_prefix = prefix.is_some_and(|prefix| f.paths.iter().any(|p| p.starts_with(prefix)));
+            // Reversed, so that the oldest is the largest, and unknown is the smallest
+            let age = if by_creation_time {f.created.or(f.modified).map(Reverse)} else {None};
+            // Fewest is the largest, and unknown the smallest
+            let extents = if contiguous {f.paths.first().and_then(|path| sys::extent_count(path)).map(Reverse)} else {None};
+            // Shortest is the largest, and sets without paths the smallest
+            let path_len = if shortest_path {f.paths.iter().map(|path| path.as_os_str().len()).min().map(Reverse)} else {None};
+            (kept, in_prefix, path_len, extents, age, f.links(), !i)
+        }).expect("fileset can't be empty");
+        Ok(largest_idx)
+    }
+
+    fn dedupe(&mut self, filesets: &mut [SharedFileSet]) -> io::Result<()> {
+        self.dedupe_group(filesets, false)
+    }
+
+    /// `extra_master` if the group is what a full master left, see `Settings::max_links_per_master`
+    fn dedupe_group(&mut self, filesets: &mut [SharedFileSet], extra_master: bool) -> io::Result<()> {
+        let largest_idx = self.master_index(filesets)?;
+        let merged_fileset = &filesets[largest_idx];
+
+        // The set is still going to be in use! So everything has to be updated to make sense for the next call
+        let merged_paths = &mut merged_fileset.lock().unwrap().paths;
+        let source_path = merged_paths[0].clone();
+        let source_metadata = fs::symlink_metadata(&source_path)?;
+
+        if !self.confined(&source_path)? {
+            self.skip_group(filesets, largest_idx, GuardReason::OutsideRoots);
+            return Ok(());
+        }
+
+        if self.settings.min_savings_per_group > 0 {
+            let other_inodes = filesets.iter().enumerate()
+                .filter(|&(i, set)| i != largest_idx && !set.lock().unwrap().paths.is_empty()).count() as u64;
+            if other_inodes.saturating_mul(source_metadata.size()) < self.settings.min_savings_per_group {
+                self.stats.low_savings_skipped += 1;
+                self.skip_group(filesets, largest_idx, GuardReason::LowSavings);
+                return Ok(());
+            }
+        }
+
+        let links = !self.plans_only() && self.settings.content_normalizer.is_none();
+        if links && self.settings.skip_readonly_mounts && self.is_readonly(&source_path, source_metadata.dev()) {
+            self.stats.readonly_skipped += 1;
+            self.skip_group(filesets, largest_idx, GuardReason::ReadOnlyMount);
+            return Ok(());
+        }
+        if links && self.settings.preflight && !self.preflight_devs.contains(&source_metadata.dev()) {
+            self.preflight(&source_path)?;
+            self.preflight_devs.insert(source_metadata.dev());
+        }
+
+        if is_sparse(&source_metadata) {
+            self.scan_listener.sparse_dupe_warning(&source_path);
+            if self.settings.skip_sparse {
+                self.stats.sparse_skipped += 1;
+                self.skip_group(filesets, largest_idx, GuardReason::Sparse);
+                return Ok(());
+            }
+        }
+
+        // Immutable files can't be linked to
+        let source_immutable = links && sys::is_immutable(&source_path)?;
+        if source_immutable {
+            match self.settings.on_immutable {
+                OnImmutable::Skip => {
+                    self.stats.immutable_skipped += 1;
+                    self.skip_group(filesets, largest_idx, GuardReason::Immutable);
+                    return Ok(());
+                },
+                OnImmutable::Error => return Err(immutable_error(&source_path)),
+                OnImmutable::TempUnset => sys::set_immutable(&source_path, false)?,
+            }
+        }
+        let restore_immutable = source_immutable;
+
+        let source_metadata = match self.settings.content_address_root {
+            Some(ref store) if links => {
+                let store = store.clone();
+                match self.store_master(&store, merged_paths, &source_path, &source_metadata) {
+                    Ok(metadata) => metadata,
+                    Err(err) => {
+                        if restore_immutable {
+                            sys::set_immutable(&source_path, true)?;
+                        }
+                        return Err(err);
+                    },
+                }
+            },
+            _ => source_metadata,
+        };
+
+        let verify_after_link = links && self.settings.verify_after_link;
+        let hash_before = if verify_after_link {
+            match sha256_file(&source_path) {
+                Ok(hash) => Some(hash),
+                Err(err) => {
+                    if restore_immutable {
+                        sys::set_immutable(&source_path, true)?;
+                    }
+                    return Err(err);
+                },
+            }
+        } else {None};
+        let replaced_before = self.stats.created_hardlinks + self.stats.reflinked + self.stats.symlinked;
+        let mut changes = GroupChanges {restore_immutable, replaced: Vec::new(), master_full: false, linked: 0, mtime: None};
+        let mut res = self.merge_into(filesets, largest_idx, merged_paths, &source_path, &source_metadata, &mut changes);
+        let replaced = self.stats.created_hardlinks + self.stats.reflinked + self.stats.symlinked != replaced_before;
+        if let (Some(hash_before), true) = (hash_before, replaced) {
+            match sha256_file(&source_path) {
+                Ok(hash) => if hash != hash_before {
+                    self.stats.corruption_detected += 1;
+                    self.scan_listener.corruption_detected(&source_path);
+                },
+                // Links that can't be verified are rolled back with `atomic_groups`, and the flag is restored anyway
+                Err(err) => res = res.and(Err(err)),
+            }
+        }
+        if res.is_err() && self.settings.atomic_groups {
+            for (dest_path, dest_metadata) in changes.replaced {
+                let temp_path = temp::unique_path(&dest_path);
+                let res = temp::remove_stale(&temp_path).and_then(|_| Self::replace_with_copy(self.filesystem, &source_path, &dest_path, &temp_path, &dest_metadata));
+                self.linked_into(&dest_path);
+                match res {
+                    Ok(()) => {
+                        self.stats.rolled_back += 1;
+                        merged_paths.retain(|path| *path != dest_path);
+                    },
+                    Err(err) => {
+                        self.scan_listener.scan_error(&dest_path, &err);
+                        self.errors.push((dest_path, err));
+                    },
+                }
+            }
+        }
+        // Rolled back duplicates are copies with their own mtime again, so the master keeps its own too
+        let rolled_back = res.is_err() && self.settings.atomic_groups;
+        if let Some(mtime) = changes.mtime.filter(|&mtime| !rolled_back && source_metadata.modified().ok() != Some(mtime)) {
+            // Before the immutable flag is back, which would forbid it
+            if let Err(err) = fs::File::open(&source_path).and_then(|file| file.set_modified(mtime)) {
+                self.scan_listener.scan_error(&source_path, &err);
+                self.errors.push((source_path.clone(), err));
+            }
+        }
+        if changes.restore_immutable {
+            sys::set_immutable(&source_path, true)?;
+        }
+        if res.is_ok() && changes.master_full {
+            // The rest are linked to one of them instead
+            let mut rest: Vec<_> = filesets.iter().enumerate()
+                .filter(|&(i, set)| i != largest_idx && !set.lock().unwrap().paths.is_empty())
+                .map(|(_, set)| set.clone()).collect();
+            if rest.len() > 1 {
+                return self.dedupe_group(&mut rest, true);
+            }
+        }
+        if extra_master && changes.linked > 0 {
+            self.stats.extra_masters += 1;
+        }
+        res
+    }
+
+    /// Puts the master in the content-addressed store (see `Settings::content_address_root`),
+    /// and returns its metadata afterwards
+    fn store_master(&mut self, store: &Path, master_paths: &[PathBuf], source_path: &Path, source_metadata: &fs::Metadata) -> io::Result<fs::Metadata> {
+        let hex = sha256::hex(&sha256_file(source_path)?);
+        let store_path = store.join(&hex[..2]).join(&hex[2..]);
+        match fs::symlink_metadata(&store_path) {
+            Ok(stored) => {
+                if stored.dev() == source_metadata.dev() && stored.ino() == source_metadata.ino() {
+                    return Ok(stored);
+                }
+                if !stored.is_file() || stored.size() != source_metadata.size() || !verify::same_content(&store_path, source_path)? {
+                    return Err(DupeError::VerificationFailed {src: source_path.to_owned(), dst: store_path}.into());
+                }
+                for path in master_paths {
+                    let links_before = if self.settings.on_link_anomaly != OnLinkAnomaly::Unchecked {
+                        fs::symlink_metadata(&store_path)?.nlink()
+                    } else {0};
+                    self.throttle();
+                    let temp_path = temp::unique_path(path);
+                    temp::remove_stale(&temp_path)?;
+                    let res = Self::replace_with_link(self.filesystem, &store_path, path, &temp_path);
+                    self.linked_into(path);
+                    res?;
+                    self.replaced(DedupeMode::Hardlink, &store_path, path, links_before)?;
+                }
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                if let Some(dir) = store_path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                // Fails rather than overwrites if another run stored it in the meantime
+                self.filesystem.hard_link(source_path, &store_path)?;
+                self.linked_into(&store_path);
+                self.stats.content_stored += 1;
+            },
+            Err(err) => return Err(err),
+        }
+        fs::symlink_metadata(source_path)
+    }
+
+    fn merge_into(&mut self, filesets: &[SharedFileSet], largest_idx: usize, merged_paths: &mut Vec<PathBuf>, source_path: &Path, source_metadata: &fs::Metadata, changes: &mut GroupChanges) -> io::Result<()> {
+        let mut budget = self.settings.max_group_members.unwrap_or(usize::MAX);
+        for (i, set) in filesets.iter().enumerate() {
+            // We don't want to merge the set with itself
+            if i == largest_idx {continue;}
+
+            let mut set = set.lock().unwrap();
+            let links_found = set.paths.len() as u64 + set.untracked_paths;
+            // For `Stats::would_reclaim_bytes`, which is once per inode
+            let mut planned = false;
+            let paths = &mut set.paths;
+            // dest_path will be "lost" on error, but that's fine, since we don't want to dedupe it if it causes errors
+            let dest_paths = mem::take(paths);
+            for dest_path in dest_paths {
+                // E.g. the file was replaced by a copy and added again, so its old and new inode are both in the group.
+                // It's the master's path already, so it's only dropped from the other set.
+                if source_path == dest_path {
+                    self.stats.self_link_skipped += 1;
+                    self.scan_listener.self_link_skipped(&dest_path);
+                    continue;
+                }
+
+                if budget == 0 {
+                    self.skip_dupe(&dest_path, GuardReason::MaxGroupMembers);
+                    paths.push(dest_path);
+                    continue;
+                }
+                budget -= 1;
+
+                if self.settings.max_links_per_master.is_some_and(|max| source_metadata.nlink() + changes.linked >= max) {
+                    changes.master_full = true;
+                    paths.push(dest_path);
+                    continue;
+                }
+
+                // The file may have been linked by someone else since it was scanned (or a re-scan found it again),
+                // in which case there's nothing left to do.
+                let dest_metadata = fs::symlink_metadata(&dest_path)?;
+                if dest_metadata.dev() == source_metadata.dev() && dest_metadata.ino() == source_metadata.ino() {
+                    self.stats.already_linked += 1;
+                    merged_paths.push(dest_path);
+                    continue;
+                }
+
+                if self.settings.protected_inodes.contains(&(dest_metadata.dev(), dest_metadata.ino())) {
+                    self.stats.protected_skipped += 1;
+                    self.skip_dupe(&dest_path, GuardReason::Protected);
+                    paths.push(dest_path);
+                    continue;
+                }
+
+                let never_link = |path: &Path| path.file_name().is_some_and(|name| self.settings.never_link_names.contains(name));
+                if never_link(source_path) || never_link(&dest_path) {
+                    self.stats.protected_name_skipped += 1;
+                    self.skip_dupe(&dest_path, GuardReason::ProtectedName);
+                    paths.push(dest_path);
+                    continue;
+                }
+
+                if !self.confined(&dest_path)? {
+                    self.skip_dupe(&dest_path, GuardReason::OutsideRoots);
+                    paths.push(dest_path);
+                    continue;
+                }
+
+                if self.settings.skip_externally_linked && dest_metadata.nlink() > links_found {
+                    self.stats.external_link_skipped += 1;
+                    self.skip_dupe(&dest_path, GuardReason::ExternallyLinked);
+                    paths.push(dest_path);
+                    continue;
+                }
+
+                if let Some(dest_root) = keep_root_of(self.settings, &dest_path) {
+                    // The master is in a keep root too, since they're preferred
+                    let master_root = merged_paths.iter().find_map(|path| keep_root_of(self.settings, path));
+                    if master_root.is_some_and(|root| root != dest_root) {
+                        return Err(DupeError::DifferentKeepRoots {a: dest_path, b: source_path.to_owned()}.into());
+                    }
+                    self.skip_dupe(&dest_path, GuardReason::KeepRoot);
+                    paths.push(dest_path);
+                    continue;
+                }
+
+                if is_sparse(&dest_metadata) {
+                    self.scan_listener.sparse_dupe_warning(&dest_path);
+                    if self.settings.skip_sparse {
+                        self.stats.sparse_skipped += 1;
+                        self.skip_dupe(&dest_path, GuardReason::Sparse);
+                        paths.push(dest_path);
+                        continue;
+                    }
+                }
+
+                if !self.same_permissions(source_metadata, &dest_metadata) {
+                    self.stats.permission_mismatches += 1;
+                    self.scan_listener.permission_mismatch(&dest_path, source_path);
+                    self.skip_dupe(&dest_path, GuardReason::PermissionMismatch);
+                    paths.push(dest_path);
+                    continue;
+                }
+
+                let links = !self.plans_only() && self.settings.content_normalizer.is_none();
+                let verify = if links {self.settings.verify_content} else {self.settings.dry_run_verify};
+                if !self.confirm(source_path, &dest_path, verify, links)? {
+                    // It's not a duplicate after all, so it's dropped from the group
+                    self.stats.verify_mismatches += 1;
+                    self.scan_listener.content_mismatch(&dest_path, source_path);
+                    continue;
+                }
+                let confidence = if verify {Confidence::Bytes} else {Confidence::Hashes};
+
+                let temp_path = temp::unique_path(&dest_path);
+
+                if self.settings.content_normalizer.is_some() {
+                    // Not identical, so it must not end up in anything that could link it
+                    self.scan_listener.duplicate_found(&dest_path, source_path);
+                    merged_paths.push(dest_path);
+                    continue;
+                }
+
+                if self.plans_only() {
+                    if let (Some(script_path), true) = (self.settings.script_output.as_ref(), self.settings.dry_run) {
+                        if self.script.is_none() {
+                            *self.script = Some(ScriptWriter::create(script_path)?);
+                        }
+                        if let Some(ref mut script) = *self.script {
+                            script.hardlink(source_path, &dest_path, &temp_path)?;
+                        }
+                    }
+                    self.scan_listener.duplicate_found(&dest_path, source_path);
+                    self.plan.links.push(PlannedLink {
+                        source: source_path.to_owned(),
+                        dest: dest_path.clone(),
+                        size: source_metadata.size(),
+                        dev: source_metadata.dev(),
+                        confidence,
+                    });
+                    if self.settings.dry_run && !planned {
+                        self.stats.would_reclaim_bytes += dest_metadata.size();
+                        planned = true;
+                    }
+                    changes.linked += 1;
+                    merged_paths.push(dest_path);
+                    continue;
+                }
+
+                if confidence < self.settings.min_confidence {
+                    self.stats.low_confidence_skipped += 1;
+                    self.skip_dupe(&dest_path, GuardReason::LowConfidence);
+                    paths.push(dest_path);
+                    continue;
+                }
+
+                // Immutable destination can't be replaced
+                let dest_immutable = sys::is_immutable(&dest_path)?;
+                if dest_immutable {
+                    match self.settings.on_immutable {
+                        OnImmutable::Skip => {
+                            self.stats.immutable_skipped += 1;
+                            self.skip_dupe(&dest_path, GuardReason::Immutable);
+                            paths.push(dest_path);
+                            continue;
+                        },
+                        OnImmutable::Error => return Err(immutable_error(&dest_path)),
+                        OnImmutable::TempUnset => sys::set_immutable(&dest_path, false)?,
+                    }
+                }
+                if let Err(err) = self.check_free_space(&dest_path) {
+                    if dest_immutable {
+                        sys::set_immutable(&dest_path, true).ok();
+                    }
+                    return Err(err);
+                }
+                let links_before = if self.settings.on_link_anomaly != OnLinkAnomaly::Unchecked {
+                    fs::symlink_metadata(source_path)?.nlink()
+                } else {0};
+                let mode = match self.replace(source_path, &dest_path, &temp_path, &dest_metadata, links_before) {
+                    Ok(mode) => mode,
+                    Err(err) => {
+                        if dest_immutable {
+                            sys::set_immutable(&dest_path, true).ok();
+                        }
+                        if let Some(reason) = self.skip_reason(&err) {
+                            self.skip_dupe(&dest_path, reason);
+                            paths.push(dest_path);
+                            continue;
+                        }
+                        return Err(err);
+                    },
+                };
+                let dest_mtime = dest_metadata.modified();
+                if self.settings.atomic_groups {
+                    changes.replaced.push((dest_path.clone(), dest_metadata));
+                }
+                match mode {
+                    DedupeMode::Hardlink => {
+                        // It's the same inode as the source now, so it'll get the flag back with the source
+                        changes.restore_immutable |= dest_immutable;
+                        changes.linked += 1;
+                        if let (Ok(master), Ok(dest)) = (source_metadata.modified(), dest_mtime) {
+                            let policy = self.settings.mtime_policy;
+                            changes.mtime = changes.mtime.map_or(policy.pick(master, dest), |mtime| policy.pick(mtime, dest));
+                        }
+                        merged_paths.push(dest_path);
+                    },
+                    // Not the master's inode, and not a duplicate anymore either, so it leaves the group
+                    DedupeMode::Reflink => if dest_immutable {
+                        sys::set_immutable(&dest_path, true)?;
+                    },
+                    // Symlinks can't be immutable
+                    DedupeMode::Symlink => {},
+                    DedupeMode::Quarantine => if dest_immutable {
+                        let dir = self.settings.quarantine_dir.as_ref().expect("quarantined");
+                        sys::set_immutable(&quarantine_path(dir, &dest_path)?, true)?;
+                    },
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the duplicate can be replaced without changing who can access it (see `Settings::ignore_permissions`)
+    fn same_permissions(&self, source: &fs::Metadata, dest: &fs::Metadata) -> bool {
+        let same_owner = self.settings.cow_across_owners || (source.uid() == dest.uid() && source.gid() == dest.gid());
+        self.settings.ignore_permissions || (source.mode() & 0o7777 == dest.mode() & 0o7777 && same_owner)
+    }
+
+    /// Failures of `replace` that leave the duplicate as it was, and only skip it (which is counted here)
+    fn skip_reason(&mut self, err: &io::Error) -> Option<GuardReason> {
+        match err.get_ref().and_then(|inner| inner.downcast_ref::<DupeError>()) {
+            Some(&DupeError::ReflinkUnsupported {..}) => {
+                self.stats.reflink_unsupported += 1;
+                Some(GuardReason::ReflinkUnsupported)
+            },
+            Some(&DupeError::CrossDevice {..}) if self.settings.cross_fs == CrossFsPolicy::Skip => {
+                self.stats.cross_fs_skipped += 1;
+                Some(GuardReason::CrossFilesystem)
+            },
+            _ => None,
+        }
+    }
+
+    /// Records the directory of a replaced path, for `Scanner::incremental_scan`
+    fn linked_into(&mut self, path: &Path) {
+        if let (Some(dirs), Some(dir)) = (self.linked_dirs.as_mut(), path.parent()) {
+            dirs.insert(dir.to_owned());
+        }
+    }
+
+    /// A duplicate is left as it is
+    fn skip_dupe(&mut self, path: &Path, reason: GuardReason) {
+        self.stats.dupes_not_linked += 1;
+        self.scan_listener.dupe_skipped(path, reason);
+    }
+
+    /// Tries out in the master's directory what linking needs, see `Settings::preflight`
+    fn preflight(&self, source_path: &Path) -> io::Result<()> {
+        let dir = source_path.parent().unwrap_or(Path::new("."));
+        let caps = probe::probe(dir)?;
+        let chain = if self.settings.mode_chain.is_empty() {&[DedupeMode::Hardlink][..]} else {&self.settings.mode_chain[..]};
+        // Every mode renames the new file over the duplicate
+        let missing = if !caps.atomic_rename {
+            Some("atomic renames")
+        } else if !caps.supports_hardlink && chain.contains(&DedupeMode::Hardlink) {
+            Some("hardlinks")
+        } else {
+            None
+        };
+        match missing {
+            Some(missing) => Err(DupeError::PreflightFailed {dir: dir.to_owned(), missing}.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether the device of the path is mounted read-only. If it can't tell, it's up to the linking to fail.
+    fn is_readonly(&mut self, path: &Path, dev: u64) -> bool {
+        *self.readonly_devs.entry(dev).or_insert_with(|| sys::is_readonly_mount(path).unwrap_or(false))
+    }
+
+    /// No duplicate of the master is linked
+    fn skip_group(&mut self, filesets: &[SharedFileSet], largest_idx: usize, reason: GuardReason) {
+        for (i, set) in filesets.iter().enumerate() {
+            if i == largest_idx {continue;}
+            for path in &set.lock().unwrap().paths {
+                self.skip_dupe(path, reason);
+            }
+        }
+    }
+
+    /// See `Scanner::apply_plan`
+    fn apply_link(&mut self, link: &PlannedLink) -> io::Result<()> {
+        if !self.confined(&link.source)? || !self.confined(&link.dest)? {
+            return Ok(());
+        }
+        let (source, dest) = match (fs::symlink_metadata(&link.source), fs::symlink_metadata(&link.dest)) {
+            (Ok(source), Ok(dest)) => (source, dest),
+            // Gone (or unreadable) since the plan was made is a change too
+            _ => {
+                self.stats.plan_skipped += 1;
+                self.scan_listener.plan_skipped(&link.dest, &link.source);
+                return Ok(());
+            },
+        };
+        let unchanged = source.is_file() && dest.is_file()
+            && source.size() == link.size && dest.size() == link.size
+            && source.dev() == link.dev && (dest.dev() == link.dev || self.settings.cross_fs == CrossFsPolicy::Symlink)
+            && source.ino() != dest.ino();
+        let readonly = self.settings.skip_readonly_mounts && !self.settings.dry_run && self.is_readonly(&link.dest, dest.dev());
+        let replaceable = unchanged && !readonly
+            && !self.settings.protected_inodes.contains(&(dest.dev(), dest.ino())) && keep_root_of(self.settings, &link.dest).is_none()
+            && !sys::is_immutable(&link.source)? && !sys::is_immutable(&link.dest)? && self.same_permissions(&source, &dest);
+        let verify = self.settings.verify_content;
+        let confidence = if verify {Confidence::Bytes} else {link.confidence};
+        if !replaceable || confidence < self.settings.min_confidence || !self.confirm(&link.source, &link.dest, verify, !self.settings.dry_run)? {
+            self.stats.plan_skipped += 1;
+            self.scan_listener.plan_skipped(&link.dest, &link.source);
+            return Ok(());
+        }
+
+        self.stats.dupes += 1;
+        if self.settings.dry_run {
+            self.scan_listener.duplicate_found(&link.dest, &link.source);
+            return Ok(());
+        }
+        self.check_free_space(&link.dest)?;
+        let temp_path = temp::unique_path(&link.dest);
+        match self.replace(&link.source, &link.dest, &temp_path, &dest, source.nlink()) {
+            Err(err) => match self.skip_reason(&err) {
+                Some(reason) => {
+                    self.skip_dupe(&link.dest, reason);
+                    Ok(())
+                },
+                None => Err(err),
+            },
+            Ok(_) => Ok(()),
+        }
+    }
+
+    /// Replaces the file with the first `Settings::mode_chain` mode that works, and reports it
+    fn replace(&mut self, source_path: &Path, dest_path: &Path, temp_path: &Path, dest_metadata: &fs::Metadata, links_before: u64) -> io::Result<DedupeMode> {
+        self.throttle();
+        let entry = match self.settings.journal {
+            Some(ref journal_path) => {
+                if self.journal.is_none() {
+                    *self.journal = Some(Journal::open(journal_path)?);
+                }
+                let journal = self.journal.as_mut().expect("just opened");
+                Some(journal.intent(source_path, dest_path, temp_path, dest_metadata)?)
+            },
+            None => None,
+        };
+        let res = self.replace_with_chain(source_path, dest_path, temp_path, dest_metadata, links_before);
+        // Also on errors, since the temporary file may have come and gone
+        self.linked_into(dest_path);
+        if let (Some(id), Some(journal)) = (entry, self.journal.as_mut()) {
+            journal.finish(id, res.is_ok())?;
+        }
+        res
+    }
+
+    fn replace_with_chain(&mut self, source_path: &Path, dest_path: &Path, temp_path: &Path, dest_metadata: &fs::Metadata, links_before: u64) -> io::Result<DedupeMode> {
+        let other_owner = self.settings.cow_across_owners && fs::symlink_metadata(source_path)?.uid() != dest_metadata.uid();
+        let chain = if other_owner {
+            &[DedupeMode::Reflink][..]
+        } else if self.settings.mode_chain.is_empty() {
+            &[DedupeMode::Hardlink][..]
+        } else {
+            &self.settings.mode_chain[..]
+        };
+        let mut last_err = None;
+        for &mode in chain {
+            let mut attempt = 0;
+            let res = loop {
+                let owner = if other_owner {Some(dest_metadata)} else {None};
+                let res = match self.replace_in_mode(mode, source_path, dest_path, temp_path, dest_metadata, owner) {
+                    // Archives often have read-only directories, which their owner can make writable for a moment
+                    Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                        let retry = Self::with_writable_dir(dest_path, || self.replace_in_mode(mode, source_path, dest_path, temp_path, dest_metadata, owner));
+                        retry.unwrap_or(Err(err))
+                    },
+                    res => res,
+                };
+                // The temporary file is gone after a failure, so it can start over
+                match res {
+                    Err(ref err) if attempt < self.settings.retry_count && sys::is_transient(err) => {
+                        thread::sleep(self.settings.retry_backoff.saturating_mul(1 << attempt.min(16)));
+                        attempt += 1;
+                        self.stats.retries += 1;
+                    },
+                    res => break res,
+                }
+            };
+            match res {
+                Ok(()) => {
+                    // The quarantine still has it
+                    if mode != DedupeMode::Quarantine {
+                        self.count_saved(dest_metadata, mode);
+                    }
+                    return self.replaced(mode, source_path, dest_path, links_before);
+                },
+                Err(err) => last_err = Some(err),
+            }
+        }
+        let err = last_err.expect("chain can't be empty");
+        // Symlinks can point to other filesystems, e.g. from a bind mount of the same one
+        let cross_fs_symlink = self.settings.cross_fs == CrossFsPolicy::Symlink && !chain.contains(&DedupeMode::Symlink);
+        if err.raw_os_error() == Some(::libc::EXDEV) && cross_fs_symlink {
+            Self::replace_with_symlink(self.filesystem, source_path, dest_path, temp_path)?;
+            self.count_saved(dest_metadata, DedupeMode::Symlink);
+            return self.replaced(DedupeMode::Symlink, source_path, dest_path, links_before);
+        }
+        let unsupported = matches!(err.raw_os_error(), Some(::libc::EOPNOTSUPP) | Some(::libc::EXDEV));
+        if unsupported && chain.last() == Some(&DedupeMode::Reflink) {
+            return Err(DupeError::ReflinkUnsupported {path: dest_path.to_owned(), source: err}.into());
+        }
+        Err(match err.raw_os_error() {
+            Some(::libc::EXDEV) => DupeError::CrossDevice {src: source_path.to_owned(), dst: dest_path.to_owned(), source: err}.into(),
+            Some(::libc::EROFS) => DupeError::ReadOnlyMount {path: dest_path.to_owned(), source: err}.into(),
+            _ => err,
+        })
+    }
+
+    /// One attempt to replace the file, without the fallbacks of `replace_with_chain`.
+    /// `other_owner` is the dest's metadata if it belongs to someone else, for `Settings::cow_across_owners`.
+    fn replace_in_mode(&self, mode: DedupeMode, source_path: &Path, dest_path: &Path, temp_path: &Path, dest_metadata: &fs::Metadata, other_owner: Option<&fs::Metadata>) -> io::Result<()> {
+        temp::remove_stale(temp_path)?;
+        let filesystem = self.filesystem;
+        match mode {
+            DedupeMode::Reflink => Self::replace_with_reflink(filesystem, source_path, dest_path, temp_path, other_owner),
+            DedupeMode::Hardlink if self.settings.use_renameat2 => Self::replace_with_exchange(filesystem, source_path, dest_path, temp_path, dest_metadata),
+            DedupeMode::Hardlink => Self::replace_with_link(filesystem, source_path, dest_path, temp_path),
+            DedupeMode::Symlink => Self::replace_with_symlink(filesystem, source_path, dest_path, temp_path),
+            DedupeMode::Quarantine => match self.settings.quarantine_dir {
+                Some(ref dir) => Self::replace_with_quarantine(filesystem, source_path, dest_path, temp_path, dir),
+                None => Err(DupeError::InvalidSettings("DedupeMode::Quarantine needs Settings::quarantine_dir").into()),
+            },
+        }
+    }
+
+    /// Runs `f` with the directory of `path` made writable by its owner, and restores its mode afterwards.
+    /// `None` if it's writable already or its mode can't be changed, then there's nothing to retry.
+    /// A failed `f` has left the dest as it was, and if only restoring fails the dest is linked, but it's an error.
+    fn with_writable_dir<T, F: FnOnce() -> io::Result<T>>(path: &Path, f: F) -> Option<io::Result<T>> {
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        let mode = fs::metadata(dir).ok()?.permissions().mode();
+        if mode & 0o200 != 0 {
+            return None;
+        }
+        fs::set_permissions(dir, fs::Permissions::from_mode(mode | 0o200)).ok()?;
+        let res = f();
+        let restored = fs::set_permissions(dir, fs::Permissions::from_mode(mode));
+        Some(res.and_then(|value| restored.map(|_| value)))
+    }
+
+    /// Whether the files are still duplicates after `Settings::equality_oracle`,
+    /// and the byte comparison if `verify` (then with an oracle only before linking)
+    fn confirm(&self, source_path: &Path, dest_path: &Path, verify: bool, links: bool) -> io::Result<bool> {
+        match self.settings.equality_oracle {
+            None => Ok(!verify || verify::same_content(source_path, dest_path)?),
+            Some(ref oracle) => Ok(oracle(dest_path, source_path)? && (!(verify && links) || verify::same_content(source_path, dest_path)?)),
+        }
+    }
+
+    /// Sleeps until the next replacement is allowed by `Settings::max_ops_per_sec`
+    fn throttle(&mut self) {
+        let ops_per_sec = match self.settings.max_ops_per_sec {
+            Some(ops_per_sec) if ops_per_sec > 0 => ops_per_sec,
+            _ => return,
+        };
+        let now = Instant::now();
+        let start = match *self.next_op {
+            Some(next) if next > now => {
+                thread::sleep(next - now);
+                next
+            },
+            _ => now,
+        };
+        *self.next_op = Some(start + Duration::from_secs(1) / ops_per_sec);
+    }
+
+    /// The duplicate's storage is freed if the replaced path was its last link
+    fn count_saved(&mut self, dest_metadata: &fs::Metadata, mode: DedupeMode) {
+        if dest_metadata.nlink() == 1 {
+            self.stats.bytes_saved += if self.settings.compression_aware_savings {
+                dest_metadata.blocks() * 512
+            } else {
+                dest_metadata.size()
+            };
+            if mode == DedupeMode::Hardlink {
+                self.stats.reclaimed_bytes += dest_metadata.size();
+            }
+        }
+    }
+
+    fn replaced(&mut self, mode: DedupeMode, source_path: &Path, dest_path: &Path, links_before: u64) -> io::Result<DedupeMode> {
+        match mode {
+            DedupeMode::Hardlink => {
+                if self.settings.on_link_anomaly != OnLinkAnomaly::Unchecked {
+                    self.check_link(source_path, dest_path, links_before)?;
+                }
+                self.stats.created_hardlinks += 1;
+                #[allow(deprecated)] {
+                    self.stats.hardlinks += 1;
+                }
+                self.scan_listener.hardlinked(dest_path, source_path);
+            },
+            DedupeMode::Reflink => {
+                self.stats.reflinked += 1;
+                self.scan_listener.reflinked(dest_path, source_path);
+            },
+            DedupeMode::Symlink => {
+                self.stats.symlinked += 1;
+                self.scan_listener.symlinked(dest_path, source_path);
+            },
+            DedupeMode::Quarantine => {
+                self.stats.quarantined += 1;
+                self.scan_listener.quarantined(dest_path, source_path);
+            },
+        }
+        Ok(mode)
+    }
+
+    /// False when `Settings::confine_to_roots` is on and the path resolves to outside of the roots.
+    /// Such paths are recorded, and must be left alone.
+    fn confined(&mut self, path: &Path) -> io::Result<bool> {
+        if !self.settings.confine_to_roots {
+            return Ok(true);
+        }
+        // The file itself is never followed, but its directories could have been replaced with symlinks since
+        let resolved = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => fs::canonicalize(parent)?.join(name),
+            _ => fs::canonicalize(path)?,
+        };
+        if self.roots.iter().any(|root| resolved.starts_with(root)) {
+            return Ok(true);
+        }
+        self.stats.outside_roots += 1;
+        if self.settings.strict {
+            return Err(outside_roots_error(path));
+        }
+        let err = outside_roots_error(path);
+        self.scan_listener.scan_error(path, &err);
+        self.errors.push((path.to_owned(), err));
+        Ok(false)
+    }
+
+    fn check_link(&mut self, source_path: &Path, dest_path: &Path, links_before: u64) -> io::Result<()> {
+        let source = fs::symlink_metadata(source_path)?;
+        let dest = fs::symlink_metadata(dest_path)?;
+        if source.dev() == dest.dev() && source.ino() == dest.ino() && source.nlink() == links_before + 1 {
+            return Ok(());
+        }
+        if self.settings.on_link_anomaly == OnLinkAnomaly::Error {
+            return Err(DupeError::LinkAnomaly {src: source_path.to_owned(), dst: dest_path.to_owned()}.into());
+        }
+        self.stats.link_anomalies += 1;
+        self.scan_listener.link_anomaly(dest_path, source_path);
+        Ok(())
+    }
+
+    fn check_free_space(&self, dest_path: &Path) -> io::Result<()> {
+        if let Some(min_free_space) = self.settings.min_free_space {
+            let dir = dest_path.parent().unwrap_or(dest_path);
+            let free = sys::free_space(dir)?;
+            if free < min_free_space {
+                return Err(io::Error::new(io::ErrorKind::StorageFull, format!("only {} bytes free on the filesystem of {}", free, dir.display())));
+            }
+        }
+        Ok(())
+    }
+
+    /// The copy gets the source's permissions and mtime, or `owned_like`'s, and its owner and group
+    fn replace_with_reflink(filesystem: &dyn FileSystem, source_path: &Path, dest_path: &Path, temp_path: &Path, owned_like: Option<&fs::Metadata>) -> io::Result<()> {
+        let source = fs::File::open(source_path)?;
+        let temp = filesystem.clone_file(&source, temp_path)?;
+        // Only the temporary file that was made here is removed on errors
+        let res = (|| {
+            let metadata = match owned_like {
+                Some(metadata) => {
+                    fchown(&temp, Some(metadata.uid()), Some(metadata.gid()))?;
+                    metadata.clone()
+                },
+                None => source.metadata()?,
+            };
+            temp.set_permissions(metadata.permissions())?;
+            temp.set_modified(metadata.modified()?)?;
+            filesystem.rename(temp_path, dest_path)
+        })();
+        if res.is_err() {
+            filesystem.remove_file(temp_path).ok();
+        }
+        res
+    }
+
+    /// A copy of the source with the permissions, owner, group and mtime of `original`
+    fn replace_with_copy(filesystem: &dyn FileSystem, source_path: &Path, dest_path: &Path, temp_path: &Path, original: &fs::Metadata) -> io::Result<()> {
+        let mut source = fs::File::open(source_path)?;
+        let mut temp = fs::OpenOptions::new().write(true).create_new(true).open(temp_path)?;
+        let res = (|| {
+            io::copy(&mut source, &mut temp)?;
+            fchown(&temp, Some(original.uid()), Some(original.gid()))?;
+            temp.set_permissions(original.permissions())?;
+            temp.set_modified(original.modified()?)?;
+            filesystem.rename(temp_path, dest_path)
+        })();
+        if res.is_err() {
+            filesystem.remove_file(temp_path).ok();
+        }
+        res
+    }
+
+    /// The duplicate is linked into the quarantine first, so that its content is never without a path
+    fn replace_with_quarantine(filesystem: &dyn FileSystem, source_path: &Path, dest_path: &Path, temp_path: &Path, dir: &Path) -> io::Result<()> {
+        let quarantined = quarantine_path(dir, dest_path)?;
+        if let Some(parent) = quarantined.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        filesystem.hard_link(dest_path, &quarantined)?;
+        let res = Self::replace_with_symlink(filesystem, source_path, dest_path, temp_path);
+        if res.is_err() {
+            filesystem.remove_file(&quarantined).ok();
+        }
+        res
+    }
+
+    fn replace_with_symlink(filesystem: &dyn FileSystem, source_path: &Path, dest_path: &Path, temp_path: &Path) -> io::Result<()> {
+        filesystem.symlink(source_path, temp_path)?;
+        filesystem.rename(temp_path, dest_path).inspect_err(|_| {
+            filesystem.remove_file(temp_path).ok();
+        })
+    }
+
+    /// Like `replace_with_link`, but swaps the link with the destination, and checks that what was swapped out
+    /// is still the file that was compared (`before`), swapping it back if it's not. Falls back to a rename where swaps aren't supported.
+    fn replace_with_exchange(filesystem: &dyn FileSystem, source_path: &Path, dest_path: &Path, temp_path: &Path, before: &fs::Metadata) -> io::Result<()> {
+        filesystem.hard_link(source_path, temp_path)?;
+        let res = match filesystem.exchange(temp_path, dest_path) {
+            Ok(()) => match fs::symlink_metadata(temp_path) {
+                Ok(ref swapped) if swapped.dev() == before.dev() && swapped.ino() == before.ino() => Ok(()),
+                Ok(_) => filesystem.exchange(temp_path, dest_path).and_then(|_| {
+                    Err(DupeError::ChangedWhileLinking {path: dest_path.to_owned()}.into())
+                }),
+                Err(err) => Err(err),
+            },
+            Err(ref err) if sys::is_exchange_unsupported(err) => {
+                return filesystem.rename(temp_path, dest_path).inspect_err(|_| {
+                    filesystem.remove_file(temp_path).ok();
+                });
+            },
+            Err(err) => Err(err),
+        };
+        // It's either the replaced file or the unused link now
+        filesystem.remove_file(temp_path).ok();
+        res
+    }
+
+    fn replace_with_link(filesystem: &dyn FileSystem, source_path: &Path, dest_path: &Path, temp_path: &Path) -> io::Result<()> {
+        debug_assert!(source_path.exists());
+        debug_assert!(dest_path.exists());
+
+        // In posix link guarantees not to overwrite, and mv guarantes to move atomically
+        // so this two-step replacement is pretty robust. A name that's taken is someone else's, so it's left alone.
+        filesystem.hard_link(source_path, temp_path)?;
+        if let Err(err) = filesystem.rename(temp_path, dest_path) {
+            filesystem.remove_file(temp_path).ok();
+            return Err(err);
+        }
+        debug_assert!(!temp_path.exists());
+        debug_assert!(source_path.exists());
+        debug_assert!(dest_path.exists());
+        Ok(())
+    }
+}
+
+struct DuplicateGroupInfo {
+    size: u64,
+    dev: u64,
+    members: Vec<DuplicateMember>,
+}
+
+/// Entries of a directory, read before they're added
+struct Listing {
+    /// Taken before listing, so that anything added after it changes the mtime (see `Settings::rescan_changed_dirs`)
+    mtime: Option<SystemTime>,
+    /// With metadata, if it was read ahead too
+    entries: io::Result<Vec<(fs::DirEntry, Option<io::Result<fs::Metadata>>)>>,
+}
+
+impl Listing {
+    fn read(path: &Path, with_mtime: bool, with_metadata: bool) -> Self {
+        let mtime = if with_mtime {fs::metadata(path).and_then(|m| m.modified()).ok()} else {None};
+        let entries = fs::read_dir(path).map(|entries| entries.filter_map(|p| p.ok())
+            .map(|entry| {
+                let metadata = if with_metadata {Some(entry.metadata())} else {None};
+                (entry, metadata)
+            })
+            .collect());
+        Listing {mtime, entries}
+    }
+}
+
+/// A directory as it was listed, see `Settings::rescan_changed_dirs`
+#[derive(Debug)]
+struct DirSnapshot {
+    inode: (u64, u64),
+    depth: usize,
+    mtime: SystemTime,
+    names: HashSet<OsString>,
+}
+
+/// Recent totals of bytes read, for the rate over `ETA_WINDOW` rather than since the last sample
+#[derive(Debug, Default)]
+struct Throughput {
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl Throughput {
+    fn sample(&mut self, now: Instant, total_bytes_read: u64) {
+        self.samples.push_back((now, total_bytes_read));
+        // The last one older than the window is kept, so that the window is covered in full
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) >= ETA_WINDOW {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Time to read `remaining` more bytes, if anything has been read in the window
+    fn eta(&self, remaining: u64) -> Option<Duration> {
+        let (&(first_time, first_bytes), &(last_time, last_bytes)) = (self.samples.front()?, self.samples.back()?);
+        let secs = last_time.duration_since(first_time).as_secs_f64();
+        if last_bytes <= first_bytes || secs == 0.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(remaining as f64 * secs / (last_bytes - first_bytes) as f64))
+    }
+}
+
+/// All paths of one inode (but in dry run also paths that would have been linked to it)
+struct DuplicateMember {
+    ino: u64,
+    paths: Vec<PathBuf>,
+    untracked_paths: u64,
+}
+
+/// Where `DedupeMode::Quarantine` puts the file: its absolute path, under the quarantine
+fn quarantine_path(dir: &Path, path: &Path) -> io::Result<PathBuf> {
+    let absolute = if path.is_absolute() {path.to_owned()} else {::std::env::current_dir()?.join(path)};
+    Ok(dir.join(absolute.strip_prefix("/").unwrap_or(&absolute)))
+}
+
+/// Moves files of the quarantine back to the path they're at under it, see `Scanner::restore_quarantine`
+fn restore_quarantined(quarantine: &Path, dir: &Path, restored: &mut usize, errors: &mut Vec<(PathBuf, io::Error)>) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        // Only the quarantine itself is an error, the rest of it is restored anyway
+        Err(err) if dir == quarantine => return Err(err),
+        Err(err) => {
+            errors.push((dir.to_owned(), err));
+            return Ok(());
+        },
+    };
+    for entry in entries {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(err) => {
+                errors.push((dir.to_owned(), err));
+                continue;
+            },
+        };
+        let is_dir = match fs::symlink_metadata(&path) {
+            Ok(m) => m.is_dir(),
+            Err(err) => {
+                errors.push((path, err));
+                continue;
+            },
+        };
+        if is_dir {
+            restore_quarantined(quarantine, &path, restored, errors)?;
+            // Unless something is left in it
+            fs::remove_dir(&path).ok();
+            continue;
+        }
+        let original = Path::new("/").join(path.strip_prefix(quarantine).expect("in the quarantine"));
+        match fs::symlink_metadata(&original) {
+            Ok(ref m) if !m.file_type().is_symlink() => {
+                errors.push((original, io::Error::new(io::ErrorKind::AlreadyExists, "replaced since it was quarantined")));
+                continue;
+            },
+            Err(err) => if err.kind() != io::ErrorKind::NotFound {
+                errors.push((original, err));
+                continue;
+            },
+            // The symlink may have been made since, so only the one to the master (or a copy of it) is replaced
+            Ok(_) => if !verify::same_content(&original, &path).unwrap_or(false) {
+                errors.push((original, io::Error::new(io::ErrorKind::AlreadyExists, "the symlink doesn't point to the quarantined content")));
+                continue;
+            },
+        }
+        // Replaces the symlink in one step
+        match fs::rename(&path, &original) {
+            Ok(()) => *restored += 1,
+            Err(err) => errors.push((original, err)),
+        }
+    }
+    Ok(())
+}
+
+/// Time of the last change of the inode (`st_ctime`), which `std` has no `SystemTime` for
+fn ctime(metadata: &fs::Metadata) -> SystemTime {
+    let secs = Duration::from_secs(metadata.ctime().unsigned_abs());
+    let whole_secs = if metadata.ctime() >= 0 {UNIX_EPOCH + secs} else {UNIX_EPOCH - secs};
+    whole_secs + Duration::from_nanos(metadata.ctime_nsec() as u64)
+}
+
+/// ID of the group of duplicates with the file's content: the hex of its SHA-256, as in `Scanner::write_manifest`.
+/// It's only derived from the content, so it's the same across runs and machines, whatever the paths or scan order.
+fn group_id(path: &Path) -> io::Result<String> {
+    Ok(sha256::hex(&sha256_file(path)?))
+}
+
+fn sha256_file(path: &Path) -> io::Result<[u8; 32]> {
+    let mut file = fs::File::open(path)?;
+    let mut hash = Sha256::new();
+    let mut buf = vec![0; 1 << 20];
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 {
+            return Ok(hash.finish());
+        }
+        hash.update(&buf[..len]);
+    }
+}
+
+/// Whether the paths (known to be of the same inode) are one directory entry, rather than two hardlinks.
+/// Names that differ only in case are the same entry on case-insensitive filesystems,
+/// which is when the directory doesn't list both of them.
+fn is_same_entry(a: &Path, b: &Path) -> bool {
+    if a == b {
+        return true;
+    }
+    let (dir_a, name_a, dir_b, name_b) = match (a.parent(), a.file_name(), b.parent(), b.file_name()) {
+        (Some(dir_a), Some(name_a), Some(dir_b), Some(name_b)) => (dir_a, name_a, dir_b, name_b),
+        _ => return false,
+    };
+    if name_a.to_string_lossy().to_lowercase() != name_b.to_string_lossy().to_lowercase() {
+        return false;
+    }
+    // The directories could be spelled differently too
+    let same_dir = match (fs::metadata(dir_a), fs::metadata(dir_b)) {
+        (Ok(m_a), Ok(m_b)) => m_a.dev() == m_b.dev() && m_a.ino() == m_b.ino(),
+        _ => false,
+    };
+    if !same_dir || name_a == name_b {
+        return same_dir;
+    }
+    let entries = match fs::read_dir(dir_a) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    let names: Vec<_> = entries.filter_map(|e| e.ok()).map(|e| e.file_name())
+        .filter(|name| name == name_a || name == name_b).collect();
+    !(names.iter().any(|name| name == name_a) && names.iter().any(|name| name == name_b))
+}
+
+/// Runs a `Settings::snapshot_command` with `{src}` and `{dst}` filled in
+fn run_snapshot_command(command: &[String], src: &Path, dst: &Path) -> io::Result<()> {
+    let path_str = |path: &Path| path.to_str().map(str::to_owned)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} isn't UTF-8", path.display())));
+    let (src, dst) = (path_str(src)?, path_str(dst)?);
+    let args: Vec<_> = command.iter().map(|arg| arg.replace("{src}", &src).replace("{dst}", &dst)).collect();
+    let (program, args) = args.split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "snapshot command is empty"))?;
+    let status = ::std::process::Command::new(program).args(args).status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("{} failed with {}", program, status)));
+    }
+    Ok(())
+}
+
+/// The one of `Settings::keep_roots` the path is in
+fn keep_root_of<'a>(settings: &'a Settings, path: &Path) -> Option<&'a Path> {
+    settings.keep_roots.iter().find(|root| path.starts_with(root)).map(|root| root.as_path())
+}
+
+fn lowercase_extension(path: &Path) -> Option<OsString> {
+    path.extension().map(|ext| OsStr::from_bytes(&ext.as_bytes().to_ascii_lowercase()).to_owned())
+}
+
+/// Has a size, but no blocks allocated
+fn is_sparse(metadata: &fs::Metadata) -> bool {
+    metadata.size() > 0 && metadata.blocks() == 0
+}
+
+/// The error with its path, for `Settings::strict`. Errors of this crate have it already.
+fn strict_error(path: &Path, err: io::Error) -> io::Error {
+    if err.get_ref().is_some_and(|inner| inner.is::<DupeError>()) {
+        return err;
+    }
+    io::Error::new(err.kind(), format!("{}: {}", path.display(), err))
+}
+
+fn immutable_error(path: &Path) -> io::Error {
+    DupeError::Immutable {path: path.to_owned()}.into()
+}
+
+fn outside_roots_error(path: &Path) -> io::Error {
+    DupeError::OutsideRoots {path: path.to_owned()}.into()
+}
+
+/// Identifies unchanged files across runs
+fn unique_key(metadata: &fs::Metadata) -> [u8; 40] {
+    let mut key = [0; 40];
+    key[0..8].copy_from_slice(&metadata.dev().to_le_bytes());
+    key[8..16].copy_from_slice(&metadata.ino().to_le_bytes());
+    key[16..24].copy_from_slice(&metadata.size().to_le_bytes());
+    key[24..32].copy_from_slice(&metadata.mtime().to_le_bytes());
+    key[32..40].copy_from_slice(&metadata.mtime_nsec().to_le_bytes());
+    key
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+    use super::*;
+
+    /// Deletes a file as soon as its directory has been listed, before its entries are stat-ed
+    #[derive(Debug)]
+    struct DeletingListener(PathBuf);
+
+    impl ScanListener for DeletingListener {
+        fn file_scanned(&mut self, _: &Path, _: &Stats) {}
+        fn scan_over(&self, _: &Scanner, _: &Stats, _: Duration) {}
+        fn hardlinked(&mut self, _: &Path, _: &Path) {}
+        fn duplicate_found(&mut self, _: &Path, _: &Path) {}
+        fn directory_scanned(&mut self, _: &Path, _: &Stats) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn deleted_during_scan() {
+        let dir = tempdir::TempDir::new("deletedtest").unwrap();
+        for name in &["a", "b", "victim"] {
+            fs::File::create(dir.path().join(name)).unwrap().write_all(b"dupe").unwrap();
+        }
+
+        let mut d = Scanner::new();
+        d.settings.ignore_small = false;
+        d.settings.strict = true;
+        d.set_listener(Box::new(DeletingListener(dir.path().join("victim"))));
+        d.scan(dir.path()).unwrap();
+        assert_eq!(d.stats().deleted_during_scan, 1);
+        assert_eq!(d.stats().added, 2);
+        assert!(d.errors().is_empty());
+    }
+
+    #[derive(Debug, Default)]
+    struct ErrorListener(Arc<Mutex<Vec<PathBuf>>>);
+
+    impl ScanListener for ErrorListener {
+        fn file_scanned(&mut self, _: &Path, _: &Stats) {}
+        fn scan_over(&self, _: &Scanner, _: &Stats, _: Duration) {}
+        fn hardlinked(&mut self, _: &Path, _: &Path) {}
+        fn duplicate_found(&mut self, _: &Path, _: &Path) {}
+        fn scan_error(&mut self, path: &Path, _: &io::Error) {
+            self.0.lock().unwrap().push(path.to_owned());
+        }
+    }
+
+    #[test]
+    fn scan_error() {
+        let dir = tempdir::TempDir::new("scanerrortest").unwrap();
+        let missing = dir.path().join("missing");
+        for name in &["a", "b"] {
+            fs::write(dir.path().join(name), "dupe").unwrap();
+        }
+
+        let listener = ErrorListener::default();
+        let seen = listener.0.clone();
+        let mut d = Scanner::new();
+        d.settings.ignore_small = false;
+        d.set_listener(Box::new(listener));
+        d.enqueue_lenient(&missing).unwrap();
+        d.enqueue_lenient(dir.path()).unwrap();
+        d.flush().unwrap();
+        assert_eq!(*seen.lock().unwrap(), vec![missing.clone()]);
+        assert_eq!(d.errors().len(), 1);
+        assert_eq!(d.stats().created_hardlinks, 1);
+    }
+
+    /// Cancels the scan at the first link, and records that `scan_over` was called
+    #[derive(Debug, Default)]
+    struct CancellingListener(Arc<AtomicBool>, Arc<AtomicBool>);
+
+    impl ScanListener for CancellingListener {
+        fn file_scanned(&mut self, _: &Path, _: &Stats) {}
+        fn scan_over(&self, _: &Scanner, _: &Stats, _: Duration) {
+            self.1.store(true, AtomicOrdering::SeqCst);
+        }
+        fn hardlinked(&mut self, _: &Path, _: &Path) {
+            self.0.store(true, AtomicOrdering::SeqCst);
+        }
+        fn duplicate_found(&mut self, _: &Path, _: &Path) {}
+    }
+
+    #[test]
+    fn flush_cancellable() {
+        let dir = tempdir::TempDir::new("canceltest").unwrap();
+        let sub = dir.path().join("sub");
+        fs::create_dir(&sub).unwrap();
+        for name in &["a", "b"] {
+            fs::write(dir.path().join(name), "dupe").unwrap();
+            fs::write(sub.join(name), "dupe").unwrap();
+        }
+
+        let listener = CancellingListener::default();
+        let (cancel, over) = (listener.0.clone(), listener.1.clone());
+        let mut d = Scanner::new();
+        d.settings.ignore_small = false;
+        d.set_listener(Box::new(listener));
+        d.enqueue(dir.path()).unwrap();
+        d.flush_cancellable(cancel.clone()).unwrap();
+        assert!(!d.completed());
+        assert!(over.load(AtomicOrdering::SeqCst));
+        assert_eq!(d.stats().created_hardlinks, 1);
+
+        cancel.store(false, AtomicOrdering::SeqCst);
+        d.flush().unwrap();
+        assert!(d.completed());
+        assert_eq!(d.stats().created_hardlinks, 3);
+    }
+
+    #[test]
+    fn throughput_window() {
+        let start = Instant::now();
+        let mut throughput = Throughput::default();
+        assert_eq!(throughput.eta(100), None);
+        throughput.sample(start, 0);
+        assert_eq!(throughput.eta(100), None);
+        // 1000 bytes per second at first, and then 100
+        throughput.sample(start + Duration::from_secs(1), 1000);
+        assert_eq!(throughput.eta(2000), Some(Duration::from_secs(2)));
+        for i in 1..=60 {
+            throughput.sample(start + Duration::from_secs(1 + i), 1000 + i * 100);
+        }
+        assert_eq!(throughput.eta(2000), Some(Duration::from_secs(20)));
+    }
+
+    /// Fails every rename, and counts them
+    #[derive(Debug, Default)]
+    struct NoRenameFileSystem(Arc<AtomicUsize>);
+
+    impl FileSystem for NoRenameFileSystem {
+        fn rename(&self, _: &Path, _: &Path) -> io::Result<()> {
+            self.0.fetch_add(1, AtomicOrdering::SeqCst);
+            Err(io::Error::from_raw_os_error(::libc::EIO))
+        }
+    }
+
+    #[test]
+    fn failing_filesystem() {
+        let dir = tempdir::TempDir::new("filesystemtest").unwrap();
+        for name in &["a", "b"] {
+            fs::write(dir.path().join(name), "dupe").unwrap();
+        }
+
+        let filesystem = NoRenameFileSystem::default();
+        let renames = filesystem.0.clone();
+        let mut d = Scanner::new();
+        d.settings.ignore_small = false;
+        d.set_filesystem(Box::new(filesystem));
+        d.scan(dir.path()).unwrap();
+        assert_eq!(renames.load(AtomicOrdering::SeqCst), 1);
+        assert_eq!(d.stats().created_hardlinks, 0);
+        assert_eq!(d.errors().len(), 1);
+        // The link made for the rename is removed again
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+        assert_eq!(fs::metadata(dir.path().join("a")).unwrap().nlink(), 1);
+    }
+
+    #[test]
+    fn writable_dir() {
+        let dir = tempdir::TempDir::new("writabletest").unwrap();
+        let locked = dir.path().join("locked");
+        fs::create_dir(&locked).unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o555)).unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        let inside = Deduper::with_writable_dir(&locked.join("a"), || Ok(mode(&locked))).unwrap().unwrap();
+        assert_eq!((inside, mode(&locked)), (0o755, 0o555));
+        // It's restored after failures too
+        let failed: io::Result<()> = Deduper::with_writable_dir(&locked.join("a"), || Err(io::ErrorKind::Other.into())).unwrap();
+        assert!(failed.is_err());
+        assert_eq!(mode(&locked), 0o555);
+        assert!(Deduper::with_writable_dir(&dir.path().join("a"), || Ok(())).is_none());
+    }
+}
@@ -0,0 +1,95 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Why `Scanner::scan` failed, for the failures that can be told apart.
+///
+/// Other methods return `io::Error`s, which for these failures carry a `DupeError` (and are converted back by `From`).
+#[derive(Debug)]
+pub enum DupeError {
+    /// Reading or linking failed for some other reason
+    Io(io::Error),
+    /// Settings can't be used as they are, e.g. changed after files have been added
+    InvalidSettings(&'static str),
+    /// The file has the immutable attribute, and `Settings::on_immutable` is `Error`
+    Immutable { path: PathBuf },
+    /// The path resolves to outside of the scanned roots (see `Settings::confine_to_roots`)
+    OutsideRoots { path: PathBuf },
+    /// The file to replace is on a read-only mount (that `Settings::skip_readonly_mounts` didn't know about)
+    ReadOnlyMount { path: PathBuf, source: io::Error },
+    /// The files are on different filesystems, so they can't be linked, e.g. `dst` is a mount point's subtree
+    CrossDevice { src: PathBuf, dst: PathBuf, source: io::Error },
+    /// `dst` was expected to have the content of `src`, but it doesn't (see `Settings::content_address_root`)
+    VerificationFailed { src: PathBuf, dst: PathBuf },
+    /// `dst` doesn't look like a hardlink of `src` after linking, and `Settings::on_link_anomaly` is `Error`
+    LinkAnomaly { src: PathBuf, dst: PathBuf },
+    /// The file was replaced by another one while it was being linked (see `Settings::use_renameat2`)
+    ChangedWhileLinking { path: PathBuf },
+    /// Files in different `Settings::keep_roots` have the same content, and neither can be replaced
+    DifferentKeepRoots { a: PathBuf, b: PathBuf },
+    /// Trying out linking in `dir` showed the filesystem lacks what's `missing` (see `Settings::preflight`)
+    PreflightFailed { dir: PathBuf, missing: &'static str },
+    /// The filesystem can't clone `path`'s master (`EOPNOTSUPP` or `EXDEV`), and `DedupeMode::Reflink` was the last mode
+    /// to try. During a scan the file is left as it is instead, see `GuardReason::ReflinkUnsupported`.
+    ReflinkUnsupported { path: PathBuf, source: io::Error },
+}
+
+impl fmt::Display for DupeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DupeError::Io(ref err) => err.fmt(f),
+            DupeError::InvalidSettings(msg) => f.write_str(msg),
+            DupeError::Immutable {ref path} => write!(f, "{} is immutable", path.display()),
+            DupeError::OutsideRoots {ref path} => write!(f, "{} is outside of the scanned roots", path.display()),
+            DupeError::ReadOnlyMount {ref path, ..} => write!(f, "{} is on a read-only mount", path.display()),
+            DupeError::CrossDevice {ref src, ref dst, ..} => write!(f, "{} and {} are on different filesystems", dst.display(), src.display()),
+            DupeError::VerificationFailed {ref src, ref dst} => write!(f, "{} doesn't have the content of {}", dst.display(), src.display()),
+            DupeError::LinkAnomaly {ref src, ref dst} => write!(f, "{} doesn't look like a hardlink of {} after linking", dst.display(), src.display()),
+            DupeError::ChangedWhileLinking {ref path} => write!(f, "{} was replaced by another file while it was being linked", path.display()),
+            DupeError::DifferentKeepRoots {ref a, ref b} => write!(f, "{} and {} are in different keep roots, but have the same content", a.display(), b.display()),
+            DupeError::PreflightFailed {ref dir, missing} => write!(f, "the filesystem of {} doesn't support {}, so nothing was linked on it", dir.display(), missing),
+            DupeError::ReflinkUnsupported {ref path, ..} => write!(f, "{} can't be reflinked on its filesystem", path.display()),
+        }
+    }
+}
+
+impl Error for DupeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            // Displayed as it is, so its source is the next one
+            DupeError::Io(ref err) => err.source(),
+            DupeError::ReadOnlyMount {ref source, ..} | DupeError::CrossDevice {ref source, ..}
+                | DupeError::ReflinkUnsupported {ref source, ..} => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DupeError {
+    fn from(err: io::Error) -> Self {
+        if err.get_ref().is_some_and(|inner| inner.is::<DupeError>()) {
+            if let Ok(inner) = err.into_inner().expect("checked").downcast::<DupeError>() {
+                return *inner;
+            }
+            unreachable!();
+        }
+        DupeError::Io(err)
+    }
+}
+
+impl From<DupeError> for io::Error {
+    fn from(err: DupeError) -> Self {
+        let kind = match err {
+            DupeError::Io(err) => return err,
+            DupeError::InvalidSettings(_) | DupeError::DifferentKeepRoots {..} => io::ErrorKind::InvalidInput,
+            DupeError::Immutable {..} | DupeError::OutsideRoots {..} => io::ErrorKind::PermissionDenied,
+            DupeError::ReadOnlyMount {..} => io::ErrorKind::ReadOnlyFilesystem,
+            DupeError::CrossDevice {..} => io::ErrorKind::CrossesDevices,
+            DupeError::PreflightFailed {..} | DupeError::ReflinkUnsupported {..} => io::ErrorKind::Unsupported,
+            DupeError::VerificationFailed {..} => io::ErrorKind::InvalidData,
+            DupeError::LinkAnomaly {..} | DupeError::ChangedWhileLinking {..} => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err)
+    }
+}
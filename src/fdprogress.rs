@@ -0,0 +1,191 @@
+use dupe::{GuardReason, ScanListener, Scanner, SpecialFileKind, Stats};
+use sha256;
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::Path;
+use std::time::Duration;
+
+/// Writes progress events to a file or pipe, one per line, with fields separated by tabs:
+///
+/// ```text
+/// started [<total files>]
+/// scanned <added> <skipped> <dupes> <path>
+/// duplicate <path> <master path>
+/// hardlinked <path> <master path>
+/// reflinked <path> <master path>
+/// symlinked <path> <master path>
+/// quarantined <path> <master path>
+/// timeout <path>
+/// slow <milliseconds> <path>
+/// special <kind> <path>
+/// dupe-skipped <reason> <path>
+/// orphan <path>
+/// indexed <size> <sha256> <path>
+/// bytes <total bytes read> [<milliseconds left>]
+/// heartbeat <milliseconds> <added> <dupes>
+/// anomaly <path> <master path>
+/// mismatch <path> <master path>
+/// corruption <master path>
+/// integrity <expected sha256> <path>
+/// error <kind> <path>
+/// permissions <path> <master path>
+/// plan-skipped <path> <master path>
+/// sparse <path>
+/// self-link <path>
+/// cross-device <path> <other path>
+/// done <added> <skipped> <dupes> <existing hardlinks> <milliseconds>
+/// ```
+///
+/// Backslashes, tabs and newlines in paths are escaped as `\\`, `\t` and `\n`.
+/// Output is buffered, and flushed at the end of the scan.
+#[derive(Debug)]
+pub struct FdProgressListener {
+    // scan_over only gets &self
+    out: RefCell<BufWriter<fs::File>>,
+}
+
+impl FdProgressListener {
+    pub fn new(file: fs::File) -> Self {
+        FdProgressListener {
+            out: RefCell::new(BufWriter::new(file)),
+        }
+    }
+
+    fn event(&self, line: &str) {
+        // Progress is best-effort. The reader going away shouldn't stop the scan.
+        let mut out = self.out.borrow_mut();
+        out.write_all(line.as_bytes()).ok();
+        out.write_all(b"\n").ok();
+    }
+}
+
+impl FromRawFd for FdProgressListener {
+    /// Takes ownership of the fd
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        Self::new(fs::File::from_raw_fd(fd))
+    }
+}
+
+impl ScanListener for FdProgressListener {
+    fn file_scanned(&mut self, path: &Path, stats: &Stats) {
+        self.event(&format!("scanned\t{}\t{}\t{}\t{}", stats.added, stats.skipped, stats.dupes, escape(path)));
+    }
+
+    fn scan_over(&self, _: &Scanner, stats: &Stats, scan_duration: Duration) {
+        self.event(&format!("done\t{}\t{}\t{}\t{}\t{}", stats.added, stats.skipped, stats.dupes, stats.existing_hardlinks, scan_duration.as_millis()));
+        self.out.borrow_mut().flush().ok();
+    }
+
+    fn hardlinked(&mut self, src: &Path, dst: &Path) {
+        self.event(&format!("hardlinked\t{}\t{}", escape(src), escape(dst)));
+    }
+
+    fn duplicate_found(&mut self, src: &Path, dst: &Path) {
+        self.event(&format!("duplicate\t{}\t{}", escape(src), escape(dst)));
+    }
+
+    fn read_timeout(&mut self, path: &Path) {
+        self.event(&format!("timeout\t{}", escape(path)));
+    }
+
+    fn special_file(&mut self, path: &Path, kind: SpecialFileKind) {
+        self.event(&format!("special\t{:?}\t{}", kind, escape(path)));
+    }
+
+    fn content_indexed(&mut self, path: &Path, size: u64, sha256: &[u8; 32]) {
+        self.event(&format!("indexed\t{}\t{}\t{}", size, sha256::hex(sha256), escape(path)));
+    }
+
+    fn orphan_temp_found(&mut self, path: &Path) {
+        self.event(&format!("orphan\t{}", escape(path)));
+    }
+
+    fn dupe_skipped(&mut self, path: &Path, reason: GuardReason) {
+        self.event(&format!("dupe-skipped\t{:?}\t{}", reason, escape(path)));
+    }
+
+    fn bytes_read_update(&mut self, total_bytes_read: u64, eta: Option<Duration>) {
+        match eta {
+            Some(eta) => self.event(&format!("bytes\t{}\t{}", total_bytes_read, eta.as_millis())),
+            None => self.event(&format!("bytes\t{}", total_bytes_read)),
+        }
+    }
+
+    fn heartbeat(&mut self, elapsed: Duration, stats: &Stats) {
+        self.event(&format!("heartbeat\t{}\t{}\t{}", elapsed.as_millis(), stats.added, stats.dupes));
+    }
+
+    fn content_mismatch(&mut self, src: &Path, dst: &Path) {
+        self.event(&format!("mismatch\t{}\t{}", escape(src), escape(dst)));
+    }
+
+    fn slow_file(&mut self, path: &Path, elapsed: Duration) {
+        self.event(&format!("slow\t{}\t{}", elapsed.as_millis(), escape(path)));
+    }
+
+    fn scan_started(&mut self, total_files: Option<u64>) {
+        match total_files {
+            Some(total_files) => self.event(&format!("started\t{}", total_files)),
+            None => self.event("started"),
+        }
+    }
+
+    fn integrity_failure(&mut self, path: &Path, expected: &[u8; 32]) {
+        self.event(&format!("integrity\t{}\t{}", sha256::hex(expected), escape(path)));
+    }
+
+    fn permission_mismatch(&mut self, a: &Path, b: &Path) {
+        self.event(&format!("permissions\t{}\t{}", escape(a), escape(b)));
+    }
+
+    fn scan_error(&mut self, path: &Path, err: &io::Error) {
+        self.event(&format!("error\t{:?}\t{}", err.kind(), escape(path)));
+    }
+
+    fn corruption_detected(&mut self, path: &Path) {
+        self.event(&format!("corruption\t{}", escape(path)));
+    }
+
+    fn reflinked(&mut self, src: &Path, dst: &Path) {
+        self.event(&format!("reflinked\t{}\t{}", escape(src), escape(dst)));
+    }
+
+    fn symlinked(&mut self, src: &Path, dst: &Path) {
+        self.event(&format!("symlinked\t{}\t{}", escape(src), escape(dst)));
+    }
+
+    fn quarantined(&mut self, src: &Path, dst: &Path) {
+        self.event(&format!("quarantined\t{}\t{}", escape(src), escape(dst)));
+    }
+
+    fn self_link_skipped(&mut self, path: &Path) {
+        self.event(&format!("self-link\t{}", escape(path)));
+    }
+
+    fn sparse_dupe_warning(&mut self, path: &Path) {
+        self.event(&format!("sparse\t{}", escape(path)));
+    }
+
+    fn cross_device_dupe(&mut self, a: &Path, b: &Path) {
+        self.event(&format!("cross-device\t{}\t{}", escape(a), escape(b)));
+    }
+
+    fn plan_skipped(&mut self, src: &Path, dst: &Path) {
+        self.event(&format!("plan-skipped\t{}\t{}", escape(src), escape(dst)));
+    }
+
+    fn link_anomaly(&mut self, src: &Path, dst: &Path) {
+        self.event(&format!("anomaly\t{}\t{}", escape(src), escape(dst)));
+    }
+}
+
+fn escape(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n")
+}
+
+#[test]
+fn escape_test() {
+    assert_eq!(escape(Path::new("a\tb\nc\\d")), "a\\tb\\nc\\\\d");
+}
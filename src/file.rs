@@ -1,38 +1,218 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::cmp::Ordering;
 use std::cmp::max;
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
 use std::io;
+use std::fmt;
+use shared::Shared;
+use std::time::{Duration, Instant, SystemTime};
 use metadata::Metadata;
-use hasher::Hasher;
+use hasher::{full_digest, Hasher};
+use sha256;
+use sys;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
 
 #[derive(Debug, Clone)]
 #[cfg_attr(feature = "json",derive(Serialize))]
 pub struct FileSet {
     /// Tracks number of hardlinks from stat to also count unseen links outside scanned dirs
     pub max_hardlinks: u64,
-    pub paths: Vec<PathBuf>
+    pub paths: Vec<PathBuf>,
+    /// Paths found, but not in `paths`, because of `Settings::max_tracked_paths_per_inode`
+    pub untracked_paths: u64,
+    /// Birth time of the inode, where the OS and filesystem have it.
+    /// On Linux it comes from `statx`, which `std` uses (when the kernel has it) for the same single stat call.
+    pub created: Option<SystemTime>,
+    /// Modification time of the inode, for `Settings::master_by_creation_time` where there's no birth time
+    pub modified: Option<SystemTime>,
 }
 
 impl FileSet {
     pub fn new(path: PathBuf, max_hardlinks: u64) -> Self {
         FileSet {
-            max_hardlinks: max_hardlinks,
+            max_hardlinks,
             paths: vec![path],
+            untracked_paths: 0,
+            created: None,
+            modified: None,
         }
     }
 
     pub fn push(&mut self, path: PathBuf, max_hardlinks: u64) {
+        self.push_limited(path, max_hardlinks, None);
+    }
+
+    /// Like `push`, but once there are `limit` paths, further ones are only counted in `untracked_paths`
+    pub fn push_limited(&mut self, path: PathBuf, max_hardlinks: u64, limit: Option<usize>) {
         self.max_hardlinks = max(self.max_hardlinks, max_hardlinks);
-        self.paths.push(path);
+        if limit.is_some_and(|limit| self.paths.len() >= limit) {
+            self.untracked_paths += 1;
+        } else {
+            self.paths.push(path);
+        }
     }
 
     /// Number of known hardlinks to this file content
     pub fn links(&self) -> u64 {
-        return max(self.max_hardlinks, self.paths.len() as u64)
+        max(self.max_hardlinks, self.paths.len() as u64 + self.untracked_paths)
+    }
+}
+
+
+pub const DEFAULT_MAX_READ_SIZE: u64 = 128*1024*1024;
+
+/// See `Settings::content_normalizer`
+#[cfg(not(feature = "sync"))]
+pub type ContentNormalizer = Shared<dyn Fn(&[u8]) -> Vec<u8>>;
+/// See `Settings::content_normalizer`
+#[cfg(feature = "sync")]
+pub type ContentNormalizer = Shared<dyn Fn(&[u8]) -> Vec<u8> + Send + Sync>;
+
+/// How files are read for comparisons. Shared by all `FileContent`s of a scan.
+pub struct ContentOptions {
+    pub read_timeout: Option<Duration>,
+    /// Compare ends of files first. Cached hashes depend on it, so it can't change for existing `FileContent`s.
+    pub compare_from_tail: bool,
+    /// Compare the last 4KB (or the first, from the tail) right after the first 4KB, see `Settings::quick_check`.
+    /// Cached hashes depend on it, so it can't change for existing `FileContent`s.
+    pub quick_check: bool,
+    /// Files that couldn't be read within `read_timeout`.
+    /// Comparisons happen deep inside `BTreeMap`, so that's the only way to report them.
+    pub timed_out: Mutex<Vec<PathBuf>>,
+    /// Files whose reads took at least this long in total are added to `slow`
+    pub slow_file_threshold: Option<Duration>,
+    /// Slow files, with the time spent reading them when they got over the threshold.
+    /// Reported the same way as `timed_out`.
+    pub slow: Mutex<Vec<(PathBuf, Duration)>>,
+    /// Bytes read for comparisons since the scanner last took them
+    pub bytes_read: AtomicU64,
+    /// Compare whole-file hashes (possibly known from `ContentDigest`s) instead of ranges.
+    /// It can't be mixed with range comparisons, since they order files differently.
+    pub use_digests: bool,
+    /// Largest chunk read at once. Chunks grow up to it, so that identical files are read in big sequential reads.
+    pub max_read_size: u64,
+    /// Files are compared by hashes of their normalized content, and only by device, not size
+    pub normalizer: Option<ContentNormalizer>,
+    /// Files are only equal to files in the same directory (see `Settings::bucket_by_parent`).
+    /// It changes the order, so it can't change for existing `FileContent`s.
+    pub bucket_by_parent: bool,
+    /// Files at least this big are mapped into memory instead of read
+    pub mmap_threshold: Option<u64>,
+    /// Ranges get a SHA-256 as well, and are equal only if both hashes are.
+    /// Cached hashes depend on it, so it can't change for existing `FileContent`s.
+    pub double_hash: bool,
+    /// Store hashes of whole files computed for comparisons in this extended attribute (see `Settings::write_hash_xattr`)
+    pub write_xattr: Option<String>,
+    /// Count comparisons in `comparisons` and `byte_comparisons`
+    pub count_comparisons: bool,
+    /// Comparisons since the scanner last took them
+    pub comparisons: AtomicU64,
+    /// Comparisons that had to read files, since the scanner last took them
+    pub byte_comparisons: AtomicU64,
+}
+
+impl fmt::Debug for ContentOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ContentOptions")
+            .field("read_timeout", &self.read_timeout)
+            .field("compare_from_tail", &self.compare_from_tail)
+            .field("quick_check", &self.quick_check)
+            .field("timed_out", &self.timed_out)
+            .field("slow_file_threshold", &self.slow_file_threshold)
+            .field("slow", &self.slow)
+            .field("bytes_read", &self.bytes_read)
+            .field("use_digests", &self.use_digests)
+            .field("max_read_size", &self.max_read_size)
+            .field("normalizer", &self.normalizer.as_ref().map(|_| "<callback>"))
+            .field("bucket_by_parent", &self.bucket_by_parent)
+            .field("mmap_threshold", &self.mmap_threshold)
+            .field("double_hash", &self.double_hash)
+            .field("write_xattr", &self.write_xattr)
+            .field("count_comparisons", &self.count_comparisons)
+            .field("comparisons", &self.comparisons)
+            .field("byte_comparisons", &self.byte_comparisons)
+            .finish()
     }
 }
 
+impl Default for ContentOptions {
+    fn default() -> Self {
+        ContentOptions {
+            read_timeout: None,
+            compare_from_tail: false,
+            quick_check: true,
+            timed_out: Mutex::new(Vec::new()),
+            slow_file_threshold: None,
+            slow: Mutex::new(Vec::new()),
+            bytes_read: AtomicU64::new(0),
+            use_digests: false,
+            max_read_size: DEFAULT_MAX_READ_SIZE,
+            normalizer: None,
+            bucket_by_parent: false,
+            mmap_threshold: None,
+            double_hash: false,
+            write_xattr: None,
+            count_comparisons: false,
+            comparisons: AtomicU64::new(0),
+            byte_comparisons: AtomicU64::new(0),
+        }
+    }
+}
+
+impl ContentOptions {
+    /// Adds the file to `slow` the first time its reads took longer than the threshold
+    fn note_slow(&self, hashes: &mut Hasher, path: &Path) {
+        if self.slow_file_threshold.is_some_and(|threshold| hashes.read_time >= threshold) && !hashes.reported_slow {
+            hashes.reported_slow = true;
+            self.slow.lock().unwrap().push((path.to_owned(), hashes.read_time));
+        }
+    }
+}
+
+/// Previously computed hash of a file's whole content. See `Scanner::with_digest_cache`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "json",derive(Serialize))]
+pub struct ContentDigest {
+    /// Modification time (seconds and nanoseconds) of the file when it was hashed
+    pub mtime: (i64, i64),
+    pub hash: [u8; 20],
+}
+
+impl ContentDigest {
+    /// Value for `Settings::hash_xattr`: `<mtime seconds>.<nanoseconds> <hex hash>`
+    pub fn to_xattr(&self) -> String {
+        format!("{}.{:09} {}", self.mtime.0, self.mtime.1, sha256::hex(&self.hash))
+    }
+
+    /// Parses a value written by `to_xattr`
+    pub fn from_xattr(value: &[u8]) -> Option<Self> {
+        let value = ::std::str::from_utf8(value).ok()?;
+        let (mtime, hex) = value.trim_end().split_once(' ')?;
+        let (secs, nsecs) = mtime.split_once('.')?;
+        if hex.len() != 40 {
+            return None;
+        }
+        let mut hash = [0; 20];
+        for (i, byte) in hash.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()?;
+        }
+        Some(ContentDigest {
+            mtime: (secs.parse().ok()?, nsecs.parse().ok()?),
+            hash,
+        })
+    }
+
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, io::Error> {
+        let path = path.as_ref();
+        let m = fs::symlink_metadata(path)?;
+        Ok(ContentDigest {
+            mtime: (m.mtime(), m.mtime_nsec()),
+            hash: full_digest(path, &AtomicU64::new(0), None)?,
+        })
+    }
+}
 
 #[derive(Debug)]
 /// File content is efficiently compared using this struct's PartialOrd implementation
@@ -41,6 +221,7 @@ pub struct FileContent {
     metadata: Metadata,
     /// Hashes of content, calculated incrementally
     hashes: Mutex<Hasher>,
+    options: Shared<ContentOptions>,
 }
 
 impl FileContent {
@@ -51,13 +232,41 @@ impl FileContent {
     }
 
     pub fn new<P: Into<PathBuf>>(path: P, metadata: Metadata) -> Self {
+        Self::with_options(path, metadata, Shared::new(ContentOptions::default()))
+    }
+
+    pub fn with_options<P: Into<PathBuf>>(path: P, metadata: Metadata, options: Shared<ContentOptions>) -> Self {
         let path = path.into();
         FileContent {
-            path: path,
-            metadata: metadata,
+            path,
+            metadata,
             hashes: Mutex::new(Hasher::new()),
+            options,
         }
     }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        self.metadata
+    }
+
+    /// Use a known hash of the content instead of reading the file. Only used with `ContentOptions::use_digests`.
+    pub fn set_digest(&self, hash: [u8; 20]) {
+        self.hashes.lock().unwrap().digest = Some(hash);
+    }
+
+    /// The hash of the whole content, if it was read or set already. Only computed with `ContentOptions::use_digests`.
+    pub fn digest(&self) -> Option<[u8; 20]> {
+        self.hashes.lock().unwrap().digest
+    }
+
+    /// The file couldn't be read in time, so it can't be a duplicate of anything
+    pub fn timed_out(&self) -> bool {
+        self.hashes.lock().unwrap().timed_out
+    }
 }
 
 impl Eq for FileContent {
@@ -76,25 +285,101 @@ impl Ord for FileContent {
 }
 
 /// That does the bulk of hasing and comparisons
+#[allow(clippy::non_canonical_partial_ord_impl)]
 impl PartialOrd for FileContent {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        if !self.options.count_comparisons {
+            return self.compare(other);
+        }
+        let bytes_before = self.options.bytes_read.load(AtomicOrdering::Relaxed);
+        let res = self.compare(other);
+        self.options.comparisons.fetch_add(1, AtomicOrdering::Relaxed);
+        if self.options.bytes_read.load(AtomicOrdering::Relaxed) != bytes_before {
+            self.options.byte_comparisons.fetch_add(1, AtomicOrdering::Relaxed);
+        }
+        res
+    }
+}
+
+impl FileContent {
+    fn compare(&self, other: &Self) -> Option<Ordering> {
         // Different file sizes mean they're obviously different.
         // Also different devices mean they're not the same as far as we're concerned
         // (since search is intended for hardlinking and hardlinking only works within the same device).
-        let cmp = self.metadata.cmp(&other.metadata);
+        // Normalization may change the size
+        let cmp = if self.options.normalizer.is_some() {
+            self.metadata.dev.cmp(&other.metadata.dev)
+        } else {
+            self.metadata.cmp(&other.metadata)
+        };
         if cmp != Ordering::Equal {
             return Some(cmp);
         }
+        if self.options.bucket_by_parent {
+            let cmp = self.path.parent().cmp(&other.path.parent());
+            if cmp != Ordering::Equal {
+                return Some(cmp);
+            }
+        }
 
         // Fast pointer comparison
-        if self as *const _ == other as *const _ {
+        if ::std::ptr::eq(self, other) {
             return Some(Ordering::Equal);
         }
 
         let mut hashes1 = self.hashes.lock().unwrap();
         let mut hashes2 = other.hashes.lock().unwrap();
 
-        hashes1.compare(&mut *hashes2, self.metadata.size, &self.path, &other.path).ok()
+        // Unreadable files are never equal to anything, but still need a stable order
+        if hashes1.timed_out || hashes2.timed_out {
+            return Some(self.path.cmp(&other.path));
+        }
+
+        if self.options.use_digests || self.options.normalizer.is_some() {
+            let normalizer = self.options.normalizer.as_ref();
+            let digest = |hashes: &mut Hasher, path: &Path| {
+                if let Some(digest) = hashes.digest {
+                    return Ok(digest);
+                }
+                // Taken before reading, so that a change while it's read makes the stored hash stale
+                let metadata = self.options.write_xattr.as_ref().and_then(|_| fs::symlink_metadata(path).ok());
+                let read_start = Instant::now();
+                let res = full_digest(path, &self.options.bytes_read, normalizer);
+                hashes.read_time += read_start.elapsed();
+                if let (Ok(hash), Some(name), Some(m), None) = (&res, &self.options.write_xattr, metadata, normalizer) {
+                    let digest = ContentDigest {mtime: (m.mtime(), m.mtime_nsec()), hash: *hash};
+                    // It's only a cache, e.g. read-only files simply don't get one
+                    let _ = sys::set_xattr(path, name, digest.to_xattr().as_bytes());
+                }
+                res
+            };
+            let digest1 = digest(&mut hashes1, &self.path);
+            let digest2 = digest(&mut hashes2, &other.path);
+            self.options.note_slow(&mut hashes1, &self.path);
+            self.options.note_slow(&mut hashes2, &other.path);
+            let (digest1, digest2) = (digest1.ok()?, digest2.ok()?);
+            hashes1.digest = Some(digest1);
+            hashes2.digest = Some(digest2);
+            return Some(digest1.cmp(&digest2));
+        }
+
+        let res = hashes1.compare(&mut hashes2, self.metadata.size, &self.path, &other.path, &self.options);
+        self.options.note_slow(&mut hashes1, &self.path);
+        self.options.note_slow(&mut hashes2, &other.path);
+        match res {
+            Ok(ord) => Some(ord),
+            Err(ref err) if err.kind() == io::ErrorKind::TimedOut => {
+                let mut timed_out = self.options.timed_out.lock().unwrap();
+                if hashes1.timed_out {
+                    timed_out.push(self.path.clone());
+                }
+                if hashes2.timed_out {
+                    timed_out.push(other.path.clone());
+                }
+                Some(self.path.cmp(&other.path))
+            },
+            Err(_) => None,
+        }
     }
 }
 
@@ -0,0 +1,44 @@
+//! The calls that change the tree while replacing duplicates, so that they can be swapped out,
+//! e.g. to inject failures in tests, or to work on something that isn't a local filesystem.
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::path::Path;
+use shared::MaybeSend;
+use sys;
+
+/// Every method defaults to the `std::fs` call (or the platform's, for clones and swaps) of the same name.
+/// See `Scanner::set_filesystem`.
+pub trait FileSystem : Debug + MaybeSend {
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        fs::hard_link(original, link)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn symlink(&self, original: &Path, link: &Path) -> io::Result<()> {
+        ::std::os::unix::fs::symlink(original, link)
+    }
+
+    /// A new file at `dest` sharing the extents of `source`. Fails if `dest` exists.
+    fn clone_file(&self, source: &fs::File, dest: &Path) -> io::Result<fs::File> {
+        sys::clone_file(source, dest)
+    }
+
+    /// Swaps the two paths in one step (`renameat2` with `RENAME_EXCHANGE`)
+    fn exchange(&self, a: &Path, b: &Path) -> io::Result<()> {
+        sys::exchange(a, b)
+    }
+}
+
+/// The local filesystem, the default
+#[derive(Debug, Default, Copy, Clone)]
+pub struct StdFileSystem;
+
+impl FileSystem for StdFileSystem {}
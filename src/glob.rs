@@ -0,0 +1,140 @@
+//! Shell-like patterns of paths, for `Scanner::add_exclude`
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// `*` is any part of a name, `**` any part of a path (including `/`), `?` one character of a name,
+/// `[a-z]`/`[!a-z]` one of (or none of) a set of characters, and `\` takes the next character literally.
+/// A pattern without `/` is matched against names, others against whole paths.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Glob {
+    tokens: Vec<Token>,
+    name_only: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Byte(u8),
+    AnyByte,
+    Star,
+    DoubleStar,
+    Class {negated: bool, ranges: Vec<(u8, u8)>},
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> io::Result<Self> {
+        let invalid = |why| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid pattern {:?}: {}", pattern, why));
+        if pattern.is_empty() {
+            return Err(invalid("it's empty"));
+        }
+        let bytes = pattern.as_bytes();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            let token = match bytes[i] {
+                b'*' if bytes.get(i + 1) == Some(&b'*') => {
+                    while bytes.get(i + 1) == Some(&b'*') {
+                        i += 1;
+                    }
+                    Token::DoubleStar
+                },
+                b'*' => Token::Star,
+                b'?' => Token::AnyByte,
+                b'\\' => {
+                    i += 1;
+                    Token::Byte(*bytes.get(i).ok_or_else(|| invalid("it ends with \\"))?)
+                },
+                b'[' => {
+                    let end = bytes[i + 1..].iter().skip(1).position(|&b| b == b']').ok_or_else(|| invalid("[ isn't closed"))? + i + 2;
+                    let mut set = &bytes[i + 1..end];
+                    let negated = set.first() == Some(&b'!');
+                    if negated {
+                        set = &set[1..];
+                    }
+                    let mut ranges = Vec::new();
+                    let mut j = 0;
+                    while j < set.len() {
+                        if set.get(j + 1) == Some(&b'-') && j + 2 < set.len() {
+                            ranges.push((set[j], set[j + 2]));
+                            j += 3;
+                        } else {
+                            ranges.push((set[j], set[j]));
+                            j += 1;
+                        }
+                    }
+                    i = end;
+                    Token::Class {negated, ranges}
+                },
+                b => Token::Byte(b),
+            };
+            tokens.push(token);
+            i += 1;
+        }
+        Ok(Glob {tokens, name_only: !bytes.contains(&b'/')})
+    }
+
+    /// Directories also match with a `/` at the end, so that `**/.git/**` excludes `.git` itself
+    pub fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        let subject = if self.name_only {
+            match path.file_name() {
+                Some(name) => name.as_bytes(),
+                None => return false,
+            }
+        } else {
+            path.as_os_str().as_bytes()
+        };
+        matches(&self.tokens, subject) || (is_dir && !self.name_only && matches(&self.tokens, &[subject, b"/"].concat()))
+    }
+}
+
+fn matches(tokens: &[Token], s: &[u8]) -> bool {
+    let (token, rest) = match tokens.split_first() {
+        Some(split) => split,
+        None => return s.is_empty(),
+    };
+    match *token {
+        Token::DoubleStar => (0..=s.len()).any(|i| matches(rest, &s[i..])),
+        Token::Star => {
+            for i in 0..=s.len() {
+                if matches(rest, &s[i..]) {
+                    return true;
+                }
+                if s.get(i) == Some(&b'/') {
+                    break;
+                }
+            }
+            false
+        },
+        _ => match s.split_first() {
+            // Only a literal `/` matches a `/`
+            Some((&c, s_rest)) => (c != b'/' || *token == Token::Byte(b'/')) && token_matches(token, c) && matches(rest, s_rest),
+            None => false,
+        },
+    }
+}
+
+fn token_matches(token: &Token, c: u8) -> bool {
+    match *token {
+        Token::Byte(b) => b == c,
+        Token::AnyByte => true,
+        Token::Class {negated, ref ranges} => ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != negated,
+        Token::Star | Token::DoubleStar => unreachable!(),
+    }
+}
+
+#[test]
+fn patterns() {
+    let glob = |pattern| Glob::new(pattern).unwrap();
+    assert!(glob("*.tmp").matches(Path::new("/home/a/b.tmp"), false));
+    assert!(!glob("*.tmp").matches(Path::new("/home/a.tmp/b"), false));
+    assert!(glob("**/.git/**").matches(Path::new("/home/a/.git"), true));
+    assert!(glob("**/.git/**").matches(Path::new("/home/a/.git/objects/ab"), false));
+    assert!(!glob("**/.git/**").matches(Path::new("/home/a/.github"), true));
+    assert!(glob("/home/*/cache").matches(Path::new("/home/a/cache"), true));
+    assert!(!glob("/home/*/cache").matches(Path::new("/home/a/b/cache"), true));
+    assert!(glob("file[0-9]?").matches(Path::new("/file1a"), false));
+    assert!(!glob("file[!0-9]").matches(Path::new("/file1"), false));
+    assert!(glob("\\*").matches(Path::new("/*"), false));
+    assert!(Glob::new("[abc").is_err());
+    assert!(Glob::new("").is_err());
+}
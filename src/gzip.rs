@@ -0,0 +1,71 @@
+//! Files that are the gzip of another file, see `Scanner::compressed_duplicates`
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+const MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Pairs of `(file, its gzip)` among `files`, which should have one path per distinct content.
+/// Candidates are found by the uncompressed size that gzip stores at the end (modulo 4GB),
+/// and only those are decompressed, with `gzip -dc`, up to `max_size` bytes.
+pub fn compressed_duplicates(files: Vec<(PathBuf, u64)>, max_size: u64) -> Vec<(PathBuf, PathBuf)> {
+    let mut by_size: HashMap<u32, Vec<&Path>> = HashMap::new();
+    for (path, size) in &files {
+        if *size <= max_size {
+            by_size.entry(*size as u32).or_default().push(path);
+        }
+    }
+
+    let mut pairs = Vec::new();
+    for (gz_path, _) in &files {
+        let candidates = match stored_size(gz_path) {
+            Ok(Some(size)) => by_size.get(&size).map_or(&[][..], |paths| &paths[..]),
+            _ => continue,
+        };
+        let candidates: Vec<_> = candidates.iter().filter(|&&path| path != gz_path.as_path()).collect();
+        if candidates.is_empty() {
+            continue;
+        }
+        let content = match decompress(gz_path, max_size) {
+            Ok(Some(content)) => content,
+            _ => continue,
+        };
+        for &&path in &candidates {
+            if fs::read(path).is_ok_and(|raw| raw == content) {
+                pairs.push((path.to_owned(), gz_path.clone()));
+            }
+        }
+    }
+    pairs.sort();
+    pairs
+}
+
+/// The uncompressed size (modulo 4GB) at the end of a gzip file, or `None` if it isn't one
+fn stored_size(path: &Path) -> io::Result<Option<u32>> {
+    let mut file = fs::File::open(path)?;
+    let mut magic = [0; 2];
+    if file.read_exact(&mut magic).is_err() || magic != MAGIC {
+        return Ok(None);
+    }
+    let mut size = [0; 4];
+    file.seek(SeekFrom::End(-4))?;
+    file.read_exact(&mut size)?;
+    Ok(Some(u32::from_le_bytes(size)))
+}
+
+/// The content, or `None` if it's bigger than `max_size` (e.g. a zip bomb) or not valid gzip
+fn decompress(path: &Path, max_size: u64) -> io::Result<Option<Vec<u8>>> {
+    let mut child = Command::new("gzip").arg("-dc").arg(path)
+        .stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::null())
+        .spawn()?;
+    let mut content = Vec::new();
+    child.stdout.take().expect("piped").take(max_size + 1).read_to_end(&mut content)?;
+    if content.len() as u64 > max_size {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Ok(None);
+    }
+    Ok(if child.wait()?.success() {Some(content)} else {None})
+}
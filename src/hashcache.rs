@@ -0,0 +1,124 @@
+//! Hashes of files of previous runs by inode, for `Scanner::load_cache` and `Scanner::save_cache`
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use file::ContentDigest;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    /// A path of the inode, to tell on save whether it's still there
+    path: PathBuf,
+    size: u64,
+    digest: ContentDigest,
+}
+
+#[derive(Debug, Default)]
+pub struct HashCache {
+    /// By `(dev, ino)`
+    entries: HashMap<(u64, u64), Entry>,
+    /// Files of this run by `(dev, ino)`, with their metadata when they were added, which is what
+    /// their hashes are saved with, so that a change while they're read makes the hash stale
+    scanned: HashMap<(u64, u64), (PathBuf, fs::Metadata)>,
+}
+
+impl HashCache {
+    /// Records are `<dev> <ino> <size> <mtime seconds>.<nanoseconds> <hex hash> <path>`, each ended by a NUL,
+    /// since paths may have newlines. A missing file is an empty cache, e.g. on the first run.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(HashCache::default()),
+            Err(err) => return Err(err),
+        };
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not a hash cache");
+        let mut cache = HashCache::default();
+        for record in BufReader::new(file).split(b'\0') {
+            let record = record?;
+            let mut fields = record.splitn(6, |&b| b == b' ');
+            let mut number = || -> io::Result<u64> {
+                fields.next().and_then(|n| ::std::str::from_utf8(n).ok()).and_then(|n| n.parse().ok()).ok_or_else(invalid)
+            };
+            let (dev, ino, size) = (number()?, number()?, number()?);
+            let (mtime, hash) = (fields.next().ok_or_else(invalid)?, fields.next().ok_or_else(invalid)?);
+            let digest = ContentDigest::from_xattr(&[mtime, b" ", hash].concat()).ok_or_else(invalid)?;
+            let path = PathBuf::from(OsStr::from_bytes(fields.next().ok_or_else(invalid)?));
+            cache.entries.insert((dev, ino), Entry {path, size, digest});
+        }
+        Ok(cache)
+    }
+
+    /// The hash of the file, if it was cached with the same size and mtime, otherwise it has to be read again
+    pub fn get(&self, metadata: &fs::Metadata) -> Option<[u8; 20]> {
+        self.entries.get(&(metadata.dev(), metadata.ino()))
+            .filter(|entry| entry.size == metadata.size() && entry.digest.mtime == (metadata.mtime(), metadata.mtime_nsec()))
+            .map(|entry| entry.digest.hash)
+    }
+
+    pub fn scanned(&mut self, path: &Path, metadata: &fs::Metadata) {
+        self.scanned.insert((metadata.dev(), metadata.ino()), (path.to_owned(), metadata.clone()));
+    }
+
+    /// Sets the hash of a file of this run, if it was added
+    pub fn insert(&mut self, dev: u64, ino: u64, hash: [u8; 20]) {
+        if let Some((path, m)) = self.scanned.get(&(dev, ino)) {
+            let digest = ContentDigest {mtime: (m.mtime(), m.mtime_nsec()), hash};
+            self.entries.insert((dev, ino), Entry {path: path.clone(), size: m.size(), digest});
+        }
+    }
+
+    /// Writes the cache, replacing the file only once it's complete.
+    /// Entries of files that are gone or changed (by size or mtime) are left out.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut temp_name = path.file_name().unwrap_or_default().to_owned();
+        temp_name.push(".tmp");
+        let temp_path = path.with_file_name(temp_name);
+        let mut file = BufWriter::new(fs::File::create(&temp_path)?);
+        for (&(dev, ino), entry) in &self.entries {
+            let current = match fs::symlink_metadata(&entry.path) {
+                Ok(m) => m,
+                Err(_) => continue,
+            };
+            if (current.dev(), current.ino()) != (dev, ino) || self.get(&current).is_none() {
+                continue;
+            }
+            write!(file, "{} {} {} {} ", dev, ino, entry.size, entry.digest.to_xattr())?;
+            file.write_all(entry.path.as_os_str().as_bytes())?;
+            file.write_all(b"\0")?;
+        }
+        file.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+        fs::rename(&temp_path, path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let dir = tempdir::TempDir::new("hashcachetest").unwrap();
+        let cache_path = dir.path().join("cache");
+        let (a, b) = (dir.path().join("a\nb c"), dir.path().join("b"));
+        fs::write(&a, "dupe").unwrap();
+        fs::write(&b, "dupe").unwrap();
+        let mut cache = HashCache::load(&cache_path).unwrap();
+        for path in &[&a, &b] {
+            let m = fs::metadata(path).unwrap();
+            cache.scanned(path, &m);
+            cache.insert(m.dev(), m.ino(), [7; 20]);
+        }
+        fs::remove_file(&b).unwrap();
+        cache.save(&cache_path).unwrap();
+
+        let cache = HashCache::load(&cache_path).unwrap();
+        assert_eq!(cache.entries.len(), 1);
+        assert_eq!(cache.get(&fs::metadata(&a).unwrap()), Some([7; 20]));
+        fs::write(&a, "changed").unwrap();
+        assert_eq!(cache.get(&fs::metadata(&a).unwrap()), None);
+    }
+}
@@ -1,38 +1,127 @@
 use sha1::Sha1;
+use sha256::Sha256;
 use std::cmp::{min, Ordering};
+use std::fs;
 use std::io;
 use std::io::{Read,Seek,SeekFrom};
 use std::path::Path;
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::thread;
+use std::time::{Duration, Instant};
 use lazyfile::LazyFile;
+use sys;
+use file::{ContentNormalizer, ContentOptions};
 
 /// A hashed chunk of data of arbitrary size. Files are compared a bit by bit.
 #[derive(Debug, PartialOrd, Eq, PartialEq, Ord)]
 struct HashedRange {
     hash: [u8; 20],
+    /// Second opinion with `ContentOptions::double_hash`
+    sha256: Option<[u8; 32]>,
     size: u64,
 }
 
 impl HashedRange {
-    pub fn from_file(file: &mut LazyFile, start: u64, size: u64) -> Result<Self, io::Error> {
-        let mut fd = file.fd()?;
-        let mut data = vec![0; size as usize];
-        fd.seek(SeekFrom::Start(start))?;
-        fd.read_exact(&mut data)?;
-        let mut sha1 = Sha1::new();
-        // So the shattered PDFs don't dedupe
-        sha1.update(b"ISpent$75KToCollideWithThisStringAndAllIGotWasADeletedFile");
+    pub fn from_file(file: &mut LazyFile, start: u64, size: u64, timeout: Option<Duration>, mmap: bool, double_hash: bool) -> Result<Self, io::Error> {
+        let fd = file.fd()?;
+        let mut sha1 = salted_sha1();
+        let mut sha256 = if double_hash {Some(Sha256::new())} else {None};
+        // Page faults can hang too, so timeouts need the thread
+        if mmap && timeout.is_none() {
+            sys::with_mapped_range(fd, start, size, |data| {
+                sha1.update(data);
+                if let Some(ref mut sha256) = sha256 {
+                    sha256.update(data);
+                }
+            })?;
+            return Ok(HashedRange {
+                hash: sha1.digest().bytes(),
+                sha256: sha256.map(Sha256::finish),
+                size,
+            });
+        }
+
+        let data = match timeout {
+            None => read_range(fd, start, size)?,
+            Some(timeout) => {
+                // A read from a bad sector or a dead network mount can block forever,
+                // so it's done on a watchdog-ed thread, which is abandoned if it gets stuck.
+                let mut fd = fd.try_clone()?;
+                let (tx, rx) = mpsc::channel();
+                thread::spawn(move || {
+                    tx.send(read_range(&mut fd, start, size)).ok();
+                });
+                match rx.recv_timeout(timeout) {
+                    Ok(data) => data?,
+                    Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "file read timed out")),
+                }
+            },
+        };
         sha1.update(&data);
+        if let Some(ref mut sha256) = sha256 {
+            sha256.update(&data);
+        }
 
         Ok(HashedRange {
             hash: sha1.digest().bytes(),
-            size: size,
+            sha256: sha256.map(Sha256::finish),
+            size,
         })
     }
 }
 
+fn salted_sha1() -> Sha1 {
+    let mut sha1 = Sha1::new();
+    // So the shattered PDFs don't dedupe
+    sha1.update(b"ISpent$75KToCollideWithThisStringAndAllIGotWasADeletedFile");
+    sha1
+}
+
+/// Hash of the whole file, for comparing files with `ContentDigest`s, or of its normalized content
+pub fn full_digest(path: &Path, bytes_read: &AtomicU64, normalizer: Option<&ContentNormalizer>) -> Result<[u8; 20], io::Error> {
+    let mut file = fs::File::open(path)?;
+    let mut sha1 = salted_sha1();
+    if let Some(normalizer) = normalizer {
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        bytes_read.fetch_add(data.len() as u64, AtomicOrdering::Relaxed);
+        sha1.update(&normalizer(&data));
+        return Ok(sha1.digest().bytes());
+    }
+    let mut buf = vec![0; 1 << 20];
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        sha1.update(&buf[..len]);
+        bytes_read.fetch_add(len as u64, AtomicOrdering::Relaxed);
+    }
+    Ok(sha1.digest().bytes())
+}
+
+/// Size of the range at the other end of the file, see `ContentOptions::quick_check`
+const QUICK_CHECK_SIZE: u64 = 4096;
+
+fn read_range<R: Read + Seek>(fd: &mut R, start: u64, size: u64) -> Result<Vec<u8>, io::Error> {
+    let mut data = vec![0; size as usize];
+    fd.seek(SeekFrom::Start(start))?;
+    fd.read_exact(&mut data)?;
+    Ok(data)
+}
+
 #[derive(Debug)]
 pub struct Hasher {
     ranges: Vec<HashedRange>,
+    /// Reading of the file timed out, so its content is unknown
+    pub timed_out: bool,
+    /// Hash of the whole file, when compared by digests instead of ranges
+    pub digest: Option<[u8; 20]>,
+    /// Time spent reading the file so far
+    pub read_time: Duration,
+    /// It's been reported as slow already (see `ContentOptions::slow_file_threshold`)
+    pub reported_slow: bool,
 }
 
 /// Compares two files using hashes by hashing incrementally until the first difference is found
@@ -41,17 +130,34 @@ struct HashIter<'a> {
     pub start_offset: u64,
     pub end_offset: u64,
     next_buffer_size: u64,
+    timeout: Option<Duration>,
+    /// Ranges are taken from the end of the file backwards
+    from_tail: bool,
+    /// The second range is from the other end
+    quick_check: bool,
+    bytes_read: &'a AtomicU64,
+    max_read_size: u64,
+    /// Map the file instead of reading (see `Settings::use_mmap_threshold`)
+    mmap: bool,
+    double_hash: bool,
     a_file: LazyFile<'a>,
     b_file: LazyFile<'a>,
 }
 
 impl<'h> HashIter<'h> {
-    pub fn new(size: u64, a_path: &'h Path, b_path: &'h Path) -> Self {
+    pub fn new(size: u64, a_path: &'h Path, b_path: &'h Path, options: &'h ContentOptions) -> Self {
         HashIter {
             index: 0,
             start_offset: 0,
             end_offset: size,
             next_buffer_size: 4096,
+            timeout: options.read_timeout,
+            from_tail: options.compare_from_tail,
+            quick_check: options.quick_check,
+            bytes_read: &options.bytes_read,
+            max_read_size: options.max_read_size,
+            mmap: options.mmap_threshold.is_some_and(|threshold| size >= threshold),
+            double_hash: options.double_hash,
             a_file: LazyFile::new(a_path),
             b_file: LazyFile::new(b_path),
         }
@@ -74,36 +180,72 @@ impl<'h> HashIter<'h> {
             (a.is_none(), b.is_none(), size)
         };
 
+        // Same for all files of a size, so that ranges at the same index are always the same bytes.
+        // Fixed headers (or footers) of a format only differ from the other end.
+        let other_end = self.quick_check && i == 1 && self.end_offset - self.start_offset > QUICK_CHECK_SIZE;
+        let size = if other_end {QUICK_CHECK_SIZE} else {size};
+        let start = if self.from_tail != other_end {self.end_offset - size} else {self.start_offset};
+
         // If any of the ranges is missing, compute it
         if a_none {
-            a_hash.ranges.push(HashedRange::from_file(&mut self.a_file, self.start_offset, size)?);
+            Self::push_range(&mut self.a_file, a_hash, start, size, self.timeout, self.mmap, self.double_hash)?;
+            self.bytes_read.fetch_add(size, AtomicOrdering::Relaxed);
         }
         if b_none {
-            b_hash.ranges.push(HashedRange::from_file(&mut self.b_file, self.start_offset, size)?);
+            Self::push_range(&mut self.b_file, b_hash, start, size, self.timeout, self.mmap, self.double_hash)?;
+            self.bytes_read.fetch_add(size, AtomicOrdering::Relaxed);
         }
 
         self.index += 1;
-        self.start_offset += size;
+        if self.from_tail != other_end {
+            self.end_offset -= size;
+        } else {
+            self.start_offset += size;
+        }
         // The buffer size is a trade-off between finding a difference quickly
         // and reading files one by one without trashing.
         // Exponential increase is meant to be a compromise that allows finding
         // the difference in the first few KB, but grow quickly to read identical files faster.
-        self.next_buffer_size = min(size * 8, 128*1024*1024);
+        if !other_end {
+            self.next_buffer_size = min(size * 8, self.max_read_size);
+        }
 
         Ok(Some((&a_hash.ranges[i], &b_hash.ranges[i])))
     }
+
+    fn push_range(file: &mut LazyFile, hash: &mut Hasher, start: u64, size: u64, timeout: Option<Duration>, mmap: bool, double_hash: bool) -> Result<(), io::Error> {
+        let read_start = Instant::now();
+        let res = HashedRange::from_file(file, start, size, timeout, mmap, double_hash);
+        hash.read_time += read_start.elapsed();
+        match res {
+            Ok(range) => {
+                hash.ranges.push(range);
+                Ok(())
+            },
+            Err(err) => {
+                if err.kind() == io::ErrorKind::TimedOut {
+                    hash.timed_out = true;
+                }
+                Err(err)
+            },
+        }
+    }
 }
 
 impl Hasher {
     pub fn new() -> Self {
         Hasher {
             ranges: Vec::new(),
+            timed_out: false,
+            digest: None,
+            read_time: Duration::ZERO,
+            reported_slow: false,
         }
     }
 
     /// Incremental comparison reading files lazily
-    pub fn compare(&mut self, other: &mut Hasher, size: u64, self_path: &Path, other_path: &Path) -> Result<Ordering,io::Error> {
-        let mut iter = HashIter::new(size, self_path, other_path);
+    pub fn compare(&mut self, other: &mut Hasher, size: u64, self_path: &Path, other_path: &Path, options: &ContentOptions) -> Result<Ordering,io::Error> {
+        let mut iter = HashIter::new(size, self_path, other_path, options);
 
         while let Some((a,b)) = iter.next(self, other)? {
             let ord = a.cmp(b);
@@ -125,14 +267,22 @@ mod test {
     fn range_sha() {
         let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
         let path = &tmp.path().join("a");
-        file::put_text(&path, "aaa\n").expect("write");
-        let mut file = LazyFile::new(&path);
-        let hashed = HashedRange::from_file(&mut file, 0, 4).expect("hash");
+        file::put_text(path, "aaa\n").expect("write");
+        let mut file = LazyFile::new(path);
+        let hashed = HashedRange::from_file(&mut file, 0, 4, None, false, false).expect("hash");
 
         assert_eq!(4, hashed.size);
         assert_eq!([199,31,32,178,46,189,89,221,26,72,162,140,182,69,43,154,40,195,32,163], hashed.hash);
 
-        let hashed = HashedRange::from_file(&mut file, 1, 2).expect("hash2");
+        let hashed = HashedRange::from_file(&mut file, 1, 2, Some(Duration::from_secs(10)), false, false).expect("hash2");
         assert_eq!(2, hashed.size);
+
+        let mapped = HashedRange::from_file(&mut file, 1, 2, None, true, false).expect("hash3");
+        assert_eq!(hashed, mapped);
+        assert!(HashedRange::from_file(&mut file, 1, 4, None, true, false).is_err());
+
+        let double = HashedRange::from_file(&mut file, 0, 4, None, false, true).expect("hash4");
+        assert_eq!(double.hash, HashedRange::from_file(&mut file, 0, 4, None, false, false).unwrap().hash);
+        assert_eq!(double.sha256.map(|hash| ::sha256::hex(&hash)), Some("17e682f060b5f8e47ea04c5c4855908b0a5ad612022260fe50e11ecb0cc0ab76".into()));
     }
 }
@@ -0,0 +1,115 @@
+//! Directory mtimes of the previous run, for `Scanner::incremental_scan`
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Default)]
+pub struct DirMtimes {
+    /// Of the previous run
+    old: HashMap<PathBuf, SystemTime>,
+    /// Subdirectories of each directory of the previous run
+    children: HashMap<PathBuf, Vec<PathBuf>>,
+    /// Of this run, so far
+    new: HashMap<PathBuf, SystemTime>,
+}
+
+impl DirMtimes {
+    /// Records are `<seconds>.<nanoseconds> <path>`, each ended by a NUL, since paths may have newlines.
+    /// A missing file is an empty snapshot, e.g. on the first run.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(DirMtimes::default()),
+            Err(err) => return Err(err),
+        };
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not a directory snapshot");
+        let mut snapshot = DirMtimes::default();
+        for record in BufReader::new(file).split(b'\0') {
+            let record = record?;
+            let space = record.iter().position(|&b| b == b' ').ok_or_else(invalid)?;
+            let mtime = ::std::str::from_utf8(&record[..space]).map_err(|_| invalid())?;
+            let (secs, nanos) = mtime.split_once('.').ok_or_else(invalid)?;
+            let since_epoch = Duration::new(secs.parse().map_err(|_| invalid())?, nanos.parse().map_err(|_| invalid())?);
+            let dir = PathBuf::from(OsStr::from_bytes(&record[space + 1..]));
+            if let Some(parent) = dir.parent() {
+                snapshot.children.entry(parent.to_owned()).or_default().push(dir.clone());
+            }
+            snapshot.old.insert(dir, UNIX_EPOCH + since_epoch);
+        }
+        Ok(snapshot)
+    }
+
+    /// Takes the mtimes of the directories files were linked into again, since linking changes them.
+    /// Those that are gone are left out. The others keep the mtime from before they were listed, so that
+    /// changes made by others while they were scanned aren't taken as seen.
+    pub fn refresh(&mut self, linked_dirs: &HashSet<PathBuf>) {
+        self.new.retain(|dir, mtime| {
+            if !linked_dirs.contains(dir) {
+                return true;
+            }
+            match fs::metadata(dir).and_then(|m| m.modified()) {
+                Ok(now) => {
+                    *mtime = now;
+                    true
+                },
+                Err(_) => false,
+            }
+        });
+    }
+
+    /// Writes the mtimes of this run, replacing the file only once it's complete
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut temp_name = path.file_name().unwrap_or_default().to_owned();
+        temp_name.push(".tmp");
+        let temp_path = path.with_file_name(temp_name);
+        let mut file = BufWriter::new(fs::File::create(&temp_path)?);
+        for (dir, mtime) in &self.new {
+            let since_epoch = mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+            write!(file, "{}.{:09} ", since_epoch.as_secs(), since_epoch.subsec_nanos())?;
+            file.write_all(dir.as_os_str().as_bytes())?;
+            file.write_all(b"\0")?;
+        }
+        file.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+        fs::rename(&temp_path, path)
+    }
+
+    /// Records the directory's mtime, taken before it's listed, and returns its subdirectories of the previous run
+    /// if it's unchanged since then
+    pub fn visit(&mut self, dir: &Path, mtime: SystemTime) -> Option<&[PathBuf]> {
+        self.new.insert(dir.to_owned(), mtime);
+        if self.old.get(dir) != Some(&mtime) {
+            return None;
+        }
+        Some(self.children.get(dir).map_or(&[], |children| &children[..]))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let dir = tempdir::TempDir::new("mtimestest").unwrap();
+        let snapshot_path = dir.path().join("snapshot");
+        let mtime = UNIX_EPOCH + Duration::new(1234, 5678);
+        let mut snapshot = DirMtimes::load(&snapshot_path).unwrap();
+        assert!(snapshot.visit(Path::new("/a"), mtime).is_none());
+        assert!(snapshot.visit(Path::new("/a/b\nc"), mtime).is_none());
+        // Only those linked into are taken again, and this one is gone
+        let gone = dir.path().join("gone");
+        assert!(snapshot.visit(&gone, mtime).is_none());
+        snapshot.refresh(&[gone.clone()].iter().cloned().collect());
+        snapshot.save(&snapshot_path).unwrap();
+
+        let mut snapshot = DirMtimes::load(&snapshot_path).unwrap();
+        assert_eq!(snapshot.visit(Path::new("/a"), mtime).unwrap(), &[PathBuf::from("/a/b\nc")]);
+        assert!(snapshot.visit(Path::new("/a/b\nc"), mtime + Duration::from_secs(1)).is_none());
+        assert!(snapshot.visit(&gone, mtime).is_none());
+    }
+}
@@ -0,0 +1,147 @@
+//! Records of link operations, for `Settings::journal` and `Scanner::recover_journal`
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use temp;
+
+/// Records are fields ended by a NUL each, since paths may have newlines:
+/// `I <id> <dev> <ino>`, dest path, source path, temporary path, and later `D <id>` or `F <id>` (done or failed).
+/// The intent is on disk before the file is replaced, the outcome isn't synced, since it can be told from the file.
+#[derive(Debug)]
+pub struct Journal {
+    file: fs::File,
+    next_id: u64,
+}
+
+/// What `Scanner::recover_journal` found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct JournalRecovery {
+    /// Operations with a completion record
+    pub completed: usize,
+    /// In flight at the crash, but the file had already been replaced
+    pub finished_in_flight: Vec<PathBuf>,
+    /// In flight at the crash, with the file as it was before, as `(dest, source)`, so they can be linked again
+    pub not_started: Vec<(PathBuf, PathBuf)>,
+    /// Temporary files of operations in flight that were removed
+    pub temp_files_removed: usize,
+}
+
+impl Journal {
+    /// Appends to the journal, so that a previous one that wasn't recovered yet is kept
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+        // Ids only need to be unique after the last record of a previous run
+        let next_id = file.metadata()?.len();
+        Ok(Journal {file, next_id})
+    }
+
+    /// Writes and syncs the intent to replace `dest` (as it is now) with `source` by way of `temp`, returning its id
+    pub fn intent(&mut self, source: &Path, dest: &Path, temp: &Path, dest_metadata: &fs::Metadata) -> io::Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut record = format!("I {} {} {}\0", id, dest_metadata.dev(), dest_metadata.ino()).into_bytes();
+        record.extend_from_slice(dest.as_os_str().as_bytes());
+        record.push(0);
+        record.extend_from_slice(source.as_os_str().as_bytes());
+        record.push(0);
+        record.extend_from_slice(temp.as_os_str().as_bytes());
+        record.push(0);
+        self.file.write_all(&record)?;
+        self.file.sync_data()?;
+        Ok(id)
+    }
+
+    pub fn finish(&mut self, id: u64, done: bool) -> io::Result<()> {
+        self.file.write_all(format!("{} {}\0", if done {"D"} else {"F"}, id).as_bytes())
+    }
+}
+
+/// Reads the journal, and for operations that have neither a completion nor a failure record tells from the dest's inode
+/// whether the file was replaced, removing its temporary file if it's left over and only another link of something.
+/// A record cut off by the crash is ignored, since its file wasn't touched yet.
+pub fn recover(path: &Path) -> io::Result<JournalRecovery> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not a link journal");
+    let mut fields = BufReader::new(fs::File::open(path)?).split(b'\0');
+    // by id: dest, source, temporary file and the dest's (dev, ino) before
+    let mut in_flight = BTreeMap::new();
+    let mut recovery = JournalRecovery::default();
+    while let Some(field) = fields.next() {
+        let field = field?;
+        let header = ::std::str::from_utf8(&field).map_err(|_| invalid())?;
+        let mut parts = header.split(' ');
+        let kind = parts.next().ok_or_else(invalid)?;
+        let mut number = || -> io::Result<u64> { parts.next().and_then(|n| n.parse().ok()).ok_or_else(invalid) };
+        match kind {
+            "I" => {
+                let (id, dev, ino) = (number()?, number()?, number()?);
+                let (dest, source, temp_path) = match (fields.next(), fields.next(), fields.next()) {
+                    (Some(dest), Some(source), Some(temp_path)) => (dest?, source?, temp_path?),
+                    _ => break,
+                };
+                let path = |bytes: &[u8]| PathBuf::from(OsStr::from_bytes(bytes));
+                in_flight.insert(id, (path(&dest), path(&source), path(&temp_path), (dev, ino)));
+            },
+            "D" | "F" => {
+                let id = number()?;
+                if in_flight.remove(&id).is_some() && kind == "D" {
+                    recovery.completed += 1;
+                }
+            },
+            _ => return Err(invalid()),
+        }
+    }
+
+    for (_, (dest, source, temp_path, before)) in in_flight {
+        // One without other links (e.g. swapped out) is left for whoever looks at it
+        if temp_path.exists() && temp::remove_stale(&temp_path).is_ok() {
+            recovery.temp_files_removed += 1;
+        }
+        // Every mode renames a new inode over the dest, so if the old one is still there, nothing happened
+        match fs::symlink_metadata(&dest) {
+            Ok(ref m) if (m.dev(), m.ino()) != before => recovery.finished_in_flight.push(dest),
+            _ => recovery.not_started.push((dest, source)),
+        }
+    }
+    Ok(recovery)
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+    use super::*;
+
+    #[test]
+    fn in_flight() {
+        let dir = tempdir::TempDir::new("journaltest").unwrap();
+        let journal_path = dir.path().join("journal");
+        let (a, b, c) = (dir.path().join("a"), dir.path().join("b"), dir.path().join("c"));
+        for path in &[&a, &b, &c] {
+            fs::write(path, "dupe").unwrap();
+        }
+        let mut journal = Journal::open(&journal_path).unwrap();
+        let temp = dir.path().join("temp");
+        let id = journal.intent(&a, &b, &temp, &fs::metadata(&b).unwrap()).unwrap();
+        journal.finish(id, true).unwrap();
+        // c was replaced without its completion record, b wasn't replaced yet but has a temporary file
+        journal.intent(&a, &c, &temp, &fs::metadata(&c).unwrap()).unwrap();
+        fs::write(&temp, "dupe").unwrap();
+        fs::rename(&temp, &c).unwrap();
+        fs::hard_link(&a, &temp).unwrap();
+        journal.intent(&a, &b, &temp, &fs::metadata(&b).unwrap()).unwrap();
+        journal.file.write_all(b"I 9 1 2\0/cut").unwrap();
+
+        let recovery = recover(&journal_path).unwrap();
+        assert_eq!(recovery, JournalRecovery {
+            completed: 1,
+            finished_in_flight: vec![c],
+            not_started: vec![(b, a)],
+            temp_files_removed: 1,
+        });
+        assert!(!temp.exists());
+    }
+}
@@ -2,7 +2,7 @@ use dupe::Stats;
 use dupe::ScanListener;
 use dupe::Scanner;
 use file::FileSet;
-use std::path::PathBuf;
+use plan::DryRunReport;
 use std::path::Path;
 use std::time::Duration;
 use serde_json;
@@ -10,6 +10,12 @@ use serde_json;
 #[derive(Debug)]
 pub struct JsonOutput;
 
+impl Default for JsonOutput {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl JsonOutput {
     pub fn new() -> Self {
         JsonOutput
@@ -17,7 +23,7 @@ impl JsonOutput {
 }
 
 impl ScanListener for JsonOutput {
-    fn file_scanned(&mut self, _: &PathBuf, _: &Stats) {
+    fn file_scanned(&mut self, _: &Path, _: &Stats) {
         // output only at scan_over
     }
     fn scan_over(&self, scanner: &Scanner, stats: &Stats, scan_duration: Duration) {
@@ -38,6 +44,8 @@ impl ScanListener for JsonOutput {
 struct JsonSerializable {
     creator: String,
     dupes: Vec<FileSet>,
+    /// Input for `Scanner::apply_plan`
+    plan: DryRunReport,
     stats: Stats,
     scan_duration: Duration,
 }
@@ -47,8 +55,9 @@ impl JsonSerializable {
         JsonSerializable {
             creator: format!("duplicate-kriller {}", env!("CARGO_PKG_VERSION")),
             dupes: scanner.dupes().into_iter().filter(|x| x.paths.len() > 1).collect(),
+            plan: scanner.dry_run_report().clone(),
             stats: *stats,
-            scan_duration: scan_duration,
+            scan_duration,
         }
     }
 }
\ No newline at end of file
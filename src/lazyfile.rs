@@ -12,7 +12,7 @@ pub struct LazyFile<'a> {
 impl<'a> LazyFile<'a> {
     pub fn new(path: &'a Path) -> Self {
         LazyFile {
-            path: path,
+            path,
             file: None,
         }
     }
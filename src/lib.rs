@@ -1,3 +1,4 @@
+extern crate libc;
 extern crate sha1;
 
 #[cfg(feature = "json")]
@@ -8,17 +9,89 @@ extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
 
+mod bloom;
+mod builder;
+#[cfg(feature = "json")]
+mod config;
+mod csv;
+mod dot;
 mod dupe;
+mod error;
+mod fdprogress;
 mod file;
+mod filesystem;
+mod glob;
+mod gzip;
+mod hashcache;
 mod hasher;
+mod incremental;
+mod journal;
 mod lazyfile;
 mod metadata;
+mod multi;
+mod near;
+mod plan;
+mod prefix;
+mod probe;
+mod rebase;
+mod script;
+mod sha256;
+mod shared;
+mod ssdeep;
+mod subtree;
+mod sys;
+mod tar;
+mod temp;
 mod ui;
+mod verify;
 #[cfg(feature = "json")]
 mod json;
+#[cfg(feature = "syslog")]
+mod syslog;
 
 pub use dupe::Scanner;
+pub use builder::ScannerBuilder;
+pub use dupe::Settings;
+pub use error::DupeError;
+pub use dupe::Stats;
+pub use dupe::SharedFileSet;
+pub use dupe::DigestCache;
+pub use dupe::OnImmutable;
+pub use dupe::CrossFsPolicy;
+pub use dupe::MtimePolicy;
+pub use dupe::SourcePolicy;
+pub use dupe::OnLinkAnomaly;
+pub use dupe::SpecialFileKind;
+pub use dupe::DedupeMode;
+pub use dupe::Confidence;
+pub use dupe::EqualityOracle;
+pub use dupe::PathPicker;
+pub use dupe::GuardReason;
+pub use dupe::{ExtensionStats, NO_EXTENSION};
+pub use dupe::WorklistItem;
+pub use dupe::DuplicateGroup;
+pub use dupe::SimulationResult;
+pub use dupe::PackGroup;
+pub use journal::JournalRecovery;
+pub use dupe::PreexistingStats;
+pub use dupe::ComparisonMetrics;
+pub use dupe::PARTIAL_DOWNLOAD_SUFFIXES;
+pub use dupe::NEVER_LINK_NAMES;
+pub use dupe::MAX_RESCAN_PASSES;
+pub use dupe::MIN_PACK_FILES;
+pub use dupe::PARANOID_MIN_AGE;
+pub use probe::FsCapabilities;
+pub use plan::{DryRunReport, PendingMerge, PlannedLink};
+pub use shared::MaybeSend;
 pub use file::FileContent;
+pub use file::ContentDigest;
+pub use file::ContentNormalizer;
+pub use file::FileSet;
+pub use filesystem::{FileSystem, StdFileSystem};
 pub use ui::UI as TextUserInterface;
+pub use fdprogress::FdProgressListener;
+pub use multi::MultiListener;
 #[cfg(feature = "json")]
 pub use json::JsonOutput as JsonOutput;
+#[cfg(feature = "syslog")]
+pub use syslog::SyslogListener;
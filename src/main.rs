@@ -39,6 +39,7 @@ fn main() {
     opts.optflag("h", "help", "This help text");
     opts.optflag("d", "dry-run", "Do not change anything on disk. Only print duplicates found");
     opts.optflag("s", "small", "Also dedupe small files (smaller than a disk block)");
+    opts.optopt("", "script", "Do not change anything on disk. Write a shell script that would dedupe to FILE", "FILE");
     opts.optopt("o", "output-mode", "How to show the results. Valid values are 'quiet', 'text' and 'json'. Default is 'text'", "MODE");
 
     let mut args = env::args();
@@ -60,7 +61,8 @@ fn main() {
     }
 
     let mut s = Scanner::new();
-    s.settings.dry_run = matches.opt_present("dry-run");
+    s.settings.script_output = matches.opt_str("script").map(PathBuf::from);
+    s.settings.dry_run = matches.opt_present("dry-run") || s.settings.script_output.is_some();
     s.settings.ignore_small = !matches.opt_present("small");
     match output_mode {
         OutputMode::Quiet => {
@@ -0,0 +1,226 @@
+//! Several listeners at once, see `MultiListener`
+use dupe::{GuardReason, ScanListener, Scanner, SpecialFileKind, Stats};
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// Passes every event on to each of its listeners, in the order they were pushed
+#[derive(Debug, Default)]
+pub struct MultiListener {
+    listeners: Vec<Box<dyn ScanListener>>,
+}
+
+impl MultiListener {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, listener: Box<dyn ScanListener>) {
+        self.listeners.push(listener);
+    }
+}
+
+impl ScanListener for MultiListener {
+    fn file_scanned(&mut self, path: &Path, stats: &Stats) {
+        for listener in &mut self.listeners {
+            listener.file_scanned(path, stats);
+        }
+    }
+
+    fn scan_over(&self, scanner: &Scanner, stats: &Stats, scan_duration: Duration) {
+        for listener in &self.listeners {
+            listener.scan_over(scanner, stats, scan_duration);
+        }
+    }
+
+    fn hardlinked(&mut self, src: &Path, dst: &Path) {
+        for listener in &mut self.listeners {
+            listener.hardlinked(src, dst);
+        }
+    }
+
+    fn duplicate_found(&mut self, src: &Path, dst: &Path) {
+        for listener in &mut self.listeners {
+            listener.duplicate_found(src, dst);
+        }
+    }
+
+    fn read_timeout(&mut self, path: &Path) {
+        for listener in &mut self.listeners {
+            listener.read_timeout(path);
+        }
+    }
+
+    fn bytes_read_update(&mut self, total_bytes_read: u64, eta: Option<Duration>) {
+        for listener in &mut self.listeners {
+            listener.bytes_read_update(total_bytes_read, eta);
+        }
+    }
+
+    fn link_anomaly(&mut self, src: &Path, dst: &Path) {
+        for listener in &mut self.listeners {
+            listener.link_anomaly(src, dst);
+        }
+    }
+
+    fn sparse_dupe_warning(&mut self, path: &Path) {
+        for listener in &mut self.listeners {
+            listener.sparse_dupe_warning(path);
+        }
+    }
+
+    fn self_link_skipped(&mut self, path: &Path) {
+        for listener in &mut self.listeners {
+            listener.self_link_skipped(path);
+        }
+    }
+
+    fn content_mismatch(&mut self, src: &Path, dst: &Path) {
+        for listener in &mut self.listeners {
+            listener.content_mismatch(src, dst);
+        }
+    }
+
+    fn corruption_detected(&mut self, path: &Path) {
+        for listener in &mut self.listeners {
+            listener.corruption_detected(path);
+        }
+    }
+
+    fn plan_skipped(&mut self, src: &Path, dst: &Path) {
+        for listener in &mut self.listeners {
+            listener.plan_skipped(src, dst);
+        }
+    }
+
+    fn reflinked(&mut self, src: &Path, dst: &Path) {
+        for listener in &mut self.listeners {
+            listener.reflinked(src, dst);
+        }
+    }
+
+    fn symlinked(&mut self, src: &Path, dst: &Path) {
+        for listener in &mut self.listeners {
+            listener.symlinked(src, dst);
+        }
+    }
+
+    fn quarantined(&mut self, src: &Path, dst: &Path) {
+        for listener in &mut self.listeners {
+            listener.quarantined(src, dst);
+        }
+    }
+
+    fn dupe_skipped(&mut self, path: &Path, reason: GuardReason) {
+        for listener in &mut self.listeners {
+            listener.dupe_skipped(path, reason);
+        }
+    }
+
+    fn content_indexed(&mut self, path: &Path, size: u64, sha256: &[u8; 32]) {
+        for listener in &mut self.listeners {
+            listener.content_indexed(path, size, sha256);
+        }
+    }
+
+    fn orphan_temp_found(&mut self, path: &Path) {
+        for listener in &mut self.listeners {
+            listener.orphan_temp_found(path);
+        }
+    }
+
+    fn directory_scanned(&mut self, path: &Path, stats: &Stats) {
+        for listener in &mut self.listeners {
+            listener.directory_scanned(path, stats);
+        }
+    }
+
+    fn cross_device_dupe(&mut self, a: &Path, b: &Path) {
+        for listener in &mut self.listeners {
+            listener.cross_device_dupe(a, b);
+        }
+    }
+
+    fn special_file(&mut self, path: &Path, kind: SpecialFileKind) {
+        for listener in &mut self.listeners {
+            listener.special_file(path, kind);
+        }
+    }
+
+    fn heartbeat(&mut self, elapsed: Duration, stats: &Stats) {
+        for listener in &mut self.listeners {
+            listener.heartbeat(elapsed, stats);
+        }
+    }
+
+    fn slow_file(&mut self, path: &Path, elapsed: Duration) {
+        for listener in &mut self.listeners {
+            listener.slow_file(path, elapsed);
+        }
+    }
+
+    fn integrity_failure(&mut self, path: &Path, expected: &[u8; 32]) {
+        for listener in &mut self.listeners {
+            listener.integrity_failure(path, expected);
+        }
+    }
+
+    fn scan_error(&mut self, path: &Path, err: &io::Error) {
+        for listener in &mut self.listeners {
+            listener.scan_error(path, err);
+        }
+    }
+
+    fn permission_mismatch(&mut self, a: &Path, b: &Path) {
+        for listener in &mut self.listeners {
+            listener.permission_mismatch(a, b);
+        }
+    }
+
+    fn scan_started(&mut self, total_files: Option<u64>) {
+        for listener in &mut self.listeners {
+            listener.scan_started(total_files);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, Default)]
+    struct LinkListener(Arc<Mutex<Vec<PathBuf>>>);
+
+    impl ScanListener for LinkListener {
+        fn file_scanned(&mut self, _: &Path, _: &Stats) {}
+        fn scan_over(&self, _: &Scanner, _: &Stats, _: Duration) {}
+        fn hardlinked(&mut self, _: &Path, dst: &Path) {
+            self.0.lock().unwrap().push(dst.to_owned());
+        }
+        fn duplicate_found(&mut self, _: &Path, _: &Path) {}
+    }
+
+    #[test]
+    fn all_listeners() {
+        let dir = tempdir::TempDir::new("multitest").unwrap();
+        for name in &["a", "b"] {
+            fs::write(dir.path().join(name), "dupe").unwrap();
+        }
+        let (first, second) = (LinkListener::default(), LinkListener::default());
+        let (first_seen, second_seen) = (first.0.clone(), second.0.clone());
+        let mut listeners = MultiListener::new();
+        listeners.push(Box::new(first));
+        listeners.push(Box::new(second));
+
+        let mut d = Scanner::new();
+        d.settings.ignore_small = false;
+        d.set_listener(Box::new(listeners));
+        d.scan(dir.path()).unwrap();
+        assert_eq!(first_seen.lock().unwrap().len(), 1);
+        assert_eq!(*first_seen.lock().unwrap(), *second_seen.lock().unwrap());
+    }
+}
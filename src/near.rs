@@ -0,0 +1,104 @@
+use std::fs;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// How much of the beginning and the end of files is compared
+const EDGE_SIZE: u64 = 4096;
+
+/// Groups files of similar size that begin and end with the same bytes.
+///
+/// `files` should have one path per distinct content (identical files aren't near-duplicates, they're duplicates).
+pub fn near_duplicates(mut files: Vec<(PathBuf, u64)>, size_tolerance: u64) -> Vec<Vec<PathBuf>> {
+    files.sort_by_key(|&(_, size)| size);
+
+    let mut edges: Vec<Option<Option<Edges>>> = (0..files.len()).map(|_| None).collect();
+    let mut groups = UnionFind::new(files.len());
+    for i in 0..files.len() {
+        for j in i+1..files.len() {
+            if files[j].1 - files[i].1 > size_tolerance {
+                break;
+            }
+            let n = EDGE_SIZE.min(files[i].1) as usize;
+            if n == 0 {
+                continue;
+            }
+            let a = Edges::cached(&mut edges, &files, i);
+            let b = Edges::cached(&mut edges, &files, j);
+            if let (Some(a), Some(b)) = (a, b) {
+                if a.head[..n] == b.head[..n] && a.tail[a.tail.len()-n..] == b.tail[b.tail.len()-n..] {
+                    groups.union(i, j);
+                }
+            }
+        }
+    }
+
+    let mut by_root: Vec<Vec<PathBuf>> = (0..files.len()).map(|_| Vec::new()).collect();
+    for (i, (path, _)) in files.into_iter().enumerate() {
+        let root = groups.find(i);
+        by_root[root].push(path);
+    }
+    let mut result: Vec<_> = by_root.into_iter().filter(|g| g.len() > 1).collect();
+    for group in &mut result {
+        group.sort();
+    }
+    result.sort();
+    result
+}
+
+#[derive(Clone)]
+struct Edges {
+    head: Vec<u8>,
+    tail: Vec<u8>,
+}
+
+impl Edges {
+    /// Unreadable files are `None` and never match
+    fn cached(cache: &mut [Option<Option<Edges>>], files: &[(PathBuf, u64)], i: usize) -> Option<Edges> {
+        if cache[i].is_none() {
+            cache[i] = Some(Self::read(&files[i].0, files[i].1).ok());
+        }
+        cache[i].clone().unwrap_or(None)
+    }
+
+    fn read(path: &Path, size: u64) -> io::Result<Edges> {
+        let n = EDGE_SIZE.min(size) as usize;
+        let mut file = fs::File::open(path)?;
+        let mut head = vec![0; n];
+        file.read_exact(&mut head)?;
+        let mut tail = vec![0; n];
+        file.seek(SeekFrom::Start(size - n as u64))?;
+        file.read_exact(&mut tail)?;
+        Ok(Edges { head, tail })
+    }
+}
+
+struct UnionFind {
+    parent: Vec<usize>,
+}
+
+impl UnionFind {
+    fn new(n: usize) -> Self {
+        UnionFind { parent: (0..n).collect() }
+    }
+
+    fn find(&mut self, i: usize) -> usize {
+        let mut root = i;
+        while self.parent[root] != root {
+            root = self.parent[root];
+        }
+        let mut i = i;
+        while self.parent[i] != root {
+            let next = self.parent[i];
+            self.parent[i] = root;
+            i = next;
+        }
+        root
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let a = self.find(a);
+        let b = self.find(b);
+        self.parent[a] = b;
+    }
+}
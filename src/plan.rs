@@ -0,0 +1,26 @@
+use std::path::PathBuf;
+use dupe::Confidence;
+
+/// A link that dry run would have made: `dest` replaced with a hardlink to `source`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+pub struct PlannedLink {
+    pub source: PathBuf,
+    pub dest: PathBuf,
+    /// Of both files, when the plan was made
+    pub size: u64,
+    pub dev: u64,
+    /// How the files were compared. Older reports don't have it, and are taken as `Confidence::Hashes`.
+    #[cfg_attr(feature = "json", serde(default))]
+    pub confidence: Confidence,
+}
+
+/// A link proposed by dry run, to be approved or rejected with `Scanner::apply_decision`
+pub type PendingMerge = PlannedLink;
+
+/// Everything a dry run would have done, in order. See `Scanner::dry_run_report` and `Scanner::apply_plan`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+pub struct DryRunReport {
+    pub links: Vec<PlannedLink>,
+}
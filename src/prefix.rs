@@ -0,0 +1,43 @@
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use verify;
+
+/// How much of the beginning of files is compared before reading candidates in full
+const HEAD_SIZE: u64 = 4096;
+
+/// Pairs each file with the shortest longer file that begins with all of its content.
+///
+/// `files` should have one path per distinct content (identical files are duplicates, not prefixes).
+pub fn prefix_duplicates(files: Vec<(PathBuf, u64)>) -> Vec<(PathBuf, PathBuf)> {
+    // Unreadable files never match
+    let mut heads: Vec<(Vec<u8>, u64, PathBuf)> = files.into_iter()
+        .filter(|&(_, size)| size > 0)
+        .filter_map(|(path, size)| Some((read_head(&path, size).ok()?, size, path)))
+        .collect();
+    // Heads starting with a file's head are right after it
+    heads.sort();
+
+    let mut pairs = Vec::new();
+    for (i, (head, size, path)) in heads.iter().enumerate() {
+        let mut candidates: Vec<_> = heads[i+1..].iter()
+            .take_while(|(other_head, _, _)| other_head.starts_with(head))
+            .filter(|&&(_, other_size, _)| other_size > *size)
+            .map(|(_, other_size, other_path)| (other_size, other_path))
+            .collect();
+        candidates.sort();
+        let longer = candidates.into_iter().find(|&(_, other_path)| verify::is_prefix(path, other_path).unwrap_or(false));
+        if let Some((_, other_path)) = longer {
+            pairs.push((path.clone(), other_path.clone()));
+        }
+    }
+    pairs.sort();
+    pairs
+}
+
+fn read_head(path: &Path, size: u64) -> io::Result<Vec<u8>> {
+    let mut head = vec![0; HEAD_SIZE.min(size) as usize];
+    fs::File::open(path)?.read_exact(&mut head)?;
+    Ok(head)
+}
@@ -0,0 +1,62 @@
+//! Checks whether a filesystem behaves the way deduping relies on
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process;
+use sys;
+
+/// What the filesystem of a directory turned out to support. See `Scanner::probe_filesystem`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FsCapabilities {
+    /// Hardlinks share the inode, and creating one never overwrites an existing file
+    pub supports_hardlink: bool,
+    /// Renaming over an existing file replaces it in one step, without disturbing other links of the replaced inode
+    pub atomic_rename: bool,
+    /// Copy-on-write clones, as on Btrfs and XFS (`FICLONE`) and APFS (`clonefile`)
+    pub supports_reflink: bool,
+}
+
+pub fn probe(dir: &Path) -> io::Result<FsCapabilities> {
+    let probe_dir = dir.join(format!(".tmp-dupe-probe-{}", process::id()));
+    fs::create_dir(&probe_dir)?;
+    let result = probe_in(&probe_dir);
+    fs::remove_dir_all(&probe_dir)?;
+    result
+}
+
+fn probe_in(dir: &Path) -> io::Result<FsCapabilities> {
+    let a = dir.join("a");
+    let b = dir.join("b");
+    let c = dir.join("c");
+    fs::File::create(&a)?.write_all(b"old")?;
+    fs::File::create(&c)?.write_all(b"new")?;
+
+    let supports_hardlink = fs::hard_link(&a, &b).is_ok() && {
+        let (a_meta, b_meta) = (fs::metadata(&a)?, fs::metadata(&b)?);
+        a_meta.ino() == b_meta.ino() && b_meta.nlink() == 2 && a_meta.nlink() == 2
+            // The temp link in `dedupe` is only safe if this fails
+            && fs::hard_link(&c, &b).is_err() && read(&b)? == b"old"
+    };
+
+    // Replaces one of the links, like `dedupe` does
+    let atomic_rename = supports_hardlink && fs::rename(&c, &b).is_ok()
+        && !c.exists() && read(&b)? == b"new" && read(&a)? == b"old"
+        && fs::metadata(&a)?.nlink() == 1;
+
+    let d = dir.join("d");
+    let supports_reflink = sys::clone_file(&fs::File::open(&a)?, &d).is_ok() && read(&d)? == b"old";
+
+    Ok(FsCapabilities {
+        supports_hardlink,
+        atomic_rename,
+        supports_reflink,
+    })
+}
+
+fn read(path: &Path) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    fs::File::open(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
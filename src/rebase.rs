@@ -0,0 +1,154 @@
+//! Paths in reports relative to `Settings::report_base`
+use dupe::{GuardReason, ScanListener, Scanner, SpecialFileKind, Stats};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The path relative to the base, or as it is outside of it. The base itself is `.`.
+pub fn relative<'a>(base: Option<&Path>, path: &'a Path) -> &'a Path {
+    match base.map(|base| path.strip_prefix(base)) {
+        Some(Ok(rel)) if rel.as_os_str().is_empty() => Path::new("."),
+        Some(Ok(rel)) => rel,
+        _ => path,
+    }
+}
+
+/// Passes events on to the scanner's listener, with paths relative to the base
+#[derive(Debug)]
+pub struct RebasedListener {
+    pub base: Option<PathBuf>,
+    pub inner: Box<dyn ScanListener>,
+}
+
+impl ScanListener for RebasedListener {
+    fn file_scanned(&mut self, path: &Path, stats: &Stats) {
+        let path = relative(self.base.as_deref(), path);
+        self.inner.file_scanned(path, stats);
+    }
+
+    fn scan_over(&self, scanner: &Scanner, stats: &Stats, scan_duration: Duration) {
+        self.inner.scan_over(scanner, stats, scan_duration);
+    }
+
+    fn hardlinked(&mut self, src: &Path, dst: &Path) {
+        let (src, dst) = (relative(self.base.as_deref(), src), relative(self.base.as_deref(), dst));
+        self.inner.hardlinked(src, dst);
+    }
+
+    fn duplicate_found(&mut self, src: &Path, dst: &Path) {
+        let (src, dst) = (relative(self.base.as_deref(), src), relative(self.base.as_deref(), dst));
+        self.inner.duplicate_found(src, dst);
+    }
+
+    fn read_timeout(&mut self, path: &Path) {
+        let path = relative(self.base.as_deref(), path);
+        self.inner.read_timeout(path);
+    }
+
+    fn bytes_read_update(&mut self, total_bytes_read: u64, eta: Option<Duration>) {
+        self.inner.bytes_read_update(total_bytes_read, eta);
+    }
+
+    fn link_anomaly(&mut self, src: &Path, dst: &Path) {
+        let (src, dst) = (relative(self.base.as_deref(), src), relative(self.base.as_deref(), dst));
+        self.inner.link_anomaly(src, dst);
+    }
+
+    fn sparse_dupe_warning(&mut self, path: &Path) {
+        let path = relative(self.base.as_deref(), path);
+        self.inner.sparse_dupe_warning(path);
+    }
+
+    fn self_link_skipped(&mut self, path: &Path) {
+        let path = relative(self.base.as_deref(), path);
+        self.inner.self_link_skipped(path);
+    }
+
+    fn content_mismatch(&mut self, src: &Path, dst: &Path) {
+        let (src, dst) = (relative(self.base.as_deref(), src), relative(self.base.as_deref(), dst));
+        self.inner.content_mismatch(src, dst);
+    }
+
+    fn corruption_detected(&mut self, path: &Path) {
+        let path = relative(self.base.as_deref(), path);
+        self.inner.corruption_detected(path);
+    }
+
+    fn plan_skipped(&mut self, src: &Path, dst: &Path) {
+        let (src, dst) = (relative(self.base.as_deref(), src), relative(self.base.as_deref(), dst));
+        self.inner.plan_skipped(src, dst);
+    }
+
+    fn reflinked(&mut self, src: &Path, dst: &Path) {
+        let (src, dst) = (relative(self.base.as_deref(), src), relative(self.base.as_deref(), dst));
+        self.inner.reflinked(src, dst);
+    }
+
+    fn symlinked(&mut self, src: &Path, dst: &Path) {
+        let (src, dst) = (relative(self.base.as_deref(), src), relative(self.base.as_deref(), dst));
+        self.inner.symlinked(src, dst);
+    }
+
+    fn quarantined(&mut self, src: &Path, dst: &Path) {
+        let (src, dst) = (relative(self.base.as_deref(), src), relative(self.base.as_deref(), dst));
+        self.inner.quarantined(src, dst);
+    }
+
+    fn dupe_skipped(&mut self, path: &Path, reason: GuardReason) {
+        let path = relative(self.base.as_deref(), path);
+        self.inner.dupe_skipped(path, reason);
+    }
+
+    fn content_indexed(&mut self, path: &Path, size: u64, sha256: &[u8; 32]) {
+        let path = relative(self.base.as_deref(), path);
+        self.inner.content_indexed(path, size, sha256);
+    }
+
+    fn orphan_temp_found(&mut self, path: &Path) {
+        let path = relative(self.base.as_deref(), path);
+        self.inner.orphan_temp_found(path);
+    }
+
+    fn directory_scanned(&mut self, path: &Path, stats: &Stats) {
+        let path = relative(self.base.as_deref(), path);
+        self.inner.directory_scanned(path, stats);
+    }
+
+    fn cross_device_dupe(&mut self, a: &Path, b: &Path) {
+        let (a, b) = (relative(self.base.as_deref(), a), relative(self.base.as_deref(), b));
+        self.inner.cross_device_dupe(a, b);
+    }
+
+    fn special_file(&mut self, path: &Path, kind: SpecialFileKind) {
+        let path = relative(self.base.as_deref(), path);
+        self.inner.special_file(path, kind);
+    }
+
+    fn heartbeat(&mut self, elapsed: Duration, stats: &Stats) {
+        self.inner.heartbeat(elapsed, stats);
+    }
+
+    fn slow_file(&mut self, path: &Path, elapsed: Duration) {
+        let path = relative(self.base.as_deref(), path);
+        self.inner.slow_file(path, elapsed);
+    }
+
+    fn integrity_failure(&mut self, path: &Path, expected: &[u8; 32]) {
+        let path = relative(self.base.as_deref(), path);
+        self.inner.integrity_failure(path, expected);
+    }
+
+    fn scan_started(&mut self, total_files: Option<u64>) {
+        self.inner.scan_started(total_files);
+    }
+
+    fn permission_mismatch(&mut self, a: &Path, b: &Path) {
+        let (a, b) = (relative(self.base.as_deref(), a), relative(self.base.as_deref(), b));
+        self.inner.permission_mismatch(a, b);
+    }
+
+    fn scan_error(&mut self, path: &Path, err: &io::Error) {
+        let path = relative(self.base.as_deref(), path);
+        self.inner.scan_error(path, err);
+    }
+}
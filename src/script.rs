@@ -0,0 +1,56 @@
+use std::fs;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+
+/// Writes a shell script with the operations a dry run would have performed,
+/// so they can be reviewed (and edited) before running them by hand.
+#[derive(Debug)]
+pub struct ScriptWriter {
+    out: BufWriter<fs::File>,
+}
+
+impl ScriptWriter {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = fs::File::create(path)?;
+        file.set_permissions(fs::Permissions::from_mode(0o755))?;
+        let mut out = BufWriter::new(file);
+        out.write_all(b"#!/bin/sh\nset -e\n")?;
+        Ok(ScriptWriter { out })
+    }
+
+    /// Same link-then-rename replacement that the scanner does itself
+    pub fn hardlink(&mut self, src: &Path, dst: &Path, temp: &Path) -> io::Result<()> {
+        self.out.write_all(b"ln -- ")?;
+        write_quoted(&mut self.out, src)?;
+        self.out.write_all(b" ")?;
+        write_quoted(&mut self.out, temp)?;
+        self.out.write_all(b" && mv -f -- ")?;
+        write_quoted(&mut self.out, temp)?;
+        self.out.write_all(b" ")?;
+        write_quoted(&mut self.out, dst)?;
+        self.out.write_all(b"\n")?;
+        self.out.flush()
+    }
+}
+
+/// Single-quotes the path for sh. Paths don't have to be UTF-8, so they're written as raw bytes.
+fn write_quoted<W: Write>(out: &mut W, path: &Path) -> io::Result<()> {
+    out.write_all(b"'")?;
+    for (i, part) in path.as_os_str().as_bytes().split(|&b| b == b'\'').enumerate() {
+        if i > 0 {
+            out.write_all(b"'\\''")?;
+        }
+        out.write_all(part)?;
+    }
+    out.write_all(b"'")
+}
+
+#[test]
+fn quoting() {
+    let mut out = Vec::new();
+    write_quoted(&mut out, Path::new("/foo/it's a file")).unwrap();
+    assert_eq!(&out[..], &b"'/foo/it'\\''s a file'"[..]);
+}
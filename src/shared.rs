@@ -0,0 +1,23 @@
+//! Reference counting used for data shared between the scanner's indexes.
+//!
+//! By default it's `Rc`, which is the cheapest, but makes `Scanner` stuck on the thread that created it.
+//! With the `sync` feature it's `Arc` (atomic refcount updates on every add), and listeners and callbacks
+//! are required to be `Send`, so that a `Scanner` can be moved to another thread,
+//! e.g. scan on a worker thread and report on the main thread.
+
+#[cfg(not(feature = "sync"))]
+pub use std::rc::Rc as Shared;
+#[cfg(feature = "sync")]
+pub use std::sync::Arc as Shared;
+
+/// `Send` with the `sync` feature, and nothing without it
+#[cfg(feature = "sync")]
+pub trait MaybeSend: Send {}
+#[cfg(feature = "sync")]
+impl<T: Send + ?Sized> MaybeSend for T {}
+
+/// `Send` with the `sync` feature, and nothing without it
+#[cfg(not(feature = "sync"))]
+pub trait MaybeSend {}
+#[cfg(not(feature = "sync"))]
+impl<T: ?Sized> MaybeSend for T {}
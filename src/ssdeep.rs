@@ -0,0 +1,187 @@
+//! Context-triggered piecewise hashes (as by `ssdeep`), for `Scanner::similar_files`
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+const ROLLING_WINDOW: usize = 7;
+const MIN_BLOCK_SIZE: u32 = 3;
+const HASH_PRIME: u32 = 0x0100_0193;
+const HASH_INIT: u32 = 0x2802_1967;
+/// Length of the first part of a hash, the second one is half of it
+const SPAMSUM_LENGTH: usize = 64;
+const B64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Pairs of `files` (which should have one path per distinct content) that are at least `threshold` similar,
+/// on `ssdeep`'s scale of 0 to 100, most similar first. Files that can't be read are left out.
+pub fn similar_files(files: Vec<PathBuf>, threshold: u32) -> Vec<(PathBuf, PathBuf, u32)> {
+    let hashes: Vec<_> = files.into_iter().filter_map(|path| FuzzyHash::of_file(&path).ok().map(|hash| (path, hash))).collect();
+    let mut pairs = Vec::new();
+    for (i, (a_path, a)) in hashes.iter().enumerate() {
+        for (b_path, b) in &hashes[i+1..] {
+            let score = a.compare(b);
+            if score > 0 && score >= threshold {
+                let (first, second) = if a_path < b_path {(a_path, b_path)} else {(b_path, a_path)};
+                pairs.push((first.clone(), second.clone(), score));
+            }
+        }
+    }
+    pairs.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| (&a.0, &a.1).cmp(&(&b.0, &b.1))));
+    pairs
+}
+
+/// A hash in two parts: one with pieces ended by the rolling hash at the block size,
+/// and one at twice the block size, so that hashes of neighboring block sizes can be compared too
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuzzyHash {
+    block_size: u32,
+    first: Vec<u8>,
+    second: Vec<u8>,
+}
+
+#[derive(Default)]
+struct RollingHash {
+    window: [u8; ROLLING_WINDOW],
+    h1: u32,
+    h2: u32,
+    h3: u32,
+    n: usize,
+}
+
+impl RollingHash {
+    fn update(&mut self, c: u8) -> u32 {
+        let c32 = c as u32;
+        self.h2 = self.h2.wrapping_sub(self.h1).wrapping_add(ROLLING_WINDOW as u32 * c32);
+        self.h1 = self.h1.wrapping_add(c32).wrapping_sub(self.window[self.n % ROLLING_WINDOW] as u32);
+        self.window[self.n % ROLLING_WINDOW] = c;
+        self.n += 1;
+        self.h3 = (self.h3 << 5) ^ c32;
+        self.h1.wrapping_add(self.h2).wrapping_add(self.h3)
+    }
+}
+
+fn sum_hash(c: u8, h: u32) -> u32 {
+    h.wrapping_mul(HASH_PRIME) ^ c as u32
+}
+
+impl FuzzyHash {
+    pub fn of_file(path: &Path) -> io::Result<Self> {
+        let size = fs::metadata(path)?.len();
+        let mut block_size = MIN_BLOCK_SIZE;
+        while (block_size as u64) * (SPAMSUM_LENGTH as u64) < size {
+            block_size *= 2;
+        }
+        loop {
+            let hash = Self::with_block_size(fs::File::open(path)?, block_size)?;
+            // Too few pieces to compare well, so smaller ones are tried
+            if block_size > MIN_BLOCK_SIZE && hash.first.len() < SPAMSUM_LENGTH / 2 {
+                block_size /= 2;
+                continue;
+            }
+            return Ok(hash);
+        }
+    }
+
+    fn with_block_size<R: Read>(mut reader: R, block_size: u32) -> io::Result<Self> {
+        let mut roll = RollingHash::default();
+        let (mut h1, mut h2) = (HASH_INIT, HASH_INIT);
+        let (mut first, mut second) = (Vec::new(), Vec::new());
+        let mut buf = vec![0; 1 << 16];
+        loop {
+            let len = reader.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+            for &c in &buf[..len] {
+                h1 = sum_hash(c, h1);
+                h2 = sum_hash(c, h2);
+                let rolled = roll.update(c);
+                // Once there's no room for more pieces, the last one takes the rest
+                if rolled % block_size == block_size - 1 && first.len() < SPAMSUM_LENGTH - 1 {
+                    first.push(B64[(h1 % 64) as usize]);
+                    h1 = HASH_INIT;
+                }
+                if rolled % (block_size * 2) == block_size * 2 - 1 && second.len() < SPAMSUM_LENGTH / 2 - 1 {
+                    second.push(B64[(h2 % 64) as usize]);
+                    h2 = HASH_INIT;
+                }
+            }
+        }
+        if h1 != HASH_INIT {
+            first.push(B64[(h1 % 64) as usize]);
+        }
+        if h2 != HASH_INIT {
+            second.push(B64[(h2 % 64) as usize]);
+        }
+        Ok(FuzzyHash {block_size, first, second})
+    }
+
+    /// 0 for nothing in common, up to 100 for (nearly) the same. Only hashes of equal or neighboring block sizes
+    /// can be compared, others are 0.
+    pub fn compare(&self, other: &FuzzyHash) -> u32 {
+        let (a1, a2, b1, b2) = (collapse_runs(&self.first), collapse_runs(&self.second), collapse_runs(&other.first), collapse_runs(&other.second));
+        if self.block_size == other.block_size {
+            score(&a1, &b1, self.block_size).max(score(&a2, &b2, self.block_size * 2))
+        } else if self.block_size == other.block_size * 2 {
+            score(&a1, &b2, self.block_size)
+        } else if other.block_size == self.block_size * 2 {
+            score(&a2, &b1, other.block_size)
+        } else {
+            0
+        }
+    }
+}
+
+/// Runs of more than 3 of the same piece hash carry little information, so they're shortened to 3
+fn collapse_runs(s: &[u8]) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::with_capacity(s.len());
+    for &c in s {
+        if out.len() < 3 || out[out.len()-3..].iter().any(|&prev| prev != c) {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn score(a: &[u8], b: &[u8], block_size: u32) -> u32 {
+    if a.len() < ROLLING_WINDOW || b.len() < ROLLING_WINDOW || !a.windows(ROLLING_WINDOW).any(|w| b.windows(ROLLING_WINDOW).any(|v| v == w)) {
+        return 0;
+    }
+    let distance = edit_distance(a, b) as u32;
+    let (len_a, len_b) = (a.len() as u32, b.len() as u32);
+    let scaled = distance * SPAMSUM_LENGTH as u32 / (len_a + len_b) * 100 / SPAMSUM_LENGTH as u32;
+    if scaled >= 100 {
+        return 0;
+    }
+    let score = 100 - scaled;
+    // Small block sizes can't be that similar just by chance, but they don't say that much either
+    let unlimited = (99 + ROLLING_WINDOW as u32) / ROLLING_WINDOW as u32 * MIN_BLOCK_SIZE;
+    if block_size >= unlimited {
+        score
+    } else {
+        score.min(block_size / MIN_BLOCK_SIZE * len_a.min(len_b))
+    }
+}
+
+/// Insertions and deletions cost 1, and replacements 2
+fn edit_distance(a: &[u8], b: &[u8]) -> usize {
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut row = vec![0; b.len() + 1];
+    for (i, &ca) in a.iter().enumerate() {
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let replace = prev[j] + if ca == cb {0} else {2};
+            row[j + 1] = replace.min(prev[j + 1] + 1).min(row[j] + 1);
+        }
+        ::std::mem::swap(&mut prev, &mut row);
+    }
+    prev[b.len()]
+}
+
+#[test]
+fn runs_and_distance() {
+    assert_eq!(collapse_runs(b"AAAAABBCCCC"), b"AAABBCCC");
+    assert_eq!(edit_distance(b"kitten", b"sitting"), 5);
+    let hash = FuzzyHash {block_size: 48, first: b"0123456789abcdef".to_vec(), second: b"01234567".to_vec()};
+    assert_eq!(hash.compare(&hash), 100);
+    assert_eq!(hash.compare(&FuzzyHash {block_size: 192, ..hash.clone()}), 0);
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Entry {
+    File(OsString, usize),
+    Dir(OsString, usize),
+}
+
+/// Finds directories with identical structure and identical file contents.
+///
+/// `groups` are paths of files with the same content. A directory is only comparable
+/// if every entry in it is either a file from one of the groups or a comparable directory,
+/// so skipped files (too small, symlinks, etc.) make the directory unique.
+pub fn duplicate_subtrees(groups: &[Vec<PathBuf>], roots: &[PathBuf]) -> Vec<Vec<PathBuf>> {
+    let mut content_ids = HashMap::new();
+    for (id, group) in groups.iter().enumerate() {
+        for path in group {
+            content_ids.insert(path.as_path(), id);
+        }
+    }
+
+    let mut walker = Walker {
+        content_ids,
+        roots,
+        signatures: HashMap::new(),
+        interned: HashMap::new(),
+    };
+
+    // Every directory that has a file in it, and every directory above it up to the scanned roots
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    for path in walker.content_ids.keys() {
+        for dir in path.ancestors().skip(1) {
+            if !walker.is_in_roots(dir) {
+                break;
+            }
+            dirs.push(dir.to_owned());
+        }
+    }
+    dirs.sort();
+    dirs.dedup();
+
+    let mut by_signature: HashMap<usize, Vec<PathBuf>> = HashMap::new();
+    for dir in dirs {
+        if let Some(sig) = walker.signature(&dir) {
+            by_signature.entry(sig).or_default().push(dir);
+        }
+    }
+
+    let mut result: Vec<Vec<PathBuf>> = by_signature.values()
+        .filter(|dirs| dirs.len() > 1)
+        .filter(|dirs| !walker.parents_are_duplicates(dirs))
+        .cloned().collect();
+    for dirs in &mut result {
+        dirs.sort();
+    }
+    result.sort();
+    result
+}
+
+struct Walker<'a> {
+    content_ids: HashMap<&'a Path, usize>,
+    roots: &'a [PathBuf],
+    /// `None` for directories that can't be compared
+    signatures: HashMap<PathBuf, Option<usize>>,
+    interned: HashMap<Vec<Entry>, usize>,
+}
+
+impl<'a> Walker<'a> {
+    fn is_in_roots(&self, dir: &Path) -> bool {
+        self.roots.iter().any(|root| dir.starts_with(root))
+    }
+
+    fn signature(&mut self, dir: &Path) -> Option<usize> {
+        if let Some(&sig) = self.signatures.get(dir) {
+            return sig;
+        }
+        let sig = self.compute_signature(dir);
+        self.signatures.insert(dir.to_owned(), sig);
+        sig
+    }
+
+    fn compute_signature(&mut self, dir: &Path) -> Option<usize> {
+        let mut entries = Vec::new();
+        let mut has_files = false;
+        for entry in fs::read_dir(dir).ok()? {
+            let entry = entry.ok()?;
+            let path = entry.path();
+            let name = entry.file_name();
+            let ty = entry.file_type().ok()?;
+            if ty.is_dir() {
+                let sig = self.signature(&path)?;
+                entries.push(Entry::Dir(name, sig));
+                has_files = true;
+            } else {
+                let &id = self.content_ids.get(path.as_path())?;
+                entries.push(Entry::File(name, id));
+                has_files = true;
+            }
+        }
+        // Empty directories are all alike, but there's nothing to dedupe in them
+        if !has_files {
+            return None;
+        }
+        entries.sort();
+        let next_id = self.interned.len();
+        Some(*self.interned.entry(entries).or_insert(next_id))
+    }
+
+    /// If all the directories are in (different) identical parents, only the parents are worth reporting.
+    fn parents_are_duplicates(&mut self, dirs: &[PathBuf]) -> bool {
+        let mut parent_sig = None;
+        let mut parents = Vec::with_capacity(dirs.len());
+        for dir in dirs {
+            let parent = match dir.parent() {
+                Some(parent) if self.is_in_roots(parent) => parent,
+                _ => return false,
+            };
+            let sig = match self.signature(parent) {
+                Some(sig) => sig,
+                None => return false,
+            };
+            if parent_sig.is_some_and(|p| p != sig) || parents.contains(&parent) {
+                return false;
+            }
+            parent_sig = Some(sig);
+            parents.push(parent);
+        }
+        true
+    }
+}
@@ -0,0 +1,329 @@
+//! Platform-specific bits that `std` doesn't have
+use std::io;
+use std::path::Path;
+
+use std::fs;
+use std::os::unix::io::AsRawFd;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use libc;
+
+/// From linux/fs.h (`chattr +i`)
+#[cfg(target_os = "linux")]
+const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+
+/// Filesystems that don't have inode flags at all say so in a few different ways
+#[cfg(target_os = "linux")]
+fn is_unsupported(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::ENOTTY) | Some(libc::EOPNOTSUPP) | Some(libc::EINVAL))
+}
+
+#[cfg(target_os = "linux")]
+fn inode_flags(file: &fs::File) -> io::Result<libc::c_int> {
+    let mut flags: libc::c_int = 0;
+    // The kernel reads and writes an int, despite the ioctl being declared with a long
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(flags)
+}
+
+/// Whether the file has the immutable attribute. Filesystems without the attribute never have it.
+#[cfg(target_os = "linux")]
+pub fn is_immutable(path: &Path) -> io::Result<bool> {
+    let file = fs::File::open(path)?;
+    match inode_flags(&file) {
+        Ok(flags) => Ok(flags & FS_IMMUTABLE_FL != 0),
+        Err(ref err) if is_unsupported(err) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Needs `CAP_LINUX_IMMUTABLE`
+#[cfg(target_os = "linux")]
+pub fn set_immutable(path: &Path, immutable: bool) -> io::Result<()> {
+    let file = fs::File::open(path)?;
+    let flags = inode_flags(&file)?;
+    let flags = if immutable {flags | FS_IMMUTABLE_FL} else {flags & !FS_IMMUTABLE_FL};
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Creates `dest_path`, which must not exist yet, sharing `src`'s data blocks (copy-on-write),
+/// on filesystems that support it (`FICLONE` on Linux, e.g. Btrfs and XFS, `clonefile` on macOS, i.e. APFS).
+/// The returned file is for setting its metadata. Nothing is left behind on errors.
+#[cfg(target_os = "linux")]
+pub fn clone_file(src: &fs::File, dest_path: &Path) -> io::Result<fs::File> {
+    let dest = fs::OpenOptions::new().write(true).create_new(true).open(dest_path)?;
+    if unsafe { libc::ioctl(dest.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) } != 0 {
+        let err = io::Error::last_os_error();
+        fs::remove_file(dest_path).ok();
+        return Err(err);
+    }
+    Ok(dest)
+}
+
+/// It has the permissions of `src`, so it's opened read-only, which is enough for changing them
+#[cfg(target_os = "macos")]
+pub fn clone_file(src: &fs::File, dest_path: &Path) -> io::Result<fs::File> {
+    let dest = path_to_cstring(dest_path)?;
+    if unsafe { libc::fclonefileat(src.as_raw_fd(), libc::AT_FDCWD, dest.as_ptr(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    fs::File::open(dest_path)
+}
+
+/// Swaps two paths in one step (`renameat2` with `RENAME_EXCHANGE`)
+#[cfg(target_os = "linux")]
+pub fn exchange(a: &Path, b: &Path) -> io::Result<()> {
+    let a = path_to_cstring(a)?;
+    let b = path_to_cstring(b)?;
+    // Through syscall, since older libcs don't have a wrapper
+    if unsafe { libc::syscall(libc::SYS_renameat2, libc::AT_FDCWD, a.as_ptr(), libc::AT_FDCWD, b.as_ptr(), libc::RENAME_EXCHANGE) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The kernel or the filesystem doesn't have `exchange`
+pub fn is_exchange_unsupported(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(::libc::ENOSYS) | Some(::libc::EINVAL) | Some(::libc::EOPNOTSUPP))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn exchange(_: &Path, _: &Path) -> io::Result<()> {
+    Err(io::Error::from_raw_os_error(::libc::ENOSYS))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_immutable(_: &Path) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_immutable(_: &Path, _: bool) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "immutable flag is not supported on this platform"))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn clone_file(_: &fs::File, _: &Path) -> io::Result<fs::File> {
+    Err(io::Error::new(io::ErrorKind::Other, "reflinks are not supported on this platform"))
+}
+
+/// Header of `struct fiemap` from linux/fiemap.h, without room for extents, which makes the kernel only count them
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default)]
+struct Fiemap {
+    fm_start: u64,
+    fm_length: u64,
+    fm_flags: u32,
+    fm_mapped_extents: u32,
+    fm_extent_count: u32,
+    fm_reserved: u32,
+}
+
+/// `_IOWR('f', 11, struct fiemap)`
+#[cfg(target_os = "linux")]
+const FS_IOC_FIEMAP: libc::c_ulong = 0xC020_660B;
+/// Flush delayed allocations first, so that they're counted
+#[cfg(target_os = "linux")]
+const FIEMAP_FLAG_SYNC: u32 = 1;
+
+/// Number of extents of the file's data (`FIEMAP`), i.e. 1 if it's contiguous on disk.
+/// `None` where the filesystem can't tell, e.g. tmpfs.
+#[cfg(target_os = "linux")]
+pub fn extent_count(path: &Path) -> Option<u32> {
+    let file = fs::File::open(path).ok()?;
+    let mut map = Fiemap {fm_length: !0, fm_flags: FIEMAP_FLAG_SYNC, ..Fiemap::default()};
+    if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut map) } != 0 {
+        return None;
+    }
+    Some(map.fm_mapped_extents)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn extent_count(_: &Path) -> Option<u32> {
+    None
+}
+
+/// Whether the block device is a spinning disk, according to sysfs. `None` if it isn't known, e.g. for tmpfs.
+#[cfg(target_os = "linux")]
+pub fn is_rotational(dev: u64) -> Option<bool> {
+    // glibc's encoding of dev_t
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    let dir = format!("/sys/dev/block/{}:{}", major, minor);
+    // Partitions have the queue in the parent device's directory
+    let flag = fs::read_to_string(format!("{}/queue/rotational", dir))
+        .or_else(|_| fs::read_to_string(format!("{}/../queue/rotational", dir))).ok()?;
+    match flag.trim() {
+        "0" => Some(false),
+        "1" => Some(true),
+        _ => None,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_rotational(_: u64) -> Option<bool> {
+    None
+}
+
+/// From linux/ioprio.h
+#[cfg(target_os = "linux")]
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+#[cfg(target_os = "linux")]
+const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+#[cfg(target_os = "linux")]
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+/// The lowest CPU priority, and the idle I/O class where there is one. False if the I/O priority couldn't be set,
+/// because there are no I/O priorities. On Linux both are of the calling thread (and ones it starts afterwards).
+#[cfg(target_os = "linux")]
+pub fn set_low_priority() -> io::Result<bool> {
+    set_lowest_nice()?;
+    let prio = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, prio) } != 0 {
+        let err = io::Error::last_os_error();
+        if matches!(err.raw_os_error(), Some(libc::ENOSYS) | Some(libc::EINVAL)) {
+            return Ok(false);
+        }
+        return Err(err);
+    }
+    Ok(true)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_low_priority() -> io::Result<bool> {
+    set_lowest_nice()?;
+    Ok(false)
+}
+
+fn set_lowest_nice() -> io::Result<()> {
+    if unsafe { ::libc::setpriority(::libc::PRIO_PROCESS, 0, 19) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Errors that network filesystems return when the same operation may well work if it's tried again
+pub fn is_transient(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(::libc::EAGAIN) | Some(::libc::ESTALE) | Some(::libc::ETIMEDOUT) | Some(::libc::EINTR))
+}
+
+/// The path is longer than `PATH_MAX` (or a component is longer than `NAME_MAX`)
+pub fn is_name_too_long(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(::libc::ENAMETOOLONG)
+}
+
+fn path_to_cstring(path: &Path) -> io::Result<::std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+    ::std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a nul byte"))
+}
+
+fn statvfs(path: &Path) -> io::Result<::libc::statvfs> {
+    let path = path_to_cstring(path)?;
+    let mut stat: ::libc::statvfs = unsafe { ::std::mem::zeroed() };
+    if unsafe { ::libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat)
+}
+
+/// Bytes available to unprivileged users on the filesystem of the path
+pub fn free_space(path: &Path) -> io::Result<u64> {
+    let stat = statvfs(path)?;
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Whether the filesystem of the path is mounted read-only
+pub fn is_readonly_mount(path: &Path) -> io::Result<bool> {
+    let stat = statvfs(path)?;
+    Ok(stat.f_flag & ::libc::ST_RDONLY != 0)
+}
+
+/// Calls `f` with bytes `start..start+size` of the file mapped into memory, and unmaps them right after.
+/// Fails if the file has become shorter, since touching a mapping past the end of a file is a SIGBUS
+/// (which can still happen if it's truncated while `f` runs).
+pub fn with_mapped_range<R, F: FnOnce(&[u8]) -> R>(file: &fs::File, start: u64, size: u64, f: F) -> io::Result<R> {
+    if file.metadata()?.len() < start + size {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file has been truncated"));
+    }
+    if size == 0 {
+        return Ok(f(&[]));
+    }
+
+    struct Mapping(*mut ::libc::c_void, usize);
+    impl Drop for Mapping {
+        fn drop(&mut self) {
+            unsafe { ::libc::munmap(self.0, self.1); }
+        }
+    }
+
+    // Offset of mmap must be page-aligned
+    let page = unsafe { ::libc::sysconf(::libc::_SC_PAGESIZE) } as u64;
+    let aligned_start = start - start % page;
+    let len = (start - aligned_start + size) as usize;
+    let ptr = unsafe {
+        ::libc::mmap(::std::ptr::null_mut(), len, ::libc::PROT_READ, ::libc::MAP_PRIVATE, file.as_raw_fd(), aligned_start as ::libc::off_t)
+    };
+    if ptr == ::libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    let mapping = Mapping(ptr, len);
+    let data = unsafe { ::std::slice::from_raw_parts((mapping.0 as *const u8).add((start - aligned_start) as usize), size as usize) };
+    Ok(f(data))
+}
+
+/// Value of an extended attribute of the file, or `None` if it doesn't have it
+/// (or the filesystem doesn't support extended attributes)
+#[cfg(target_os = "linux")]
+pub fn get_xattr(path: &Path, name: &str) -> io::Result<Option<Vec<u8>>> {
+    let path = path_to_cstring(path)?;
+    let name = ::std::ffi::CString::new(name).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "attribute name contains a nul byte"))?;
+    // Values of this crate are short, and longer ones aren't understood anyway
+    let mut value = vec![0u8; 256];
+    let len = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), value.as_mut_ptr() as *mut libc::c_void, value.len()) };
+    if len < 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::ENODATA) | Some(libc::ENOTSUP) | Some(libc::ERANGE) => Ok(None),
+            _ => Err(err),
+        };
+    }
+    value.truncate(len as usize);
+    Ok(Some(value))
+}
+
+#[cfg(target_os = "linux")]
+pub fn set_xattr(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    let path = path_to_cstring(path)?;
+    let name = ::std::ffi::CString::new(name).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "attribute name contains a nul byte"))?;
+    if unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), value.as_ptr() as *const libc::c_void, value.len(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn get_xattr(_: &Path, _: &str) -> io::Result<Option<Vec<u8>>> {
+    Ok(None)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_xattr(_: &Path, _: &str, _: &[u8]) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "extended attributes are not supported on this platform"))
+}
+
+/// Owner of files this process creates, and the one that's normally allowed to change them
+pub fn effective_uid() -> u32 {
+    unsafe { ::libc::geteuid() }
+}
+
+/// Whether a process with the id is running (or exists, but belongs to someone else)
+pub fn process_exists(pid: u32) -> bool {
+    let res = unsafe { ::libc::kill(pid as ::libc::pid_t, 0) };
+    res == 0 || io::Error::last_os_error().raw_os_error() == Some(::libc::EPERM)
+}
@@ -0,0 +1,173 @@
+use dupe::{GuardReason, ScanListener, Scanner, SpecialFileKind, Stats};
+use libc::{self, c_int};
+use std::cell::Cell;
+use std::ffi::CString;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// At most one `file_scanned` progress message per this long
+const SCANNED_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Sends events to the system log: links made and duplicates found as `LOG_INFO`, skipped files as `LOG_WARNING`,
+/// and failures (errors of the scan, link anomalies, content mismatches) as `LOG_ERR`, but corruption as `LOG_CRIT`.
+/// The summary of a scan is `LOG_NOTICE`, and progress of the scan is logged at most every 10 seconds.
+///
+/// It uses `openlog(3)`, which is global to the process, so there should be only one at a time.
+#[derive(Debug)]
+pub struct SyslogListener {
+    // openlog keeps the pointer
+    _ident: CString,
+    last_scanned: Option<Instant>,
+    // scan_over only gets &self
+    errors_logged: Cell<usize>,
+}
+
+impl SyslogListener {
+    /// `facility` is one of the `LOG_*` facilities of `libc`, e.g. `libc::LOG_DAEMON`.
+    /// `identity` is prepended to the messages, usually the program name.
+    pub fn new(identity: &str, facility: c_int) -> Self {
+        let ident = CString::new(identity.replace('\0', "")).expect("no NULs");
+        unsafe {
+            libc::openlog(ident.as_ptr(), libc::LOG_PID, facility);
+        }
+        SyslogListener {
+            _ident: ident,
+            last_scanned: None,
+            errors_logged: Cell::new(0),
+        }
+    }
+
+    fn log(&self, priority: c_int, message: &str) {
+        let message = CString::new(message.replace('\0', "")).expect("no NULs");
+        unsafe {
+            libc::syslog(priority, b"%s\0".as_ptr() as *const libc::c_char, message.as_ptr());
+        }
+    }
+
+    /// Whether the progress should be logged now
+    fn scanned_due(&mut self, now: Instant) -> bool {
+        if self.last_scanned.is_some_and(|last| now.duration_since(last) < SCANNED_INTERVAL) {
+            return false;
+        }
+        self.last_scanned = Some(now);
+        true
+    }
+}
+
+impl Drop for SyslogListener {
+    fn drop(&mut self) {
+        unsafe {
+            libc::closelog();
+        }
+    }
+}
+
+impl ScanListener for SyslogListener {
+    fn file_scanned(&mut self, path: &Path, stats: &Stats) {
+        if self.scanned_due(Instant::now()) {
+            self.log(libc::LOG_INFO, &format!("scanned {} files, {} dupes so far, at {}", stats.added, stats.dupes, path.display()));
+        }
+    }
+
+    fn scan_over(&self, scanner: &Scanner, stats: &Stats, scan_duration: Duration) {
+        // Errors are kept for all scans, so only new ones are logged
+        let errors = scanner.errors();
+        for (path, err) in errors.iter().skip(self.errors_logged.get()) {
+            self.log(libc::LOG_ERR, &format!("{}: {}", path.display(), err));
+        }
+        self.errors_logged.set(errors.len());
+        self.log(libc::LOG_NOTICE, &format!("scan done in {}s: {} files, {} dupes, {} hardlinks made, {} bytes freed",
+            scan_duration.as_secs(), stats.added, stats.dupes, stats.created_hardlinks, stats.bytes_saved));
+    }
+
+    fn hardlinked(&mut self, src: &Path, dst: &Path) {
+        self.log(libc::LOG_INFO, &format!("hardlinked {} to {}", src.display(), dst.display()));
+    }
+
+    fn duplicate_found(&mut self, src: &Path, dst: &Path) {
+        self.log(libc::LOG_INFO, &format!("{} is a duplicate of {}", src.display(), dst.display()));
+    }
+
+    fn reflinked(&mut self, src: &Path, dst: &Path) {
+        self.log(libc::LOG_INFO, &format!("reflinked {} to {}", src.display(), dst.display()));
+    }
+
+    fn symlinked(&mut self, src: &Path, dst: &Path) {
+        self.log(libc::LOG_INFO, &format!("symlinked {} to {}", src.display(), dst.display()));
+    }
+
+    fn quarantined(&mut self, src: &Path, dst: &Path) {
+        self.log(libc::LOG_INFO, &format!("quarantined {} and symlinked it to {}", src.display(), dst.display()));
+    }
+
+    fn read_timeout(&mut self, path: &Path) {
+        self.log(libc::LOG_WARNING, &format!("reading {} timed out", path.display()));
+    }
+
+    fn slow_file(&mut self, path: &Path, elapsed: Duration) {
+        self.log(libc::LOG_WARNING, &format!("reading {} took {}ms so far", path.display(), elapsed.as_millis()));
+    }
+
+    fn special_file(&mut self, path: &Path, kind: SpecialFileKind) {
+        self.log(libc::LOG_WARNING, &format!("skipped {} ({:?})", path.display(), kind));
+    }
+
+    fn dupe_skipped(&mut self, path: &Path, reason: GuardReason) {
+        self.log(libc::LOG_WARNING, &format!("left duplicate {} as it is ({:?})", path.display(), reason));
+    }
+
+    fn orphan_temp_found(&mut self, path: &Path) {
+        self.log(libc::LOG_WARNING, &format!("{} is a temporary file left by an interrupted run", path.display()));
+    }
+
+    fn self_link_skipped(&mut self, path: &Path) {
+        self.log(libc::LOG_WARNING, &format!("skipped linking {} to itself", path.display()));
+    }
+
+    fn sparse_dupe_warning(&mut self, path: &Path) {
+        self.log(libc::LOG_WARNING, &format!("{} is sparse", path.display()));
+    }
+
+    fn cross_device_dupe(&mut self, a: &Path, b: &Path) {
+        self.log(libc::LOG_WARNING, &format!("{} and {} are duplicates on different filesystems", a.display(), b.display()));
+    }
+
+    fn permission_mismatch(&mut self, a: &Path, b: &Path) {
+        self.log(libc::LOG_WARNING, &format!("left duplicate {} as it is, its permissions or owner differ from {}", a.display(), b.display()));
+    }
+
+    fn plan_skipped(&mut self, src: &Path, dst: &Path) {
+        self.log(libc::LOG_WARNING, &format!("skipped planned link of {} to {}", src.display(), dst.display()));
+    }
+
+    fn link_anomaly(&mut self, src: &Path, dst: &Path) {
+        self.log(libc::LOG_ERR, &format!("{} doesn't look like a hardlink of {} after linking", src.display(), dst.display()));
+    }
+
+    fn content_mismatch(&mut self, src: &Path, dst: &Path) {
+        self.log(libc::LOG_ERR, &format!("{} doesn't have the content of {}", src.display(), dst.display()));
+    }
+
+    fn integrity_failure(&mut self, path: &Path, _expected: &[u8; 32]) {
+        self.log(libc::LOG_ERR, &format!("{} doesn't have the content listed in the manifest", path.display()));
+    }
+
+    fn scan_error(&mut self, path: &Path, err: &io::Error) {
+        self.log(libc::LOG_ERR, &format!("{}: {}", path.display(), err));
+    }
+
+    fn corruption_detected(&mut self, path: &Path) {
+        self.log(libc::LOG_CRIT, &format!("content of {} changed while it was linked, it and its links may be corrupt", path.display()));
+    }
+}
+
+#[test]
+fn scanned_rate_limit() {
+    let mut listener = SyslogListener::new("syslogtest", libc::LOG_USER);
+    let start = Instant::now();
+    assert!(listener.scanned_due(start));
+    assert!(!listener.scanned_due(start + Duration::from_secs(1)));
+    assert!(listener.scanned_due(start + SCANNED_INTERVAL));
+    assert!(!listener.scanned_due(start + SCANNED_INTERVAL + Duration::from_secs(9)));
+}
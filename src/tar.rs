@@ -0,0 +1,142 @@
+//! Just enough of the tar format to read regular files from a stream (ustar, with GNU and pax long names)
+use std::ffi::OsStr;
+use std::io;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+const BLOCK: u64 = 512;
+
+/// Calls `f` with path, size and content of every regular file in the archive, in order.
+/// Content that `f` doesn't read is skipped.
+pub fn for_each_file<R: Read, F: FnMut(PathBuf, u64, &mut dyn Read) -> io::Result<()>>(mut reader: R, mut f: F) -> io::Result<()> {
+    // Set by GNU `L` and pax `x` entries for the entry that follows them
+    let mut long_name: Option<Vec<u8>> = None;
+    let mut header = [0; BLOCK as usize];
+    loop {
+        if !read_block(&mut reader, &mut header)? || header.iter().all(|&b| b == 0) {
+            return Ok(());
+        }
+        let size = parse_size(&header[124..136])?;
+        let mut content = (&mut reader).take(size);
+        match header[156] {
+            b'0' | b'\0' | b'7' => {
+                let name = long_name.take().unwrap_or_else(|| header_name(&header));
+                f(PathBuf::from(OsStr::from_bytes(&name)), size, &mut content)?;
+            },
+            b'L' => {
+                let mut name = Vec::new();
+                content.read_to_end(&mut name)?;
+                let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+                name.truncate(len);
+                long_name = Some(name);
+            },
+            b'x' => {
+                let mut records = Vec::new();
+                content.read_to_end(&mut records)?;
+                if let Some(path) = pax_path(&records) {
+                    long_name = Some(path);
+                }
+            },
+            // Directories, links, devices, and global headers have nothing to compare
+            _ => long_name = None,
+        }
+        io::copy(&mut content, &mut io::sink())?;
+        let padding = (BLOCK - size % BLOCK) % BLOCK;
+        io::copy(&mut (&mut reader).take(padding), &mut io::sink())?;
+    }
+}
+
+/// False at the end of the stream
+fn read_block<R: Read>(reader: &mut R, block: &mut [u8]) -> io::Result<bool> {
+    let mut len = 0;
+    while len < block.len() {
+        match reader.read(&mut block[len..]) {
+            Ok(0) if len == 0 => return Ok(false),
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated tar header")),
+            Ok(n) => len += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(true)
+}
+
+fn header_name(header: &[u8]) -> Vec<u8> {
+    let field = |bytes: &[u8]| bytes[..bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len())].to_vec();
+    let name = field(&header[0..100]);
+    let prefix = if &header[257..262] == b"ustar" {field(&header[345..500])} else {Vec::new()};
+    if prefix.is_empty() {
+        return name;
+    }
+    let mut path = prefix;
+    path.push(b'/');
+    path.extend_from_slice(&name);
+    path
+}
+
+/// Octal, or big-endian binary (GNU) if the high bit of the first byte is set
+fn parse_size(field: &[u8]) -> io::Result<u64> {
+    if field[0] & 0x80 != 0 {
+        return Ok(field[1..].iter().fold(0, |size, &b| (size << 8) | u64::from(b)));
+    }
+    let digits: Vec<u8> = field.iter().cloned().skip_while(|&b| b == b' ').take_while(|b| (b'0'..=b'7').contains(b)).collect();
+    let digits = ::std::str::from_utf8(&digits).unwrap_or("");
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad size in tar header"))
+}
+
+/// `path` from pax records, which are `<length> <key>=<value>\n`
+fn pax_path(mut records: &[u8]) -> Option<Vec<u8>> {
+    let mut path = None;
+    while !records.is_empty() {
+        let space = records.iter().position(|&b| b == b' ')?;
+        let len: usize = ::std::str::from_utf8(&records[..space]).ok()?.parse().ok()?;
+        if len <= space || len > records.len() {
+            return None;
+        }
+        let record = &records[space+1..len];
+        let record = record.strip_suffix(b"\n").unwrap_or(record);
+        if let Some(value) = record.strip_prefix(b"path=") {
+            path = Some(value.to_vec());
+        }
+        records = &records[len..];
+    }
+    path
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+    use super::*;
+    use std::fs;
+    use std::process::Command;
+
+    #[test]
+    fn members() {
+        let tmp = tempdir::TempDir::new("tartest").unwrap();
+        let long = "d".repeat(120);
+        fs::create_dir(tmp.path().join(&long)).unwrap();
+        fs::write(tmp.path().join(&long).join("a"), "aaa").unwrap();
+        fs::write(tmp.path().join("b"), vec![b'b'; 1000]).unwrap();
+        let tar = match Command::new("tar").arg("-cf").arg("-").arg("-C").arg(tmp.path()).arg("b").arg(&long).output() {
+            Ok(out) if out.status.success() => out.stdout,
+            _ => return, // No tar to make the archive
+        };
+
+        let mut files = Vec::new();
+        for_each_file(&tar[..], |path, size, content| {
+            let mut data = Vec::new();
+            content.read_to_end(&mut data)?;
+            assert_eq!(data.len() as u64, size);
+            files.push((path, data));
+            Ok(())
+        }).unwrap();
+        assert_eq!(files, vec![
+            (PathBuf::from("b"), vec![b'b'; 1000]),
+            (PathBuf::from(format!("{}/a", long)), b"aaa".to_vec()),
+        ]);
+    }
+}
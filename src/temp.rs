@@ -0,0 +1,56 @@
+//! Temporary files next to the files they replace. Names are unique per operation (`<prefix>.<pid>.<counter>`),
+//! so that runs in the same directory don't use each other's.
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use sys;
+
+/// Also the whole name of temporary files of older versions
+pub const TEMP_FILE_PREFIX: &str = ".tmp-dupe-e1iIQcBFn5pC4MUSm-xkcd-221";
+
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+/// A new name for a temporary file in the directory of `dest`. Nothing is created.
+pub fn unique_path(dest: &Path) -> PathBuf {
+    dest.with_file_name(format!("{}.{}.{}", TEMP_FILE_PREFIX, process::id(), NEXT.fetch_add(1, Ordering::Relaxed)))
+}
+
+pub fn is_temp_name(name: &OsStr) -> bool {
+    name.to_str().is_some_and(|name| name == TEMP_FILE_PREFIX || name.starts_with(&format!("{}.", TEMP_FILE_PREFIX)))
+}
+
+/// Whether the process that made the temporary file is still running, so it may still need it
+pub fn in_use(name: &OsStr) -> bool {
+    let pid = name.to_str()
+        .and_then(|name| name.strip_prefix(TEMP_FILE_PREFIX)?.strip_prefix('.')?.split('.').next()?.parse().ok());
+    pid.is_some_and(sys::process_exists)
+}
+
+/// Gets a temporary file of a crashed run that had the same name out of the way. It's removed only if it has other links,
+/// since otherwise it may be the only copy of a replaced file (e.g. swapped out with `Settings::use_renameat2`),
+/// and then it's an `AlreadyExists` error.
+pub fn remove_stale(temp_path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(temp_path) {
+        Ok(ref m) if m.is_file() && m.nlink() > 1 => fs::remove_file(temp_path),
+        Ok(_) => Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} is left over from an earlier run", temp_path.display()))),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+#[test]
+fn names() {
+    let (a, b) = (unique_path(Path::new("/x/a")), unique_path(Path::new("/x/a")));
+    assert_ne!(a, b);
+    assert_eq!(a.parent(), Some(Path::new("/x")));
+    for path in &[&a, &b] {
+        let name = path.file_name().unwrap();
+        assert!(is_temp_name(name) && in_use(name));
+    }
+    assert!(is_temp_name(OsStr::new(TEMP_FILE_PREFIX)) && !in_use(OsStr::new(TEMP_FILE_PREFIX)));
+    assert!(!is_temp_name(OsStr::new(".tmp-dupe-e1iIQcBFn5pC4MUSm-xkcd-2210")));
+}
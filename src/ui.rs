@@ -1,7 +1,10 @@
+use std::cmp::Reverse;
 use std::time::{Instant,Duration};
 use dupe::Stats;
 use dupe::ScanListener;
 use dupe::Scanner;
+use std::io;
+#[cfg(test)]
 use std::path::PathBuf;
 use std::path::Path;
 
@@ -17,6 +20,12 @@ pub struct UI {
     timing: Timing,
 }
 
+impl Default for UI {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl UI {
     pub fn new() -> Self {
         UI {
@@ -29,28 +38,112 @@ impl UI {
 }
 
 impl ScanListener for UI {
-    fn file_scanned(&mut self, path: &PathBuf, stats: &Stats) {
+    fn file_scanned(&mut self, path: &Path, stats: &Stats) {
         let elapsed = self.timing.start_time.elapsed().as_secs();
         if elapsed > self.timing.next_update {
             self.timing.next_update = elapsed+1;
             println!("{}+{} dupes. {}+{} files scanned. {}/…",
-                stats.dupes, stats.hardlinks, stats.added, stats.skipped,
+                stats.dupes, stats.existing_hardlinks, stats.added, stats.skipped,
                 path.parent().unwrap_or(path).display());
         }
     }
 
-    fn scan_over(&self, _: &Scanner, stats: &Stats, scan_duration: Duration) {
-        println!("Dupes found: {}. Existing hardlinks: {}. Scanned: {}. Skipped {}. Total scan duration: {:?}",
-            stats.dupes, stats.hardlinks, stats.added, stats.skipped, scan_duration);
+    fn scan_over(&self, scanner: &Scanner, stats: &Stats, scan_duration: Duration) {
+        println!("Dupes found: {}. Existing hardlinks: {}. Hardlinks created: {}. Scanned: {} in {} directories. Skipped {}. Total scan duration: {:?}",
+            stats.dupes, stats.existing_hardlinks, stats.created_hardlinks, stats.added, stats.directories, stats.skipped, scan_duration);
+        println!("Size of scanned files: {} bytes, {} bytes on disk", stats.total_apparent_bytes, stats.total_allocated_bytes);
+        if stats.verify_mismatches > 0 {
+            println!("Files with same hashes, but different content: {}", stats.verify_mismatches);
+        }
+        if stats.dupes_not_linked > 0 {
+            println!("Dupes left unlinked by guards (e.g. protected, immutable): {}", stats.dupes_not_linked);
+        }
+        let mut by_extension: Vec<_> = scanner.stats_by_extension().into_iter().collect();
+        by_extension.sort_by_key(|(_, ext)| (Reverse(ext.reclaimable_bytes), Reverse(ext.dupes)));
+        if !by_extension.is_empty() {
+            println!("Dupes by extension:");
+        }
+        for (name, ext) in by_extension.iter().take(10) {
+            println!("  {}: {} dupes, {} bytes reclaimable", name.to_string_lossy(), ext.dupes, ext.reclaimable_bytes);
+        }
+        if stats.bytes_saved > 0 {
+            println!("Freed: {} bytes", stats.bytes_saved);
+        }
+        if stats.would_reclaim_bytes > 0 {
+            println!("Would free: {} bytes", stats.would_reclaim_bytes);
+        }
+        if stats.cross_device_bytes > 0 {
+            println!("Dupes on other devices, which can't be linked: {} bytes", stats.cross_device_bytes);
+        }
     }
 
     fn hardlinked(&mut self, src: &Path, dst: &Path) {
         println!("Hardlinked {}", combined_paths(src, dst));
     }
 
+    fn reflinked(&mut self, src: &Path, dst: &Path) {
+        println!("Reflinked {}", combined_paths(src, dst));
+    }
+
+    fn symlinked(&mut self, src: &Path, dst: &Path) {
+        println!("Symlinked {}", combined_paths(src, dst));
+    }
+
+    fn quarantined(&mut self, src: &Path, dst: &Path) {
+        println!("Quarantined {}", combined_paths(src, dst));
+    }
+
     fn duplicate_found(&mut self, src: &Path, dst: &Path) {
         println!("Found dupe {}", combined_paths(src, dst));
     }
+
+    fn read_timeout(&mut self, path: &Path) {
+        println!("Skipped {}, because reading it timed out", path.display());
+    }
+
+    fn slow_file(&mut self, path: &Path, elapsed: Duration) {
+        println!("Slow: reading {} took {:.1}s so far", path.display(), elapsed.as_secs_f64());
+    }
+
+    fn orphan_temp_found(&mut self, path: &Path) {
+        println!("Found a leftover temporary file {}", path.display());
+    }
+
+    fn content_mismatch(&mut self, src: &Path, dst: &Path) {
+        println!("Warning: same hashes, but different content {}", combined_paths(src, dst));
+    }
+
+    fn integrity_failure(&mut self, path: &Path, _expected: &[u8; 32]) {
+        println!("Error: {} doesn't have the content listed in the manifest", path.display());
+    }
+
+    fn permission_mismatch(&mut self, a: &Path, b: &Path) {
+        println!("Left {} alone, since its permissions or owner differ from {}", a.display(), b.display());
+    }
+
+    fn scan_error(&mut self, path: &Path, err: &io::Error) {
+        println!("Error: {}: {}", path.display(), err);
+    }
+
+    fn corruption_detected(&mut self, path: &Path) {
+        println!("Error: content of {} changed while it was linked, it and its links may be corrupt", path.display());
+    }
+
+    fn plan_skipped(&mut self, src: &Path, dst: &Path) {
+        println!("Skipped {}, because the files changed since the plan was made", combined_paths(src, dst));
+    }
+
+    fn link_anomaly(&mut self, src: &Path, dst: &Path) {
+        println!("Warning: linked {}, but it doesn't look like a hardlink", combined_paths(src, dst));
+    }
+
+    fn self_link_skipped(&mut self, path: &Path) {
+        println!("Warning: skipped {}, because it's the same path as its master", path.display());
+    }
+
+    fn sparse_dupe_warning(&mut self, path: &Path) {
+        println!("Warning: {} is fully sparse, so linking it saves less than its size", path.display());
+    }
 }
 
 fn combined_paths(base: &Path, relativize: &Path) -> String {
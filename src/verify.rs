@@ -0,0 +1,87 @@
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+/// Byte-for-byte comparison, not relying on hashes
+pub fn same_content(a_path: &Path, b_path: &Path) -> io::Result<bool> {
+    let mut a = fs::File::open(a_path)?;
+    let mut b = fs::File::open(b_path)?;
+    if a.metadata()?.len() != b.metadata()?.len() {
+        return Ok(false);
+    }
+    let mut a_buf = vec![0; 1 << 20];
+    let mut b_buf = vec![0; 1 << 20];
+    loop {
+        let len = read_full(&mut a, &mut a_buf)?;
+        if len != read_full(&mut b, &mut b_buf[..len])? || a_buf[..len] != b_buf[..len] {
+            return Ok(false);
+        }
+        if len < a_buf.len() {
+            // The other file must end here too
+            return Ok(b.read(&mut b_buf[..1])? == 0);
+        }
+    }
+}
+
+/// Whether all of `a` is the beginning of `b`
+pub fn is_prefix(a_path: &Path, b_path: &Path) -> io::Result<bool> {
+    let mut a = fs::File::open(a_path)?;
+    let mut b = fs::File::open(b_path)?;
+    if a.metadata()?.len() > b.metadata()?.len() {
+        return Ok(false);
+    }
+    let mut a_buf = vec![0; 1 << 20];
+    let mut b_buf = vec![0; 1 << 20];
+    loop {
+        let len = read_full(&mut a, &mut a_buf)?;
+        if len != read_full(&mut b, &mut b_buf[..len])? || a_buf[..len] != b_buf[..len] {
+            return Ok(false);
+        }
+        if len < a_buf.len() {
+            return Ok(true);
+        }
+    }
+}
+
+/// Like `read_exact`, but the end of file is not an error
+fn read_full(file: &mut fs::File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn compare() {
+        let tmp = tempdir::TempDir::new("verifytest").unwrap();
+        let write = |name: &str, data: &[u8]| {
+            let path = tmp.path().join(name);
+            fs::File::create(&path).unwrap().write_all(data).unwrap();
+            path
+        };
+        let a = write("a", b"same");
+        let b = write("b", b"same");
+        let c = write("c", b"diff");
+        assert!(same_content(&a, &b).unwrap());
+        assert!(!same_content(&a, &c).unwrap());
+
+        let longer = write("longer", b"same, but longer");
+        assert!(is_prefix(&a, &longer).unwrap());
+        assert!(is_prefix(&a, &b).unwrap());
+        assert!(!is_prefix(&longer, &a).unwrap());
+        assert!(!is_prefix(&c, &longer).unwrap());
+    }
+}
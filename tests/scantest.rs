@@ -1,9 +1,15 @@
 extern crate tempdir;
 extern crate duplicate_kriller;
+extern crate libc;
+#[cfg(feature = "json")]
+extern crate serde_json;
 
 use duplicate_kriller::*;
+use std::ffi::OsStr;
 use std::io::Write;
 use std::fs;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::PathBuf;
 use tempdir::TempDir;
 
 #[test]
@@ -41,3 +47,3023 @@ fn scan_hardlink() {
     assert_eq!(dupes.len(), 1);
     assert_eq!(dupes[0].paths.len(), 2);
 }
+
+#[test]
+fn follow_named_symlink() {
+    let dir = TempDir::new("symlinktest").unwrap();
+    let scanned = dir.path().join("scanned");
+    let pool = dir.path().join("pool");
+    fs::create_dir(&scanned).unwrap();
+    fs::create_dir(&pool).unwrap();
+
+    fs::File::create(scanned.join("a")).unwrap().write_all(b"dupe").unwrap();
+    fs::File::create(pool.join("b")).unwrap().write_all(b"dupe").unwrap();
+    ::std::os::unix::fs::symlink(&pool, scanned.join("latest")).unwrap();
+    ::std::os::unix::fs::symlink(&pool, scanned.join("other")).unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.settings.follow_symlink_names.insert("latest".into());
+    d.scan(&scanned).unwrap();
+    let dupes: Vec<_> = d.dupes().into_iter().filter(|f| f.paths.len() > 1).collect();
+    assert_eq!(dupes.len(), 1);
+    assert_eq!(dupes[0].paths.len(), 2);
+}
+
+#[test]
+fn follow_symlinks() {
+    let dir = TempDir::new("followtest").unwrap();
+    let (scanned, pool) = (dir.path().join("scanned"), dir.path().join("pool"));
+    fs::create_dir(&scanned).unwrap();
+    fs::create_dir(&pool).unwrap();
+    fs::write(scanned.join("a"), "dupe").unwrap();
+    fs::write(pool.join("b"), "dupe").unwrap();
+    ::std::os::unix::fs::symlink(&pool, scanned.join("pool")).unwrap();
+    // Loops back to the root and to itself
+    ::std::os::unix::fs::symlink(&scanned, pool.join("back")).unwrap();
+    ::std::os::unix::fs::symlink(&pool, pool.join("again")).unwrap();
+
+    for &follow in &[false, true] {
+        let mut d = Scanner::new();
+        d.settings.ignore_small = false;
+        d.settings.dry_run = true;
+        d.settings.follow_symlinks = follow;
+        d.scan(&scanned).unwrap();
+        let stats = d.stats();
+        let expected = if follow {(1, 1, 2)} else {(0, 0, 1)};
+        assert_eq!((stats.dupes, stats.symlinks_followed, stats.symlinks_skipped), expected);
+    }
+}
+
+#[test]
+fn duplicate_subtrees() {
+    let dir = TempDir::new("subtreetest").unwrap();
+    for copy in &["a", "b"] {
+        let sub = dir.path().join(copy).join("sub");
+        fs::create_dir_all(&sub).unwrap();
+        fs::File::create(sub.join("x")).unwrap().write_all(b"x").unwrap();
+        fs::File::create(dir.path().join(copy).join("y")).unwrap().write_all(b"y").unwrap();
+    }
+    fs::create_dir(dir.path().join("c")).unwrap();
+    fs::File::create(dir.path().join("c").join("y")).unwrap().write_all(b"y").unwrap();
+    fs::File::create(dir.path().join("c").join("z")).unwrap().write_all(b"z").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.scan(dir.path()).unwrap();
+    let root = fs::canonicalize(dir.path()).unwrap();
+    assert_eq!(d.duplicate_subtrees(), vec![vec![root.join("a"), root.join("b")]]);
+}
+
+#[test]
+fn unique_bloom() {
+    let dir = TempDir::new("bloomtest").unwrap();
+    let files = dir.path().join("files");
+    fs::create_dir(&files).unwrap();
+    let filter = dir.path().join("unique.bloom");
+    fs::File::create(files.join("a")).unwrap().write_all(b"aaaa").unwrap();
+    fs::File::create(files.join("b")).unwrap().write_all(b"bbbb").unwrap();
+
+    let scan = || {
+        let mut d = Scanner::new();
+        d.settings.ignore_small = false;
+        d.settings.dry_run = true;
+        d.settings.unique_bloom = Some(filter.clone());
+        d.scan(&files).unwrap();
+        d.stats()
+    };
+
+    assert_eq!(scan().known_unique, 0);
+    assert_eq!(scan().known_unique, 2);
+
+    fs::File::create(files.join("c")).unwrap().write_all(b"aaaa").unwrap();
+    let stats = scan();
+    assert_eq!(stats.known_unique, 1);
+    assert_eq!(stats.dupes, 1);
+}
+
+#[test]
+fn choose_master() {
+    let dir = TempDir::new("mastertest").unwrap();
+    fs::File::create(dir.path().join("a")).unwrap().write_all(b"dupe").unwrap();
+    fs::File::create(dir.path().join("b")).unwrap().write_all(b"dupe").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.choose_master = Some(Box::new(|sets: &[FileSet]| {
+        sets.iter().position(|set| set.paths[0].ends_with("b")).unwrap()
+    }));
+    d.scan(dir.path()).unwrap();
+    let dupes: Vec<_> = d.dupes().into_iter().filter(|f| f.paths.len() > 1).collect();
+    assert!(dupes[0].paths[0].ends_with("b"));
+}
+
+#[test]
+fn permission_mismatch() {
+    let dir = TempDir::new("permtest").unwrap();
+    for name in &["a", "b"] {
+        fs::write(dir.path().join(name), "dupe").unwrap();
+    }
+    fs::set_permissions(dir.path().join("b"), fs::Permissions::from_mode(0o600)).unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().permission_mismatches, 1);
+    assert_eq!(d.stats().created_hardlinks, 0);
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.ignore_permissions = true;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().permission_mismatches, 0);
+    assert_eq!(d.stats().created_hardlinks, 1);
+}
+
+#[test]
+fn source_policy() {
+    let dir = TempDir::new("sourcetest").unwrap();
+    let root = fs::canonicalize(dir.path()).unwrap();
+    fs::create_dir_all(root.join("downloads/tmp")).unwrap();
+    fs::create_dir(root.join("music")).unwrap();
+    fs::write(root.join("downloads/tmp/song"), "dupe").unwrap();
+    fs::hard_link(root.join("downloads/tmp/song"), root.join("downloads/tmp/song2")).unwrap();
+    fs::write(root.join("music/song"), "dupe").unwrap();
+    let master = |d: &Scanner| d.dupes().into_iter().find(|f| f.paths.len() > 1).unwrap().paths[0].clone();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.scan(&root).unwrap();
+    assert!(master(&d).starts_with(root.join("downloads")));
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.settings.source_policy = SourcePolicy::ShortestPath;
+    d.scan(&root).unwrap();
+    assert_eq!(master(&d), root.join("music/song"));
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.settings.source_policy = SourcePolicy::Custom(Box::new(|paths: &[PathBuf]| paths.len()));
+    d.scan(&root).unwrap();
+    assert_eq!(d.errors()[0].1.kind(), std::io::ErrorKind::InvalidInput);
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.source_policy = SourcePolicy::Custom(Box::new(|paths: &[PathBuf]| {
+        paths.iter().position(|path| path.ends_with("music/song")).unwrap_or(0)
+    }));
+    let ino = fs::metadata(root.join("music/song")).unwrap().ino();
+    d.scan(&root).unwrap();
+    assert_eq!(d.stats().created_hardlinks, 2);
+    assert_eq!(fs::metadata(root.join("downloads/tmp/song2")).unwrap().ino(), ino);
+}
+
+#[test]
+fn master_path_prefix() {
+    let dir = TempDir::new("prefixtest").unwrap();
+    let root = fs::canonicalize(dir.path()).unwrap();
+    fs::create_dir(root.join("a")).unwrap();
+    fs::create_dir(root.join("originals")).unwrap();
+    fs::File::create(root.join("a/1")).unwrap().write_all(b"dupe").unwrap();
+    fs::hard_link(root.join("a/1"), root.join("a/2")).unwrap();
+    fs::File::create(root.join("originals/1")).unwrap().write_all(b"dupe").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.master_path_prefix = Some(root.join("originals"));
+    d.scan(&root).unwrap();
+    let dupes: Vec<_> = d.dupes().into_iter().filter(|f| f.paths.len() > 1).collect();
+    assert_eq!(dupes.len(), 1);
+    assert_eq!(dupes[0].paths[0], root.join("originals/1"));
+}
+
+#[test]
+fn master_by_creation_time() {
+    let dir = TempDir::new("btimetest").unwrap();
+    let root = fs::canonicalize(dir.path()).unwrap();
+    fs::File::create(root.join("old")).unwrap().write_all(b"dupe").unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    fs::File::create(root.join("new")).unwrap().write_all(b"dupe").unwrap();
+    let created = |name| fs::metadata(root.join(name)).and_then(|m| m.created()).ok();
+    if created("old").is_none() || created("old") == created("new") {
+        // The filesystem doesn't have birth times
+        return;
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.settings.master_by_creation_time = true;
+    d.scan(&root).unwrap();
+    let dupes: Vec<_> = d.dupes().into_iter().filter(|f| f.paths.len() > 1).collect();
+    assert_eq!(dupes[0].paths[0], root.join("old"));
+}
+
+#[test]
+fn compare_from_tail() {
+    let dir = TempDir::new("tailtest").unwrap();
+    let mut content = vec![b'x'; 100000];
+    fs::File::create(dir.path().join("a")).unwrap().write_all(&content).unwrap();
+    fs::File::create(dir.path().join("b")).unwrap().write_all(&content).unwrap();
+    content[0] = b'y';
+    fs::File::create(dir.path().join("c")).unwrap().write_all(&content).unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.dry_run = true;
+    d.settings.compare_from_tail = true;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().dupes, 1);
+    // a and b fully, and one chunk of c and whichever it was compared to
+    assert!(d.stats().bytes_read >= 200000);
+
+    d.settings.compare_from_tail = false;
+    assert!(d.scan(dir.path()).is_err());
+}
+
+#[test]
+fn only_own_files() {
+    let dir = TempDir::new("ownertest").unwrap();
+    fs::File::create(dir.path().join("a")).unwrap().write_all(b"dupe").unwrap();
+    fs::File::create(dir.path().join("b")).unwrap().write_all(b"dupe").unwrap();
+    fs::File::create(dir.path().join("c")).unwrap().write_all(b"dupe").unwrap();
+    // Only root can give files away
+    let given_away = std::os::unix::fs::chown(dir.path().join("c"), Some(12345), None).is_ok();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.settings.only_own_files = true;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().dupes, 1);
+    assert_eq!(d.stats().not_owned_skipped, if given_away {1} else {0});
+}
+
+#[test]
+fn cow_across_owners() {
+    let dir = TempDir::new("cowownertest").unwrap();
+    fs::File::create(dir.path().join("a")).unwrap().write_all(b"dupe").unwrap();
+    fs::File::create(dir.path().join("b")).unwrap().write_all(b"dupe").unwrap();
+    if std::os::unix::fs::chown(dir.path().join("b"), Some(12345), None).is_err() {
+        return; // Only root can give files away
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.cow_across_owners = true;
+    // Fails where reflinks aren't supported, but never hardlinks them
+    d.scan(dir.path()).ok();
+    let a = fs::metadata(dir.path().join("a")).unwrap();
+    let b = fs::metadata(dir.path().join("b")).unwrap();
+    assert_ne!(a.ino(), b.ino());
+    assert_eq!(b.uid(), 12345);
+    assert_eq!(fs::read(dir.path().join("b")).unwrap(), b"dupe");
+}
+
+#[test]
+fn max_links_per_master() {
+    let dir = TempDir::new("masterlimittest").unwrap();
+    let names = ["a", "b", "c", "d", "e"];
+    for name in &names {
+        fs::write(dir.path().join(name), "dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.max_links_per_master = Some(2);
+    for name in &names {
+        let path = dir.path().join(name);
+        let metadata = fs::symlink_metadata(&path).unwrap();
+        d.add_with_metadata(path, metadata).unwrap();
+    }
+    let nlinks: Vec<_> = names.iter().map(|name| fs::metadata(dir.path().join(name)).unwrap().nlink()).collect();
+    assert_eq!(nlinks, vec![2, 2, 2, 2, 1]);
+    assert_eq!(d.stats().extra_masters, 1);
+}
+
+#[test]
+fn simulate_link_cap() {
+    let dir = TempDir::new("simulatecaptest").unwrap();
+    for name in &["a", "b", "c", "d", "e"] {
+        fs::write(dir.path().join(name), "dupe").unwrap();
+    }
+    fs::write(dir.path().join("other"), "other").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.simulate_link_cap(2), SimulationResult {masters: 3, reclaimable_bytes: 8, uncapped_reclaimable_bytes: 16});
+    assert_eq!(d.simulate_link_cap(5), SimulationResult {masters: 1, reclaimable_bytes: 16, uncapped_reclaimable_bytes: 16});
+    assert_eq!(d.simulate_link_cap(1).reclaimable_bytes, 0);
+}
+
+#[test]
+fn min_confidence() {
+    let dir = TempDir::new("confidencetest").unwrap();
+    let a_path = dir.path().join("a");
+    let b_path = dir.path().join("b");
+    fs::write(&a_path, "dupe").unwrap();
+    fs::write(&b_path, "dupe").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.dry_run_report().links[0].confidence, Confidence::Hashes);
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.min_confidence = Confidence::Bytes;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().low_confidence_skipped, 1);
+    assert_ne!(fs::metadata(&a_path).unwrap().ino(), fs::metadata(&b_path).unwrap().ino());
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.min_confidence = Confidence::Bytes;
+    d.settings.verify_content = true;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().low_confidence_skipped, 0);
+    assert_eq!(fs::metadata(&a_path).unwrap().ino(), fs::metadata(&b_path).unwrap().ino());
+}
+
+#[test]
+fn max_group_members() {
+    let dir = TempDir::new("grouplimittest").unwrap();
+    let root = dir.path();
+    fs::File::create(root.join("a")).unwrap().write_all(b"dupe").unwrap();
+    // Not added, so that a stays the master
+    fs::hard_link(root.join("a"), root.join("a2")).unwrap();
+    fs::File::create(root.join("b")).unwrap().write_all(b"dupe").unwrap();
+    fs::hard_link(root.join("b"), root.join("b2")).unwrap();
+    fs::File::create(root.join("c")).unwrap().write_all(b"dupe").unwrap();
+
+    let sets_with_paths = |limit| {
+        let mut d = Scanner::new();
+        d.settings.ignore_small = false;
+        d.settings.dry_run = true;
+        d.settings.max_group_members = limit;
+        // b2 joins b's set after b has been merged, so c's dupe has two paths to merge
+        for name in &["a", "b", "b2", "c"] {
+            let path = root.join(name);
+            let metadata = fs::symlink_metadata(&path).unwrap();
+            d.add_with_metadata(path, metadata).unwrap();
+        }
+        let mut sets: Vec<_> = d.dupes().into_iter().map(|f| f.paths.len()).filter(|&n| n > 0).collect();
+        sets.sort();
+        sets
+    };
+    assert_eq!(sets_with_paths(None), vec![4]);
+    assert_eq!(sets_with_paths(Some(1)), vec![1, 3]);
+}
+
+#[test]
+fn dupes_not_linked() {
+    let dir = TempDir::new("guardtest").unwrap();
+    for name in &["a", "b", "c"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(b"dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.min_savings_per_group = 1000;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().dupes, 2);
+    // One duplicate is skipped when the 2nd file is found, and two with the 3rd
+    assert_eq!(d.stats().dupes_not_linked, 3);
+    assert_eq!(d.stats().created_hardlinks, 0);
+}
+
+#[test]
+fn min_savings_per_group() {
+    let dir = TempDir::new("savingstest").unwrap();
+    for name in &["a", "b", "c"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(b"dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.settings.min_savings_per_group = 8;
+    d.scan(dir.path()).unwrap();
+    // Only the third copy makes it worth 8 bytes
+    assert_eq!(d.stats().low_savings_skipped, 1);
+    let dupes: Vec<_> = d.dupes().into_iter().filter(|f| !f.paths.is_empty()).collect();
+    assert_eq!(dupes.len(), 1);
+    assert_eq!(dupes[0].paths.len(), 3);
+}
+
+#[test]
+fn digest_cache() {
+    let dir = TempDir::new("digesttest").unwrap();
+    let root = fs::canonicalize(dir.path()).unwrap();
+    fs::File::create(root.join("a")).unwrap().write_all(b"dupe").unwrap();
+    fs::File::create(root.join("b")).unwrap().write_all(b"dupe").unwrap();
+    fs::File::create(root.join("c")).unwrap().write_all(b"uniq").unwrap();
+
+    let mut cache = DigestCache::new();
+    for name in &["a", "b"] {
+        cache.insert((4, root.join(name)), ContentDigest::from_path(root.join(name)).unwrap());
+    }
+    // A stale digest is ignored
+    let mut stale = ContentDigest::from_path(root.join("a")).unwrap();
+    stale.mtime.0 -= 1;
+    cache.insert((4, root.join("c")), stale);
+
+    let mut d = Scanner::with_digest_cache(cache);
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.scan(&root).unwrap();
+    assert_eq!(d.stats().cached_digests, 2);
+    assert_eq!(d.stats().dupes, 1);
+    assert_eq!(d.stats().bytes_read, 4);
+}
+
+#[test]
+fn hash_cache() {
+    let dir = TempDir::new("hashcachetest").unwrap();
+    let root = fs::canonicalize(dir.path()).unwrap();
+    let cache_path = root.join("cache");
+    let an_hour_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+    for &(name, content) in &[("a", "dupe"), ("b", "dupe"), ("c", "uniq")] {
+        let mut file = fs::File::create(root.join(name)).unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        file.set_modified(an_hour_ago).unwrap();
+    }
+    let scan = || {
+        let mut d = Scanner::new();
+        d.settings.ignore_small = false;
+        d.settings.dry_run = true;
+        d.load_cache(&cache_path).unwrap();
+        d.scan(&root).unwrap();
+        d.save_cache(&cache_path).unwrap();
+        d
+    };
+    let d = scan();
+    assert_eq!((d.stats().cached_digests, d.stats().bytes_read), (0, 12));
+    let d = scan();
+    assert_eq!((d.stats().cached_digests, d.stats().bytes_read), (3, 0));
+    assert_eq!(d.stats().dupes, 1);
+
+    // A changed mtime means it's read again, even if the size is the same
+    fs::write(root.join("c"), "dupe").unwrap();
+    let d = scan();
+    assert_eq!((d.stats().cached_digests, d.stats().bytes_read), (2, 4));
+    assert_eq!(d.stats().dupes, 2);
+    fs::remove_file(root.join("c")).unwrap();
+    let d = scan();
+    assert_eq!((d.stats().cached_digests, d.stats().bytes_read), (2, 0));
+}
+
+#[test]
+fn link_anomaly_check() {
+    let dir = TempDir::new("anomalytest").unwrap();
+    for name in &["a", "b", "c"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(b"dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.on_link_anomaly = OnLinkAnomaly::Error;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().link_anomalies, 0);
+    assert_eq!(fs::metadata(dir.path().join("a")).unwrap().nlink(), 3);
+}
+
+#[test]
+fn protected_inodes() {
+    let dir = TempDir::new("protecttest").unwrap();
+    for name in &["a", "b", "c"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(b"dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.protect_path(dir.path().join("b")).unwrap();
+    // In this order a is the master
+    for name in &["a", "b", "c"] {
+        let path = dir.path().join(name);
+        let metadata = fs::symlink_metadata(&path).unwrap();
+        d.add_with_metadata(path, metadata).unwrap();
+    }
+    let links = |name| fs::metadata(dir.path().join(name)).unwrap().nlink();
+    assert_eq!(links("a"), 2);
+    assert_eq!(links("b"), 1);
+    assert_eq!(links("c"), 2);
+    assert_eq!(d.stats().protected_skipped, 2);
+    assert_eq!(d.stats().dupes_not_linked, 2);
+}
+
+#[test]
+fn auto_tune() {
+    let dir = TempDir::new("tunetest").unwrap();
+    fs::create_dir_all(dir.path().join("x/y")).unwrap();
+    fs::File::create(dir.path().join("x/a")).unwrap().write_all(b"dupe").unwrap();
+    fs::File::create(dir.path().join("x/y/b")).unwrap().write_all(b"dupe").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.settings.auto_tune = true;
+    d.settings.inode_order = Some(false);
+    d.settings.max_read_size = Some(1);
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().dupes, 1);
+}
+
+#[test]
+fn mmap_threshold() {
+    let dir = TempDir::new("mmaptest").unwrap();
+    let mut big = vec![b'm'; 100_000];
+    for name in &["a", "b"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(&big).unwrap();
+    }
+    big[99_999] = b'!';
+    fs::File::create(dir.path().join("c")).unwrap().write_all(&big).unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.dry_run = true;
+    d.settings.use_mmap_threshold = Some(1);
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().dupes, 1);
+}
+
+#[test]
+fn pending_directories() {
+    let a = TempDir::new("pendingtest").unwrap();
+    let b = TempDir::new("pendingtest").unwrap();
+
+    let mut d = Scanner::new();
+    assert_eq!(d.pending_directories(), 0);
+    d.enqueue(a.path()).unwrap();
+    d.enqueue(b.path()).unwrap();
+    assert_eq!(d.pending_directories(), 2);
+    assert!(d.current_directory().is_none());
+    d.flush().unwrap();
+    assert_eq!(d.pending_directories(), 0);
+    assert!(d.current_directory().is_none());
+}
+
+#[test]
+fn skip_partial_downloads() {
+    let dir = TempDir::new("partialtest").unwrap();
+    for name in &["a", "b.part", "c.CRDOWNLOAD", "d.wip"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(b"dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.settings.skip_partial_downloads = true;
+    d.settings.partial_suffixes.push(".wip".into());
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().partial_skipped, 3);
+    assert_eq!(d.stats().added, 1);
+    assert_eq!(d.stats().dupes, 0);
+}
+
+#[test]
+fn skip_externally_linked() {
+    for &skip in &[false, true] {
+        let dir = TempDir::new("externaltest").unwrap();
+        let (root, outside) = (dir.path().join("root"), dir.path().join("outside"));
+        fs::create_dir(&root).unwrap();
+        fs::create_dir(&outside).unwrap();
+        for name in &["a", "b", "c"] {
+            fs::File::create(root.join(name)).unwrap().write_all(b"dupe").unwrap();
+        }
+        fs::hard_link(root.join("a"), outside.join("a")).unwrap();
+        fs::hard_link(root.join("b"), outside.join("b")).unwrap();
+
+        let mut d = Scanner::new();
+        d.settings.ignore_small = false;
+        d.settings.skip_externally_linked = skip;
+        d.scan(&root).unwrap();
+        // One of a and b is the master, and only c has no other links
+        assert_eq!(d.stats().created_hardlinks, if skip {1} else {2});
+        assert_eq!(d.stats().external_link_skipped, if skip {1} else {0});
+        assert_eq!(fs::metadata(root.join("c")).unwrap().nlink(), if skip {3} else {4});
+    }
+}
+
+#[test]
+fn confine_to_roots() {
+    let root = TempDir::new("confinetest").unwrap();
+    let outside = TempDir::new("confinetest").unwrap();
+    fs::File::create(root.path().join("a")).unwrap().write_all(b"dupe").unwrap();
+    fs::File::create(outside.path().join("b")).unwrap().write_all(b"dupe").unwrap();
+    std::os::unix::fs::symlink(outside.path(), root.path().join("escape")).unwrap();
+
+    let mut plan = None;
+    for &confine in &[false, true] {
+        let mut d = Scanner::new();
+        d.settings.ignore_small = false;
+        d.settings.dry_run = true;
+        d.settings.follow_symlink_names.insert("escape".into());
+        d.settings.confine_to_roots = confine;
+        d.scan(root.path()).unwrap();
+        assert_eq!(d.stats().dupes, if confine {0} else {1});
+        assert_eq!(d.stats().outside_roots, if confine {1} else {0});
+        assert_eq!(d.errors().len(), d.stats().outside_roots);
+        plan.get_or_insert_with(|| d.dry_run_report().clone());
+    }
+
+    // Linking is checked too
+    let mut d = Scanner::new();
+    d.settings.confine_to_roots = true;
+    d.enqueue(root.path()).unwrap();
+    let stats = d.apply_plan(&plan.unwrap()).unwrap();
+    assert_eq!(stats.outside_roots, 1);
+    assert_eq!(stats.dupes, 0);
+    assert_eq!(fs::metadata(outside.path().join("b")).unwrap().nlink(), 1);
+}
+
+#[test]
+fn unique_sizes_are_not_read() {
+    let dir = TempDir::new("sizetest").unwrap();
+    for i in 1..20 {
+        fs::File::create(dir.path().join(i.to_string())).unwrap().write_all(&vec![b'x'; i]).unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().added, 19);
+    assert_eq!(d.stats().bytes_read, 0);
+}
+
+#[test]
+fn stable_order() {
+    let dir = TempDir::new("stabletest").unwrap();
+    for sub in &["b", "a", "a/z", "c"] {
+        fs::create_dir(dir.path().join(sub)).unwrap();
+    }
+    for path in &["c/1", "a/z/1", "b/2", "b/1", "a/1"] {
+        fs::File::create(dir.path().join(path)).unwrap().write_all(b"dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.settings.stable_order = true;
+    d.scan(dir.path()).unwrap();
+    let found: Vec<_> = d.dry_run_report().links.iter()
+        .map(|link| link.dest.strip_prefix(dir.path()).unwrap().to_owned()).collect();
+    let expected: Vec<PathBuf> = ["a/z/1", "b/1", "b/2", "c/1"].iter().map(PathBuf::from).collect();
+    assert_eq!(found, expected);
+    assert!(d.dry_run_report().links.iter().all(|link| link.source == dir.path().join("a/1")));
+}
+
+#[test]
+fn enqueue_with_sizes() {
+    let dir = TempDir::new("sizelisttest").unwrap();
+    for &(name, content) in &[("a", "dupe"), ("b", "dupe"), ("c", "uniq!"), ("d", "dupe"), ("e", "different")] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(content.as_bytes()).unwrap();
+    }
+    // d has changed since it was listed, and "gone" has been deleted
+    let mut list = Vec::new();
+    for &(name, size) in &[("a", 4), ("b", 4), ("c", 5), ("d", 9), ("e", 9), ("gone", 4)] {
+        writeln!(list, "{}\t{}", dir.path().join(name).display(), size).unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.enqueue_with_sizes(&list[..]).unwrap();
+    assert_eq!(d.stats().listed_unique_skipped, 1);
+    assert_eq!(d.stats().listed_size_changed, 1);
+    assert_eq!(d.stats().added, 4);
+    assert_eq!(d.stats().dupes, 2);
+    assert_eq!(d.errors().len(), 1);
+
+    let err = Scanner::new().enqueue_with_sizes(&b"no size here\n"[..]).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn clean_orphans() {
+    let dir = TempDir::new("orphantest").unwrap();
+    let temp = dir.path().join(".tmp-dupe-e1iIQcBFn5pC4MUSm-xkcd-221");
+    fs::File::create(dir.path().join("a")).unwrap().write_all(b"dupe").unwrap();
+    fs::hard_link(dir.path().join("a"), &temp).unwrap();
+
+    for &(dry_run, clean) in &[(false, false), (true, true), (false, true)] {
+        let mut d = Scanner::new();
+        d.settings.ignore_small = false;
+        d.settings.dry_run = dry_run;
+        d.settings.clean_orphans = clean;
+        d.scan(dir.path()).unwrap();
+        assert_eq!(d.stats().orphan_temps, 1);
+        assert_eq!(d.stats().skipped, 1);
+        assert_eq!(d.stats().existing_hardlinks, 0);
+        assert_eq!(temp.exists(), !clean || dry_run);
+    }
+
+    // The only copy of its content
+    fs::File::create(&temp).unwrap().write_all(b"changed").unwrap();
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.clean_orphans = true;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().orphan_temps, 1);
+    assert_eq!(d.errors().len(), 1);
+    assert!(temp.exists());
+}
+
+#[test]
+fn temp_names_of_other_runs() {
+    let dir = TempDir::new("othertemptest").unwrap();
+    let running = dir.path().join(format!(".tmp-dupe-e1iIQcBFn5pC4MUSm-xkcd-221.{}.77", std::process::id()));
+    let crashed = dir.path().join(".tmp-dupe-e1iIQcBFn5pC4MUSm-xkcd-221.999999999.1");
+    for name in &["a", "b"] {
+        fs::write(dir.path().join(name), "dupe").unwrap();
+    }
+    fs::hard_link(dir.path().join("a"), &running).unwrap();
+    fs::hard_link(dir.path().join("a"), &crashed).unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.clean_orphans = true;
+    d.scan(dir.path()).unwrap();
+    assert_eq!((d.stats().orphan_temps, d.stats().created_hardlinks), (1, 1));
+    assert!(running.exists());
+    assert!(!crashed.exists());
+    // No temporary files of this run are left
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+}
+
+#[test]
+fn hash_only() {
+    let dir = TempDir::new("indextest").unwrap();
+    fs::create_dir(dir.path().join("files")).unwrap();
+    fs::File::create(dir.path().join("files/a")).unwrap().write_all(b"abc").unwrap();
+    fs::File::create(dir.path().join("files/b")).unwrap().write_all(b"abc").unwrap();
+    fs::File::create(dir.path().join("files/empty")).unwrap();
+    let progress_path = dir.path().join("progress");
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.hash_only = true;
+    d.set_listener(Box::new(FdProgressListener::new(fs::File::create(&progress_path).unwrap())));
+    d.scan(dir.path().join("files")).unwrap();
+    assert_eq!(d.stats().indexed, 2);
+    assert_eq!(d.stats().dupes, 0);
+    assert!(d.dupes().is_empty());
+    assert_eq!(fs::metadata(dir.path().join("files/a")).unwrap().nlink(), 1);
+
+    let progress = fs::read_to_string(&progress_path).unwrap();
+    let indexed: Vec<_> = progress.lines().filter(|l| l.starts_with("indexed\t")).collect();
+    assert_eq!(indexed.len(), 2);
+    assert!(indexed[0].starts_with("indexed\t3\tba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad\t"));
+}
+
+#[test]
+fn path_aliases() {
+    let dir = TempDir::new("aliastest").unwrap();
+    fs::File::create(dir.path().join("foo")).unwrap().write_all(b"dupe").unwrap();
+    // Two entries on a case-sensitive filesystem, so they're real hardlinks
+    let case_sensitive = fs::hard_link(dir.path().join("foo"), dir.path().join("Foo")).is_ok();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.scan(dir.path()).unwrap();
+    // The same entry again
+    d.scan(dir.path()).unwrap();
+    let metadata = fs::symlink_metadata(dir.path().join("FOO"));
+    if let Ok(metadata) = metadata {
+        // Case-insensitive
+        d.add_with_metadata(dir.path().join("FOO"), metadata).unwrap();
+    }
+
+    let dupes = d.dupes();
+    assert_eq!(dupes.len(), 1);
+    assert_eq!(dupes[0].paths.len(), if case_sensitive {2} else {1});
+    assert_eq!(d.stats().existing_hardlinks, if case_sensitive {1} else {0});
+    assert_eq!(d.stats().dupes, 0);
+    assert!(d.stats().path_aliases >= 2);
+}
+
+#[test]
+fn max_ops_per_sec() {
+    let dir = TempDir::new("ratetest").unwrap();
+    for name in &["a", "b", "c", "d"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(b"dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.max_ops_per_sec = Some(20);
+    let start = std::time::Instant::now();
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().created_hardlinks, 3);
+    // The first one is free, and then 50ms each
+    assert!(start.elapsed() >= std::time::Duration::from_millis(100));
+}
+
+#[test]
+fn verify_after_link() {
+    for &verify in &[false, true] {
+        let dir = TempDir::new("afterlinktest").unwrap();
+        for name in &["a", "b"] {
+            fs::File::create(dir.path().join(name)).unwrap().write_all(b"dupe").unwrap();
+        }
+        let progress_path = dir.path().join("progress");
+
+        let mut d = Scanner::new();
+        d.settings.ignore_small = false;
+        d.settings.verify_after_link = verify;
+        // Changes the master behind the scanner's back, like a bad disk would
+        d.settings.equality_oracle = Some(Box::new(|_, master| {
+            fs::write(master, b"epud")?;
+            Ok(true)
+        }));
+        d.set_listener(Box::new(FdProgressListener::new(fs::File::create(&progress_path).unwrap())));
+        d.scan(dir.path().join("a")).unwrap();
+        d.scan(dir.path().join("b")).unwrap();
+        assert_eq!(d.stats().created_hardlinks, 1);
+        assert_eq!(d.stats().corruption_detected, if verify {1} else {0});
+        let progress = fs::read_to_string(&progress_path).unwrap();
+        assert_eq!(progress.lines().filter(|l| l.starts_with("corruption\t")).count(), if verify {1} else {0});
+    }
+}
+
+#[test]
+fn equality_oracle() {
+    let dir = TempDir::new("oracletest").unwrap();
+    for name in &["a", "b", "keep"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(b"dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.equality_oracle = Some(Box::new(|dupe, master| Ok(!dupe.ends_with("keep") && !master.ends_with("keep"))));
+    for name in &["a", "b", "keep"] {
+        let path = dir.path().join(name);
+        let metadata = fs::symlink_metadata(&path).unwrap();
+        d.add_with_metadata(path, metadata).unwrap();
+    }
+    assert_eq!(d.stats().created_hardlinks, 1);
+    assert_eq!(d.stats().verify_mismatches, 1);
+    assert_eq!(fs::metadata(dir.path().join("keep")).unwrap().nlink(), 1);
+}
+
+#[test]
+fn directories() {
+    let dir = TempDir::new("dirstest").unwrap();
+    fs::create_dir_all(dir.path().join("a/b")).unwrap();
+    fs::create_dir(dir.path().join("c")).unwrap();
+
+    let mut d = Scanner::new();
+    d.enqueue(dir.path().join("a")).unwrap();
+    d.enqueue(dir.path()).unwrap();
+    d.flush().unwrap();
+    assert_eq!(d.stats().directories, 4);
+}
+
+#[test]
+fn repair() {
+    let dir = TempDir::new("repairtest").unwrap();
+    for name in &["a", "b", "c"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(b"dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(fs::metadata(dir.path().join("a")).unwrap().nlink(), 3);
+
+    // Saved by an editor that writes a new file: one unchanged, one changed
+    let dupes = d.dupes();
+    let master = dupes.iter().find(|set| !set.paths.is_empty()).unwrap().paths[0].clone();
+    let others: Vec<_> = ["a", "b", "c"].iter().map(|name| dir.path().join(name)).filter(|p| *p != master).collect();
+    for (path, content) in others.iter().zip(&[&b"dupe"[..], &b"edit"[..]]) {
+        fs::remove_file(path).unwrap();
+        fs::File::create(path).unwrap().write_all(content).unwrap();
+    }
+
+    let stats = d.repair().unwrap();
+    assert_eq!(stats.repaired, 1);
+    assert_eq!(fs::metadata(&master).unwrap().nlink(), 2);
+    assert_eq!(fs::metadata(&others[1]).unwrap().nlink(), 1);
+}
+
+#[test]
+fn breadth_first() {
+    let dir = TempDir::new("bfstest").unwrap();
+    fs::create_dir_all(dir.path().join("a/deep/er")).unwrap();
+    fs::create_dir(dir.path().join("b")).unwrap();
+    fs::create_dir(dir.path().join("c")).unwrap();
+    for path in &["a/deep/er/f", "b/f", "c/f"] {
+        fs::File::create(dir.path().join(path)).unwrap().write_all(b"dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.settings.breadth_first = true;
+    d.scan(dir.path()).unwrap();
+    let links = &d.dry_run_report().links;
+    assert_eq!(links.len(), 2);
+    assert_eq!(links[1].dest, dir.path().join("a/deep/er/f"));
+}
+
+#[test]
+fn max_depth() {
+    let dir = TempDir::new("depthtest").unwrap();
+    fs::create_dir_all(dir.path().join("a/b/c")).unwrap();
+    for path in &["f", "a/f", "a/b/f", "a/b/c/f"] {
+        fs::write(dir.path().join(path), "dupe").unwrap();
+    }
+
+    for &(max_depth, dupes, too_deep) in &[(Some(2), 2, 1), (Some(0), 0, 1), (None, 3, 0)] {
+        let mut d = Scanner::new();
+        d.settings.ignore_small = false;
+        d.settings.dry_run = true;
+        d.settings.max_depth = max_depth;
+        d.scan(dir.path()).unwrap();
+        assert_eq!((d.stats().dupes, d.stats().too_deep), (dupes, too_deep), "{:?}", max_depth);
+    }
+}
+
+#[test]
+fn check_file() {
+    let dir = TempDir::new("checktest").unwrap();
+    let root = fs::canonicalize(dir.path()).unwrap();
+    fs::create_dir(root.join("scanned")).unwrap();
+    for &(path, content) in &[("scanned/a", "dupe"), ("scanned/b", "dupe"), ("c", "dupe"), ("d", "uniq")] {
+        fs::write(root.join(path), content).unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.scan(root.join("scanned")).unwrap();
+    let mut others = d.check_file(root.join("c")).unwrap().unwrap();
+    others.sort();
+    assert_eq!(others, vec![root.join("scanned/a"), root.join("scanned/b")]);
+    assert_eq!(fs::metadata(root.join("c")).unwrap().nlink(), 1);
+    assert_eq!(d.check_file(root.join("d")).unwrap(), None);
+    assert_eq!(d.stats().dupes, 2);
+    assert!(d.check_file(&root).is_err());
+}
+
+#[test]
+fn link_groups_from_reader() {
+    let dir = TempDir::new("groupstest").unwrap();
+    for &(name, content) in &[("a", "dupe"), ("b", "dupe"), ("c", "longer"), ("d", "same"), ("e", "size")] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(content.as_bytes()).unwrap();
+    }
+    let mut groups = Vec::new();
+    for name in &["a", "b", "c", "", "d", "e", "gone"] {
+        if name.is_empty() {
+            writeln!(groups).unwrap();
+        } else {
+            writeln!(groups, "{}", dir.path().join(name).display()).unwrap();
+        }
+    }
+
+    let mut d = Scanner::new();
+    d.settings.verify_content = true;
+    let stats = d.link_groups_from_reader(&groups[..]).unwrap();
+    assert_eq!(stats.created_hardlinks, 1);
+    assert_eq!(stats.verify_mismatches, 1);
+    assert_eq!(d.errors().len(), 1);
+    let links = |name| fs::metadata(dir.path().join(name)).unwrap().nlink();
+    assert_eq!((links("a"), links("b"), links("c"), links("d"), links("e")), (2, 2, 1, 1, 1));
+}
+
+#[test]
+fn scan_snapshot() {
+    let dir = TempDir::new("snapshottest").unwrap();
+    let root = dir.path().join("live");
+    fs::create_dir(&root).unwrap();
+    for name in &["a", "b", "c"] {
+        fs::File::create(root.join(name)).unwrap().write_all(b"dupe").unwrap();
+    }
+
+    let scanner = |snapshot_command: &[&str]| {
+        let mut d = Scanner::new();
+        d.settings.ignore_small = false;
+        d.settings.use_snapshot = true;
+        // A copy is as good as a snapshot here
+        d.settings.snapshot_command = snapshot_command.iter().map(|s| s.to_string()).collect();
+        d.settings.snapshot_delete_command = vec!["rm".into(), "-r".into(), "{dst}".into()];
+        d.settings.snapshot_path = Some(dir.path().join("snap"));
+        d
+    };
+    let mut d = scanner(&["cp", "-a", "{src}", "{dst}"]);
+    d.scan(&root).unwrap();
+    assert!(!dir.path().join("snap").exists());
+    let links = |name| fs::metadata(root.join(name)).unwrap().nlink();
+    assert_eq!((links("a"), links("b"), links("c")), (3, 3, 3));
+
+    // e changes after the snapshot is made
+    fs::File::create(root.join("d")).unwrap().write_all(b"dupe").unwrap();
+    fs::File::create(root.join("e")).unwrap().write_all(b"dupe").unwrap();
+    let mut d = scanner(&["sh", "-c", "cp -a {src} {dst} && echo edit > {src}/e"]);
+    let stats = d.scan_snapshot(&root).unwrap();
+    assert_eq!(stats.created_hardlinks, 1);
+    assert_eq!(stats.verify_mismatches + stats.plan_skipped, 1);
+    assert_eq!((links("d"), links("e")), (4, 1));
+}
+
+#[test]
+fn report_cross_device_single_device() {
+    let dir = TempDir::new("crossdevtest").unwrap();
+    for name in &["a", "b"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(b"dupe").unwrap();
+    }
+    fs::File::create(dir.path().join("c")).unwrap().write_all(b"uniq").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.report_cross_device = true;
+    d.scan(dir.path()).unwrap();
+    // Everything's on one device, so there's nothing another filesystem would save
+    assert_eq!(d.stats().created_hardlinks, 1);
+    assert_eq!(d.stats().cross_device_bytes, 0);
+}
+
+#[test]
+fn max_duration() {
+    let dir = TempDir::new("durationtest").unwrap();
+    let sub = dir.path().join("sub");
+    fs::create_dir(&sub).unwrap();
+    for name in &["a", "b"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(b"dupe").unwrap();
+        fs::File::create(sub.join(name)).unwrap().write_all(b"dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.max_duration = Some(std::time::Duration::from_secs(0));
+    d.scan(dir.path()).unwrap();
+    assert!(!d.completed());
+    assert_eq!(d.stats().added, 0);
+
+    // Picks up where it stopped
+    d.settings.max_duration = None;
+    d.flush().unwrap();
+    assert!(d.completed());
+    assert_eq!(d.stats().added, 4);
+    assert_eq!(d.stats().created_hardlinks, 3);
+}
+
+#[test]
+fn mtime_policy() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    for &(policy, secs) in &[(MtimePolicy::Oldest, 1000), (MtimePolicy::Newest, 3000)] {
+        let dir = TempDir::new("mtimetest").unwrap();
+        for (name, secs) in &[("a", 2000), ("b", 1000), ("c", 3000)] {
+            let path = dir.path().join(name);
+            fs::write(&path, "dupe").unwrap();
+            fs::File::open(&path).unwrap().set_modified(UNIX_EPOCH + Duration::from_secs(*secs)).unwrap();
+        }
+
+        let mut d = Scanner::new();
+        d.settings.ignore_small = false;
+        d.settings.mtime_policy = policy;
+        d.scan(dir.path()).unwrap();
+        assert_eq!(d.stats().created_hardlinks, 2);
+        let m = fs::metadata(dir.path().join("a")).unwrap();
+        assert_eq!((m.nlink(), m.mtime()), (3, secs), "{:?}", policy);
+    }
+}
+
+#[test]
+fn skip_sparse() {
+    let dir = TempDir::new("sparsetest").unwrap();
+    for name in &["a", "b", "c"] {
+        // Only a hole, so no blocks, if the filesystem supports sparse files
+        fs::File::create(dir.path().join(name)).unwrap().set_len(1 << 20).unwrap();
+    }
+    if fs::metadata(dir.path().join("a")).unwrap().blocks() != 0 {
+        return;
+    }
+
+    let mut d = Scanner::new();
+    d.settings.skip_sparse = true;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().dupes, 2);
+    assert_eq!(d.stats().sparse_skipped, 2);
+    assert_eq!(d.stats().created_hardlinks, 0);
+
+    let mut d = Scanner::new();
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().sparse_skipped, 0);
+    assert_eq!(d.stats().created_hardlinks, 2);
+}
+
+#[test]
+fn builder() {
+    let dir = TempDir::new("buildertest").unwrap();
+    for name in &["a", "b"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(b"dupe").unwrap();
+    }
+
+    let mut d = Scanner::builder()
+        .ignore_small(false)
+        .dry_run(true)
+        .configure(|s| s.stable_order = true)
+        .build();
+    assert!(d.settings.dry_run && d.settings.stable_order);
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().dupes, 1);
+    assert_eq!(d.stats().created_hardlinks, 0);
+
+    let mut d = ScannerBuilder::new().ignore_small(false).build();
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().created_hardlinks, 1);
+}
+
+#[test]
+fn paranoid() {
+    let dir = TempDir::new("paranoidtest").unwrap();
+    for name in &["a", "b", "fresh1", "fresh2"] {
+        let mut file = fs::File::create(dir.path().join(name)).unwrap();
+        file.write_all(if name.starts_with("fresh") {b"new!"} else {b"dupe"}).unwrap();
+        if !name.starts_with("fresh") {
+            file.set_modified(std::time::SystemTime::now() - PARANOID_MIN_AGE * 2).unwrap();
+        }
+    }
+
+    let settings = Settings::paranoid();
+    assert!(settings.verify_content && settings.double_hash && settings.confine_to_roots);
+    assert_eq!(settings.min_age, Some(PARANOID_MIN_AGE));
+
+    let mut d = Scanner::builder().ignore_small(false).paranoid().build();
+    assert!(!d.settings.ignore_small && d.settings.verify_content);
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().created_hardlinks, 1);
+    assert_eq!(d.stats().too_fresh_skipped, 2);
+}
+
+#[test]
+fn scan_all() {
+    let dir = TempDir::new("scanalltest").unwrap();
+    let roots: Vec<PathBuf> = (0..3).map(|i| dir.path().join(format!("root{}", i))).collect();
+    for root in &roots {
+        for sub in &["x", "y/z"] {
+            fs::create_dir_all(root.join(sub)).unwrap();
+            fs::File::create(root.join(sub).join("dupe")).unwrap().write_all(b"dupe").unwrap();
+            fs::File::create(root.join(sub).join("unique")).unwrap().write_all(format!("{}{}", root.display(), sub).as_bytes()).unwrap();
+        }
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.scan_all(&roots, 4).unwrap();
+    assert_eq!(d.stats().added, 12);
+    assert_eq!(d.stats().directories, 3 * 4);
+    assert_eq!(d.stats().created_hardlinks, 5);
+    let master = fs::metadata(roots[0].join("x/dupe")).unwrap();
+    assert_eq!(master.nlink(), 6);
+    assert!(roots.iter().all(|root| fs::metadata(root.join("y/z/dupe")).unwrap().ino() == master.ino()));
+}
+
+#[test]
+fn flush_parallel() {
+    let dir = TempDir::new("paralleltest").unwrap();
+    for i in 0..20 {
+        let sub = dir.path().join(format!("d{}", i % 4));
+        fs::create_dir_all(&sub).unwrap();
+        // 4 groups of 5 duplicates, all of the same size, and a file of a unique size each
+        fs::write(sub.join(format!("dupe{}", i)), format!("group{}", i % 4)).unwrap();
+        fs::write(sub.join(format!("unique{}", i)), "u".repeat(10 + i)).unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.enqueue(dir.path()).unwrap();
+    d.flush_parallel(4).unwrap();
+    assert_eq!(d.stats().added, 40);
+    assert_eq!(d.stats().dupes, 16);
+    assert_eq!(d.stats().created_hardlinks, 16);
+    for group in 0..4 {
+        let nlink = fs::metadata(dir.path().join(format!("d{}/dupe{}", group, group))).unwrap().nlink();
+        assert_eq!(nlink, 5);
+    }
+    // Unique sizes aren't read
+    assert_eq!(d.stats().bytes_read, 20 * 6);
+
+    // Ranges and digests can't be mixed
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.scan(dir.path()).unwrap();
+    assert!(d.flush_parallel(2).is_err());
+}
+
+#[test]
+fn content_address_root() {
+    let dir = TempDir::new("castest").unwrap();
+    let files = dir.path().join("files");
+    let store = dir.path().join("store");
+    fs::create_dir(&files).unwrap();
+    for name in &["a", "b", "c"] {
+        fs::File::create(files.join(name)).unwrap().write_all(b"dupe").unwrap();
+    }
+    let stored = store.join("43").join("56f4252f6d90ead88cb6e0e47f76b0e03fddb883c97458d08be77a2d751b82");
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.settings.content_address_root = Some(store.clone());
+    d.scan(&files).unwrap();
+    assert!(!store.exists());
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.content_address_root = Some(store.clone());
+    d.scan(&files).unwrap();
+    assert_eq!(d.stats().content_stored, 1);
+    assert_eq!(fs::metadata(&stored).unwrap().nlink(), 4);
+
+    // New copies are linked to the stored file
+    let more = dir.path().join("more");
+    fs::create_dir(&more).unwrap();
+    for name in &["d", "e"] {
+        fs::File::create(more.join(name)).unwrap().write_all(b"dupe").unwrap();
+    }
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.content_address_root = Some(store.clone());
+    d.scan(&more).unwrap();
+    assert_eq!(d.stats().content_stored, 0);
+    assert_eq!(d.stats().created_hardlinks, 2);
+    assert_eq!(fs::metadata(&stored).unwrap().nlink(), 6);
+}
+
+#[test]
+fn dupe_error() {
+    let dir = TempDir::new("dupeerrortest").unwrap();
+    let files = dir.path().join("files");
+    let store = dir.path().join("store");
+    fs::create_dir(&files).unwrap();
+    for name in &["a", "b"] {
+        fs::File::create(files.join(name)).unwrap().write_all(b"dupe").unwrap();
+    }
+    // At the path of the content, but something else
+    let stored = store.join("43").join("56f4252f6d90ead88cb6e0e47f76b0e03fddb883c97458d08be77a2d751b82");
+    fs::create_dir_all(stored.parent().unwrap()).unwrap();
+    fs::File::create(&stored).unwrap().write_all(b"epud").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.content_address_root = Some(store.clone());
+    d.scan(files.join("a")).unwrap();
+    match d.scan_report(files.join("b")) {
+        Err(DupeError::VerificationFailed {src, dst}) => {
+            assert_eq!(src.parent(), Some(files.as_path()));
+            assert_eq!(dst, stored);
+        },
+        res => panic!("{:?}", res),
+    }
+    assert_eq!(fs::metadata(files.join("a")).unwrap().nlink(), 1);
+
+
+    d.settings.quick_check = false;
+    match d.scan(&files) {
+        Err(DupeError::InvalidSettings(_)) => {},
+        res => panic!("{:?}", res),
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    let stats = d.scan_report(&files).unwrap();
+    assert_eq!(stats.added, 2);
+    assert_eq!(stats.created_hardlinks, 1);
+}
+
+#[test]
+fn min_size() {
+    let dir = TempDir::new("minsizetest").unwrap();
+    for name in &["small1", "small2"] {
+        fs::write(dir.path().join(name), [b'x'; 511]).unwrap();
+    }
+    for name in &["big1", "big2"] {
+        fs::write(dir.path().join(name), [b'x'; 512]).unwrap();
+    }
+    fs::File::create(dir.path().join("empty1")).unwrap();
+    fs::File::create(dir.path().join("empty2")).unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.min_size = Some(512);
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().added, 2);
+    assert_eq!(d.stats().skipped, 4);
+    assert_eq!(d.stats().created_hardlinks, 1);
+
+    let mut d = Scanner::new();
+    d.settings.min_size = Some(0);
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().skipped, 2);
+}
+
+#[test]
+fn add_exclude() {
+    let dir = TempDir::new("excludetest").unwrap();
+    for sub in &[".git/objects", "node_modules", "src"] {
+        fs::create_dir_all(dir.path().join(sub)).unwrap();
+    }
+    for name in &["a", "src/b", "c.tmp", ".git/objects/d", "node_modules/e"] {
+        fs::write(dir.path().join(name), "dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    assert!(d.add_exclude("[abc").is_err());
+    for pattern in &["**/.git/**", "**/node_modules/**", "*.tmp"] {
+        d.add_exclude(pattern).unwrap();
+    }
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().added, 2);
+    assert_eq!(d.stats().excluded, 3);
+    assert_eq!(d.stats().skipped, 3);
+    assert_eq!(d.stats().created_hardlinks, 1);
+    // Neither excluded directory was listed
+    assert_eq!(d.stats().directories, 2);
+}
+
+#[test]
+fn always_consider_extensions() {
+    let dir = TempDir::new("smallexttest").unwrap();
+    for name in &["a.json", "b.JSON", "c.txt", "d.txt"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(b"{}").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.always_consider_extensions.insert("json".into());
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().added, 2);
+    assert_eq!(fs::metadata(dir.path().join("a.json")).unwrap().ino(), fs::metadata(dir.path().join("b.JSON")).unwrap().ino());
+    assert_eq!(fs::metadata(dir.path().join("c.txt")).unwrap().nlink(), 1);
+}
+
+#[test]
+fn comparison_metrics() {
+    let dir = TempDir::new("metricstest").unwrap();
+    fs::File::create(dir.path().join("a")).unwrap().write_all(b"dupe").unwrap();
+    fs::File::create(dir.path().join("b")).unwrap().write_all(b"dupe").unwrap();
+    fs::File::create(dir.path().join("c")).unwrap().write_all(b"other size").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.comparison_metrics(), ComparisonMetrics::default());
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.collect_comparison_metrics = true;
+    d.settings.dry_run = true;
+    d.scan(dir.path()).unwrap();
+    let metrics = d.comparison_metrics();
+    assert!(metrics.comparisons > metrics.byte_reads);
+    assert!(metrics.byte_reads >= 1);
+    assert!(metrics.bytes_compared >= 8);
+}
+
+#[test]
+fn content_groups() {
+    let dir = TempDir::new("contentgroupstest").unwrap();
+    fs::File::create(dir.path().join("a")).unwrap().write_all(b"dupe").unwrap();
+    fs::File::create(dir.path().join("b")).unwrap().write_all(b"dupe").unwrap();
+    fs::File::create(dir.path().join("c")).unwrap().write_all(b"unique").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.scan(dir.path()).unwrap();
+    let mut groups: Vec<_> = d.content_groups().map(|(content, filesets)| (content.metadata().size, filesets.len())).collect();
+    groups.sort();
+    assert_eq!(groups, vec![(4, 2), (6, 1)]);
+    let (_, filesets) = d.content_groups().find(|(content, _)| content.metadata().size == 4).unwrap();
+    let paths: usize = filesets.iter().map(|set| set.lock().unwrap().paths.len()).sum();
+    assert_eq!(paths, 2);
+}
+
+#[test]
+fn quick_check() {
+    let dir = TempDir::new("quickchecktest").unwrap();
+    let mut data = vec![0; 100000];
+    fs::File::create(dir.path().join("a")).unwrap().write_all(&data).unwrap();
+    data[99999] = 1;
+    fs::File::create(dir.path().join("b")).unwrap().write_all(&data).unwrap();
+    data[50000] = 1;
+    fs::File::create(dir.path().join("c")).unwrap().write_all(&data).unwrap();
+
+    let bytes_compared = |quick_check| {
+        let mut d = Scanner::new();
+        d.settings.quick_check = quick_check;
+        d.settings.collect_comparison_metrics = true;
+        d.scan(dir.path()).unwrap();
+        assert_eq!(d.stats().dupes, 0);
+        d.comparison_metrics().bytes_compared
+    };
+    // a and b differ at the end, and are read in full otherwise
+    assert_eq!(bytes_compared(false), 3 * 100000);
+    assert!(bytes_compared(true) < 2 * 100000 + 20000);
+}
+
+#[test]
+fn prefer_contiguous_master() {
+    use std::io::{Seek, SeekFrom};
+    let dir = TempDir::new("contiguoustest").unwrap();
+    let mut data = vec![0; 1 << 20];
+    data[0] = 1;
+    data[(1 << 20) - 1] = 1;
+    // In a subdirectory, so that it's found after both links of the other copy
+    fs::create_dir(dir.path().join("sub")).unwrap();
+    fs::File::create(dir.path().join("sub/contiguous")).unwrap().write_all(&data).unwrap();
+    // Same content with a hole in the middle, which is two extents where there are extents
+    let mut holey = fs::File::create(dir.path().join("holey")).unwrap();
+    holey.write_all(&data[..4096]).unwrap();
+    holey.seek(SeekFrom::Start((1 << 20) - 4096)).unwrap();
+    holey.write_all(&data[(1 << 20) - 4096..]).unwrap();
+    drop(holey);
+    // More links, which would make it the master otherwise
+    fs::hard_link(dir.path().join("holey"), dir.path().join("holey2")).unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.prefer_contiguous_master = true;
+    d.scan(dir.path()).unwrap();
+    let ino = |name| fs::metadata(dir.path().join(name)).unwrap().ino();
+    assert_eq!(ino("holey"), ino("holey2"));
+    assert_eq!(ino("holey"), ino("sub/contiguous"));
+    // Where extents can be counted, the copy without a hole was kept
+    let has_extents = std::process::Command::new("filefrag").arg(dir.path().join("holey")).output().is_ok_and(|out| out.status.success());
+    if has_extents {
+        assert!(fs::metadata(dir.path().join("holey")).unwrap().blocks() * 512 >= 1 << 20);
+    }
+}
+
+#[test]
+fn stats_by_extension() {
+    let dir = TempDir::new("extensiontest").unwrap();
+    for name in &["a.jpg", "b.JPG", "c.jpg"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(b"photo").unwrap();
+    }
+    for name in &["d", "e"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(b"nothing").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.scan(dir.path()).unwrap();
+    let by_extension = d.stats_by_extension();
+    assert_eq!(by_extension.len(), 2);
+    assert_eq!(by_extension[OsStr::new("jpg")], ExtensionStats {dupes: 2, reclaimable_bytes: 10});
+    assert_eq!(by_extension[OsStr::new(NO_EXTENSION)], ExtensionStats {dupes: 1, reclaimable_bytes: 7});
+
+    // Nothing left to reclaim once they're linked
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats_by_extension()[OsStr::new("jpg")], ExtensionStats {dupes: 2, reclaimable_bytes: 0});
+}
+
+#[test]
+fn self_link_skipped() {
+    let dir = TempDir::new("selflinktest").unwrap();
+    let path = dir.path().join("x");
+    fs::File::create(&path).unwrap().write_all(b"same").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.scan(dir.path()).unwrap();
+    // Replaced with a copy after it was scanned, so the path is in two filesets of one content group
+    let copy = dir.path().join("copy");
+    fs::File::create(&copy).unwrap().write_all(b"same").unwrap();
+    fs::rename(&copy, &path).unwrap();
+    let metadata = fs::symlink_metadata(&path).unwrap();
+    d.add_with_metadata(path.clone(), metadata).unwrap();
+    assert_eq!(d.stats().self_link_skipped, 1);
+    assert_eq!(d.stats().created_hardlinks, 0);
+    assert_eq!(fs::read(&path).unwrap(), b"same");
+}
+
+#[test]
+fn min_age() {
+    let dir = TempDir::new("minagetest").unwrap();
+    let an_hour_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+    for name in &["a", "b", "c"] {
+        let mut file = fs::File::create(dir.path().join(name)).unwrap();
+        file.write_all(b"dupe").unwrap();
+        if *name != "c" {
+            file.set_modified(an_hour_ago).unwrap();
+        }
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.min_age = Some(std::time::Duration::from_secs(300));
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().too_fresh_skipped, 1);
+    assert_eq!(d.stats().created_hardlinks, 1);
+    assert_eq!(fs::metadata(dir.path().join("c")).unwrap().nlink(), 1);
+}
+
+#[test]
+fn ctime_after() {
+    let dir = TempDir::new("ctimetest").unwrap();
+    for name in &["a", "b", "c"] {
+        fs::write(dir.path().join(name), "dupe").unwrap();
+    }
+    // The kernel takes ctimes from a clock that may lag behind by a few milliseconds
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    let reference = std::time::SystemTime::now();
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    for name in &["d", "e"] {
+        fs::write(dir.path().join(name), "dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.ctime_after = Some(reference);
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().ctime_skipped, 3);
+    assert_eq!(d.stats().created_hardlinks, 1);
+    assert_eq!(fs::metadata(dir.path().join("d")).unwrap().ino(), fs::metadata(dir.path().join("e")).unwrap().ino());
+}
+
+#[test]
+fn preexisting_link_stats() {
+    let dir = TempDir::new("preexistingtest").unwrap();
+    for name in &["a", "c"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(b"linked").unwrap();
+    }
+    fs::hard_link(dir.path().join("a"), dir.path().join("b")).unwrap();
+    fs::File::create(dir.path().join("d")).unwrap().write_all(b"other").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.scan(dir.path()).unwrap();
+    // c is linked now, but wasn't before
+    assert_eq!(d.stats().created_hardlinks, 1);
+    assert_eq!(d.preexisting_link_stats(), PreexistingStats {inodes: 1, bytes: 6, saved_bytes: 6});
+}
+
+#[test]
+fn double_hash() {
+    let dir = TempDir::new("doublehashtest").unwrap();
+    for name in &["a", "b"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(&vec![7; 100_000]).unwrap();
+    }
+    fs::File::create(dir.path().join("c")).unwrap().write_all(&vec![8; 100_000]).unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.double_hash = true;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().dupes, 1);
+    assert_eq!(d.stats().created_hardlinks, 1);
+
+    d.settings.double_hash = false;
+    assert!(d.scan(dir.path()).is_err());
+
+    let mut d = Scanner::with_digest_cache(DigestCache::new());
+    d.settings.double_hash = true;
+    assert!(d.scan(dir.path()).is_err());
+}
+
+#[test]
+fn scan_tar() {
+    let dir = TempDir::new("tarscantest").unwrap();
+    fs::create_dir(dir.path().join("sub")).unwrap();
+    for name in &["a", "sub/b"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(b"dupe").unwrap();
+    }
+    fs::File::create(dir.path().join("sub/c")).unwrap().write_all(b"uniq").unwrap();
+    fs::File::create(dir.path().join("d")).unwrap().write_all(b"dupe").unwrap();
+    let tar = std::process::Command::new("tar").arg("--format=pax").arg("-cf").arg("-")
+        .arg("-C").arg(dir.path()).args(["a", "sub", "d"]).output();
+    let tar = match tar {
+        Ok(ref out) if out.status.success() => &out.stdout,
+        _ => return,
+    };
+
+    let mut d = Scanner::new();
+    d.scan_tar(&tar[..]).unwrap();
+    assert_eq!(d.stats().added, 4);
+    assert_eq!(d.stats().dupes, 2);
+    assert_eq!(d.tar_duplicates(), vec![vec![PathBuf::from("a"), PathBuf::from("sub/b"), PathBuf::from("d")]]);
+    // Nothing on disk is touched
+    assert_eq!(fs::metadata(dir.path().join("a")).unwrap().nlink(), 1);
+}
+
+#[test]
+fn rescan_changed_dirs() {
+    let dir = TempDir::new("rescantest").unwrap();
+    for name in &["a", "b"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(b"dupe").unwrap();
+    }
+
+    let scan = |rescan| {
+        let mut d = Scanner::new();
+        d.settings.ignore_small = false;
+        d.settings.rescan_changed_dirs = rescan;
+        // Another copy appears while the directory is being scanned
+        let late = dir.path().join(if rescan {"late1"} else {"late2"});
+        d.settings.choose_master = Some(Box::new(move |_| {
+            if !late.exists() {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                fs::File::create(&late).unwrap().write_all(b"dupe").unwrap();
+            }
+            0
+        }));
+        d.scan(dir.path()).unwrap();
+        d.stats()
+    };
+    let stats = scan(false);
+    assert_eq!(stats.rescanned_dirs, 0);
+    assert_eq!(fs::metadata(dir.path().join("late2")).unwrap().nlink(), 1);
+
+    let stats = scan(true);
+    // Linking changes the directory too, but maybe within the same mtime tick
+    assert!(stats.rescanned_dirs >= 1);
+    assert_eq!(fs::metadata(dir.path().join("late1")).unwrap().nlink(), 4);
+}
+
+#[test]
+fn use_renameat2() {
+    let dir = TempDir::new("renameat2test").unwrap();
+    for name in &["a", "b"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(b"dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.use_renameat2 = true;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().created_hardlinks, 1);
+    assert_eq!(fs::metadata(dir.path().join("a")).unwrap().nlink(), 2);
+
+    // The duplicate is replaced after it was compared, so that it's not the file that would be swapped out
+    for name in &["c", "d"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(b"more").unwrap();
+    }
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.use_renameat2 = true;
+    d.settings.equality_oracle = Some(Box::new(|dupe, _| {
+        let copy = dupe.with_file_name("copy");
+        fs::File::create(&copy)?.write_all(b"more")?;
+        fs::rename(&copy, dupe)?;
+        Ok(true)
+    }));
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().created_hardlinks, 0);
+    for name in &["c", "d"] {
+        assert_eq!(fs::metadata(dir.path().join(name)).unwrap().nlink(), 1);
+    }
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 4);
+}
+
+#[test]
+fn total_bytes() {
+    let dir = TempDir::new("totalbytestest").unwrap();
+    fs::File::create(dir.path().join("a")).unwrap().write_all(&vec![1; 10000]).unwrap();
+    fs::hard_link(dir.path().join("a"), dir.path().join("b")).unwrap();
+    fs::File::create(dir.path().join("hole")).unwrap().set_len(1 << 20).unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.dry_run = true;
+    d.scan(dir.path()).unwrap();
+    let allocated = |name| fs::metadata(dir.path().join(name)).unwrap().blocks() * 512;
+    assert_eq!(d.stats().total_apparent_bytes, 10000 + (1 << 20));
+    assert_eq!(d.stats().total_allocated_bytes, allocated("a") + allocated("hole"));
+}
+
+#[test]
+fn bytes_saved() {
+    for &compression_aware in &[false, true] {
+        let dir = TempDir::new("bytessavedtest").unwrap();
+        for name in &["a", "b"] {
+            fs::File::create(dir.path().join(name)).unwrap().write_all(&vec![1; 10000]).unwrap();
+        }
+        // Replacing only one of the names of an inode frees nothing
+        fs::hard_link(dir.path().join("b"), dir.path().join("c")).unwrap();
+        let allocated = fs::metadata(dir.path().join("a")).unwrap().blocks() * 512;
+
+        let mut d = Scanner::new();
+        d.settings.compression_aware_savings = compression_aware;
+        d.scan(dir.path()).unwrap();
+        assert_eq!(d.stats().bytes_saved, if compression_aware {allocated} else {10000});
+    }
+}
+
+#[test]
+fn reclaimed_bytes() {
+    for &dry_run in &[false, true] {
+        let dir = TempDir::new("reclaimedtest").unwrap();
+        for name in &["a", "b", "c"] {
+            fs::write(dir.path().join(name), vec![1; 10000]).unwrap();
+        }
+        // Its inode is freed once, when both of its names are replaced
+        fs::hard_link(dir.path().join("c"), dir.path().join("d")).unwrap();
+
+        let mut d = Scanner::new();
+        d.settings.dry_run = dry_run;
+        d.scan(dir.path()).unwrap();
+        let (reclaimed, would_reclaim) = if dry_run {(0, 20000)} else {(20000, 0)};
+        assert_eq!((d.stats().reclaimed_bytes, d.stats().would_reclaim_bytes), (reclaimed, would_reclaim));
+    }
+}
+
+#[test]
+fn files_before_subdirs() {
+    let dir = TempDir::new("filesfirsttest").unwrap();
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("a")).unwrap();
+    fs::File::create(root.join("a/c")).unwrap().write_all(b"dupe").unwrap();
+    fs::File::create(root.join("b")).unwrap().write_all(b"dupe").unwrap();
+    let progress_path = dir.path().join("progress");
+
+    let mut d = Scanner::new();
+    d.settings.stable_order = true;
+    d.settings.files_before_subdirs = true;
+    d.set_listener(Box::new(FdProgressListener::new(fs::File::create(&progress_path).unwrap())));
+    d.scan(&root).unwrap();
+
+    let progress = fs::read_to_string(&progress_path).unwrap();
+    let scanned: Vec<_> = progress.lines()
+        .filter(|l| l.starts_with("scanned\t"))
+        .map(|l| PathBuf::from(l.rsplit('\t').next().unwrap()))
+        .collect();
+    assert_eq!(scanned, vec![root.clone(), root.join("b"), root.join("a"), root.join("a/c")]);
+}
+
+#[test]
+fn defer_links() {
+    let dir = TempDir::new("defertest").unwrap();
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("x/y")).unwrap();
+    for path in &["a", "x/b", "x/y/c"] {
+        fs::File::create(root.join(path)).unwrap().write_all(b"dupe").unwrap();
+    }
+    let progress_path = dir.path().join("progress");
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.defer_links = true;
+    d.set_listener(Box::new(FdProgressListener::new(fs::File::create(&progress_path).unwrap())));
+    d.scan(&root).unwrap();
+    assert_eq!(d.stats().created_hardlinks, 2);
+    assert!(d.dry_run_report().links.is_empty());
+    assert_eq!(fs::metadata(root.join("a")).unwrap().nlink(), 3);
+
+    // Nothing is linked until everything is scanned
+    let progress = fs::read_to_string(&progress_path).unwrap();
+    let events: Vec<_> = progress.lines().map(|l| l.split('\t').next().unwrap()).collect();
+    let last_scanned = events.iter().rposition(|&e| e == "scanned").unwrap();
+    let first_linked = events.iter().position(|&e| e == "hardlinked").unwrap();
+    assert!(last_scanned < first_linked);
+    assert_eq!(events.iter().filter(|&&e| e == "hardlinked").count(), 2);
+}
+
+#[test]
+fn count_entries() {
+    let dir = TempDir::new("counttest").unwrap();
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("x/y")).unwrap();
+    for path in &["a", "x/b", "x/y/c", "x/d.tmp"] {
+        fs::write(root.join(path), "dupe").unwrap();
+    }
+    fs::File::create(root.join("empty")).unwrap();
+    fs::hard_link(root.join("a"), root.join("x/a")).unwrap();
+    let progress_path = dir.path().join("progress");
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.add_exclude("*.tmp").unwrap();
+    d.set_listener(Box::new(FdProgressListener::new(fs::File::create(&progress_path).unwrap())));
+    d.enqueue(&root).unwrap();
+    let total = d.count_entries().unwrap();
+    assert_eq!(total, 4);
+    d.set_expected_files(total);
+    d.flush().unwrap();
+    assert_eq!(d.stats().added as u64, total);
+    d.flush().unwrap();
+
+    let progress = fs::read_to_string(&progress_path).unwrap();
+    let started: Vec<_> = progress.lines().filter(|l| l.starts_with("started")).collect();
+    assert_eq!(started, vec!["started\t4", "started"]);
+}
+
+#[test]
+fn dedup_directory_visits() {
+    let dir = TempDir::new("dirvisittest").unwrap();
+    let real = dir.path().join("real");
+    fs::create_dir(&real).unwrap();
+    for name in &["a", "b"] {
+        fs::File::create(real.join(name)).unwrap().write_all(b"dupe").unwrap();
+    }
+    // Another path to the same directory, like a bind mount
+    let alias = dir.path().join("alias");
+    ::std::os::unix::fs::symlink(&real, &alias).unwrap();
+
+    for &dedup in &[true, false] {
+        let mut d = Scanner::new();
+        d.settings.ignore_small = false;
+        d.settings.dry_run = true;
+        d.settings.dedup_directory_visits = dedup;
+        d.scan(&real).unwrap();
+        d.add_with_metadata(alias.clone(), fs::metadata(&real).unwrap()).unwrap();
+        d.flush().unwrap();
+        assert_eq!(d.stats().added, if dedup {2} else {4});
+        assert_eq!(d.stats().directories, 1);
+        // The same path again is scanned again
+        d.scan(&real).unwrap();
+        assert_eq!(d.stats().added, if dedup {4} else {6});
+    }
+}
+
+#[test]
+fn keep_root() {
+    let dir = TempDir::new("keeproottest").unwrap();
+    let old = dir.path().join("old");
+    let new = dir.path().join("new");
+    fs::create_dir(&old).unwrap();
+    fs::create_dir(&new).unwrap();
+    for path in &[old.join("a"), old.join("b"), new.join("a"), new.join("b")] {
+        fs::File::create(path).unwrap().write_all(b"dupe").unwrap();
+    }
+    // More links in new, which would make it the master otherwise
+    fs::hard_link(new.join("a"), new.join("c")).unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.set_keep_root(&old).unwrap();
+    d.scan(&new).unwrap();
+    d.scan(&old).unwrap();
+    let ino = |path: PathBuf| fs::metadata(path).unwrap().ino();
+    let master = ino(new.join("a"));
+    assert!(master == ino(old.join("a")) || master == ino(old.join("b")));
+    assert_eq!(ino(new.join("b")), master);
+    assert_eq!(ino(new.join("c")), master);
+    // Dupes within the keep root stay as they are
+    assert_ne!(ino(old.join("b")), ino(old.join("a")));
+    assert_eq!(d.stats().dupes_not_linked, 1);
+
+    // The same content in two keep roots can't be linked either way
+    let other = dir.path().join("other");
+    fs::create_dir(&other).unwrap();
+    fs::File::create(other.join("a")).unwrap().write_all(b"dupe").unwrap();
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.set_keep_root(&old).unwrap();
+    d.set_keep_root(&other).unwrap();
+    d.scan(&old).unwrap();
+    assert!(d.enqueue(other.join("a")).is_err());
+    assert_eq!(fs::metadata(other.join("a")).unwrap().nlink(), 1);
+}
+
+#[test]
+fn atomic_groups() {
+    for &atomic in &[false, true] {
+        let dir = TempDir::new("atomictest").unwrap();
+        let (kept, other, files) = (dir.path().join("kept"), dir.path().join("other"), dir.path().join("files"));
+        for (d, names) in &[(&kept, &["m"][..]), (&files, &["a", "b"][..]), (&other, &["c"][..])] {
+            fs::create_dir(d).unwrap();
+            for name in *names {
+                fs::File::create(d.join(name)).unwrap().write_all(b"dupe").unwrap();
+            }
+        }
+
+        let mut d = Scanner::new();
+        d.settings.ignore_small = false;
+        d.settings.atomic_groups = atomic;
+        // The whole group is linked at once, when c comes
+        d.settings.min_savings_per_group = 12;
+        d.set_keep_root(&kept).unwrap();
+        d.set_keep_root(&other).unwrap();
+        d.scan(&kept).unwrap();
+        d.scan(&files).unwrap();
+        assert_eq!(d.stats().created_hardlinks, 0);
+        // c can't be linked, since it's in another keep root than the master
+        assert!(d.enqueue(other.join("c")).is_err());
+        assert_eq!(d.stats().created_hardlinks, 2);
+
+        let master = fs::metadata(kept.join("m")).unwrap();
+        for name in &["a", "b"] {
+            let metadata = fs::metadata(files.join(name)).unwrap();
+            assert_eq!(metadata.ino() == master.ino(), !atomic);
+            assert_eq!(fs::read(files.join(name)).unwrap(), b"dupe");
+        }
+        assert_eq!(master.nlink(), if atomic {1} else {3});
+        assert_eq!(d.stats().rolled_back, if atomic {2} else {0});
+    }
+}
+
+#[test]
+fn dry_run_verify() {
+    let dir = TempDir::new("verifytest").unwrap();
+    for name in &["a", "b"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(b"dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.settings.dry_run_verify = true;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().dupes, 1);
+    assert_eq!(d.stats().verify_mismatches, 0);
+    let dupes: Vec<_> = d.dupes().into_iter().filter(|f| f.paths.len() > 1).collect();
+    assert_eq!(dupes.len(), 1);
+}
+
+#[test]
+fn unreadable_dir() {
+    let dir = TempDir::new("unreadabletest").unwrap();
+    let locked = dir.path().join("locked");
+    fs::create_dir(&locked).unwrap();
+    fs::File::create(locked.join("a")).unwrap().write_all(b"dupe").unwrap();
+    fs::File::create(dir.path().join("b")).unwrap().write_all(b"dupe").unwrap();
+    fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+    // Root can read it anyway
+    let readable = fs::read_dir(&locked).is_ok();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    let res = d.scan(dir.path());
+    fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+    res.unwrap();
+    if !readable {
+        assert_eq!(d.stats().unreadable_dirs, 1);
+        assert_eq!(d.errors().len(), 1);
+        assert_eq!(d.stats().added, 1);
+    }
+}
+
+#[test]
+fn name_too_long() {
+    use std::ffi::CString;
+    use std::os::unix::io::AsRawFd;
+
+    let dir = TempDir::new("longtest").unwrap();
+    // Only relative paths can go deeper than PATH_MAX
+    let name = CString::new(vec![b'x'; 250]).unwrap();
+    let mut parent = fs::File::open(dir.path()).unwrap();
+    for _ in 0..20 {
+        unsafe {
+            assert_eq!(0, libc::mkdirat(parent.as_raw_fd(), name.as_ptr(), 0o755));
+            let fd = libc::openat(parent.as_raw_fd(), name.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY);
+            assert!(fd >= 0);
+            parent = std::os::unix::io::FromRawFd::from_raw_fd(fd);
+        }
+    }
+
+    let mut d = Scanner::new();
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().name_too_long, 1);
+    assert_eq!(d.errors().len(), 1);
+
+    // TempDir can't remove it either
+    std::process::Command::new("rm").arg("-rf").arg(dir.path()).status().unwrap();
+}
+
+#[test]
+fn apply_plan() {
+    let dir = TempDir::new("plantest").unwrap();
+    for name in &["a", "b", "c", "d"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(b"dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.scan(dir.path()).unwrap();
+    let plan = d.dry_run_report().clone();
+    assert_eq!(plan.links.len(), 3);
+
+    // One destination changes after the plan was made
+    let changed = &plan.links[0].dest;
+    fs::File::create(changed).unwrap().write_all(b"changed").unwrap();
+
+    let mut d = Scanner::new();
+    let stats = d.apply_plan(&plan).unwrap();
+    assert_eq!(stats.dupes, 2);
+    assert_eq!(stats.plan_skipped, 1);
+    assert_eq!(fs::metadata(&plan.links[0].source).unwrap().nlink(), 3);
+    assert_eq!(fs::metadata(changed).unwrap().nlink(), 1);
+}
+
+#[test]
+fn apply_plan_deleted() {
+    let dir = TempDir::new("plandeletedtest").unwrap();
+    for name in &["a", "b", "c"] {
+        fs::write(dir.path().join(name), "dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.scan(dir.path()).unwrap();
+    let plan = d.dry_run_report().clone();
+    assert_eq!(plan.links.len(), 2);
+    fs::remove_file(&plan.links[0].dest).unwrap();
+
+    // The rest of the plan is still applied
+    let stats = Scanner::new().apply_plan(&plan).unwrap();
+    assert_eq!(stats.plan_skipped, 1);
+    assert_eq!(stats.dupes, 1);
+    assert_eq!(fs::metadata(&plan.links[1].dest).unwrap().nlink(), 2);
+}
+
+#[test]
+fn apply_decision() {
+    let dir = TempDir::new("decisiontest").unwrap();
+    for name in &["a", "b", "c"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(b"dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.scan(dir.path()).unwrap();
+    let pending = d.pending_decisions();
+    assert_eq!(pending.len(), 2);
+
+    d.apply_decision(&pending[0], true).unwrap();
+    d.apply_decision(&pending[1], false).unwrap();
+    assert!(d.pending_decisions().is_empty());
+    assert!(d.apply_decision(&pending[0], true).is_err());
+    assert_eq!(d.stats().created_hardlinks, 1);
+    assert_eq!(fs::metadata(&pending[0].source).unwrap().ino(), fs::metadata(&pending[0].dest).unwrap().ino());
+    assert_eq!(fs::metadata(&pending[1].dest).unwrap().nlink(), 1);
+}
+
+#[test]
+fn content_normalizer() {
+    let dir = TempDir::new("normalizetest").unwrap();
+    fs::File::create(dir.path().join("a")).unwrap().write_all(b"one\r\ntwo\r\n").unwrap();
+    fs::File::create(dir.path().join("b")).unwrap().write_all(b"one\ntwo\n").unwrap();
+    fs::File::create(dir.path().join("c")).unwrap().write_all(b"one\nthree\n").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    let strip_cr = |data: &[u8]| data.iter().cloned().filter(|&c| c != b'\r').collect();
+    #[cfg(not(feature = "sync"))]
+    let strip_cr = std::rc::Rc::new(strip_cr);
+    #[cfg(feature = "sync")]
+    let strip_cr = std::sync::Arc::new(strip_cr);
+    d.settings.content_normalizer = Some(strip_cr);
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().dupes, 1);
+    // It's not a dry run, but it mustn't link
+    assert_eq!(fs::metadata(dir.path().join("a")).unwrap().nlink(), 1);
+    assert_eq!(fs::metadata(dir.path().join("b")).unwrap().nlink(), 1);
+    assert!(d.dry_run_report().links.is_empty());
+}
+
+#[test]
+fn min_free_space() {
+    let dir = TempDir::new("freespacetest").unwrap();
+    for name in &["a", "b"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(b"dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.min_free_space = Some(u64::MAX);
+    for name in &["a", "b"] {
+        let path = dir.path().join(name);
+        let metadata = fs::symlink_metadata(&path).unwrap();
+        let res = d.add_with_metadata(path, metadata);
+        assert_eq!(res.is_err(), *name == "b");
+    }
+    assert_eq!(fs::metadata(dir.path().join("a")).unwrap().nlink(), 1);
+}
+
+#[test]
+fn write_csv() {
+    let dir = TempDir::new("csvtest").unwrap();
+    fs::File::create(dir.path().join("a,1")).unwrap().write_all(b"dupe").unwrap();
+    fs::File::create(dir.path().join("b")).unwrap().write_all(b"dupe").unwrap();
+    fs::File::create(dir.path().join("c")).unwrap().write_all(b"uniq").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.scan(dir.path()).unwrap();
+    let mut out = Vec::new();
+    d.write_csv(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let lines: Vec<_> = out.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0], "group,path,size,is_master,device,inode,group_id");
+    assert!(lines.iter().any(|l| l.contains("a,1\",4,")));
+    // SHA-256 of "dupe"
+    let group_id = "4356f4252f6d90ead88cb6e0e47f76b0e03fddb883c97458d08be77a2d751b82";
+    assert!(lines[1..].iter().all(|l| l.ends_with(group_id)));
+    assert_eq!(lines.iter().filter(|l| l.contains(",true,")).count(), 1);
+}
+
+#[test]
+fn write_dot() {
+    let dir = TempDir::new("dottest").unwrap();
+    for name in &["a\"1", "b", "c"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(b"dupe").unwrap();
+    }
+    fs::File::create(dir.path().join("d")).unwrap().write_all(b"uniq").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.settings.report_base = Some(dir.path().to_owned());
+    d.scan(dir.path()).unwrap();
+    let mut out = Vec::new();
+    d.write_dot(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let lines: Vec<_> = out.lines().collect();
+    assert_eq!(lines[0], "digraph duplicates {");
+    assert_eq!(lines[lines.len() - 1], "}");
+    assert_eq!(lines.iter().filter(|l| l.contains(" [label=") && !l.contains("->")).count(), 3);
+    assert_eq!(lines.iter().filter(|l| l.contains("shape=box")).count(), 1);
+    assert_eq!(lines.iter().filter(|l| l.ends_with("-> n0 [label=\"4\"];")).count(), 2);
+    assert!(lines.contains(&"    n0 [label=\"a\\\"1\", shape=box];") || lines.iter().any(|l| l.contains("[label=\"a\\\"1\"];")));
+    assert!(!out.contains(&*dir.path().to_string_lossy()));
+}
+
+#[test]
+fn report_base() {
+    let dir = TempDir::new("reportbasetest").unwrap();
+    let root = fs::canonicalize(dir.path()).unwrap().join("root");
+    fs::create_dir(&root).unwrap();
+    fs::File::create(root.join("a")).unwrap().write_all(b"dupe").unwrap();
+    fs::File::create(root.join("b")).unwrap().write_all(b"dupe").unwrap();
+    let progress_path = dir.path().join("progress");
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.settings.report_base = Some(root.clone());
+    d.set_listener(Box::new(FdProgressListener::new(fs::File::create(&progress_path).unwrap())));
+    d.scan(&root).unwrap();
+
+    let progress = fs::read_to_string(&progress_path).unwrap();
+    let scanned: Vec<_> = progress.lines().filter(|l| l.starts_with("scanned\t")).map(|l| l.rsplit('\t').next().unwrap()).collect();
+    assert_eq!(scanned.len(), 3);
+    assert!(scanned.contains(&".") && scanned.contains(&"a") && scanned.contains(&"b"));
+    assert!(progress.lines().any(|l| l == "duplicate\tb\ta" || l == "duplicate\ta\tb"));
+
+    let mut out = Vec::new();
+    d.write_csv(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.lines().skip(1).all(|l| l.starts_with("0,a,") || l.starts_with("0,b,")));
+    // Dupes found so far still have absolute paths
+    assert!(d.dupes().iter().all(|set| set.paths.iter().all(|path| path.starts_with(&root))));
+}
+
+#[test]
+fn max_tracked_paths_per_inode() {
+    let dir = TempDir::new("trackedpathstest").unwrap();
+    fs::File::create(dir.path().join("a")).unwrap().write_all(b"dupe").unwrap();
+    for name in &["a2", "a3", "a4"] {
+        fs::hard_link(dir.path().join("a"), dir.path().join(name)).unwrap();
+    }
+    // In a subdirectory, so that it's found after all of a's paths
+    fs::create_dir(dir.path().join("sub")).unwrap();
+    fs::File::create(dir.path().join("sub/b")).unwrap().write_all(b"dupe").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.settings.max_tracked_paths_per_inode = Some(2);
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().existing_hardlinks, 3);
+    let set = d.dupes().into_iter().find(|set| set.untracked_paths > 0).unwrap();
+    assert_eq!(set.untracked_paths, 2);
+    // Two of a's paths, and b merged in by dry run
+    assert_eq!(set.paths.len(), 3);
+
+    let mut out = Vec::new();
+    d.write_csv(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(out.lines().filter(|l| l.contains(",+2 more,")).count(), 1);
+}
+
+#[test]
+fn write_manifest() {
+    let dir = TempDir::new("manifesttest").unwrap();
+    let root = fs::canonicalize(dir.path()).unwrap();
+    fs::File::create(root.join("a")).unwrap().write_all(b"abc").unwrap();
+    fs::File::create(root.join("b")).unwrap().write_all(b"abc").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.scan(&root).unwrap();
+    let mut out = Vec::new();
+    d.write_manifest(&mut out).unwrap();
+    let ino = fs::metadata(root.join("a")).unwrap().ino();
+    let hash = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    assert_eq!(String::from_utf8(out).unwrap(), format!("{h}  {i}  {}\n{h}  {i}  {}\n",
+        root.join("a").display(), root.join("b").display(), h = hash, i = ino));
+}
+
+#[test]
+fn near_duplicates() {
+    let dir = TempDir::new("neartest").unwrap();
+    let mut content = vec![b'x'; 20000];
+    fs::File::create(dir.path().join("a")).unwrap().write_all(&content).unwrap();
+    content[10000] = b'y';
+    fs::File::create(dir.path().join("b")).unwrap().write_all(&content).unwrap();
+    content.insert(10000, b'z');
+    fs::File::create(dir.path().join("c")).unwrap().write_all(&content).unwrap();
+    content[0] = b'y';
+    fs::File::create(dir.path().join("d")).unwrap().write_all(&content).unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.dry_run = true;
+    d.scan(dir.path()).unwrap();
+    let root = fs::canonicalize(dir.path()).unwrap();
+    assert_eq!(d.near_duplicates(0), vec![vec![root.join("a"), root.join("b")]]);
+    assert_eq!(d.near_duplicates(1), vec![vec![root.join("a"), root.join("b"), root.join("c")]]);
+}
+
+#[test]
+fn prefix_duplicates() {
+    let dir = TempDir::new("prefixtest").unwrap();
+    let mut log = Vec::new();
+    for (day, lines) in [("day1", 500), ("day2", 800), ("day3", 1000)].iter() {
+        while log.len() < lines * 10 {
+            log.extend_from_slice(format!("line {:04}\n", log.len() / 10).as_bytes());
+        }
+        fs::File::create(dir.path().join(day)).unwrap().write_all(&log).unwrap();
+    }
+    // Same beginning, but it's not what day2 appended
+    log.truncate(6000);
+    log.extend_from_slice(b"rotated");
+    fs::File::create(dir.path().join("other")).unwrap().write_all(&log).unwrap();
+    fs::File::create(dir.path().join("short")).unwrap().write_all(b"sh").unwrap();
+    fs::File::create(dir.path().join("shorter")).unwrap().write_all(b"s").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.scan(dir.path()).unwrap();
+    let root = fs::canonicalize(dir.path()).unwrap();
+    assert_eq!(d.prefix_duplicates(), vec![
+        (root.join("day1"), root.join("other")),
+        (root.join("day2"), root.join("day3")),
+        (root.join("shorter"), root.join("short")),
+    ]);
+}
+
+#[test]
+fn add_with_metadata() {
+    let dir = TempDir::new("metadatatest").unwrap();
+    fs::File::create(dir.path().join("a")).unwrap().write_all(b"dupe").unwrap();
+    fs::File::create(dir.path().join("b")).unwrap().write_all(b"dupe").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    for entry in fs::read_dir(dir.path()).unwrap() {
+        let entry = entry.unwrap();
+        let metadata = entry.metadata().unwrap();
+        d.add_with_metadata(entry.path(), metadata).unwrap();
+    }
+    d.flush().unwrap();
+    assert_eq!(d.stats().added, 2);
+    assert_eq!(d.stats().dupes, 1);
+}
+
+#[test]
+fn enqueue_lenient() {
+    let dir = TempDir::new("lenienttest").unwrap();
+    let missing = dir.path().join("missing");
+
+    let mut d = Scanner::new();
+    assert!(d.enqueue(&missing).is_err());
+    d.enqueue_lenient(&missing).unwrap();
+    d.enqueue_lenient(dir.path()).unwrap();
+    d.flush().unwrap();
+    assert_eq!(d.errors().len(), 1);
+    assert_eq!(d.errors()[0].0, missing);
+    assert_eq!(d.errors()[0].1.kind(), std::io::ErrorKind::NotFound);
+}
+
+#[test]
+fn enqueue_trusted() {
+    let dir = TempDir::new("trustedtest").unwrap();
+    let root = fs::canonicalize(dir.path()).unwrap();
+    for name in &["a", "b"] {
+        fs::write(root.join(name), "dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    assert_eq!(d.enqueue_trusted("relative/path").unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    d.enqueue_trusted(root.join("a")).unwrap();
+    d.enqueue_trusted(root.join("b")).unwrap();
+    d.flush().unwrap();
+    assert_eq!(d.stats().created_hardlinks, 1);
+}
+
+#[test]
+fn special_files() {
+    let dir = TempDir::new("specialtest").unwrap();
+    let _socket = ::std::os::unix::net::UnixListener::bind(dir.path().join("socket")).unwrap();
+
+    let mut d = Scanner::new();
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().special_files, 1);
+    assert_eq!(d.stats().skipped, 1);
+}
+
+#[test]
+fn fd_progress_listener() {
+    let dir = TempDir::new("progresstest").unwrap();
+    fs::create_dir(dir.path().join("files")).unwrap();
+    fs::File::create(dir.path().join("files/a")).unwrap().write_all(b"dupe").unwrap();
+    fs::File::create(dir.path().join("files/b")).unwrap().write_all(b"dupe").unwrap();
+    let progress_path = dir.path().join("progress");
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.set_listener(Box::new(FdProgressListener::new(fs::File::create(&progress_path).unwrap())));
+    d.scan(dir.path().join("files")).unwrap();
+
+    let progress = fs::read_to_string(&progress_path).unwrap();
+    let lines: Vec<_> = progress.lines().collect();
+    assert_eq!(lines.iter().filter(|l| l.starts_with("scanned\t")).count(), 3);
+    assert_eq!(lines.iter().filter(|l| l.starts_with("duplicate\t")).count(), 1);
+    assert!(lines.last().unwrap().starts_with("done\t2\t0\t1\t0\t"));
+}
+
+#[test]
+fn preflight() {
+    let dir = TempDir::new("preflighttest").unwrap();
+    let a_path = dir.path().join("a");
+    let b_path = dir.path().join("b");
+    fs::write(&a_path, "same").unwrap();
+    fs::write(&b_path, "same").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.preflight = true;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(fs::metadata(&a_path).unwrap().ino(), fs::metadata(&b_path).unwrap().ino());
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+}
+
+#[test]
+fn probe_filesystem() {
+    let dir = TempDir::new("probetest").unwrap();
+    let caps = Scanner::probe_filesystem(dir.path()).unwrap();
+    assert!(caps.supports_hardlink);
+    assert!(caps.atomic_rename);
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn mode_chain() {
+    let dir = TempDir::new("modetest").unwrap();
+    let reflinks = Scanner::probe_filesystem(dir.path()).unwrap().supports_reflink;
+    for name in &["a", "b"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(b"dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.mode_chain = vec![DedupeMode::Reflink, DedupeMode::Symlink];
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().created_hardlinks, 0);
+    assert_eq!(d.stats().reflinked + d.stats().symlinked, 1);
+    assert_eq!(d.stats().reflinked, if reflinks {1} else {0});
+
+    let linked = ["a", "b"].iter().map(|name| dir.path().join(name))
+        .find(|path| fs::symlink_metadata(path).unwrap().file_type().is_symlink() || reflinks).unwrap();
+    assert_eq!(fs::read(linked).unwrap(), b"dupe");
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+}
+
+#[test]
+fn reflink_unsupported() {
+    let dir = TempDir::new("noreflinktest").unwrap();
+    let reflinks = Scanner::probe_filesystem(dir.path()).unwrap().supports_reflink;
+    for name in &["a", "b"] {
+        fs::write(dir.path().join(name), "dupe").unwrap();
+    }
+    let inodes = || ["a", "b"].iter().map(|name| fs::metadata(dir.path().join(name)).unwrap().ino()).collect::<Vec<_>>();
+    let before = inodes();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.mode_chain = vec![DedupeMode::Reflink];
+    d.scan(dir.path()).unwrap();
+    if reflinks {
+        assert_eq!(d.stats().reflinked, 1);
+    } else {
+        assert_eq!(d.stats().reflink_unsupported, 1);
+        assert_eq!(d.stats().dupes_not_linked, 1);
+        assert_eq!(inodes(), before);
+    }
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+}
+
+/// Unmounts at the end of the test, even if it fails
+struct BindMount(PathBuf);
+
+impl Drop for BindMount {
+    fn drop(&mut self) {
+        let _ = std::process::Command::new("umount").arg(&self.0).status();
+    }
+}
+
+/// A bind mount is the same device, but links out of it fail with `EXDEV`. It needs root, so it's skipped without,
+/// and it mounts on the host, so it only runs with `--ignored`.
+#[test]
+#[ignore]
+fn cross_fs() {
+    let dir = TempDir::new("crossfstest").unwrap();
+    let (outside, root) = (dir.path().join("outside"), dir.path().join("root"));
+    fs::create_dir_all(root.join("mnt")).unwrap();
+    fs::create_dir(&outside).unwrap();
+    let mounted = std::process::Command::new("mount").arg("--bind").arg(&outside).arg(root.join("mnt"))
+        .stderr(std::process::Stdio::null()).status().is_ok_and(|status| status.success());
+    if !mounted {
+        return;
+    }
+    let _mount = BindMount(root.join("mnt"));
+
+    for policy in &[CrossFsPolicy::Error, CrossFsPolicy::Skip, CrossFsPolicy::Symlink] {
+        fs::write(root.join("a"), "dupe").unwrap();
+        fs::write(root.join("mnt/b"), "dupe").unwrap();
+        let mut d = Scanner::new();
+        d.settings.ignore_small = false;
+        d.settings.cross_fs = *policy;
+        let res = d.scan(&root);
+        let symlinks = ["a", "mnt/b"].iter().filter(|name| fs::symlink_metadata(root.join(name)).unwrap().file_type().is_symlink()).count();
+        match *policy {
+            CrossFsPolicy::Error => {
+                res.unwrap();
+                assert_eq!(d.errors().len(), 1);
+                assert_eq!(d.errors()[0].1.kind(), std::io::ErrorKind::CrossesDevices);
+            },
+            CrossFsPolicy::Skip => {
+                res.unwrap();
+                assert_eq!(d.stats().cross_fs_skipped, 1);
+                assert_eq!(symlinks, 0);
+            },
+            CrossFsPolicy::Symlink => {
+                res.unwrap();
+                assert_eq!(d.stats().symlinked, 1);
+                assert_eq!(symlinks, 1);
+            },
+        }
+        assert_eq!(fs::read(root.join("a")).unwrap(), b"dupe");
+        assert_eq!(fs::read(root.join("mnt/b")).unwrap(), b"dupe");
+        assert_eq!(fs::read_dir(&root).unwrap().count() + fs::read_dir(&outside).unwrap().count(), 3);
+        fs::remove_file(root.join("a")).unwrap();
+        fs::remove_file(root.join("mnt/b")).unwrap();
+    }
+}
+
+/// Needs /dev/shm on another device than the temporary directory, and is skipped otherwise
+#[test]
+fn cross_fs_devices() {
+    let (here, there) = (TempDir::new("crossdevtest").unwrap(), match TempDir::new_in("/dev/shm", "crossdevtest") {
+        Ok(dir) => dir,
+        Err(_) => return,
+    });
+    if fs::metadata(here.path()).unwrap().dev() == fs::metadata(there.path()).unwrap().dev() {
+        return;
+    }
+    let (a, b) = (here.path().join("a"), there.path().join("b"));
+    for path in &[&a, &b] {
+        fs::write(path, "dupe").unwrap();
+    }
+
+    for policy in &[CrossFsPolicy::Error, CrossFsPolicy::Symlink] {
+        let mut d = Scanner::new();
+        d.settings.ignore_small = false;
+        d.settings.cross_fs = *policy;
+        d.enqueue(here.path()).unwrap();
+        d.enqueue(there.path()).unwrap();
+        d.flush().unwrap();
+        let symlinks = [&a, &b].iter().filter(|path| fs::symlink_metadata(path).unwrap().file_type().is_symlink()).count();
+        // Only found at all if they're grouped across devices
+        assert_eq!((d.stats().dupes, symlinks), if *policy == CrossFsPolicy::Symlink {(1, 1)} else {(0, 0)});
+        assert!(d.errors().is_empty());
+    }
+    assert_eq!(fs::read(&a).unwrap(), b"dupe");
+    assert_eq!(fs::read(&b).unwrap(), b"dupe");
+}
+
+/// The temp dir is on APFS, which has `clonefile`
+#[cfg(target_os = "macos")]
+#[test]
+fn reflink_clonefile() {
+    let dir = TempDir::new("clonefiletest").unwrap();
+    let a_path = dir.path().join("a");
+    let b_path = dir.path().join("b");
+    fs::write(&a_path, "dupe").unwrap();
+    fs::write(&b_path, "dupe").unwrap();
+    assert!(Scanner::probe_filesystem(dir.path()).unwrap().supports_reflink);
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.mode_chain = vec![DedupeMode::Reflink];
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().reflinked, 1);
+    assert_ne!(fs::metadata(&a_path).unwrap().ino(), fs::metadata(&b_path).unwrap().ino());
+    assert_eq!(fs::read(&a_path).unwrap(), b"dupe");
+    assert_eq!(fs::read(&b_path).unwrap(), b"dupe");
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+}
+
+#[test]
+fn quarantine() {
+    let dir = TempDir::new("quarantinetest").unwrap();
+    let data = fs::canonicalize(dir.path()).unwrap().join("data");
+    let quarantine = dir.path().join("quarantine");
+    fs::create_dir(&data).unwrap();
+    for name in &["a", "b"] {
+        fs::write(data.join(name), "dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.mode_chain = vec![DedupeMode::Quarantine];
+    d.settings.quarantine_dir = Some(quarantine.clone());
+    d.scan(&data).unwrap();
+    assert_eq!(d.stats().quarantined, 1);
+    assert_eq!(d.stats().bytes_saved, 0);
+
+    let moved = ["a", "b"].iter().map(|name| data.join(name))
+        .find(|path| fs::symlink_metadata(path).unwrap().file_type().is_symlink()).unwrap();
+    assert_eq!(fs::read(&moved).unwrap(), b"dupe");
+    let quarantined = quarantine.join(moved.strip_prefix("/").unwrap());
+    assert_eq!(fs::read(&quarantined).unwrap(), b"dupe");
+
+    // A symlink made since isn't replaced
+    let target = fs::read_link(&moved).unwrap();
+    fs::write(data.join("other"), "other").unwrap();
+    fs::remove_file(&moved).unwrap();
+    std::os::unix::fs::symlink(data.join("other"), &moved).unwrap();
+    assert_eq!(d.restore_quarantine(&quarantine).unwrap(), 0);
+    assert_eq!(d.errors().len(), 1);
+    assert!(quarantined.exists());
+    fs::remove_file(&moved).unwrap();
+    std::os::unix::fs::symlink(target, &moved).unwrap();
+
+    let mut d = Scanner::new();
+    assert_eq!(d.restore_quarantine(&quarantine).unwrap(), 1);
+    assert!(fs::symlink_metadata(&moved).unwrap().is_file());
+    assert_eq!(fs::read(&moved).unwrap(), b"dupe");
+    assert_eq!(fs::read_dir(&quarantine).unwrap().count(), 0);
+    assert!(d.errors().is_empty());
+}
+
+/// Counted the same when the links are made at the end
+#[test]
+fn quarantine_deferred() {
+    let dir = TempDir::new("quarantinedefertest").unwrap();
+    let data = fs::canonicalize(dir.path()).unwrap().join("data");
+    fs::create_dir(&data).unwrap();
+    for name in &["a", "b"] {
+        fs::write(data.join(name), "dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.defer_links = true;
+    d.settings.mode_chain = vec![DedupeMode::Quarantine];
+    d.settings.quarantine_dir = Some(dir.path().join("quarantine"));
+    d.scan(&data).unwrap();
+    assert_eq!(d.stats().quarantined, 1);
+}
+
+#[test]
+fn journal() {
+    let dir = TempDir::new("journaltest").unwrap();
+    let data = dir.path().join("data");
+    let journal = dir.path().join("journal");
+    fs::create_dir(&data).unwrap();
+    for name in &["a", "b", "c"] {
+        fs::write(data.join(name), "dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.journal = Some(journal.clone());
+    d.scan(&data).unwrap();
+    assert_eq!(d.stats().created_hardlinks, 2);
+    let recovery = d.recover_journal(&journal).unwrap();
+    assert_eq!(recovery, JournalRecovery {completed: 2, ..JournalRecovery::default()});
+}
+
+#[cfg(feature = "sync")]
+#[test]
+fn scanner_is_send() {
+    fn assert_send<T: Send>(_: &T) {}
+    let d = Scanner::new();
+    assert_send(&d);
+    ::std::thread::spawn(move || d.dupes()).join().unwrap();
+}
+
+#[test]
+fn set_low_priority() {
+    // On its own thread, since on Linux it's of the thread
+    ::std::thread::spawn(|| {
+        let d = Scanner::new();
+        d.set_low_priority().unwrap();
+        assert_eq!(unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) }, 19);
+    }).join().unwrap();
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn settings_from_config() {
+    let settings: Settings = serde_json::from_str(r#"{
+        "dry_run": true,
+        "min_age": 1.5,
+        "always_consider_extensions": ["json"],
+        "on_immutable": "Error",
+        "mode_chain": ["Reflink", "Hardlink"],
+        "protected_inodes": [[1, 2]]
+    }"#).unwrap();
+    assert!(settings.dry_run);
+    assert_eq!(settings.min_age, Some(::std::time::Duration::from_millis(1500)));
+    assert!(settings.always_consider_extensions.contains(OsStr::new("json")));
+    assert_eq!(settings.on_immutable, OnImmutable::Error);
+    assert_eq!(settings.mode_chain, vec![DedupeMode::Reflink, DedupeMode::Hardlink]);
+    assert!(settings.protected_inodes.contains(&(1, 2)));
+    // The rest are the defaults
+    let defaults = Settings::default();
+    assert_eq!(settings.ignore_small, defaults.ignore_small);
+    assert_eq!(settings.partial_suffixes, defaults.partial_suffixes);
+    assert_eq!(settings.progress_interval, defaults.progress_interval);
+    assert_eq!(settings.snapshot_command, defaults.snapshot_command);
+
+    assert!(serde_json::from_str::<Settings>(r#"{"dry_runn": true}"#).is_err());
+    assert!(serde_json::from_str::<Settings>(r#"{"min_age": -1}"#).is_err());
+}
+
+#[test]
+fn heartbeat() {
+    let dir = TempDir::new("heartbeattest").unwrap();
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("x")).unwrap();
+    for path in &["a", "x/b"] {
+        fs::File::create(root.join(path)).unwrap().write_all(b"dupe").unwrap();
+    }
+    let progress_path = dir.path().join("progress");
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    // Due whenever it's checked
+    d.settings.heartbeat_interval = Some(std::time::Duration::from_secs(0));
+    d.set_listener(Box::new(FdProgressListener::new(fs::File::create(&progress_path).unwrap())));
+    d.scan(&root).unwrap();
+    // Checks after the scan are ignored
+    d.add_with_metadata(root.join("a"), fs::symlink_metadata(root.join("a")).unwrap()).unwrap();
+    drop(d);
+
+    let progress = fs::read_to_string(&progress_path).unwrap();
+    let events: Vec<_> = progress.lines().map(|l| l.split('\t').collect::<Vec<_>>()).collect();
+    let done = events.iter().position(|e| e[0] == "done").unwrap();
+    assert!(events[done..].iter().all(|e| e[0] != "heartbeat"));
+    let heartbeats: Vec<_> = events.iter().filter(|e| e[0] == "heartbeat").collect();
+    assert!(heartbeats.len() >= 2);
+    assert_eq!(heartbeats.last().unwrap()[2], "2");
+}
+
+#[test]
+fn scan_with_manifest() {
+    let dir = TempDir::new("manifesttest").unwrap();
+    for &(name, content) in &[("a", "dupe"), ("b", "dupe"), ("c", "dupe"), ("d", "rotten")] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(content.as_bytes()).unwrap();
+    }
+    let hex = "4356f4252f6d90ead88cb6e0e47f76b0e03fddb883c97458d08be77a2d751b82";
+    let mut dupe_hash = [0; 32];
+    for (i, byte) in dupe_hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
+    }
+    let manifest: std::collections::HashMap<_, _> = ["a", "b", "d", "gone"].iter()
+        .map(|name| (dir.path().join(name), dupe_hash))
+        .collect();
+
+    let mut d = Scanner::new();
+    let stats = d.scan_with_manifest(&manifest).unwrap();
+    assert_eq!(stats.integrity_failures, 1);
+    assert_eq!(stats.created_hardlinks, 1);
+    assert_eq!(d.errors().len(), 1);
+    let links = |name| fs::metadata(dir.path().join(name)).unwrap().nlink();
+    // c isn't in the manifest
+    assert_eq!((links("a"), links("b"), links("c"), links("d")), (2, 2, 1, 1));
+}
+
+#[test]
+fn scan_image_not_an_image() {
+    let dir = TempDir::new("imagetest").unwrap();
+    let image = dir.path().join("image");
+    fs::File::create(&image).unwrap().write_all(b"not a filesystem").unwrap();
+
+    let mut d = Scanner::new();
+    assert!(d.scan_image(&image, "").is_err());
+    assert_eq!(d.stats().added, 0);
+    assert!(!std::env::temp_dir().join(format!("dupe-image-{}", std::process::id())).exists());
+}
+
+#[test]
+fn slow_files() {
+    let dir = TempDir::new("slowtest").unwrap();
+    for name in &["a", "b"] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(b"dupe").unwrap();
+    }
+    let progress_path = dir.path().join("progress");
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    // Every file that's read is slow
+    d.settings.slow_file_threshold = Some(std::time::Duration::from_secs(0));
+    d.set_listener(Box::new(FdProgressListener::new(fs::File::create(&progress_path).unwrap())));
+    d.scan(dir.path()).unwrap();
+    let slow = d.slow_files();
+    assert_eq!(slow.len(), 2);
+    assert!(slow[0].1 >= slow[1].1);
+    drop(d);
+
+    let progress = fs::read_to_string(&progress_path).unwrap();
+    assert_eq!(progress.lines().filter(|l| l.starts_with("slow\t")).count(), 2);
+}
+
+#[test]
+fn never_link_names() {
+    let dir = TempDir::new("namestest").unwrap();
+    for path in &["a/LICENSE", "b/LICENSE", "a/x", "b/x"] {
+        let path = dir.path().join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::File::create(&path).unwrap().write_all(if path.ends_with("x") {b"same"} else {b"dupe"}).unwrap();
+    }
+    let links = |path| fs::metadata(dir.path().join(path)).unwrap().nlink();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().dupes, 2);
+    assert_eq!(d.stats().protected_name_skipped, 1);
+    assert_eq!((links("a/LICENSE"), links("a/x")), (1, 2));
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.never_link_names.clear();
+    d.scan(dir.path()).unwrap();
+    assert_eq!(links("a/LICENSE"), 2);
+}
+
+#[test]
+fn hash_xattr() {
+    let dir = TempDir::new("xattrtest").unwrap();
+    for &(name, content) in &[("a", "dupe"), ("b", "dupe"), ("c", "diff")] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(content.as_bytes()).unwrap();
+    }
+    let probe = std::ffi::CString::new(dir.path().join("c").into_os_string().into_encoded_bytes()).unwrap();
+    if unsafe { libc::setxattr(probe.as_ptr(), b"user.probe\0".as_ptr() as *const libc::c_char, std::ptr::null(), 0, 0) } != 0 {
+        // The filesystem doesn't have user xattrs
+        return;
+    }
+
+    let scanner = || {
+        let mut d = Scanner::new();
+        d.settings.ignore_small = false;
+        d.settings.dry_run = true;
+        d.settings.hash_xattr = Some("user.dupe.sha1".to_owned());
+        d.settings.write_hash_xattr = true;
+        d
+    };
+    let mut d = scanner();
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().dupes, 1);
+    assert_eq!(d.stats().xattr_digests, 0);
+
+    let mut d = scanner();
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().dupes, 1);
+    assert_eq!(d.stats().xattr_digests, 3);
+    assert_eq!(d.stats().bytes_read, 0);
+}
+
+#[test]
+fn strict() {
+    let dir = TempDir::new("stricttest").unwrap();
+    let gone = dir.path().join("gone");
+
+    let mut d = Scanner::new();
+    d.enqueue_lenient(&gone).unwrap();
+    assert_eq!(d.errors().len(), 1);
+
+    let mut d = Scanner::new();
+    d.settings.strict = true;
+    let err = d.enqueue_lenient(&gone).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    assert!(err.to_string().contains("gone"));
+    assert!(d.errors().is_empty());
+}
+
+#[test]
+fn bucket_by_parent() {
+    let dir = TempDir::new("buckettest").unwrap();
+    for path in &["ab/1", "ab/2", "cd/3"] {
+        let path = dir.path().join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::File::create(&path).unwrap().write_all(b"dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.settings.bucket_by_parent = true;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.stats().dupes, 1);
+    assert!(d.stats().cross_bucket_skipped >= 1);
+    let groups: Vec<_> = d.dupes().into_iter().filter(|f| f.paths.len() > 1).collect();
+    assert_eq!(groups.len(), 1);
+    assert!(groups[0].paths.iter().all(|p| p.parent() == Some(&dir.path().join("ab"))));
+
+    d.settings.bucket_by_parent = false;
+    assert!(d.scan(dir.path()).is_err());
+}
+
+#[test]
+fn incremental_scan() {
+    let dir = TempDir::new("incrementaltest").unwrap();
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("old")).unwrap();
+    fs::create_dir_all(root.join("new")).unwrap();
+    for path in &["old/a", "old/b"] {
+        fs::File::create(root.join(path)).unwrap().write_all(b"dupe").unwrap();
+    }
+    let snapshot_path = dir.path().join("snapshot");
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.incremental_scan(&root, &snapshot_path).unwrap();
+    assert_eq!(d.stats().created_hardlinks, 1);
+    assert_eq!(d.stats().unchanged_dirs, 0);
+
+    for path in &["new/c", "new/d"] {
+        fs::File::create(root.join(path)).unwrap().write_all(b"dupe").unwrap();
+    }
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.incremental_scan(&root, &snapshot_path).unwrap();
+    // The root and old are unchanged
+    assert_eq!(d.stats().unchanged_dirs, 2);
+    assert_eq!(d.stats().added, 2);
+    assert_eq!(d.stats().created_hardlinks, 1);
+    assert_eq!(fs::metadata(root.join("new/c")).unwrap().nlink(), 2);
+}
+
+#[test]
+fn incremental_scan_cache() {
+    let dir = TempDir::new("incrementalcachetest").unwrap();
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("old")).unwrap();
+    fs::create_dir_all(root.join("new")).unwrap();
+    fs::write(root.join("old/a"), "dupe").unwrap();
+    let (snapshot_path, cache_path) = (dir.path().join("snapshot"), dir.path().join("cache"));
+
+    let scan = || {
+        let mut d = Scanner::new();
+        d.settings.ignore_small = false;
+        d.load_cache(&cache_path).unwrap();
+        d.incremental_scan(&root, &snapshot_path).unwrap();
+        d.save_cache(&cache_path).unwrap();
+        d
+    };
+    assert_eq!(scan().stats().added, 1);
+
+    // A new copy of a file in an unchanged directory
+    fs::write(root.join("new/b"), "dupe").unwrap();
+    let d = scan();
+    assert_eq!(d.stats().unchanged_dirs, 2);
+    assert_eq!(d.stats().seeded_files, 1);
+    assert_eq!(d.stats().created_hardlinks, 1);
+    assert_eq!(fs::metadata(root.join("old/a")).unwrap().nlink(), 2);
+}
+
+#[test]
+fn cleanup_worklist() {
+    let dir = TempDir::new("worklisttest").unwrap();
+    for &(name, content) in &[("a", "small"), ("b", "small"), ("c", "bigger file"), ("d", "bigger file"), ("e", "bigger file"), ("f", "unique")] {
+        fs::File::create(dir.path().join(name)).unwrap().write_all(content.as_bytes()).unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.scan(dir.path()).unwrap();
+    let worklist = d.cleanup_worklist();
+    assert_eq!(worklist.len(), 2);
+    assert_eq!((worklist[0].size, worklist[0].remove.len(), worklist[0].reclaimable_bytes), (11, 2, 22));
+    assert_eq!((worklist[1].size, worklist[1].remove.len(), worklist[1].reclaimable_bytes), (5, 1, 5));
+    assert!(!worklist[0].remove.contains(&worklist[0].keep));
+    assert_ne!(worklist[0].group_id, worklist[1].group_id);
+    assert_eq!(worklist[0].group_id.as_ref().unwrap().len(), 64);
+
+    // Linked, there's nothing left to clean up
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.scan(dir.path()).unwrap();
+    assert!(d.cleanup_worklist().is_empty());
+}
+
+#[test]
+fn report() {
+    let dir = TempDir::new("reporttest").unwrap();
+    for &(name, content) in &[("a", "small"), ("b", "small"), ("c", "bigger file"), ("d", "bigger file"), ("e", "bigger file"), ("f", "unique")] {
+        fs::write(dir.path().join(name), content).unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.scan(dir.path()).unwrap();
+    let report = d.report();
+    assert_eq!(report.len(), 2);
+    assert_eq!((report[0].file_size, report[0].paths.len(), report[0].wasted_bytes), (11, 3, 22));
+    assert_eq!((report[1].file_size, report[1].paths.len(), report[1].wasted_bytes), (5, 2, 5));
+    assert!(report[1].paths.contains(&dir.path().join("a")));
+}
+
+#[test]
+fn packing_candidates() {
+    let dir = TempDir::new("packtest").unwrap();
+    for i in 0..MIN_PACK_FILES {
+        fs::write(dir.path().join(format!("tiny{}", i)), "tiny").unwrap();
+    }
+    for i in 0..MIN_PACK_FILES - 1 {
+        fs::write(dir.path().join(format!("other{}", i)), "other").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.dry_run = true;
+    d.scan(dir.path()).unwrap();
+    let candidates = d.packing_candidates();
+    assert_eq!(candidates.len(), 1);
+    assert_eq!((candidates[0].size, candidates[0].paths.len(), candidates[0].inodes), (4, MIN_PACK_FILES, MIN_PACK_FILES));
+}
+
+#[test]
+fn similar_files() {
+    let dir = TempDir::new("similartest").unwrap();
+    // Words from a fixed pseudo-random sequence
+    let mut state = 12345u32;
+    let mut words = |n: usize| -> String {
+        (0..n).map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            format!("w{} ", state >> 20)
+        }).collect()
+    };
+    let original = words(3000);
+    let mut edited = original.clone();
+    edited.insert_str(original.len() / 2, "a few new words ");
+    fs::write(dir.path().join("a"), &original).unwrap();
+    fs::write(dir.path().join("b"), &edited).unwrap();
+    fs::write(dir.path().join("c"), words(3000)).unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.dry_run = true;
+    d.scan(dir.path()).unwrap();
+    let similar = d.similar_files(50);
+    assert_eq!(similar.len(), 1);
+    assert_eq!((similar[0].0.file_name().unwrap(), similar[0].1.file_name().unwrap()), ("a".as_ref(), "b".as_ref()));
+    assert!(similar[0].2 >= 80);
+}
+
+#[test]
+fn compressed_duplicates() {
+    let dir = TempDir::new("gziptest").unwrap();
+    let content = b"some text that compresses well, some text that compresses well\n".repeat(10);
+    fs::File::create(dir.path().join("raw")).unwrap().write_all(&content).unwrap();
+    fs::File::create(dir.path().join("other")).unwrap().write_all(&content[1..]).unwrap();
+    let gz = std::process::Command::new("gzip").arg("-c").arg(dir.path().join("raw")).output().unwrap();
+    // Another name, found by content
+    fs::File::create(dir.path().join("archived")).unwrap().write_all(&gz.stdout).unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(d.compressed_duplicates(), vec![(dir.path().join("raw"), dir.path().join("archived"))]);
+
+    d.settings.max_decompressed_size = 100;
+    assert!(d.compressed_duplicates().is_empty());
+}